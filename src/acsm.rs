@@ -1,19 +1,202 @@
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
 use log::{debug, info, warn};
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
 use std::{
-    path::Path,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::fs;
 
-#[derive(Debug, Deserialize)]
+use crate::{backups, compression, error_reporting, s3, sandbox, sftp, telemetry};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BasicDriver {
     pub name: String,
     pub car: String,
     pub steam_id: u64,
     pub team_name: Option<String>,
+    /// Put the driver in the class with this name, instead of the first class
+    /// that has the car
+    #[serde(default)]
+    pub class: Option<String>,
+    /// Raw skill metadata, for routing the driver to a class
+    #[serde(default)]
+    pub skill: Option<String>,
+    /// For balancing splits, only the ordering matters
+    #[serde(default)]
+    pub pace: Option<f64>,
+    /// ISO 3166-1 alpha-3 country code
+    #[serde(default)]
+    pub nation: Option<String>,
+    /// Where the driver came from in Eventix, not set for manual drivers
+    #[serde(default)]
+    pub order_guid: Option<String>,
+    #[serde(default)]
+    pub ticket_guid: Option<String>,
+    /// When the order was paid, which decides who gets a slot first
+    #[serde(default)]
+    pub paid_at: Option<DateTime<Utc>>,
+    /// Steam IDs of drivers from add-on tickets, who share the slot
+    #[serde(default)]
+    pub co_drivers: Vec<u64>,
+    /// May take a slot from RESERVED_SLOTS, like manual drivers and ticket
+    /// types that say so in the ticket map
+    #[serde(default)]
+    pub reserved: bool,
+    /// Goes ahead of the waitlist, and of drivers on the grid without it
+    #[serde(default)]
+    pub priority: bool,
+    /// From the ticket map
+    #[serde(default)]
+    pub entry: EntrySettings,
+    /// Of the buyer, to send results to. Never written anywhere.
+    #[serde(default, skip_serializing)]
+    pub email: Option<String>,
+}
+
+/// Settings for the driver's entry beyond the car, left as they are in the
+/// slot when not set
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EntrySettings {
+    /// In kg
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ballast: Option<u32>,
+    /// In percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restrictor: Option<u32>,
+    /// To pick a skin from that no other entrant in the class has
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skins: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub race_numbers: Option<RaceNumbers>,
+    /// Setup file the driver has to use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_setup: Option<String>,
+}
+
+/// Race numbers a driver can get, both included
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct RaceNumbers {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl RaceNumbers {
+    fn contains(&self, number: u64) -> bool {
+        (self.first..=self.last).contains(&number)
+    }
+}
+
+impl BasicDriver {
+    /// Whether the driver belongs in this class. It has to have the driver's
+    /// car, and the right name if the driver was routed to a specific class.
+    fn fits_class(&self, class: &Value) -> bool {
+        let has_car = class["AvailableCars"]
+            .as_array()
+            .is_some_and(|cars| cars.iter().any(|car| car.as_str() == Some(&self.car)));
+        has_car
+            && self
+                .class
+                .as_ref()
+                .is_none_or(|name| class["Name"].as_str() == Some(name))
+    }
+
+    /// A driver with only what every driver has, for tests to set the rest
+    /// of with `..BasicDriver::test(steam_id, car)`
+    #[cfg(test)]
+    pub fn test(steam_id: u64, car: &str) -> BasicDriver {
+        serde_json::from_value(serde_json::json!({
+            "name": format!("Driver {}", steam_id),
+            "car": car,
+            "steam_id": steam_id,
+            "team_name": null,
+        }))
+        .unwrap()
+    }
+}
+
+/// Fields a driver's entry always gets from us, so they can't have a default
+const DRIVER_FIELDS: [&str; 4] = ["GUID", "Name", "Team", "Nation"];
+
+/// Per class name, entrant fields like `SpectatorMode` or `FixedSetup` to set
+/// when a driver goes in an empty slot, instead of whatever the slot had
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct EntrantDefaults(HashMap<String, Map<String, Value>>);
+
+impl EntrantDefaults {
+    /// From EMPTY_SLOT_DEFAULTS
+    pub fn from_env() -> Result<EntrantDefaults> {
+        match dotenv::var("EMPTY_SLOT_DEFAULTS") {
+            Ok(text) if !text.is_empty() => EntrantDefaults::parse(&text),
+            _ => Ok(EntrantDefaults::default()),
+        }
+    }
+
+    fn parse(text: &str) -> Result<EntrantDefaults> {
+        let defaults: EntrantDefaults = serde_json::from_str(text)
+            .context("EMPTY_SLOT_DEFAULTS is not a JSON object of class names to fields")?;
+        for (class_name, fields) in &defaults.0 {
+            if let Some(field) = fields
+                .keys()
+                .find(|field| DRIVER_FIELDS.contains(&field.as_str()))
+            {
+                return Err(anyhow!(
+                    "EMPTY_SLOT_DEFAULTS can't set {} for class {}",
+                    field,
+                    class_name
+                ));
+            }
+        }
+        Ok(defaults)
+    }
+
+    pub fn class_names(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    fn apply(&self, class_name: &str, entrant: &mut Value) {
+        for (field, value) in self.0.get(class_name).into_iter().flatten() {
+            entrant[field] = value.clone();
+        }
+    }
+}
+
+/// Between the Steam IDs of drivers sharing an entry
+const GUID_SEPARATOR: &str = ";";
+
+/// The Steam ID of the entrant's main driver, without co-drivers
+fn main_guid(entrant: &Value) -> &str {
+    let guid = entrant["GUID"].as_str().unwrap_or_default();
+    guid.split(GUID_SEPARATOR).next().unwrap_or(guid)
+}
+
+/// The slots of a class, to work out where drivers will fit before writing
+#[derive(Debug)]
+pub struct ClassSlots {
+    pub name: String,
+    pub cars: Vec<String>,
+    /// Main GUID of every entrant, empty for free slots
+    pub guids: Vec<String>,
+}
+
+impl ClassSlots {
+    /// Same as `BasicDriver::fits_class`
+    pub fn fits(&self, driver: &BasicDriver) -> bool {
+        self.cars.contains(&driver.car)
+            && driver.class.as_ref().is_none_or(|name| name == &self.name)
+    }
+
+    pub fn free_slots(&self) -> usize {
+        self.guids.iter().filter(|guid| guid.is_empty()).count()
+    }
 }
 
 async fn get_modified_time(path: &Path) -> Result<SystemTime> {
@@ -24,59 +207,149 @@ async fn get_modified_time(path: &Path) -> Result<SystemTime> {
         .context("last modified time not available on this platform")
 }
 
-async fn read_json_file(json_file: &Path) -> Result<(Value, SystemTime)> {
-    // To minimize chances of ACSM messing things up while we read/write, get
-    // the modification time of the file before we start. If at any point this
-    // changes, we restart the process.
-    let last_modified = get_modified_time(json_file).await?;
-    // Read file to JSON Value
-    let json_text = fs::read_to_string(json_file)
-        .await
-        .context("Reading JSON file")?;
-    let data: Value = serde_json::from_str(&json_text)?;
-    // Check if the file has been modified since we started
-    if last_modified != get_modified_time(json_file).await? {
-        warn!(
-            "File {} modified while reading, restarting add/update process",
-            json_file.display()
-        );
-        return Err(anyhow!("JSON file modified while reading"));
+/// Where an ACSM file is, on this machine or on a server reachable over SFTP
+enum Storage {
+    Local,
+    Sftp(Box<sftp::Connection>),
+}
+
+impl Storage {
+    async fn open(json_file: &Path) -> Result<Storage> {
+        match sftp::Location::parse(json_file) {
+            None => Ok(Storage::Local),
+            Some(location) => Ok(Storage::Sftp(Box::new(
+                sftp::Connection::open(&location?).await?,
+            ))),
+        }
+    }
+
+    async fn modified(&mut self, path: &Path) -> Result<SystemTime> {
+        match self {
+            Storage::Local => get_modified_time(path).await,
+            Storage::Sftp(connection) => connection.modified(path).await,
+        }
+    }
+
+    async fn read(&mut self, path: &Path) -> Result<String> {
+        match self {
+            Storage::Local => fs::read_to_string(path).await.context("Reading JSON file"),
+            Storage::Sftp(connection) => connection.read(path).await,
+        }
+    }
+
+    async fn write(&mut self, path: &Path, text: &str) -> Result<()> {
+        match self {
+            Storage::Local => Ok(fs::write(path, text).await?),
+            Storage::Sftp(connection) => connection.write(path, text).await,
+        }
+    }
+
+    async fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        match self {
+            Storage::Local => Ok(fs::rename(from, to).await?),
+            Storage::Sftp(connection) => connection.rename(from, to).await,
+        }
+    }
+
+    async fn remove(&mut self, path: &Path) -> Result<()> {
+        match self {
+            Storage::Local => Ok(fs::remove_file(path).await?),
+            Storage::Sftp(connection) => connection.remove(path).await,
+        }
+    }
+
+    async fn close(self) -> Result<()> {
+        match self {
+            Storage::Local => Ok(()),
+            Storage::Sftp(connection) => (*connection).close().await,
+        }
     }
-    Ok((data, last_modified))
 }
 
-async fn write_json_file(json_file: &Path, data: &Value, last_modified: SystemTime) -> Result<()> {
-    // Check if the file has been modified since we started
-    if last_modified != get_modified_time(json_file).await? {
-        warn!("File {} modified while updating data", json_file.display());
-        return Err(anyhow!("JSON file modified while updating data"));
+/// What the file was when we read it, to only replace it if it still is
+enum Version {
+    /// The SHA-256 of the contents, as modification times are too coarse on
+    /// some filesystems, and ACSM rewrites files without changing them. When it
+    /// was modified is only for naming the backup.
+    Contents { hash: String, modified: SystemTime },
+    /// In S3, where writes are conditional on it instead
+    ETag(String),
+}
+
+fn hash(json_text: &str) -> String {
+    hex::encode(Sha256::digest(json_text.as_bytes()))
+}
+
+async fn read_json_file(json_file: &Path) -> Result<(Value, Version)> {
+    if let Some(object) = s3::Object::parse(json_file) {
+        let (json_text, etag) = object?.get().await?;
+        return Ok((serde_json::from_str(&json_text)?, Version::ETag(etag)));
     }
+    let mut storage = Storage::open(json_file).await?;
+    let modified = storage.modified(json_file).await?;
+    let json_text = storage.read(json_file).await?;
+    let data: Value = serde_json::from_str(&json_text)?;
+    storage.close().await?;
+    let hash = hash(&json_text);
+    Ok((data, Version::Contents { hash, modified }))
+}
+
+/// Locally, the backup goes where BACKUP_DIR and BACKUP_FILENAME say, compressed
+/// with BACKUP_COMPRESSION. Over SFTP this uploads the new file next to the old
+/// one and renames it into place, leaving a backup next to it. In S3 it copies
+/// the old object to a backup and replaces it, both only if its ETag didn't
+/// change. With SANDBOX on, nothing is written.
+async fn write_json_file(json_file: &Path, data: &Value, version: Version) -> Result<()> {
+    if sandbox::is_on() {
+        info!("[SANDBOX] Not writing {}", json_file.display());
+        return Ok(());
+    }
+    let (read_hash, last_modified) = match version {
+        Version::Contents { hash, modified } => (hash, modified),
+        Version::ETag(etag) => {
+            let object = s3::Object::parse(json_file).context("Not in S3")??;
+            return object
+                .put(serde_json::to_string_pretty(&data)?, &etag)
+                .await;
+        }
+    };
+    let mut storage = Storage::open(json_file).await?;
     // Write the file back out, to a temporary file first
     let random_extension = radix_fmt::radix(rand::random::<u64>(), 36).to_string();
     let mut tmp_filename = json_file.as_os_str().to_os_string();
     tmp_filename.push(".");
     tmp_filename.push(random_extension);
     let new_json_text = serde_json::to_string_pretty(&data)?;
-    fs::write(&tmp_filename, new_json_text).await?;
+    let tmp_filename = Path::new(&tmp_filename);
+    storage.write(tmp_filename, &new_json_text).await?;
 
-    // Check if the file has been modified since we started
-    if last_modified != get_modified_time(json_file).await? {
-        warn!(
-            "File {} modified while writing temporary file",
-            json_file.display()
-        );
-        return Err(anyhow!("JSON file modified while writing temporary file"));
+    // Check if the file has been changed since we read it, as late as we can
+    if hash(&storage.read(json_file).await?) != read_hash {
+        warn!("File {} modified while updating data", json_file.display());
+        storage.remove(tmp_filename).await?;
+        return Err(anyhow!("JSON file modified while updating data"));
     }
 
-    // If it has not changed, rename the original to a backup name, and then the
+    // If it has not changed, move the original to a backup, and then rename the
     // temporary file to the original file
-    let mut backup_filename = json_file.as_os_str().to_os_string();
-    backup_filename.push(".backup_");
-    let since_epoch = last_modified.duration_since(UNIX_EPOCH).unwrap();
-    backup_filename.push(&format!("{}", since_epoch.as_secs()));
-    let backup_filename = Path::new(&backup_filename);
-    fs::rename(json_file, &backup_filename).await?;
-    fs::rename(tmp_filename, json_file).await?;
+    let backup_filename = match storage {
+        Storage::Local => backups::take(json_file, last_modified).await?,
+        Storage::Sftp(_) => {
+            let mut backup_filename = json_file.as_os_str().to_os_string();
+            backup_filename.push(".backup_");
+            let since_epoch = last_modified.duration_since(UNIX_EPOCH).unwrap();
+            backup_filename.push(format!("{}", since_epoch.as_secs()));
+            let backup_filename = PathBuf::from(backup_filename);
+            storage.rename(json_file, &backup_filename).await?;
+            backup_filename
+        }
+    };
+    storage.rename(tmp_filename, json_file).await?;
+    let backup_filename = match &storage {
+        Storage::Local => compression::compress_backup(&backup_filename).await?,
+        Storage::Sftp(_) => backup_filename,
+    };
+    storage.close().await?;
     info!(
         "Update complete, backup file: {}",
         backup_filename.display()
@@ -84,6 +357,82 @@ async fn write_json_file(json_file: &Path, data: &Value, last_modified: SystemTi
     Ok(())
 }
 
+/// The whole file, for a snapshot
+pub async fn read_grid(json_file: &Path) -> Result<Value> {
+    Ok(read_json_file(json_file).await?.0)
+}
+
+/// Put back a snapshot of the whole file, returning whether that changed it
+pub async fn restore_grid(json_file: &Path, data: &Value) -> Result<bool> {
+    let (current, version) = read_json_file(json_file).await?;
+    if current == *data {
+        return Ok(false);
+    }
+    write_json_file(json_file, data, version).await?;
+    info!("Restored snapshot of {}", json_file.display());
+    Ok(true)
+}
+
+pub async fn class_slots(json_file: &Path) -> Result<Vec<ClassSlots>> {
+    let (data, _) = read_json_file(json_file).await?;
+    data.get("Classes")
+        .context("Classes not found in JSON")?
+        .as_array()
+        .context("Classes is not an array")?
+        .iter()
+        .map(|class| {
+            Ok(ClassSlots {
+                name: class["Name"].as_str().unwrap_or_default().to_string(),
+                cars: class["AvailableCars"]
+                    .as_array()
+                    .context("AvailableCars is not an array")?
+                    .iter()
+                    .filter_map(|car| car.as_str().map(str::to_string))
+                    .collect(),
+                guids: class["Entrants"]
+                    .as_object()
+                    .context("Entrants is not an object")?
+                    .values()
+                    .map(|entrant| main_guid(entrant).to_string())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+pub async fn class_names(json_file: &Path) -> Result<Vec<String>> {
+    let (data, _) = read_json_file(json_file).await?;
+    data.get("Classes")
+        .context("Classes not found in JSON")?
+        .as_array()
+        .context("Classes is not an array")?
+        .iter()
+        .map(|class| {
+            Ok(class["Name"]
+                .as_str()
+                .context("Class Name is not a string")?
+                .to_string())
+        })
+        .collect()
+}
+
+/// The first available car of the class with this name
+pub async fn car_for_class(json_file: &Path, class_name: &str) -> Result<String> {
+    let (data, _) = read_json_file(json_file).await?;
+    let class = data
+        .get("Classes")
+        .context("Classes not found in JSON")?
+        .as_array()
+        .context("Classes is not an array")?
+        .iter()
+        .find(|class| class["Name"] == class_name)
+        .with_context(|| format!("No class named {}", class_name))?;
+    Ok(class["AvailableCars"][0]
+        .as_str()
+        .with_context(|| format!("Class {} has no available cars", class_name))?
+        .to_string())
+}
+
 async fn delete_missing_drivers(
     data: &mut Value,
     drivers: &[BasicDriver],
@@ -97,37 +446,34 @@ async fn delete_missing_drivers(
         .context("Classes is not an array")?;
     // Go through each class
     for class in classes {
-        let available_cars = class
+        class
             .get("AvailableCars")
             .context("AvailableCars not found in class")?
             .as_array()
-            .context("AvailableCars is not an array")?
+            .context("AvailableCars is not an array")?;
+        let fitting_drivers = drivers
             .iter()
-            .map(|car| {
-                Ok(car
-                    .as_str()
-                    .context("Contents of AvailableCars is not all Strings")?
-                    .to_string())
-            })
-            .collect::<Result<Vec<_>>>()?;
+            .filter(|driver| driver.fits_class(class))
+            .collect::<Vec<_>>();
         let entrants = class["Entrants"].as_object_mut().unwrap();
         // Go through each entrant
         for (_slot, entrant) in entrants.iter_mut() {
             // Check if the entrant is in the list of drivers
-            let steam_id = entrant["GUID"].as_str().unwrap();
+            let steam_id = main_guid(entrant);
             if steam_id.is_empty() {
                 continue;
             }
-            let steam_id = entrant["GUID"].as_str().unwrap().parse::<u64>().unwrap();
+            let steam_id = steam_id.parse::<u64>().unwrap();
             // Check if the entrant is in the list of ignored steam ids
             if ignored_steam_ids.contains(&steam_id) {
                 continue;
             }
-            // If there's any driver that has the Steam ID and has a car in the
+            // If there's any driver that has the Steam ID and belongs in the
             // current class, then we don't delete it
-            if drivers.iter().any(|driver| {
-                driver.steam_id == steam_id && available_cars.iter().any(|car| car == &driver.car)
-            }) {
+            if fitting_drivers
+                .iter()
+                .any(|driver| driver.steam_id == steam_id)
+            {
                 continue;
             }
             // Otherwise, delete it
@@ -142,24 +488,247 @@ async fn delete_missing_drivers(
                     format!(" team_name={}", entrant["Team"])
                 }
             );
-            entrant["Name"] = "".into();
-            entrant["Team"] = "".into();
-            entrant["GUID"] = "".into();
+            clear_entrant(entrant);
         }
     }
     Ok(())
 }
 
+fn clear_entrant(entrant: &mut Value) {
+    entrant["Name"] = "".into();
+    entrant["Team"] = "".into();
+    entrant["GUID"] = "".into();
+    if entrant.get("Nation").is_some() {
+        entrant["Nation"] = "".into();
+    }
+}
+
+/// Take the Steam ID out of every entrant, clearing the entrant if it's the
+/// main driver. Returns whether it was in any.
+pub fn scrub_steam_id(data: &mut Value, steam_id: &str) -> Result<bool> {
+    let mut found = false;
+    for class in data
+        .get_mut("Classes")
+        .context("Classes not found in JSON")?
+        .as_array_mut()
+        .context("Classes is not an array")?
+    {
+        for entrant in class["Entrants"]
+            .as_object_mut()
+            .context("Entrants is not an object")?
+            .values_mut()
+        {
+            let guids: Vec<&str> = entrant["GUID"]
+                .as_str()
+                .unwrap_or_default()
+                .split(GUID_SEPARATOR)
+                .collect();
+            if !guids.contains(&steam_id) {
+                continue;
+            }
+            found = true;
+            if guids[0] == steam_id {
+                clear_entrant(entrant);
+            } else {
+                entrant["GUID"] = guids
+                    .iter()
+                    .filter(|guid| **guid != steam_id)
+                    .join(GUID_SEPARATOR)
+                    .into();
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Take the driver off the grid, also as a co-driver, returning whether it was
+/// on it
+pub async fn scrub_driver(json_file: &Path, steam_id: u64) -> Result<bool> {
+    let (mut data, version) = read_json_file(json_file).await?;
+    if !scrub_steam_id(&mut data, &steam_id.to_string())? {
+        return Ok(false);
+    }
+    write_json_file(json_file, &data, version).await?;
+    Ok(true)
+}
+
+/// Take the driver out of a backup, compressed or not, returning whether it was
+/// in there
+pub async fn scrub_backup(backup: &Path, steam_id: u64) -> Result<bool> {
+    let mut data: Value = serde_json::from_str(&compression::read_backup(backup).await?)?;
+    if !scrub_steam_id(&mut data, &steam_id.to_string())? {
+        return Ok(false);
+    }
+    compression::write_backup(backup, &serde_json::to_string_pretty(&data)?).await?;
+    Ok(true)
+}
+
+/// Take the driver off the grid, returning whether it was on it
+pub async fn remove_driver(json_file: &Path, steam_id: u64) -> Result<bool> {
+    let (mut data, version) = read_json_file(json_file).await?;
+    let steam_id = steam_id.to_string();
+    let mut removed = false;
+    for class in data
+        .get_mut("Classes")
+        .context("Classes not found in JSON")?
+        .as_array_mut()
+        .context("Classes is not an array")?
+    {
+        for entrant in class["Entrants"]
+            .as_object_mut()
+            .context("Entrants is not an object")?
+            .values_mut()
+        {
+            if main_guid(entrant) == steam_id {
+                info!(
+                    "Removing driver: {} steam_id={} from {}",
+                    entrant["Name"],
+                    steam_id,
+                    json_file.display()
+                );
+                clear_entrant(entrant);
+                removed = true;
+            }
+        }
+    }
+    if removed {
+        write_json_file(json_file, &data, version).await?;
+    }
+    Ok(removed)
+}
+
 async fn update_drivers_inner(
     delete_missing: bool,
     json_file: &Path,
     drivers: &[BasicDriver],
     ignored_steam_ids: &[u64],
+    entrant_defaults: &EntrantDefaults,
+) -> Result<bool> {
+    let (mut data, version) = read_json_file(json_file).await?;
+    let before = data.clone();
+    apply_drivers(
+        &mut data,
+        delete_missing,
+        drivers,
+        ignored_steam_ids,
+        entrant_defaults,
+    )
+    .await?;
+    write_json_file(json_file, &data, version).await?;
+    Ok(data != before)
+}
+
+/// An occupied slot, as written to the file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Entrant {
+    pub class: String,
+    /// With co-drivers, if any
+    pub guid: String,
+    pub name: String,
+    pub team: String,
+    pub nation: String,
+}
+
+impl Entrant {
+    pub fn main_guid(&self) -> &str {
+        self.guid.split(GUID_SEPARATOR).next().unwrap_or(&self.guid)
+    }
+}
+
+fn entrants(data: &Value) -> Vec<Entrant> {
+    let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+    data["Classes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|class| {
+            class["Entrants"]
+                .as_object()
+                .into_iter()
+                .flat_map(|entrants| entrants.values())
+                .filter(|entrant| !main_guid(entrant).is_empty())
+                .map(|entrant| Entrant {
+                    class: text(&class["Name"]),
+                    guid: text(&entrant["GUID"]),
+                    name: text(&entrant["Name"]),
+                    team: text(&entrant["Team"]),
+                    nation: text(&entrant["Nation"]),
+                })
+        })
+        .collect()
+}
+
+/// The occupied slots of every class in the file
+pub async fn read_entrants(json_file: &Path) -> Result<Vec<Entrant>> {
+    let (data, _) = read_json_file(json_file).await?;
+    Ok(entrants(&data))
+}
+
+/// The entrants now and after adding/updating the drivers, without writing
+pub async fn preview_drivers(
+    delete_missing: bool,
+    json_file: &Path,
+    drivers: &[BasicDriver],
+    ignored_steam_ids: &[u64],
+    entrant_defaults: &EntrantDefaults,
+) -> Result<(Vec<Entrant>, Vec<Entrant>)> {
+    let (mut data, _) = read_json_file(json_file).await?;
+    let before = entrants(&data);
+    apply_drivers(
+        &mut data,
+        delete_missing,
+        drivers,
+        ignored_steam_ids,
+        entrant_defaults,
+    )
+    .await?;
+    Ok((before, entrants(&data)))
+}
+
+/// A skin from the pool for the slot, the first one no other entrant of the
+/// class has, unless the slot already has one of those. `None` to leave the
+/// slot's skin.
+fn pick_skin(entrants: &Map<String, Value>, slot: &str, skins: &[String]) -> Option<String> {
+    if skins.is_empty() {
+        return None;
+    }
+    let taken: Vec<_> = entrants
+        .iter()
+        .filter(|(other, entrant)| *other != slot && !main_guid(entrant).is_empty())
+        .filter_map(|(_, entrant)| entrant["Skin"].as_str())
+        .collect();
+    let current = entrants[slot]["Skin"].as_str().unwrap_or_default();
+    if skins.iter().any(|skin| skin == current) && !taken.contains(&current) {
+        return None;
+    }
+    skins
+        .iter()
+        .find(|skin| !taken.contains(&skin.as_str()))
+        .or(skins.first())
+        .cloned()
+}
+
+async fn apply_drivers(
+    data: &mut Value,
+    delete_missing: bool,
+    drivers: &[BasicDriver],
+    ignored_steam_ids: &[u64],
+    entrant_defaults: &EntrantDefaults,
 ) -> Result<()> {
-    let (mut data, last_modified) = read_json_file(json_file).await?;
     if delete_missing {
-        delete_missing_drivers(&mut data, drivers, ignored_steam_ids).await?;
+        delete_missing_drivers(data, drivers, ignored_steam_ids).await?;
     }
+    // Race numbers are per file, not per class
+    let mut used_race_numbers: HashSet<u64> = data["Classes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|class| class["Entrants"].as_object())
+        .flat_map(|entrants| entrants.values())
+        .filter(|entrant| !main_guid(entrant).is_empty())
+        .filter_map(|entrant| entrant["RaceNumber"].as_u64())
+        .filter(|number| *number != 0)
+        .collect();
     // Get the classes array
     let classes = data
         .get_mut("Classes")
@@ -169,6 +738,10 @@ async fn update_drivers_inner(
     // Go through each supplied driver and update them, or add them to the
     // correct class
     for driver in drivers {
+        if ignored_steam_ids.contains(&driver.steam_id) {
+            debug!("Not adding ignored driver steam_id={}", driver.steam_id);
+            continue;
+        }
         debug!(
             "Adding driver: {} steam_id={} car={}{}",
             driver.name,
@@ -182,56 +755,144 @@ async fn update_drivers_inner(
         );
         let class = classes
             .iter_mut()
-            .find(|class| {
-                class
-                    .get("AvailableCars")
-                    .context("AvailableCars not found in class")
-                    .unwrap()
-                    .as_array()
-                    .context("AvailableCars is not an array")
-                    .unwrap()
-                    .contains(&driver.car.clone().into())
-            })
-            .unwrap_or_else(|| panic!("Can't find class with car: {}", driver.car));
+            .find(|class| driver.fits_class(class))
+            .unwrap_or_else(|| {
+                panic!(
+                    "Can't find class{} with car: {}",
+                    driver
+                        .class
+                        .as_ref()
+                        .map(|name| format!(" {}", name))
+                        .unwrap_or_default(),
+                    driver.car
+                )
+            });
+        let class_name = class["Name"].as_str().unwrap_or_default().to_string();
         let entrants = class["Entrants"].as_object_mut().unwrap();
         // Check by steam id if the driver is already there
         let steam_id_str = driver.steam_id.to_string();
-        let mut entry_slot = entrants.iter_mut().find_map(|(_, entrant)| {
-            if entrant["GUID"] == steam_id_str {
+        let mut slot = entrants.iter().find_map(|(slot, entrant)| {
+            if main_guid(entrant) == steam_id_str {
                 debug!("Updating existing driver by steam_id={}", driver.steam_id);
-                Some(entrant)
+                Some(slot.clone())
             } else {
                 None
             }
         });
+        let is_new = slot.is_none();
         // If not, get empty slot (which should be by empty GUID)
-        if entry_slot.is_none() {
-            entry_slot = entrants.iter_mut().find_map(|(slot, entrant)| {
+        if slot.is_none() {
+            slot = entrants.iter().find_map(|(slot, entrant)| {
                 if entrant["GUID"].as_str().unwrap().is_empty() {
                     debug!("Adding new driver to slot: {}", slot);
-                    Some(entrant)
+                    Some(slot.clone())
                 } else {
                     None
                 }
             })
         }
-        if let Some(entry_slot) = entry_slot {
-            entry_slot["Name"] = driver.name.clone().into();
-            entry_slot["Team"] = driver.team_name.clone().unwrap_or_default().into();
-            entry_slot["GUID"] = steam_id_str.into();
-        } else {
+        let Some(slot) = slot else {
             return Err(anyhow!("Couldn't find empty slot for: {:?}", driver));
+        };
+        if is_new {
+            entrant_defaults.apply(&class_name, &mut entrants[&slot]);
+        }
+        let skin = pick_skin(entrants, &slot, &driver.entry.skins);
+        let entry_slot = &mut entrants[&slot];
+        entry_slot["Name"] = driver.name.clone().into();
+        entry_slot["Team"] = driver.team_name.clone().unwrap_or_default().into();
+        entry_slot["GUID"] = std::iter::once(driver.steam_id)
+            .chain(driver.co_drivers.iter().copied())
+            .join(GUID_SEPARATOR)
+            .into();
+        // Don't add the field to files that never had nations
+        if driver.nation.is_some() || entry_slot.get("Nation").is_some() {
+            entry_slot["Nation"] = driver.nation.clone().unwrap_or_default().into();
+        }
+        if let Some(ballast) = driver.entry.ballast {
+            entry_slot["Ballast"] = ballast.into();
+        }
+        if let Some(restrictor) = driver.entry.restrictor {
+            entry_slot["Restrictor"] = restrictor.into();
+        }
+        if let Some(skin) = skin {
+            entry_slot["Skin"] = skin.into();
+        }
+        if let Some(fixed_setup) = &driver.entry.fixed_setup {
+            entry_slot["FixedSetup"] = fixed_setup.clone().into();
+        }
+        if let Some(race_numbers) = &driver.entry.race_numbers {
+            let current = entry_slot["RaceNumber"].as_u64().unwrap_or_default();
+            // An empty slot's number is free, unless another entrant has it
+            let keep = race_numbers.contains(current)
+                && (!is_new || !used_race_numbers.contains(&current));
+            if !keep {
+                match (race_numbers.first..=race_numbers.last)
+                    .find(|number| !used_race_numbers.contains(number))
+                {
+                    Some(number) => {
+                        if !is_new {
+                            used_race_numbers.remove(&current);
+                        }
+                        used_race_numbers.insert(number);
+                        entry_slot["RaceNumber"] = number.into();
+                    }
+                    None => warn!(
+                        "No free race number from {} to {} for steam_id={}",
+                        race_numbers.first, race_numbers.last, driver.steam_id
+                    ),
+                }
+            } else if is_new {
+                used_race_numbers.insert(current);
+            }
         }
     }
-    write_json_file(json_file, &data, last_modified).await
+    Ok(())
+}
+
+/// Retries of a write before it's reported as an error
+const REPORT_AFTER_RETRIES: usize = 5;
+
+/// Number of updates that failed at least once and are being retried
+static RETRYING_UPDATES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn retrying_updates() -> usize {
+    RETRYING_UPDATES.load(Ordering::Relaxed)
 }
 
+/// Returns whether the entry list changed
 pub async fn update_drivers(
     delete_missing: bool,
     json_file: &Path,
     drivers: &[BasicDriver],
     ignored_steam_ids: &[u64],
-) -> Result<()> {
+    entrant_defaults: &EntrantDefaults,
+) -> Result<bool> {
+    telemetry::in_span(
+        "write ACSM file".to_string(),
+        vec![
+            ("file", json_file.display().to_string()),
+            ("drivers", drivers.len().to_string()),
+        ],
+        update_drivers_with_retries(
+            delete_missing,
+            json_file,
+            drivers,
+            ignored_steam_ids,
+            entrant_defaults,
+        ),
+    )
+    .await
+}
+
+/// Retry until ACSM or anyone else is done with the file
+async fn update_drivers_with_retries(
+    delete_missing: bool,
+    json_file: &Path,
+    drivers: &[BasicDriver],
+    ignored_steam_ids: &[u64],
+    entrant_defaults: &EntrantDefaults,
+) -> Result<bool> {
     info!(
         "Adding/updating {} drivers to {}",
         drivers.len(),
@@ -240,15 +901,36 @@ pub async fn update_drivers(
     let mut retries = 0_usize;
     let mut wait_time = Duration::from_millis(125);
     let max_wait_time = Duration::from_secs(16);
-    loop {
-        match update_drivers_inner(delete_missing, json_file, drivers, ignored_steam_ids).await {
-            Ok(_) => break,
+    let changed = loop {
+        match update_drivers_inner(
+            delete_missing,
+            json_file,
+            drivers,
+            ignored_steam_ids,
+            entrant_defaults,
+        )
+        .await
+        {
+            Ok(changed) => break changed,
             Err(e) => {
                 warn!(
                     "Error adding/updating drivers: {} (retries: {})",
                     e, retries
                 );
-                /* TODO: alert if retries above X */
+                if retries == 0 {
+                    RETRYING_UPDATES.fetch_add(1, Ordering::Relaxed);
+                }
+                // By then it's no longer ACSM writing at the same time
+                if retries == REPORT_AFTER_RETRIES {
+                    error_reporting::capture(&e, &[("file", &json_file.display().to_string())]);
+                }
+                telemetry::event(
+                    "retry",
+                    vec![
+                        ("failure", e.to_string()),
+                        ("backoff_ms", wait_time.as_millis().to_string()),
+                    ],
+                );
                 tokio::time::sleep(wait_time).await;
                 if wait_time < max_wait_time {
                     wait_time *= 2;
@@ -256,8 +938,11 @@ pub async fn update_drivers(
             }
         }
         retries += 1;
+    };
+    if retries > 0 {
+        RETRYING_UPDATES.fetch_sub(1, Ordering::Relaxed);
     }
-    Ok(())
+    Ok(changed)
 }
 
 #[cfg(test)]
@@ -268,6 +953,8 @@ mod test {
 
     #[test_case("fixtures/test.json", "fixtures/test_add_all_new_drivers.json"; "add all new drivers")]
     #[test_case("fixtures/test.json", "fixtures/test_add_one_update_one.json"; "add one update one")]
+    #[test_case("fixtures/test.json", "fixtures/test_co_drivers.json"; "co-drivers")]
+    #[test_case("fixtures/test.json", "fixtures/test_entry_settings.json"; "entry settings")]
     #[tokio::test]
     async fn test(in_json: &str, drivers_json: &str) {
         let out_json = drivers_json.replace(".json", "_output.json");
@@ -277,15 +964,105 @@ mod test {
         fs::copy(in_json, &json_file).unwrap();
         let drivers_strings = fs::read_to_string(drivers_json).unwrap();
         let drivers: Vec<BasicDriver> = serde_json::from_str(&drivers_strings).unwrap();
-        update_drivers_inner(false, &json_file, &drivers, &[])
-            .await
-            .unwrap();
+        update_drivers_inner(
+            false,
+            &json_file,
+            &drivers,
+            &[],
+            &EntrantDefaults::default(),
+        )
+        .await
+        .unwrap();
         // diff the output file with the expected output file
         let output = fs::read_to_string(&json_file).unwrap();
         let expected_output = fs::read_to_string(out_json).unwrap();
         assert!(output == expected_output);
     }
 
+    #[tokio::test]
+    async fn entrant_defaults() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let json_file = tempdir.path().join("test.json");
+        fs::copy("fixtures/test.json", &json_file).unwrap();
+        let defaults = EntrantDefaults::parse(
+            r#"{"BMW E30 Group A": {"Skin": "white", "FixedSetup": "wet.ini"}}"#,
+        )
+        .unwrap();
+        let drivers: Vec<BasicDriver> = serde_json::from_str(
+            r#"[
+                {"name": "Existing", "car": "bmw_m3_e30_gra", "steam_id": 123123123},
+                {"name": "New", "car": "bmw_m3_e30_gra", "steam_id": 456456456},
+                {"name": "Mazda", "car": "ks_mazda_max5_racing", "steam_id": 789789789}
+            ]"#,
+        )
+        .unwrap();
+        update_drivers_inner(false, &json_file, &drivers, &[], &defaults)
+            .await
+            .unwrap();
+        let data: Value = serde_json::from_str(&fs::read_to_string(&json_file).unwrap()).unwrap();
+        let bmw = &data["Classes"][0]["Entrants"];
+        assert_eq!(bmw["CAR_0"]["Skin"], "random_skin");
+        assert_eq!(bmw["CAR_0"]["FixedSetup"], "");
+        assert_eq!(bmw["CAR_1"]["Skin"], "white");
+        assert_eq!(bmw["CAR_1"]["FixedSetup"], "wet.ini");
+        assert_eq!(data["Classes"][1]["Entrants"]["CAR_0"]["FixedSetup"], "");
+    }
+
+    #[tokio::test]
+    async fn reports_changes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let json_file = tempdir.path().join("test.json");
+        fs::copy("fixtures/test.json", &json_file).unwrap();
+        let drivers: Vec<BasicDriver> = serde_json::from_str(
+            &fs::read_to_string("fixtures/test_add_one_update_one.json").unwrap(),
+        )
+        .unwrap();
+        let defaults = EntrantDefaults::default();
+        let update = || update_drivers_inner(false, &json_file, &drivers, &[], &defaults);
+        assert!(update().await.unwrap());
+        assert!(!update().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn detects_changed_contents() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let json_file = tempdir.path().join("test.json");
+        fs::copy("fixtures/test.json", &json_file).unwrap();
+        let original = fs::read_to_string(&json_file).unwrap();
+        // Rewritten with the same contents, as ACSM does
+        let (data, version) = read_json_file(&json_file).await.unwrap();
+        fs::write(&json_file, &original).unwrap();
+        write_json_file(&json_file, &data, version).await.unwrap();
+        // Changed, even if the modification time were the same
+        let (data, version) = read_json_file(&json_file).await.unwrap();
+        fs::write(&json_file, original.replace("BMW", "Audi")).unwrap();
+        assert!(write_json_file(&json_file, &data, version).await.is_err());
+        // Only the file and the backup from the first write, no temporary file
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn scrubs_steam_id() {
+        let mut data = serde_json::json!({"Classes": [{"Entrants": {
+            "CAR_0": {"Name": "Main", "GUID": "1;2", "Model": "car"},
+            "CAR_1": {"Name": "Other", "GUID": "3;1", "Model": "car"},
+            "CAR_2": {"Name": "Third", "GUID": "4", "Model": "car"}
+        }}]});
+        assert!(scrub_steam_id(&mut data, "1").unwrap());
+        let entrants = &data["Classes"][0]["Entrants"];
+        assert_eq!(entrants["CAR_0"]["GUID"], "");
+        assert_eq!(entrants["CAR_0"]["Name"], "");
+        assert_eq!(entrants["CAR_1"]["GUID"], "3");
+        assert_eq!(entrants["CAR_2"]["GUID"], "4");
+        assert!(!scrub_steam_id(&mut data, "1").unwrap());
+    }
+
+    #[test]
+    fn entrant_defaults_cant_set_driver_fields() {
+        assert!(EntrantDefaults::parse(r#"{"GT3": {"Name": "Empty"}}"#).is_err());
+        assert!(EntrantDefaults::parse(r#"{"GT3": 1}"#).is_err());
+    }
+
     #[test_case("fixtures/test.json", "fixtures/too_many_drivers.json"; "too many drivers")]
     #[tokio::test]
     #[should_panic]
@@ -295,8 +1072,14 @@ mod test {
         fs::copy(in_json, &json_file).unwrap();
         let drivers_strings = fs::read_to_string(drivers_json).unwrap();
         let drivers: Vec<BasicDriver> = serde_json::from_str(&drivers_strings).unwrap();
-        update_drivers_inner(false, &json_file, &drivers, &[])
-            .await
-            .unwrap();
+        update_drivers_inner(
+            false,
+            &json_file,
+            &drivers,
+            &[],
+            &EntrantDefaults::default(),
+        )
+        .await
+        .unwrap();
     }
 }
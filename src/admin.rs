@@ -0,0 +1,552 @@
+use anyhow::Result;
+use axum::{
+    extract::{self, Request},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use axum_macros::debug_handler;
+use itertools::Itertools;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc};
+
+use crate::{
+    acsm,
+    acsm::{BasicDriver, EntrySettings},
+    compression::Compression,
+    full_update, nation,
+    report::{ProblemKind, Report},
+    self_service::{self, AuditEntry, DriverEdit},
+    ticket_map::{self, TicketMap},
+    validate, writes, State,
+};
+
+/// Reject requests without `Authorization: Bearer <ADMIN_TOKEN>`. Without an
+/// ADMIN_TOKEN configured the admin API is disabled altogether.
+pub async fn require_admin_token(
+    extract::State(state): extract::State<Arc<State>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_token) = &state.admin_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided)
+            if constant_time_eq(provided.as_bytes(), admin_token.expose().as_bytes()) =>
+        {
+            next.run(req).await
+        }
+        _ => {
+            warn!("Rejected admin request to {}", req.uri().path());
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddDriverRequest {
+    pub name: String,
+    pub steam_id: u64,
+    /// Either the car, or the class to take the first available car of
+    pub car: Option<String>,
+    pub class: Option<String>,
+    pub team_name: Option<String>,
+    pub nation: Option<String>,
+}
+
+#[debug_handler]
+pub async fn handle_add_driver(
+    extract::State(state): extract::State<Arc<State>>,
+    Json(request): Json<AddDriverRequest>,
+) -> Result<(StatusCode, Html<&'static str>), StatusCode> {
+    let first_json_file = state.acsm_json_files.lock().await[0].clone();
+    let car = match (request.car, &request.class) {
+        (Some(car), _) => car,
+        (None, Some(class)) => acsm::car_for_class(&first_json_file, class)
+            .await
+            .map_err(|e| {
+                warn!("Can't add manual driver: {:?}", e);
+                StatusCode::BAD_REQUEST
+            })?,
+        (None, None) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let driver = BasicDriver {
+        name: request.name,
+        car,
+        steam_id: request.steam_id,
+        team_name: request.team_name.filter(|team_name| !team_name.is_empty()),
+        class: request.class,
+        skill: None,
+        pace: None,
+        nation: request
+            .nation
+            .as_deref()
+            .and_then(nation::normalize)
+            .map(str::to_string),
+        order_guid: None,
+        ticket_guid: None,
+        paid_at: None,
+        co_drivers: Vec::new(),
+        reserved: true,
+        priority: false,
+        entry: EntrySettings::default(),
+        email: None,
+    };
+    info!(
+        "Adding manual driver: {} steam_id={} car={}",
+        driver.name, driver.steam_id, driver.car
+    );
+    // Remember it first, so the next full update keeps the driver even if
+    // placing it now fails
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            data.manual_drivers
+                .retain(|manual_driver| manual_driver.steam_id != driver.steam_id);
+            data.manual_drivers.push(driver.clone());
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to store manual driver: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let mut report = Report::default();
+    let written = state
+        .place_drivers(&[driver], false, &mut report)
+        .await
+        .map_err(|e| {
+            error!("Failed to add manual driver: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    report.log();
+    // A truncated name still got placed
+    if report
+        .problems
+        .iter()
+        .any(|problem| problem.kind != ProblemKind::TruncatedName)
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+    if !written {
+        // Added once writes resume
+        return Ok((StatusCode::ACCEPTED, Html("driver queued")));
+    }
+    Ok((StatusCode::OK, Html("driver added")))
+}
+
+/// Take a driver off the grid, also after the registration cutoff. Drivers
+/// with a ticket come back with the next full update before the cutoff, unless
+/// they're ignored.
+#[debug_handler]
+pub async fn handle_remove_driver(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(steam_id): extract::Path<u64>,
+) -> Result<Html<&'static str>, StatusCode> {
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            data.manual_drivers
+                .retain(|manual_driver| manual_driver.steam_id != steam_id)
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to remove manual driver: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let mut write_gate = state.write_gate.lock().await;
+    if write_gate.is_held() {
+        warn!("Writes held back, not removing steam_id={}", steam_id);
+        return Err(StatusCode::CONFLICT);
+    }
+    let removed = writes::remove_driver(&state, &mut write_gate, steam_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to remove driver: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    drop(write_gate);
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Html("driver removed"))
+}
+
+#[derive(Debug, Serialize)]
+pub struct IgnoredSteamIds {
+    /// From IGNORED_STEAM_IDS, can't be removed at runtime
+    pub configured: Vec<u64>,
+    pub runtime: Vec<u64>,
+}
+
+#[debug_handler]
+pub async fn handle_list_ignored_steam_ids(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Json<IgnoredSteamIds> {
+    Json(IgnoredSteamIds {
+        configured: state.ignored_steam_ids.clone(),
+        runtime: state.store.lock().await.data().ignored_steam_ids.clone(),
+    })
+}
+
+#[debug_handler]
+pub async fn handle_add_ignored_steam_id(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(steam_id): extract::Path<u64>,
+) -> Result<Html<&'static str>, StatusCode> {
+    ignore_steam_id(&state, steam_id).await.map_err(|e| {
+        error!("Failed to ignore Steam ID: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Html("steam id ignored"))
+}
+
+/// Ignore the Steam ID until it's removed again, also across restarts, and
+/// take the driver off the grid
+pub async fn ignore_steam_id(state: &State, steam_id: u64) -> Result<()> {
+    info!("Ignoring steam_id={}", steam_id);
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            if !data.ignored_steam_ids.contains(&steam_id) {
+                data.ignored_steam_ids.push(steam_id);
+            }
+        })
+        .await?;
+    let mut write_gate = state.write_gate.lock().await;
+    if !write_gate.queue_removal_if_held(steam_id) {
+        writes::remove_driver(state, &mut write_gate, steam_id).await?;
+    }
+    Ok(())
+}
+
+#[debug_handler]
+pub async fn handle_remove_ignored_steam_id(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(steam_id): extract::Path<u64>,
+) -> Result<Html<&'static str>, StatusCode> {
+    if state.ignored_steam_ids.contains(&steam_id) {
+        warn!(
+            "steam_id={} is in IGNORED_STEAM_IDS, can't remove it at runtime",
+            steam_id
+        );
+        return Err(StatusCode::CONFLICT);
+    }
+    let mut store = state.store.lock().await;
+    if !store.data().ignored_steam_ids.contains(&steam_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    info!("No longer ignoring steam_id={}", steam_id);
+    store
+        .update(|data| data.ignored_steam_ids.retain(|id| *id != steam_id))
+        .await
+        .map_err(|e| {
+            error!("Failed to store ignored Steam IDs: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Html("steam id no longer ignored"))
+}
+
+#[derive(Debug, Serialize)]
+pub struct IgnoredGuids {
+    /// From IGNORED_GUIDS, can't be removed at runtime
+    pub configured: Vec<String>,
+    pub runtime: Vec<String>,
+}
+
+#[debug_handler]
+pub async fn handle_list_ignored_guids(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Json<IgnoredGuids> {
+    Json(IgnoredGuids {
+        configured: state.ignored_guids.clone(),
+        runtime: state.store.lock().await.data().ignored_guids.clone(),
+    })
+}
+
+#[debug_handler]
+pub async fn handle_add_ignored_guid(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(guid): extract::Path<String>,
+) -> Result<Html<&'static str>, StatusCode> {
+    info!("Ignoring order/ticket {}", guid);
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            if !data.ignored_guids.contains(&guid) {
+                data.ignored_guids.push(guid);
+            }
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to store ignored GUID: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Html("guid ignored"))
+}
+
+#[debug_handler]
+pub async fn handle_remove_ignored_guid(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(guid): extract::Path<String>,
+) -> Result<Html<&'static str>, StatusCode> {
+    if state.ignored_guids.contains(&guid) {
+        warn!("{} is in IGNORED_GUIDS, can't remove it at runtime", guid);
+        return Err(StatusCode::CONFLICT);
+    }
+    let mut store = state.store.lock().await;
+    if !store.data().ignored_guids.contains(&guid) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    info!("No longer ignoring order/ticket {}", guid);
+    store
+        .update(|data| data.ignored_guids.retain(|ignored| *ignored != guid))
+        .await
+        .map_err(|e| {
+            error!("Failed to store ignored GUIDs: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Html("guid no longer ignored"))
+}
+
+#[debug_handler]
+pub async fn handle_audit_log(
+    extract::State(state): extract::State<Arc<State>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let audit_log: Vec<AuditEntry> = state.store.lock().await.data().audit_log.clone();
+    // It grows with every change, so compress it for clients that take that
+    let Some(compression) = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Compression::accepted)
+    else {
+        return Ok(Json(audit_log).into_response());
+    };
+    let json = serde_json::to_vec(&audit_log).map_err(|e| {
+        error!("Failed to serialize the audit log: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let compressed = compression.compress(&json).map_err(|e| {
+        error!("Failed to compress the audit log: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CONTENT_ENCODING, compression.name()),
+        ],
+        compressed,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessCode {
+    pub ticket_guid: String,
+    pub order_guid: Option<String>,
+    pub name: String,
+    pub code: String,
+    /// The edit page with the code filled in, for emailing, with PORTAL_URL
+    pub url: Option<String>,
+}
+
+/// Access codes for every ticket as of the last full update. Without
+/// ACCESS_CODE_SECRET there are none.
+#[debug_handler]
+pub async fn handle_access_codes(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Result<Json<Vec<AccessCode>>, StatusCode> {
+    let Some(access_codes) = &state.access_codes else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let cached_orders = state.cached_orders.lock().await;
+    let codes = cached_orders
+        .values()
+        .flat_map(|(drivers, _)| drivers)
+        .filter_map(|driver| {
+            let ticket_guid = driver.ticket_guid.clone()?;
+            let code = access_codes.code(&ticket_guid);
+            Some(AccessCode {
+                url: state
+                    .portal
+                    .as_ref()
+                    .map(|portal| portal.edit_url(&ticket_guid, &code)),
+                ticket_guid,
+                order_guid: driver.order_guid.clone(),
+                name: driver.name.clone(),
+                code,
+            })
+        })
+        .collect();
+    Ok(Json(codes))
+}
+
+/// The ticket map in effect, in the TICKET_MAP_FILE format
+pub async fn handle_get_ticket_map(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Json<TicketMap> {
+    Json(state.ticket_map().await.as_ref().clone())
+}
+
+/// Replace the ticket map for the syncs after, if it matches the ACSM files
+/// and the ticket sources. Written to TICKET_MAP_FILE too, if set, to keep it
+/// after a restart.
+#[debug_handler]
+pub async fn handle_replace_ticket_map(
+    extract::State(state): extract::State<Arc<State>>,
+    Json(ticket_map): Json<TicketMap>,
+) -> Result<Html<&'static str>, StatusCode> {
+    let reject = |e: anyhow::Error| {
+        warn!("Rejecting ticket map: {:?}", e);
+        StatusCode::BAD_REQUEST
+    };
+    ticket_map.check().map_err(reject)?;
+    if let Some(car_content) = &state.car_content {
+        let models = car_content
+            .car_models(&state.http.acsm)
+            .await
+            .map_err(|e| {
+                error!("Failed to list the cars on the server: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        validate::validate_car_models(&ticket_map, &models).map_err(reject)?;
+    }
+    let acsm_json_files = state.acsm_json_files.lock().await.clone();
+    for acsm_json_file in &acsm_json_files {
+        validate::validate_acsm_file(acsm_json_file, &ticket_map)
+            .await
+            .map_err(reject)?;
+    }
+    for source in &state.sources {
+        source.validate(&state, &ticket_map).await.map_err(reject)?;
+    }
+    match ticket_map::path_from_env() {
+        Some(path) => ticket_map.save(&path).await.map_err(|e| {
+            error!("Failed to save ticket map: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+        None => warn!("TICKET_MAP_FILE not set, a restart goes back to TICKET_ID_TO_CAR_MAP"),
+    }
+    info!(
+        "Replacing the ticket map: {} ticket types, {} name patterns",
+        ticket_map.tickets.len(),
+        ticket_map.patterns.len()
+    );
+    *state.ticket_map.lock().await = Arc::new(ticket_map);
+    // Tickets of newly mapped ticket types were skipped until now
+    tokio::spawn(full_update(state.clone()));
+    Ok(Html("ticket map replaced"))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AcsmJsonFiles {
+    pub acsm_json_file: Vec<PathBuf>,
+}
+
+/// The ACSM files drivers are placed in
+pub async fn handle_get_acsm_json_files(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Json<AcsmJsonFiles> {
+    Json(AcsmJsonFiles {
+        acsm_json_file: state.acsm_json_files.lock().await.clone(),
+    })
+}
+
+/// Place drivers in other ACSM files from now on, e.g. the next round's
+/// championship, if they match the settings. Only until a restart, which goes
+/// back to ACSM_JSON_FILE.
+#[debug_handler]
+pub async fn handle_replace_acsm_json_files(
+    extract::State(state): extract::State<Arc<State>>,
+    Json(request): Json<AcsmJsonFiles>,
+) -> Result<Html<&'static str>, StatusCode> {
+    if request.acsm_json_file.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let ticket_map = state.ticket_map().await;
+    for acsm_json_file in &request.acsm_json_file {
+        state
+            .validate_acsm_file(acsm_json_file, &ticket_map)
+            .await
+            .map_err(|e| {
+                warn!("Rejecting ACSM files: {:?}", e);
+                StatusCode::BAD_REQUEST
+            })?;
+    }
+    // Between writes, so none goes partly to the old files and partly to the
+    // new ones
+    let write_gate = state.write_gate.lock().await;
+    let mut acsm_json_files = state.acsm_json_files.lock().await;
+    info!(
+        "Switching from {} to {}",
+        acsm_json_files.iter().map(|path| path.display()).join(", "),
+        request
+            .acsm_json_file
+            .iter()
+            .map(|path| path.display())
+            .join(", ")
+    );
+    *acsm_json_files = request.acsm_json_file;
+    drop(acsm_json_files);
+    drop(write_gate);
+    warn!("Set ACSM_JSON_FILE to match, a restart goes back to it");
+    // Fill the new files
+    tokio::spawn(full_update(state.clone()));
+    Ok(Html("ACSM files replaced"))
+}
+
+/// Let the ticket's current names through the name filter. If the driver
+/// changes them, they need approval again.
+#[debug_handler]
+pub async fn handle_approve_name(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(ticket_guid): extract::Path<String>,
+) -> Result<Html<&'static str>, StatusCode> {
+    let Some(driver) = self_service::find_driver(&state, &ticket_guid).await else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    info!(
+        "Approving name {:?} team_name={:?} for ticket {}",
+        driver.name, driver.team_name, ticket_guid
+    );
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            data.approved_names.insert(
+                ticket_guid,
+                DriverEdit {
+                    name: driver.name,
+                    team_name: driver.team_name,
+                },
+            );
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to store approved name: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    tokio::spawn(full_update(state.clone()));
+    Ok(Html("name approved"))
+}
@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{self, ConnectInfo, Request},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use log::warn;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::State;
+
+/// Which addresses may call the webhook routes
+#[derive(Debug, Default)]
+pub struct IpAllowlist {
+    /// Everyone is allowed if empty
+    pub allowed: Vec<IpNet>,
+    /// Proxies whose X-Forwarded-For we believe
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Comma separated CIDRs, or plain addresses
+fn parse_nets(var: &str) -> Result<Vec<IpNet>> {
+    dotenv::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|net| !net.is_empty())
+        .map(|net| {
+            net.parse::<IpNet>()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("Invalid address or CIDR in {}: {}", var, net))
+        })
+        .collect()
+}
+
+impl IpAllowlist {
+    pub fn from_env() -> Result<IpAllowlist> {
+        Ok(IpAllowlist {
+            allowed: parse_nets("WEBHOOK_ALLOWED_IPS")?,
+            trusted_proxies: parse_nets("TRUSTED_PROXIES")?,
+        })
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address of whoever is behind our trusted proxies, going from the
+    /// right of X-Forwarded-For, since anything left of that can be spoofed
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted_proxy(peer) {
+            return peer;
+        }
+        let forwarded_for = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        let mut client = peer;
+        for forwarded in forwarded_for.into_iter().rev() {
+            let Ok(ip) = forwarded.parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted_proxy(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Reject webhooks from anywhere but WEBHOOK_ALLOWED_IPS, before even looking
+/// at the body
+pub async fn require_allowed_ip(
+    extract::State(state): extract::State<Arc<State>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let allowlist = &state.webhook_allowlist;
+    let client_ip = allowlist.client_ip(peer.ip(), req.headers());
+    if !allowlist.is_allowed(client_ip) {
+        warn!(
+            "Rejected request to {} from {}",
+            req.uri().path(),
+            client_ip
+        );
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn allowlist() -> IpAllowlist {
+        IpAllowlist {
+            allowed: vec!["192.0.2.0/24".parse().unwrap()],
+            trusted_proxies: vec!["10.0.0.1/32".parse().unwrap()],
+        }
+    }
+
+    #[test_case("203.0.113.9", None, "203.0.113.9"; "direct")]
+    #[test_case("203.0.113.9", Some("192.0.2.1"), "203.0.113.9"; "untrusted peer")]
+    #[test_case("10.0.0.1", Some("192.0.2.1"), "192.0.2.1"; "trusted proxy")]
+    #[test_case("10.0.0.1", Some("192.0.2.1, 10.0.0.1"), "192.0.2.1"; "proxy chain")]
+    #[test_case("10.0.0.1", Some("192.0.2.1, 203.0.113.9"), "203.0.113.9"; "spoofed left")]
+    #[test_case("10.0.0.1", None, "10.0.0.1"; "proxy without header")]
+    fn client_ip(peer: &str, forwarded_for: Option<&str>, expected: &str) {
+        let mut headers = HeaderMap::new();
+        if let Some(forwarded_for) = forwarded_for {
+            headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        }
+        assert_eq!(
+            allowlist().client_ip(peer.parse().unwrap(), &headers),
+            expected.parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test_case("192.0.2.77", true)]
+    #[test_case("203.0.113.9", false)]
+    fn is_allowed(ip: &str, expected: bool) {
+        assert_eq!(allowlist().is_allowed(ip.parse().unwrap()), expected);
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{fs, time::sleep};
+
+use crate::{
+    acsm::{self, Entrant},
+    cutoff,
+    events::EventKind,
+    full_update,
+    store::StoreData,
+    State,
+};
+
+/// Often enough to archive within a minute of the end
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// From EVENT_END and ARCHIVE_DIR, when to stop writing and where the final
+/// entry list goes
+#[derive(Debug)]
+pub struct Archive {
+    end: DateTime<Utc>,
+    dir: PathBuf,
+}
+
+impl Archive {
+    pub fn from_env() -> Result<Option<Archive>> {
+        let Some(end) = dotenv::var("EVENT_END").ok().filter(|end| !end.is_empty()) else {
+            return Ok(None);
+        };
+        Ok(Some(Archive {
+            end: cutoff::parse_at(&end).context("Invalid EVENT_END")?,
+            dir: dotenv::var("ARCHIVE_DIR")
+                .ok()
+                .filter(|dir| !dir.is_empty())
+                .unwrap_or_else(|| "archive".to_string())
+                .into(),
+        }))
+    }
+
+    /// Whether the final sync and export for this EVENT_END happened, maybe
+    /// before a restart
+    pub fn is_done(&self, data: &StoreData) -> bool {
+        data.archived.contains(&self.end)
+    }
+
+    /// Where the entry list of an ACSM file goes, like
+    /// `archive/2026-03-01/championship.csv`
+    fn path(&self, json_file: &Path, extension: &str) -> PathBuf {
+        let stem = json_file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "entry-list".to_string());
+        self.dir
+            .join(self.end.format("%Y-%m-%d").to_string())
+            .join(format!("{}.{}", stem, extension))
+    }
+}
+
+fn to_csv(entrants: &[Entrant]) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for entrant in entrants {
+        writer.serialize(entrant)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// Write the entry list of every ACSM file as JSON and CSV, returning the
+/// files written
+async fn export(state: &State, archive: &Archive) -> Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for json_file in state.acsm_json_files.lock().await.iter() {
+        let entrants = acsm::read_entrants(json_file).await?;
+        let json = archive.path(json_file, "json");
+        if let Some(dir) = json.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        fs::write(&json, serde_json::to_string_pretty(&entrants)?)
+            .await
+            .with_context(|| format!("Failed to write {}", json.display()))?;
+        let csv = archive.path(json_file, "csv");
+        fs::write(&csv, to_csv(&entrants)?)
+            .await
+            .with_context(|| format!("Failed to write {}", csv.display()))?;
+        written.extend([json, csv]);
+    }
+    Ok(written)
+}
+
+/// Sync one last time, stop writing for good and export what's on the grid
+async fn finish(state: &Arc<State>, archive: &Archive) -> Result<()> {
+    info!("Event ended, syncing one last time");
+    if let Err(e) = full_update(state.clone()).await {
+        error!("Final sync failed, archiving the grid as it is: {:?}", e);
+    }
+    // Waits for a write in progress, so the export is what stays
+    state.write_gate.lock().await.archived = true;
+    let files = export(state, archive).await?;
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| data.archived.push(archive.end))
+        .await?;
+    let files: Vec<String> = files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect();
+    info!(
+        "Event archived to {}, not writing anymore",
+        files.join(", ")
+    );
+    state.events.emit(EventKind::Archived { files });
+    Ok(())
+}
+
+/// With EVENT_END, archive the event once it's over
+pub async fn task(state: Arc<State>) {
+    let Some(archive) = &state.archive else {
+        return;
+    };
+    if archive.is_done(state.store.lock().await.data()) {
+        return;
+    }
+    while Utc::now() < archive.end {
+        sleep(CHECK_INTERVAL).await;
+    }
+    if let Err(e) = finish(&state, archive).await {
+        error!("Failed to archive the event: {:?}", e);
+        // Stop writing anyway, it's over
+        state.write_gate.lock().await.archived = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let archive = Archive {
+            end: cutoff::parse_at("2026-03-01T22:00:00Z").unwrap(),
+            dir: "archive".into(),
+        };
+        assert_eq!(
+            archive.path(Path::new("sftp://acsm@host/gt3.json"), "csv"),
+            Path::new("archive/2026-03-01/gt3.csv")
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let entrants = [Entrant {
+            class: "GT3".to_string(),
+            guid: "76561198000000001".to_string(),
+            name: "Max, Jr.".to_string(),
+            team: String::new(),
+            nation: "NED".to_string(),
+        }];
+        assert_eq!(
+            String::from_utf8(to_csv(&entrants).unwrap()).unwrap(),
+            "class,guid,name,team,nation\nGT3,76561198000000001,\"Max, Jr.\",,NED\n"
+        );
+    }
+}
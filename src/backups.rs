@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{extract, http::StatusCode, Json};
+use axum_macros::debug_handler;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::fs;
+
+use crate::{compression, s3, sandbox, sftp, timezone, State};
+
+/// Where backups of local ACSM files go
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupLocation {
+    /// Next to the ACSM file if not set
+    dir: Option<PathBuf>,
+    /// With `{name}` for the ACSM file's name and `{time}` for when it was
+    /// last modified, in seconds since the epoch
+    pattern: String,
+}
+
+const DEFAULT_PATTERN: &str = "{name}.backup_{time}";
+
+/// From BACKUP_DIR and BACKUP_FILENAME, set once at startup
+static LOCATION: OnceLock<BackupLocation> = OnceLock::new();
+
+fn var(name: &str) -> Option<String> {
+    dotenv::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl Default for BackupLocation {
+    fn default() -> Self {
+        BackupLocation {
+            dir: None,
+            pattern: DEFAULT_PATTERN.to_string(),
+        }
+    }
+}
+
+impl BackupLocation {
+    fn new(dir: Option<PathBuf>, pattern: String) -> Result<BackupLocation> {
+        if pattern.matches("{name}").count() != 1 || pattern.matches("{time}").count() != 1 {
+            return Err(anyhow!(
+                "BACKUP_FILENAME needs `{{name}}` and `{{time}}` once each, like `{}`",
+                DEFAULT_PATTERN
+            ));
+        }
+        if pattern.contains(['/', '\\']) {
+            return Err(anyhow!(
+                "BACKUP_FILENAME is only a file name, set BACKUP_DIR for the directory"
+            ));
+        }
+        Ok(BackupLocation { dir, pattern })
+    }
+
+    /// Read BACKUP_DIR and BACKUP_FILENAME, creating the directory if it
+    /// doesn't exist yet
+    pub fn init_from_env() -> Result<()> {
+        let location = BackupLocation::new(
+            var("BACKUP_DIR").map(PathBuf::from),
+            var("BACKUP_FILENAME").unwrap_or_else(|| DEFAULT_PATTERN.to_string()),
+        )?;
+        if let Some(dir) = &location.dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create BACKUP_DIR {}", dir.display()))?;
+        }
+        LOCATION.get_or_init(|| location);
+        Ok(())
+    }
+
+    fn get() -> BackupLocation {
+        LOCATION.get().cloned().unwrap_or_default()
+    }
+
+    fn directory(&self, json_file: &Path) -> PathBuf {
+        match (&self.dir, json_file.parent()) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(parent)) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            (None, _) => PathBuf::from("."),
+        }
+    }
+
+    fn file_name(&self, json_file: &Path, time: u64) -> String {
+        self.pattern
+            .replace("{name}", &file_name(json_file))
+            .replace("{time}", &time.to_string())
+    }
+
+    /// When the backup of this ACSM file with this file name was taken, if it
+    /// is one
+    fn time(&self, json_file: &Path, backup_name: &str) -> Option<u64> {
+        let pattern = self.pattern.replace("{name}", &file_name(json_file));
+        let (prefix, suffix) = pattern.split_once("{time}")?;
+        compression::strip_extension(backup_name)
+            .strip_prefix(prefix)?
+            .strip_suffix(suffix)?
+            .parse()
+            .ok()
+    }
+}
+
+fn file_name(json_file: &Path) -> String {
+    json_file
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Move a local ACSM file to its backup, returning where that is
+pub async fn take(json_file: &Path, modified: SystemTime) -> Result<PathBuf> {
+    let location = BackupLocation::get();
+    let directory = location.directory(json_file);
+    let time = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let backup = directory.join(location.file_name(json_file, time.as_secs()));
+    if location.dir.is_some() {
+        fs::create_dir_all(&directory)
+            .await
+            .with_context(|| format!("Failed to create {}", directory.display()))?;
+        // Renaming fails if it's on another file system
+        if fs::rename(json_file, &backup).await.is_err() {
+            fs::copy(json_file, &backup)
+                .await
+                .with_context(|| format!("Failed to copy to {}", backup.display()))?;
+            fs::remove_file(json_file).await?;
+        }
+    } else {
+        fs::rename(json_file, &backup).await?;
+    }
+    Ok(backup)
+}
+
+/// Backups of an ACSM file, with when they were taken. Only for files on this
+/// machine.
+pub async fn list(json_file: &Path) -> Result<Vec<(PathBuf, SystemTime)>> {
+    if sftp::Location::parse(json_file).is_some() || s3::Object::parse(json_file).is_some() {
+        return Ok(Vec::new());
+    }
+    let location = BackupLocation::get();
+    let directory = location.directory(json_file);
+    let mut backups = Vec::new();
+    let mut entries = match fs::read_dir(&directory).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", directory.display())),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(secs) = location.time(json_file, &name) {
+            backups.push((entry.path(), UNIX_EPOCH + Duration::from_secs(secs)));
+        }
+    }
+    Ok(backups)
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupFile {
+    /// The file name, to delete it by
+    name: String,
+    /// The ACSM file it's a backup of
+    of: PathBuf,
+    size: u64,
+    #[serde(serialize_with = "timezone::serialize")]
+    taken: DateTime<Utc>,
+}
+
+/// Backups of all ACSM files on this machine, by file name, newest first
+async fn list_all(state: &State) -> Result<Vec<(PathBuf, BackupFile)>> {
+    let json_files = state.acsm_json_files.lock().await.clone();
+    let mut backups = Vec::new();
+    for json_file in json_files {
+        for (backup, taken) in list(&json_file).await? {
+            let size = fs::metadata(&backup)
+                .await
+                .with_context(|| format!("Failed to read {}", backup.display()))?
+                .len();
+            backups.push((
+                backup.clone(),
+                BackupFile {
+                    name: file_name(&backup),
+                    of: json_file.clone(),
+                    size,
+                    taken: taken.into(),
+                },
+            ));
+        }
+    }
+    backups.sort_by_key(|(_, file)| std::cmp::Reverse(file.taken));
+    Ok(backups)
+}
+
+/// `GET /admin/v1/backups`, for checking disk usage without a shell on the
+/// server
+#[debug_handler]
+pub async fn handle_list(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Result<Json<Vec<BackupFile>>, StatusCode> {
+    match list_all(&state).await {
+        Ok(backups) => Ok(Json(backups.into_iter().map(|(_, file)| file).collect())),
+        Err(e) => {
+            error!("Failed to list backups: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteBackupsRequest {
+    /// File names as `GET /admin/v1/backups` lists them
+    names: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletedBackups {
+    deleted: usize,
+    /// Bytes freed
+    size: u64,
+}
+
+/// `DELETE /admin/v1/backups` with the names of the backups to delete. Only
+/// backups as listed can be deleted, and if one of the names isn't, nothing is.
+#[debug_handler]
+pub async fn handle_delete(
+    extract::State(state): extract::State<Arc<State>>,
+    Json(request): Json<DeleteBackupsRequest>,
+) -> Result<Json<DeletedBackups>, (StatusCode, String)> {
+    if sandbox::is_on() {
+        return Err((
+            StatusCode::CONFLICT,
+            "SANDBOX is on, backups are left alone".to_string(),
+        ));
+    }
+    let backups: HashMap<String, (PathBuf, u64)> = list_all(&state)
+        .await
+        .map_err(|e| {
+            error!("Failed to list backups: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to list backups".to_string(),
+            )
+        })?
+        .into_iter()
+        .map(|(path, file)| (file.name, (path, file.size)))
+        .collect();
+    if let Some(unknown) = request
+        .names
+        .iter()
+        .find(|name| !backups.contains_key(*name))
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No backup named {}", unknown),
+        ));
+    }
+    let mut deleted = DeletedBackups {
+        deleted: 0,
+        size: 0,
+    };
+    for name in &request.names {
+        let (path, size) = &backups[name];
+        if let Err(e) = fs::remove_file(path).await {
+            error!("Failed to delete {}: {:?}", path.display(), e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!(
+                    "Failed to delete {}, {} deleted before it",
+                    name, deleted.deleted
+                ),
+            ));
+        }
+        info!("Deleted backup {}", path.display());
+        deleted.deleted += 1;
+        deleted.size += size;
+    }
+    Ok(Json(deleted))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(DEFAULT_PATTERN, "c.json.backup_1700000000.zst", Some(1700000000); "default")]
+    #[test_case("{time}-{name}", "1700000000-c.json.gz", Some(1700000000); "time first")]
+    #[test_case(DEFAULT_PATTERN, "d.json.backup_1700000000", None; "other file")]
+    #[test_case(DEFAULT_PATTERN, "c.json.backup_latest", None; "not a time")]
+    fn finds_backups(pattern: &str, backup_name: &str, expected: Option<u64>) {
+        let location = BackupLocation::new(None, pattern.to_string()).unwrap();
+        assert_eq!(
+            location.time(Path::new("json/c.json"), backup_name),
+            expected
+        );
+    }
+
+    #[test_case("backup_{time}"; "no name")]
+    #[test_case("{name}.{time}.{time}"; "time twice")]
+    #[test_case("old/{name}.{time}"; "directory")]
+    fn rejects_patterns(pattern: &str) {
+        assert!(BackupLocation::new(None, pattern.to_string()).is_err());
+    }
+
+    #[test]
+    fn names_backups() {
+        let location = BackupLocation::new(
+            Some(PathBuf::from("/var/backups/acsm")),
+            "{time}-{name}".to_string(),
+        )
+        .unwrap();
+        let json_file = Path::new("json/c.json");
+        assert_eq!(
+            location.directory(json_file),
+            PathBuf::from("/var/backups/acsm")
+        );
+        assert_eq!(location.file_name(json_file, 1), "1-c.json");
+        assert_eq!(
+            BackupLocation::default().directory(json_file),
+            PathBuf::from("json")
+        );
+    }
+}
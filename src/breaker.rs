@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::time::{Duration, Instant};
+
+/// Stops calling an API after too many consecutive failures, letting a single
+/// probe through every so often to see if it's back
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// Consecutive failures before opening, never opens if 0
+    threshold: usize,
+    probe_interval: Duration,
+    consecutive_failures: usize,
+    /// Set while open
+    next_probe: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, probe_interval: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            probe_interval,
+            consecutive_failures: 0,
+            next_probe: None,
+        }
+    }
+
+    pub fn from_env() -> Result<CircuitBreaker> {
+        Ok(CircuitBreaker::new(
+            dotenv::var("EVENTIX_BREAKER_THRESHOLD")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .context("EVENTIX_BREAKER_THRESHOLD is not a number")?,
+            Duration::from_secs(
+                dotenv::var("EVENTIX_BREAKER_PROBE_SECONDS")
+                    .unwrap_or_else(|_| "60".to_string())
+                    .parse()
+                    .context("EVENTIX_BREAKER_PROBE_SECONDS is not a number")?,
+            ),
+        ))
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.next_probe.is_some()
+    }
+
+    pub fn probe_interval(&self) -> Duration {
+        self.probe_interval
+    }
+
+    /// Whether a call may go ahead now
+    pub fn allow(&mut self) -> bool {
+        match self.next_probe {
+            None => true,
+            Some(next_probe) if Instant::now() >= next_probe => {
+                self.next_probe = Some(Instant::now() + self.probe_interval);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub fn record(&mut self, success: bool) {
+        if success {
+            if self.is_open() {
+                info!("Eventix API is back, closing circuit");
+            }
+            self.consecutive_failures = 0;
+            self.next_probe = None;
+            return;
+        }
+        self.consecutive_failures += 1;
+        if self.threshold > 0 && self.consecutive_failures >= self.threshold && !self.is_open() {
+            warn!(
+                "{} consecutive Eventix API failures, opening circuit",
+                self.consecutive_failures
+            );
+            self.next_probe = Some(Instant::now() + self.probe_interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.record(false);
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn probe_closes() {
+        let mut breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record(false);
+        assert!(breaker.is_open());
+        assert!(breaker.allow());
+        breaker.record(true);
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record(false);
+        }
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use itertools::Itertools;
+use log::{error, info};
+use serde::Deserialize;
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
+use tokio::time::sleep;
+
+use crate::{events::EventKind, full_update, ticket_map::TicketMap, timezone, State};
+
+/// Rounds change by the day, so this is often enough
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One round of a series, which syncs its own event to its own ACSM files
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Round {
+    /// The first and last day, in TIMEZONE
+    pub from: NaiveDate,
+    pub until: NaiveDate,
+    pub event_guid: String,
+    pub acsm_json_file: Vec<PathBuf>,
+    /// Each event has its own ticket GUIDs, so without name patterns each
+    /// round needs a ticket map of its own
+    #[serde(default)]
+    pub ticket_map_file: Option<PathBuf>,
+}
+
+impl fmt::Display for Round {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} to {}, event {} into {}",
+            self.from,
+            self.until,
+            self.event_guid,
+            self.acsm_json_file
+                .iter()
+                .map(|path| path.display())
+                .join(", ")
+        )
+    }
+}
+
+impl Round {
+    pub fn ticket_map(&self) -> Result<Option<TicketMap>> {
+        self.ticket_map_file
+            .as_deref()
+            .map(TicketMap::load)
+            .transpose()
+    }
+}
+
+/// From the JSON file at CALENDAR_FILE
+#[derive(Debug)]
+pub struct Calendar {
+    /// In order, without overlap
+    rounds: Vec<Round>,
+}
+
+impl Calendar {
+    pub fn from_env() -> Result<Option<Calendar>> {
+        let Some(path) = dotenv::var("CALENDAR_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+        else {
+            return Ok(None);
+        };
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read CALENDAR_FILE {}", path))?;
+        Calendar::parse(&text)
+            .with_context(|| format!("Invalid CALENDAR_FILE {}", path))
+            .map(Some)
+    }
+
+    fn parse(text: &str) -> Result<Calendar> {
+        let mut rounds: Vec<Round> = serde_json::from_str(text)?;
+        for round in &rounds {
+            if round.until < round.from {
+                return Err(anyhow!("Round {} ends before it starts", round));
+            }
+            if round.acsm_json_file.is_empty() {
+                return Err(anyhow!("Round {} has no ACSM file", round));
+            }
+        }
+        rounds.sort_by_key(|round| round.from);
+        if let Some((round, next)) = rounds
+            .iter()
+            .tuple_windows()
+            .find(|(round, next)| next.from <= round.until)
+        {
+            return Err(anyhow!("Rounds {} and {} overlap", round, next));
+        }
+        if rounds.is_empty() {
+            return Err(anyhow!("No rounds"));
+        }
+        Ok(Calendar { rounds })
+    }
+
+    /// The round on `today`, else the next one, so its tickets sell in
+    /// between, else the last one
+    fn round_on(&self, today: NaiveDate) -> &Round {
+        self.rounds
+            .iter()
+            .find(|round| today <= round.until)
+            .unwrap_or_else(|| &self.rounds[self.rounds.len() - 1])
+    }
+
+    pub fn current(&self) -> &Round {
+        self.round_on(timezone::now().date())
+    }
+}
+
+/// Sync the round's event to its ACSM files from now on
+async fn switch(state: &State, round: &Round) -> Result<()> {
+    let ticket_map = match round.ticket_map()? {
+        Some(ticket_map) => Arc::new(ticket_map),
+        None => state.ticket_map().await,
+    };
+    for acsm_json_file in &round.acsm_json_file {
+        state
+            .validate_acsm_file(acsm_json_file, &ticket_map)
+            .await?;
+    }
+    let source = state
+        .source("eventix")
+        .context("CALENDAR_FILE needs eventix in TICKET_SOURCE")?;
+    // Between writes, so none goes partly to the previous round
+    let write_gate = state.write_gate.lock().await;
+    source.switch_event(&round.event_guid).await?;
+    *state.acsm_json_files.lock().await = round.acsm_json_file.clone();
+    *state.ticket_map.lock().await = ticket_map;
+    state.cached_orders.lock().await.remove(source.name());
+    *state.registration_closes.lock().await = None;
+    drop(write_gate);
+    state.events.emit(EventKind::RoundStarted {
+        event_guid: round.event_guid.clone(),
+        files: round
+            .acsm_json_file
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+    });
+    Ok(())
+}
+
+/// With CALENDAR_FILE, switch to the next round when it's due, and fill its
+/// ACSM files
+pub async fn switch_task(state: Arc<State>) {
+    let Some(calendar) = &state.calendar else {
+        return;
+    };
+    let mut active = calendar.current().clone();
+    let mut failed = None;
+    loop {
+        sleep(CHECK_INTERVAL).await;
+        let round = calendar.current();
+        if *round == active {
+            continue;
+        }
+        match switch(&state, round).await {
+            Ok(()) => {
+                info!("Switched to the round of {}", round);
+                active = round.clone();
+                failed = None;
+                if let Err(e) = full_update(state.clone()).await {
+                    error!("Failed to fill the new round: {:?}", e);
+                }
+            }
+            // Tried again every minute, but only logged once
+            Err(e) if failed.as_ref() != Some(round) => {
+                error!("Failed to switch to the round of {}: {:?}", round, e);
+                failed = Some(round.clone());
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    const CALENDAR: &str = r#"[
+        {"from": "2026-02-01", "until": "2026-02-28", "event_guid": "feb", "acsm_json_file": ["feb.json"]},
+        {"from": "2026-01-01", "until": "2026-01-31", "event_guid": "jan", "acsm_json_file": ["jan.json"]}
+    ]"#;
+
+    #[test_case("2025-12-01", "jan"; "before the first")]
+    #[test_case("2026-01-31", "jan"; "last day")]
+    #[test_case("2026-02-01", "feb"; "first day")]
+    #[test_case("2026-03-01", "feb"; "after the last")]
+    fn round_on(today: &str, expected: &str) {
+        let calendar = Calendar::parse(CALENDAR).unwrap();
+        let today = today.parse().unwrap();
+        assert_eq!(calendar.round_on(today).event_guid, expected);
+    }
+
+    #[test_case("[]"; "empty")]
+    #[test_case(
+        r#"[{"from": "2026-01-31", "until": "2026-01-01", "event_guid": "jan", "acsm_json_file": ["jan.json"]}]"#;
+        "backwards"
+    )]
+    #[test_case(
+        r#"[{"from": "2026-01-01", "until": "2026-01-31", "event_guid": "jan", "acsm_json_file": []}]"#;
+        "no files"
+    )]
+    #[test_case(
+        r#"[
+            {"from": "2026-01-01", "until": "2026-01-31", "event_guid": "jan", "acsm_json_file": ["jan.json"]},
+            {"from": "2026-01-31", "until": "2026-02-28", "event_guid": "feb", "acsm_json_file": ["feb.json"]}
+        ]"#;
+        "overlap"
+    )]
+    fn rejects(text: &str) {
+        assert!(Calendar::parse(text).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::{collections::HashMap, fmt};
+
+use crate::acsm::ClassSlots;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapacityLevel {
+    #[default]
+    Normal,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for CapacityLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapacityLevel::Normal => write!(f, "normal"),
+            CapacityLevel::Warning => write!(f, "warning"),
+            CapacityLevel::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// Filled slots at which a class gets to each level
+#[derive(Debug, PartialEq)]
+struct Thresholds {
+    warning: usize,
+    critical: usize,
+}
+
+impl Thresholds {
+    fn level(&self, filled: usize) -> CapacityLevel {
+        if filled >= self.critical {
+            CapacityLevel::Critical
+        } else if filled >= self.warning {
+            CapacityLevel::Warning
+        } else {
+            CapacityLevel::Normal
+        }
+    }
+}
+
+/// Filled and total slots of a class, summed over the files
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub filled: usize,
+    pub slots: usize,
+}
+
+/// By class name, as the same class in several files is one class for the
+/// tickets
+pub fn usage_by_class(splits: &[Vec<ClassSlots>]) -> HashMap<&str, Usage> {
+    let mut usage: HashMap<&str, Usage> = HashMap::new();
+    for class in splits.iter().flatten() {
+        let class_usage = usage.entry(&class.name).or_default();
+        class_usage.filled += class.guids.len() - class.free_slots();
+        class_usage.slots += class.guids.len();
+    }
+    usage
+}
+
+/// A class that went to another level
+#[derive(Debug, PartialEq)]
+pub struct LevelChange {
+    pub class: String,
+    pub level: CapacityLevel,
+    pub filled: usize,
+    pub slots: usize,
+}
+
+/// Tracks how full each class with thresholds is, to notify once when it
+/// crosses one instead of on every sync
+#[derive(Debug, Default)]
+pub struct CapacityAlerts {
+    thresholds: HashMap<String, Thresholds>,
+    /// Slots a class has to drop below a threshold before it goes back down,
+    /// so a driver leaving and another joining doesn't notify twice
+    hysteresis: usize,
+    levels: HashMap<String, CapacityLevel>,
+}
+
+impl CapacityAlerts {
+    /// From CLASS_CAPACITY_THRESHOLDS and CLASS_CAPACITY_HYSTERESIS
+    pub fn from_env() -> Result<CapacityAlerts> {
+        let thresholds = dotenv::var("CLASS_CAPACITY_THRESHOLDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.is_empty())
+            .map(parse_thresholds)
+            .collect::<Result<_>>()?;
+        let hysteresis = dotenv::var("CLASS_CAPACITY_HYSTERESIS")
+            .unwrap_or_else(|_| "2".to_string())
+            .parse()
+            .context("CLASS_CAPACITY_HYSTERESIS is not a number")?;
+        Ok(CapacityAlerts {
+            thresholds,
+            hysteresis,
+            levels: HashMap::new(),
+        })
+    }
+
+    pub fn class_names(&self) -> impl Iterator<Item = &String> {
+        self.thresholds.keys()
+    }
+
+    /// Update the levels from the classes of every file and return the classes
+    /// that changed level
+    pub fn check(&mut self, splits: &[Vec<ClassSlots>]) -> Vec<LevelChange> {
+        let usage = usage_by_class(splits);
+        let mut changes = Vec::new();
+        for (class, thresholds) in &self.thresholds {
+            let Usage { filled, slots } = usage.get(class.as_str()).copied().unwrap_or_default();
+            let current = self.levels.get(class).copied().unwrap_or_default();
+            let raised = thresholds.level(filled);
+            let lowered = thresholds.level(filled + self.hysteresis);
+            let level = if raised > current {
+                raised
+            } else if lowered < current {
+                lowered
+            } else {
+                current
+            };
+            if level != current {
+                self.levels.insert(class.clone(), level);
+                changes.push(LevelChange {
+                    class: class.clone(),
+                    level,
+                    filled,
+                    slots,
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.class.cmp(&b.class));
+        changes
+    }
+}
+
+/// `class:warning:critical`, where the class name may contain `:` itself
+fn parse_thresholds(entry: &str) -> Result<(String, Thresholds)> {
+    let mut parts = entry.rsplitn(3, ':');
+    let (Some(critical), Some(warning), Some(class)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(anyhow!(
+            "CLASS_CAPACITY_THRESHOLDS entry {} is not class:warning:critical",
+            entry
+        ));
+    };
+    let thresholds = Thresholds {
+        warning: warning
+            .trim()
+            .parse()
+            .with_context(|| format!("Warning threshold for {} is not a number", class))?,
+        critical: critical
+            .trim()
+            .parse()
+            .with_context(|| format!("Critical threshold for {} is not a number", class))?,
+    };
+    if thresholds.warning > thresholds.critical {
+        return Err(anyhow!(
+            "Warning threshold for {} is above the critical one",
+            class
+        ));
+    }
+    Ok((class.trim().to_string(), thresholds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn class(filled: usize) -> Vec<ClassSlots> {
+        vec![ClassSlots {
+            name: "GT3".to_string(),
+            cars: vec!["gt3".to_string()],
+            guids: (0..40)
+                .map(|slot| {
+                    if slot < filled {
+                        slot.to_string()
+                    } else {
+                        String::new()
+                    }
+                })
+                .collect(),
+        }]
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut alerts = CapacityAlerts {
+            thresholds: HashMap::from([parse_thresholds("GT3:35:40").unwrap()]),
+            hysteresis: 2,
+            levels: HashMap::new(),
+        };
+        let mut levels = Vec::new();
+        for filled in [30, 35, 34, 36, 40, 39, 38, 37, 33, 35, 32, 40] {
+            levels.push(
+                alerts
+                    .check(&[class(filled)])
+                    .pop()
+                    .map(|change| change.level),
+            );
+        }
+        use CapacityLevel::*;
+        assert_eq!(
+            levels,
+            [
+                None,
+                Some(Warning),
+                None,
+                None,
+                Some(Critical),
+                None,
+                None,
+                Some(Warning),
+                None,
+                None,
+                Some(Normal),
+                Some(Critical),
+            ]
+        );
+    }
+
+    #[test]
+    fn summed_over_splits() {
+        let mut alerts = CapacityAlerts {
+            thresholds: HashMap::from([parse_thresholds("GT3:35:40").unwrap()]),
+            hysteresis: 2,
+            levels: HashMap::new(),
+        };
+        let changes = alerts.check(&[class(20), class(20)]);
+        assert_eq!(
+            changes,
+            [LevelChange {
+                class: "GT3".to_string(),
+                level: CapacityLevel::Critical,
+                filled: 40,
+                slots: 80,
+            }]
+        );
+    }
+
+    #[test_case("GT3:35:40", Some(("GT3", 35, 40)))]
+    #[test_case("LMP: Pro:10:12", Some(("LMP: Pro", 10, 12)))]
+    #[test_case("GT3:40:35", None)]
+    #[test_case("GT3:35", None)]
+    #[test_case("GT3:x:40", None)]
+    fn parse(entry: &str, expected: Option<(&str, usize, usize)>) {
+        assert_eq!(
+            parse_thresholds(entry).ok(),
+            expected.map(|(class, warning, critical)| (
+                class.to_string(),
+                Thresholds { warning, critical }
+            ))
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::Result;
+use axum::{extract, http::StatusCode, response::Html, Json};
+use axum_macros::debug_handler;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    acsm::{self, BasicDriver},
+    events::EventKind,
+    reload,
+    report::{ProblemKind, Report},
+    State,
+};
+
+/// Order and payment statuses of a disputed payment, as opposed to a refund
+const DISPUTE_STATUSES: [&str; 3] = ["chargeback", "charged_back", "disputed"];
+
+/// Webhook events for disputed payments, taken like order-paid
+pub const WEBHOOK_EVENTS: [&str; 2] = ["order-chargeback", "order-disputed"];
+
+/// A driver whose payment was disputed, kept off the grid until an admin
+/// clears them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chargeback {
+    pub steam_id: u64,
+    pub name: String,
+    pub order_guid: String,
+    pub time: DateTime<Utc>,
+}
+
+pub fn is_disputed(status: &str) -> bool {
+    DISPUTE_STATUSES.contains(&status)
+}
+
+/// For a source to report the drivers of a disputed order
+pub fn report(report: &mut Report, order_guid: &str, driver: &BasicDriver) {
+    report.add(
+        ProblemKind::Chargeback,
+        Some(order_guid),
+        driver.ticket_guid.as_deref(),
+        format!(
+            "{} steam_id={} is in an order with a disputed payment",
+            driver.name, driver.steam_id
+        ),
+    );
+    report.chargebacks.push(Chargeback {
+        steam_id: driver.steam_id,
+        name: driver.name.clone(),
+        order_guid: order_guid.to_string(),
+        time: Utc::now(),
+    });
+}
+
+/// Hold back drivers on the review list, and those just reported, also when
+/// they have another ticket that's fine
+pub async fn hold_under_review(state: &State, drivers: &mut Vec<BasicDriver>, report: &mut Report) {
+    let mut steam_ids: Vec<u64> = state
+        .store
+        .lock()
+        .await
+        .data()
+        .chargebacks
+        .iter()
+        .map(|chargeback| chargeback.steam_id)
+        .collect();
+    steam_ids.extend(
+        report
+            .chargebacks
+            .iter()
+            .map(|chargeback| chargeback.steam_id),
+    );
+    let (held, allowed): (Vec<_>, Vec<_>) = drivers
+        .drain(..)
+        .partition(|driver| steam_ids.contains(&driver.steam_id));
+    for driver in held {
+        report.add(
+            ProblemKind::Chargeback,
+            driver.order_guid.as_deref(),
+            driver.ticket_guid.as_deref(),
+            format!(
+                "{} steam_id={} is on the review list after a disputed payment",
+                driver.name, driver.steam_id
+            ),
+        );
+    }
+    *drivers = allowed;
+}
+
+/// Put newly reported chargebacks on the review list, and let the organizer
+/// know through the events, the status webhook and CHARGEBACK_EMAIL
+pub async fn review(state: &State, report: &Report) -> Result<()> {
+    let mut store = state.store.lock().await;
+    let new: Vec<Chargeback> = report
+        .chargebacks
+        .iter()
+        .filter(|chargeback| {
+            !store.data().chargebacks.iter().any(|known| {
+                known.steam_id == chargeback.steam_id && known.order_guid == chargeback.order_guid
+            })
+        })
+        .cloned()
+        .collect();
+    if new.is_empty() {
+        return Ok(());
+    }
+    store
+        .update(|data| data.chargebacks.extend(new.iter().cloned()))
+        .await?;
+    drop(store);
+    for chargeback in new {
+        warn!(
+            "Disputed payment in order {}, {} steam_id={} is on the review list",
+            chargeback.order_guid, chargeback.name, chargeback.steam_id
+        );
+        state.events.emit(EventKind::Chargeback {
+            order_guid: chargeback.order_guid.clone(),
+            steam_id: chargeback.steam_id,
+        });
+        let (Some(mailer), Some(to)) = (&state.mailer, &state.chargeback_email) else {
+            continue;
+        };
+        let subject = format!("Disputed payment by {}", chargeback.name);
+        let body = format!(
+            "The payment of order {} was disputed. {} (steam_id={}) is off the grid and on \
+             the review list, until cleared with DELETE /admin/v1/chargebacks/{}.\n",
+            chargeback.order_guid, chargeback.name, chargeback.steam_id, chargeback.steam_id
+        );
+        if let Err(e) = mailer.send(to, &subject, body).await {
+            error!("Failed to email about the disputed payment: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Take the drivers off the grid right away, for webhooks. Full updates leave
+/// them out anyway, so if writes are held back that's queued instead.
+pub async fn remove(state: &State, chargebacks: &[Chargeback]) -> Result<()> {
+    if chargebacks.is_empty() {
+        return Ok(());
+    }
+    let mut write_gate = state.write_gate.lock().await;
+    if write_gate.is_held() {
+        info!("Writes held back, removing disputed drivers with the next full update");
+        write_gate.full_update_queued = true;
+        return Ok(());
+    }
+    let mut removed = false;
+    for json_file in state.acsm_json_files.lock().await.iter() {
+        for chargeback in chargebacks {
+            removed |= acsm::remove_driver(json_file, chargeback.steam_id).await?;
+        }
+    }
+    if removed {
+        reload::after_change(state, &mut write_gate).await;
+    }
+    Ok(())
+}
+
+/// `GET /admin/v1/chargebacks`, the review list
+#[debug_handler]
+pub async fn handle_list(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Json<Vec<Chargeback>> {
+    Json(state.store.lock().await.data().chargebacks.clone())
+}
+
+/// Clear a driver after review, so they can race again with the next update
+#[debug_handler]
+pub async fn handle_clear(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(steam_id): extract::Path<u64>,
+) -> Result<Html<&'static str>, StatusCode> {
+    let mut store = state.store.lock().await;
+    if !store
+        .data()
+        .chargebacks
+        .iter()
+        .any(|chargeback| chargeback.steam_id == steam_id)
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    store
+        .update(|data| {
+            data.chargebacks
+                .retain(|chargeback| chargeback.steam_id != steam_id)
+        })
+        .await
+        .map_err(|e| {
+            error!("Failed to clear chargeback: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    info!("Cleared steam_id={} after a disputed payment", steam_id);
+    Ok(Html("chargeback cleared"))
+}
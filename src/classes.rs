@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::collections::HashMap;
+
+use crate::acsm::{BasicDriver, ClassSlots};
+
+/// Routes drivers to classes by their skill metadata, e.g. Pro and Am classes
+/// that both run the car of the ticket they bought
+#[derive(Debug, Default)]
+pub struct SkillClasses {
+    /// Lowercase skill value to class names, one per car when the cars race in
+    /// classes of their own
+    pub skill_to_class: HashMap<String, Vec<String>>,
+    /// For drivers whose skill is missing or not in the map
+    pub fallback_class: Vec<String>,
+}
+
+impl SkillClasses {
+    pub fn from_env() -> Result<SkillClasses> {
+        let mut skill_to_class: HashMap<String, Vec<String>> = HashMap::new();
+        for pair in dotenv::var("SKILL_TO_CLASS_MAP")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+        {
+            let (skill, class) = pair
+                .split_once(':')
+                .context("Missing : separator in SKILL_TO_CLASS_MAP")?;
+            skill_to_class
+                .entry(skill.trim().to_lowercase())
+                .or_default()
+                .push(class.trim().to_string());
+        }
+        let fallback_class = dotenv::var("SKILL_FALLBACK_CLASS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|class| !class.is_empty())
+            .map(str::to_string)
+            .collect();
+        Ok(SkillClasses {
+            skill_to_class,
+            fallback_class,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.skill_to_class.is_empty() || !self.fallback_class.is_empty()
+    }
+
+    /// All classes drivers can be routed to
+    pub fn class_names(&self) -> impl Iterator<Item = &String> {
+        self.skill_to_class
+            .values()
+            .flatten()
+            .chain(&self.fallback_class)
+    }
+
+    /// Of `cars`, those that drivers of a skill, or without one, can't be
+    /// routed with, as `car (skill)`
+    pub fn missing_cars<'a>(
+        &self,
+        classes: &[ClassSlots],
+        cars: impl Iterator<Item = &'a String> + Clone,
+    ) -> Vec<String> {
+        let has_car = |names: &[&String], car: &String| {
+            classes
+                .iter()
+                .any(|class| names.contains(&&class.name) && class.cars.contains(car))
+        };
+        let mut missing = Vec::new();
+        if !self.fallback_class.is_empty() {
+            let names: Vec<_> = self.fallback_class.iter().collect();
+            missing.extend(
+                cars.clone()
+                    .filter(|car| !has_car(&names, car))
+                    .map(|car| format!("{} (fallback)", car)),
+            );
+        }
+        for (skill, skill_classes) in &self.skill_to_class {
+            // The fallback takes the cars the skill's classes don't have
+            let names: Vec<_> = skill_classes.iter().chain(&self.fallback_class).collect();
+            missing.extend(
+                cars.clone()
+                    .filter(|car| !has_car(&names, car))
+                    .map(|car| format!("{} ({})", car, skill)),
+            );
+        }
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+
+    /// The first of `names` with the driver's car in `classes`
+    fn with_car<'a>(
+        names: &'a [String],
+        classes: &[ClassSlots],
+        driver: &BasicDriver,
+    ) -> Option<&'a String> {
+        names.iter().find(|name| {
+            classes
+                .iter()
+                .any(|class| &class.name == *name && class.cars.contains(&driver.car))
+        })
+    }
+
+    /// Set the class of drivers that don't have one yet, to one of `classes`
+    /// that has their car
+    pub fn assign(&self, drivers: &mut [BasicDriver], classes: &[ClassSlots]) {
+        if !self.is_enabled() {
+            return;
+        }
+        for driver in drivers.iter_mut().filter(|driver| driver.class.is_none()) {
+            let class = driver
+                .skill
+                .as_ref()
+                .and_then(|skill| self.skill_to_class.get(&skill.trim().to_lowercase()))
+                .and_then(|names| SkillClasses::with_car(names, classes, driver))
+                .or_else(|| SkillClasses::with_car(&self.fallback_class, classes, driver))
+                .cloned();
+            if class.is_none() {
+                warn!(
+                    "No skill class has car {} for steam_id={} with skill {:?}",
+                    driver.car, driver.steam_id, driver.skill
+                );
+                continue;
+            }
+            debug!(
+                "Routing steam_id={} with skill {:?} to class {:?}",
+                driver.steam_id, driver.skill, class
+            );
+            driver.class = class;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn class(name: &str, car: &str) -> ClassSlots {
+        ClassSlots {
+            name: name.to_string(),
+            cars: vec![car.to_string()],
+            guids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn missing_cars() {
+        let skill_classes = SkillClasses {
+            skill_to_class: HashMap::from([("pro".to_string(), vec!["GT3 Pro".to_string()])]),
+            fallback_class: vec!["Am".to_string()],
+        };
+        let classes = [
+            class("GT3 Pro", "gt3"),
+            class("Am", "gt3"),
+            class("GT4", "gt4"),
+        ];
+        let cars = ["gt3".to_string(), "gt4".to_string()];
+        assert_eq!(
+            skill_classes.missing_cars(&classes, cars.iter()),
+            vec!["gt4 (fallback)", "gt4 (pro)"]
+        );
+    }
+
+    #[test_case("gt3", Some("pro"), Some("GT3 Pro"); "skill")]
+    #[test_case("gt4", Some("PRO"), Some("GT4 Pro"); "skill for the other car")]
+    #[test_case("gt3", Some("rookie"), Some("GT3 Am"); "unknown skill")]
+    #[test_case("gt3", None, Some("GT3 Am"); "no skill")]
+    #[test_case("lmp1", Some("pro"), None; "car in no skill class")]
+    fn assign(car: &str, skill: Option<&str>, expected: Option<&str>) {
+        let skill_classes = SkillClasses {
+            skill_to_class: HashMap::from([(
+                "pro".to_string(),
+                vec!["GT3 Pro".to_string(), "GT4 Pro".to_string()],
+            )]),
+            fallback_class: vec!["GT3 Am".to_string()],
+        };
+        let classes = [
+            class("GT3 Pro", "gt3"),
+            class("GT3 Am", "gt3"),
+            class("GT4 Pro", "gt4"),
+            class("LMP1", "lmp1"),
+        ];
+        let mut drivers = vec![BasicDriver {
+            skill: skill.map(str::to_string),
+            ..BasicDriver::test(1, car)
+        }];
+        skill_classes.assign(&mut drivers, &classes);
+        assert_eq!(drivers[0].class.as_deref(), expected);
+    }
+}
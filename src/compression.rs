@@ -0,0 +1,191 @@
+use anyhow::{anyhow, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use tokio::fs;
+
+/// How to compress backups of the ACSM files, and responses to clients that
+/// accept it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// From BACKUP_COMPRESSION, set once at startup
+static BACKUP_COMPRESSION: OnceLock<Option<Compression>> = OnceLock::new();
+
+impl Compression {
+    fn parse(name: &str) -> Result<Option<Compression>> {
+        match name {
+            "" | "none" => Ok(None),
+            "gzip" => Ok(Some(Compression::Gzip)),
+            "zstd" => Ok(Some(Compression::Zstd)),
+            _ => Err(anyhow!(
+                "BACKUP_COMPRESSION must be `none`, `gzip` or `zstd`, not `{}`",
+                name
+            )),
+        }
+    }
+
+    /// Read BACKUP_COMPRESSION, for [`backup_compression`] to return from now on
+    pub fn init_from_env() -> Result<()> {
+        let compression =
+            Compression::parse(&dotenv::var("BACKUP_COMPRESSION").unwrap_or_default())?;
+        BACKUP_COMPRESSION.get_or_init(|| compression);
+        Ok(())
+    }
+
+    /// Also the name for the `Accept-Encoding` and `Content-Encoding` headers
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// From the extension of a compressed file
+    fn of(path: &Path) -> Option<Compression> {
+        let name = path.file_name()?.to_string_lossy();
+        [Compression::Gzip, Compression::Zstd]
+            .into_iter()
+            .find(|compression| name.ends_with(compression.extension()))
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(data).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            Compression::Zstd => Ok(zstd::decode_all(data)?),
+        }
+    }
+
+    /// The first encoding in an `Accept-Encoding` header we can do, ignoring
+    /// quality values other than zero
+    pub fn accepted(accept_encoding: &str) -> Option<Compression> {
+        accept_encoding
+            .split(',')
+            .filter_map(|encoding| {
+                let mut parts = encoding.split(';').map(str::trim);
+                let name = parts.next()?;
+                let refused = parts.any(|parameter| {
+                    parameter
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        == Some(0.0)
+                });
+                (!refused).then_some(name)
+            })
+            .find_map(|name| Compression::parse(name).ok().flatten())
+    }
+}
+
+/// From BACKUP_COMPRESSION, none before [`Compression::init_from_env`]
+pub fn backup_compression() -> Option<Compression> {
+    BACKUP_COMPRESSION.get().copied().flatten()
+}
+
+/// A backup without the compression extension, if any
+pub fn strip_extension(name: &str) -> &str {
+    Compression::of(Path::new(name))
+        .and_then(|compression| name.strip_suffix(compression.extension()))
+        .unwrap_or(name)
+}
+
+/// Compress a local backup with BACKUP_COMPRESSION, replacing it with one with
+/// the extension added. Returns where the backup is now.
+pub async fn compress_backup(backup: &Path) -> Result<PathBuf> {
+    let Some(compression) = backup_compression() else {
+        return Ok(backup.to_path_buf());
+    };
+    let mut compressed = backup.as_os_str().to_os_string();
+    compressed.push(compression.extension());
+    let compressed = PathBuf::from(compressed);
+    let data = fs::read(backup)
+        .await
+        .with_context(|| format!("Failed to read {}", backup.display()))?;
+    fs::write(&compressed, compression.compress(&data)?)
+        .await
+        .with_context(|| format!("Failed to write {}", compressed.display()))?;
+    fs::remove_file(backup).await?;
+    Ok(compressed)
+}
+
+/// Read a backup, compressed or not
+pub async fn read_backup(backup: &Path) -> Result<String> {
+    let data = fs::read(backup)
+        .await
+        .with_context(|| format!("Failed to read {}", backup.display()))?;
+    let data = match Compression::of(backup) {
+        Some(compression) => compression
+            .decompress(&data)
+            .with_context(|| format!("Failed to decompress {}", backup.display()))?,
+        None => data,
+    };
+    String::from_utf8(data).with_context(|| format!("{} is not text", backup.display()))
+}
+
+/// Replace a backup, compressed the same way it was
+pub async fn write_backup(backup: &Path, text: &str) -> Result<()> {
+    let data = match Compression::of(backup) {
+        Some(compression) => compression.compress(text.as_bytes())?,
+        None => text.as_bytes().to_vec(),
+    };
+    fs::write(backup, data)
+        .await
+        .with_context(|| format!("Failed to write {}", backup.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(Compression::Gzip; "gzip")]
+    #[test_case(Compression::Zstd; "zstd")]
+    fn round_trip(compression: Compression) {
+        let data = br#"{"Classes": []}"#.repeat(100);
+        let compressed = compression.compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(compression.decompress(&compressed).unwrap(), data);
+    }
+
+    #[test_case("gzip, deflate, br", Some(Compression::Gzip); "gzip")]
+    #[test_case("br;q=1.0, zstd;q=0.5, gzip", Some(Compression::Zstd); "first")]
+    #[test_case("zstd;q=0, gzip", Some(Compression::Gzip); "refused")]
+    #[test_case("identity", None; "none")]
+    fn accepted(accept_encoding: &str, expected: Option<Compression>) {
+        assert_eq!(Compression::accepted(accept_encoding), expected);
+    }
+
+    #[test]
+    fn strips_extension() {
+        assert_eq!(strip_extension("c.json.backup_1.gz"), "c.json.backup_1");
+        assert_eq!(strip_extension("c.json.backup_1.zst"), "c.json.backup_1");
+        assert_eq!(strip_extension("c.json.backup_1"), "c.json.backup_1");
+    }
+}
@@ -0,0 +1,753 @@
+use anyhow::{anyhow, Context, Result};
+use figment::{
+    providers::{Format, Toml},
+    value::{Dict, Map, Value},
+    Figment, Metadata, Profile, Provider,
+};
+use itertools::Itertools;
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    redact,
+    splits::SplitPolicy,
+    tickets::{DuplicatePolicy, UnmappedTicketPolicy},
+};
+
+/// Every setting there is, with example values for some
+pub const TEMPLATE: &str = include_str!("../.env-template");
+
+/// What the code uses for settings that aren't set. Values in `.env-template`
+/// that aren't here, like LISTEN_ADDRESS, are only examples.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("SANDBOX", "false"),
+    ("VAULT_REFRESH_SECONDS", "300"),
+    ("CREDENTIAL_STORE", "file"),
+    ("TICKET_SOURCE", "eventix"),
+    ("S3_REGION", "us-east-1"),
+    ("ACSM_LIVE_POLL_SECONDS", "30"),
+    ("DRIFT_THRESHOLD", "0"),
+    ("BACKUP_COMPRESSION", "none"),
+    ("BACKUP_FILENAME", "{name}.backup_{time}"),
+    ("SPLIT_POLICY", "fill-first"),
+    ("UNMAPPED_TICKET_POLICY", "skip"),
+    ("DUPLICATE_STEAM_ID_POLICY", "earliest"),
+    ("MAX_BAD_TICKET_FRACTION", "0.5"),
+    ("REQUIRE_CHECK_IN", "false"),
+    ("CHECK_IN_POLL_SECONDS", "60"),
+    ("TRANSLITERATE_NAMES", "false"),
+    ("NAME_TRUNCATION", "cut"),
+    ("CLASS_CAPACITY_HYSTERESIS", "2"),
+    ("CLOSE_SALES_WHEN_FULL", "false"),
+    ("SALES_WAITLIST_DEPTH", "0"),
+    ("SYNC_TICKET_AVAILABILITY", "false"),
+    ("AVAILABILITY_SAFETY_MARGIN", "0"),
+    ("OTEL_SERVICE_NAME", "eventix2acsm"),
+    ("RUST_LOG", "info"),
+    ("LOG_FORMAT", "text"),
+    ("LOG_FILE_MAX_SIZE_MB", "10"),
+    ("LOG_FILE_ROTATE_DAILY", "false"),
+    ("LOG_FILE_KEEP", "5"),
+    ("LOG_TO_STDERR", "true"),
+    ("ARCHIVE_DIR", "archive"),
+    ("STATE_FILE", "eventix2acsm-state.json"),
+    ("LOG_REQUESTS", "false"),
+    ("EVENTIX_BREAKER_THRESHOLD", "5"),
+    ("EVENTIX_BREAKER_PROBE_SECONDS", "60"),
+    ("OAUTH2_LOCAL_CALLBACK_ADDRESS", "127.0.0.1:8765"),
+    ("STEAM_CHECK_OWNERSHIP", "false"),
+    (
+        "STEAM_GROUP_EMAIL_SUBJECT",
+        "Join our Steam group to get on the grid",
+    ),
+];
+
+/// The defaults of every `<prefix>_HTTP_*` setting, by suffix
+const HTTP_DEFAULTS: &[(&str, &str)] = &[
+    ("_HTTP_CONNECT_TIMEOUT_SECONDS", "10"),
+    ("_HTTP_TIMEOUT_SECONDS", "30"),
+    ("_HTTP_RETRIES", "2"),
+    ("_HTTP_BACKOFF_MILLISECONDS", "500"),
+];
+
+/// Where the value of a setting comes from
+#[derive(Debug, PartialEq)]
+enum Origin {
+    /// A `--set NAME=value` flag, which wins over everything
+    Flag,
+    /// The process environment, which wins over `.env`
+    Environment,
+    DotEnv,
+    /// CONFIG_FILE, which `.env` and the environment win over
+    ConfigFile,
+    /// Not set, so the built-in default applies
+    Default,
+    /// Not set, and there's no default
+    Unset,
+    /// Set, but not a setting we know, e.g. a typo
+    Unknown,
+}
+
+#[derive(Debug, PartialEq)]
+struct Setting {
+    name: String,
+    value: String,
+    origin: Origin,
+}
+
+/// The setting names in `.env-template`, in order
+fn known_settings(template: &str) -> Vec<&str> {
+    template
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, _)| name)
+        .filter(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        })
+        .collect()
+}
+
+/// The value the code uses when the setting isn't set, if any
+fn default(name: &str) -> Option<&'static str> {
+    DEFAULTS
+        .iter()
+        .find(|(setting, _)| *setting == name)
+        .or_else(|| {
+            HTTP_DEFAULTS
+                .iter()
+                .find(|(suffix, _)| name.ends_with(suffix))
+        })
+        .map(|(_, value)| *value)
+}
+
+fn is_secret(known: &[&str], name: &str) -> bool {
+    redact::is_sensitive_name(name) && !name.ends_with("_URL") && known.contains(&name)
+}
+
+/// The example or default value of the setting in `.env-template`
+pub fn template_value(name: &str) -> Option<&'static str> {
+    TEMPLATE
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(known, _)| *known == name)
+        .map(|(_, value)| value)
+}
+
+/// Whether the setting holds a secret, like a token or password
+pub fn is_secret_setting(name: &str) -> bool {
+    is_secret(&known_settings(TEMPLATE), name)
+}
+
+/// Every setting that holds a secret
+pub fn secret_settings() -> Vec<&'static str> {
+    let known = known_settings(TEMPLATE);
+    known
+        .iter()
+        .copied()
+        .filter(|name| is_secret(&known, name))
+        .collect()
+}
+
+/// The setting a `<NAME>_FILE` setting reads its secret from a file for, if
+/// it is one
+fn secret_for_file<'a>(known: &[&str], name: &'a str) -> Option<&'a str> {
+    name.strip_suffix("_FILE")
+        .filter(|secret| is_secret(known, secret))
+}
+
+/// Read every secret setting with a `<NAME>_FILE` from that file, so secrets
+/// can be mounted as files instead of sitting in the environment. Trailing
+/// newlines are left out.
+pub fn load_secret_files() -> Result<()> {
+    let known = known_settings(TEMPLATE);
+    for name in &known {
+        let file_setting = format!("{}_FILE", name);
+        if secret_for_file(&known, &file_setting).is_none() {
+            continue;
+        }
+        let Some(path) = dotenv::var(&file_setting)
+            .ok()
+            .filter(|path| !path.is_empty())
+        else {
+            continue;
+        };
+        if dotenv::var(name).is_ok_and(|value| !value.is_empty()) {
+            return Err(anyhow!("Set {} or {}, not both", name, file_setting));
+        }
+        let value = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {} {}", file_setting, path))?;
+        // The environment wins over `.env`, so this is what everyone reads
+        std::env::set_var(name, value.trim_end_matches(['\r', '\n']));
+    }
+    Ok(())
+}
+
+/// `--config <file>` and `--set NAME=value` from the command line, after
+/// the subcommand if any
+#[derive(Debug, Default)]
+pub struct Flags {
+    config_file: Option<PathBuf>,
+    set: Vec<(String, String)>,
+}
+
+impl Flags {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Flags> {
+        let known = known_settings(TEMPLATE);
+        let mut flags = Flags::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    flags.config_file = Some(args.next().context("--config needs a file")?.into());
+                }
+                "--set" => {
+                    let setting = args.next().context("--set needs NAME=value")?;
+                    let (name, value) = setting
+                        .split_once('=')
+                        .with_context(|| format!("--set {} is not NAME=value", setting))?;
+                    if !is_known(&known, name) {
+                        return Err(anyhow!("{} from --set is not a setting", name));
+                    }
+                    flags.set.push((name.to_string(), value.to_string()));
+                }
+                _ => {}
+            }
+        }
+        Ok(flags)
+    }
+
+    /// From `--config`, or else CONFIG_FILE
+    fn config_file(&self) -> Option<PathBuf> {
+        self.config_file.clone().or_else(|| {
+            dotenv::var("CONFIG_FILE")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+        })
+    }
+}
+
+fn is_known(known: &[&str], name: &str) -> bool {
+    known.contains(&name) || secret_for_file(known, name).is_some()
+}
+
+/// A setting's value from the config file as text, like it would be in `.env`
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(_, value) => Some(value.clone()),
+        Value::Char(_, value) => Some(value.to_string()),
+        Value::Bool(_, value) => Some(value.to_string()),
+        Value::Num(_, value) => value
+            .to_i128()
+            .map(|value| value.to_string())
+            .or_else(|| value.to_f64().map(|value| value.to_string())),
+        // Lists are comma separated in `.env`
+        Value::Array(_, values) => values
+            .iter()
+            .map(text)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        Value::Dict(..) | Value::Empty(..) => None,
+    }
+}
+
+/// The settings in a TOML config file, which uses the same names as `.env`,
+/// like `SPLIT_POLICY = "overflow"`
+fn read_config_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let toml = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let data = Toml::string(&toml)
+        .data()
+        .map_err(|e| anyhow!("{}", e))
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+    let known = known_settings(TEMPLATE);
+    let mut settings = Vec::new();
+    for (name, value) in data.into_values().flatten() {
+        if !is_known(&known, &name) {
+            return Err(anyhow!(
+                "{} in config file {} is not a setting",
+                name,
+                path.display()
+            ));
+        }
+        let value = text(&value).with_context(|| {
+            format!("{} in config file {} is not a value", name, path.display())
+        })?;
+        settings.push((name, value));
+    }
+    Ok(settings)
+}
+
+/// One source of settings, with the values as text
+struct Layer {
+    name: String,
+    values: Vec<(String, String)>,
+}
+
+impl Provider for Layer {
+    fn metadata(&self) -> Metadata {
+        Metadata::named(self.name.clone())
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+        Ok(Profile::Default.collect(
+            self.values
+                .iter()
+                // Empty is the same as not set
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(name, value)| (name.clone(), Value::from(value.clone())))
+                .collect(),
+        ))
+    }
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let text = String::deserialize(deserializer)?;
+    text.parse()
+        .map_err(|e| de::Error::custom(format!("{:?} is invalid: {}", text, e)))
+}
+
+fn optional<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    parsed(deserializer).map(Some)
+}
+
+/// Comma separated
+fn list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse()
+                .map_err(|e| de::Error::custom(format!("{:?} is invalid: {}", item, e)))
+        })
+        .collect()
+}
+
+/// The settings `main` reads itself. Each comes from, in order of precedence,
+/// a `--set` flag, the environment, `.env`, CONFIG_FILE or the default.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE", default)]
+pub struct Config {
+    #[serde(deserialize_with = "list")]
+    pub acsm_json_file: Vec<PathBuf>,
+    #[serde(deserialize_with = "list")]
+    pub acsm_live_timing_url: Vec<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub acsm_live_poll_seconds: u64,
+    #[serde(deserialize_with = "parsed")]
+    pub split_policy: SplitPolicy,
+    #[serde(deserialize_with = "list")]
+    pub add_on_ticket_ids: Vec<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub unmapped_ticket_policy: UnmappedTicketPolicy,
+    #[serde(deserialize_with = "parsed")]
+    pub duplicate_steam_id_policy: DuplicatePolicy,
+    #[serde(deserialize_with = "parsed")]
+    pub max_bad_ticket_fraction: f64,
+    #[serde(deserialize_with = "parsed")]
+    pub require_check_in: bool,
+    #[serde(deserialize_with = "parsed")]
+    pub check_in_poll_seconds: u64,
+    #[serde(deserialize_with = "parsed")]
+    pub transliterate_names: bool,
+    #[serde(deserialize_with = "optional")]
+    pub webhook_max_age_seconds: Option<i64>,
+    #[serde(deserialize_with = "list")]
+    pub ignored_guids: Vec<String>,
+    #[serde(deserialize_with = "list")]
+    pub ignored_steam_ids: Vec<u64>,
+    #[serde(deserialize_with = "parsed")]
+    pub log_requests: bool,
+    #[serde(deserialize_with = "optional")]
+    pub listen_address: Option<String>,
+    #[serde(deserialize_with = "optional")]
+    pub admin_listen_address: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            acsm_json_file: Vec::new(),
+            acsm_live_timing_url: Vec::new(),
+            acsm_live_poll_seconds: 30,
+            split_policy: SplitPolicy::FillFirst,
+            add_on_ticket_ids: Vec::new(),
+            unmapped_ticket_policy: UnmappedTicketPolicy::Skip,
+            duplicate_steam_id_policy: DuplicatePolicy::Earliest,
+            max_bad_ticket_fraction: 0.5,
+            require_check_in: false,
+            check_in_poll_seconds: 60,
+            transliterate_names: false,
+            webhook_max_age_seconds: None,
+            ignored_guids: Vec::new(),
+            ignored_steam_ids: Vec::new(),
+            log_requests: false,
+            listen_address: None,
+            admin_listen_address: None,
+        }
+    }
+}
+
+/// Every problem with the settings, each with the setting and where it came
+/// from
+fn describe(error: figment::Error) -> anyhow::Error {
+    anyhow!(error
+        .into_iter()
+        .map(|e| {
+            let source = e
+                .metadata
+                .as_ref()
+                .map_or("default".to_string(), |metadata| metadata.name.to_string());
+            format!("{} from {}: {}", e.path.join("."), source, e.kind)
+        })
+        .join("\n"))
+}
+
+impl Config {
+    fn extract(layers: Vec<Layer>) -> Result<Config> {
+        layers
+            .into_iter()
+            .fold(Figment::new(), Figment::merge)
+            .extract()
+            .map_err(describe)
+            .context("Invalid configuration")
+    }
+
+    /// Read the settings from every layer. Settings from the config file and
+    /// flags are put in the environment too, where the rest are read from.
+    pub fn load(flags: &Flags) -> Result<Config> {
+        let config_file = match flags.config_file() {
+            Some(path) => Some((read_config_file(&path)?, path)),
+            None => None,
+        };
+        let mut layers = Vec::new();
+        if let Some((values, path)) = &config_file {
+            layers.push(Layer {
+                name: format!("config file {}", path.display()),
+                values: values.clone(),
+            });
+        }
+        layers.push(Layer {
+            name: "environment or .env".to_string(),
+            values: known_settings(TEMPLATE)
+                .into_iter()
+                .filter_map(|name| Some((name.to_string(), dotenv::var(name).ok()?)))
+                .collect(),
+        });
+        layers.push(Layer {
+            name: "--set".to_string(),
+            values: flags.set.clone(),
+        });
+        let config = Config::extract(layers)?;
+        for (name, value) in config_file.into_iter().flat_map(|(values, _)| values) {
+            if dotenv::var(&name).map_or(true, |value| value.is_empty()) {
+                std::env::set_var(name, value);
+            }
+        }
+        for (name, value) in &flags.set {
+            std::env::set_var(name, value);
+        }
+        Ok(config)
+    }
+}
+
+/// Work out every setting's effective value, with secrets masked
+fn settings(
+    template: &str,
+    environment: impl Fn(&str) -> Option<String>,
+    dot_env: &[(String, String)],
+    config_file: &[(String, String)],
+    flags: &[(String, String)],
+) -> Vec<Setting> {
+    let known = known_settings(template);
+    let dot_env_map: HashMap<_, _> = dot_env.iter().cloned().collect();
+    let config_file_map: HashMap<_, _> = config_file.iter().cloned().collect();
+    let flags_map: HashMap<_, _> = flags.iter().cloned().collect();
+    let mut settings = Vec::new();
+    for name in &known {
+        let set = |value: Option<String>| value.filter(|value| !value.is_empty());
+        let (value, origin) = None
+            .or_else(|| {
+                flags_map
+                    .get(*name)
+                    .map(|value| (value.clone(), Origin::Flag))
+            })
+            .or_else(|| set(environment(name)).map(|value| (value, Origin::Environment)))
+            .or_else(|| set(dot_env_map.get(*name).cloned()).map(|value| (value, Origin::DotEnv)))
+            .or_else(|| {
+                config_file_map
+                    .get(*name)
+                    .map(|value| (value.clone(), Origin::ConfigFile))
+            })
+            // Set, but empty
+            .or_else(|| environment(name).map(|value| (value, Origin::Environment)))
+            .or_else(|| {
+                dot_env_map
+                    .get(*name)
+                    .map(|value| (value.clone(), Origin::DotEnv))
+            })
+            .or_else(|| default(name).map(|value| (value.to_string(), Origin::Default)))
+            .unwrap_or_else(|| (String::new(), Origin::Unset));
+        settings.push(Setting {
+            name: name.to_string(),
+            value,
+            origin,
+        });
+    }
+    for (name, value) in dot_env {
+        if !known.contains(&name.as_str()) {
+            settings.push(Setting {
+                name: name.clone(),
+                value: value.clone(),
+                origin: if secret_for_file(&known, name).is_some() {
+                    Origin::DotEnv
+                } else {
+                    Origin::Unknown
+                },
+            });
+        }
+    }
+    for setting in &mut settings {
+        if !setting.value.is_empty()
+            && redact::is_sensitive_name(&setting.name)
+            && !setting.name.ends_with("_URL")
+            && !setting.name.ends_with("_FILE")
+        {
+            setting.value = redact::REDACTED.to_string();
+        }
+    }
+    settings
+}
+
+/// For `eventix2acsm config show`, before `.env` gets loaded into the
+/// environment, so we can still tell where each value comes from
+pub fn show() -> Result<()> {
+    let environment: HashMap<_, _> = std::env::vars().collect();
+    match dotenv::dotenv() {
+        Ok(_) => {}
+        Err(e) if e.not_found() => {}
+        Err(e) => return Err(e).context("Failed to load .env"),
+    }
+    // Loading .env doesn't override the environment, so new ones are from it
+    let mut dot_env: Vec<_> = std::env::vars()
+        .filter(|(name, _)| !environment.contains_key(name))
+        .collect();
+    dot_env.sort();
+    let flags = Flags::parse(std::env::args().skip(3))?;
+    let config_file = match flags.config_file() {
+        Some(path) => read_config_file(&path)?,
+        None => Vec::new(),
+    };
+    for setting in settings(
+        TEMPLATE,
+        |name| environment.get(name).cloned(),
+        &dot_env,
+        &config_file,
+        &flags.set,
+    ) {
+        let origin = match setting.origin {
+            Origin::Flag => "--set",
+            Origin::Environment => "environment",
+            Origin::DotEnv => ".env",
+            Origin::ConfigFile => "config file",
+            Origin::Default => "not set, default",
+            Origin::Unset => "not set",
+            Origin::Unknown => ".env, unknown setting",
+        };
+        println!("{}={} # {}", setting.name, setting.value, origin);
+    }
+    let flags: Vec<_> = std::env::args()
+        .skip(1)
+        .filter(|arg| arg.starts_with("--"))
+        .collect();
+    if !flags.is_empty() {
+        println!("# Flags: {}", flags.join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEMPLATE: &str = "# Comment with an = sign\n\
+        TICKET_ID_TO_CAR_MAP=\n\
+        SPLIT_POLICY=fill-first\n\
+        LISTEN_ADDRESS=127.0.0.1:8888\n\
+        PRETIX_HTTP_RETRIES=2\n\
+        PRETIX_API_TOKEN=\n\
+        CHECK_IN_POLL_SECONDS=60\n\
+        # EXAMPLE=commented out\n";
+
+    #[test]
+    fn origins() {
+        let dot_env = [
+            ("TICKET_ID_TO_CAR_MAP".to_string(), "1:a".to_string()),
+            ("PRETIX_API_TOKEN".to_string(), "tok".to_string()),
+            ("SPLT_POLICY".to_string(), "overflow".to_string()),
+            (
+                "PRETIX_API_TOKEN_FILE".to_string(),
+                "/run/secrets/pretix".to_string(),
+            ),
+            (
+                "SPLIT_POLICY_FILE".to_string(),
+                "/run/secrets/split".to_string(),
+            ),
+        ];
+        let environment = |name: &str| (name == "TICKET_ID_TO_CAR_MAP").then(|| "2:b".to_string());
+        let config_file = [
+            ("SPLIT_POLICY".to_string(), "overflow".to_string()),
+            ("TICKET_ID_TO_CAR_MAP".to_string(), "3:c".to_string()),
+        ];
+        let flags = [("CHECK_IN_POLL_SECONDS".to_string(), "5".to_string())];
+        let settings = settings(TEMPLATE, environment, &dot_env, &config_file, &flags);
+        let summary: Vec<_> = settings
+            .iter()
+            .map(|setting| {
+                (
+                    setting.name.as_str(),
+                    setting.value.as_str(),
+                    &setting.origin,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("TICKET_ID_TO_CAR_MAP", "2:b", &Origin::Environment),
+                ("SPLIT_POLICY", "overflow", &Origin::ConfigFile),
+                ("LISTEN_ADDRESS", "", &Origin::Unset),
+                ("PRETIX_HTTP_RETRIES", "2", &Origin::Default),
+                ("PRETIX_API_TOKEN", "[redacted]", &Origin::DotEnv),
+                ("CHECK_IN_POLL_SECONDS", "5", &Origin::Flag),
+                ("SPLT_POLICY", "overflow", &Origin::Unknown),
+                (
+                    "PRETIX_API_TOKEN_FILE",
+                    "/run/secrets/pretix",
+                    &Origin::DotEnv
+                ),
+                ("SPLIT_POLICY_FILE", "/run/secrets/split", &Origin::Unknown),
+            ]
+        );
+    }
+
+    fn layer(name: &str, values: &[(&str, &str)]) -> Layer {
+        Layer {
+            name: name.to_string(),
+            values: values
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn layered() {
+        let config = Config::extract(vec![
+            layer(
+                "config file",
+                &[
+                    ("SPLIT_POLICY", "overflow"),
+                    ("CHECK_IN_POLL_SECONDS", "10"),
+                ],
+            ),
+            layer(
+                "environment or .env",
+                &[
+                    ("CHECK_IN_POLL_SECONDS", "20"),
+                    ("IGNORED_STEAM_IDS", "1,2"),
+                ],
+            ),
+            layer("--set", &[("IGNORED_STEAM_IDS", "")]),
+        ])
+        .unwrap();
+        assert_eq!(config.split_policy, SplitPolicy::Overflow);
+        assert_eq!(config.check_in_poll_seconds, 20);
+        // Empty is not set, so it doesn't override
+        assert_eq!(config.ignored_steam_ids, vec![1, 2]);
+        assert_eq!(config.acsm_live_poll_seconds, 30);
+    }
+
+    #[test]
+    fn errors_name_setting_and_source() {
+        let message = |layer| format!("{:#}", Config::extract(vec![layer]).unwrap_err());
+        assert_eq!(
+            message(layer("config file c.toml", &[("SPLIT_POLICY", "sideways")])),
+            "Invalid configuration: SPLIT_POLICY from config file c.toml: \"sideways\" is \
+             invalid: Unknown split policy: sideways"
+        );
+        assert_eq!(
+            message(layer(
+                "environment or .env",
+                &[("CHECK_IN_POLL_SECONDS", "soon")]
+            )),
+            "Invalid configuration: CHECK_IN_POLL_SECONDS from environment or .env: \"soon\" \
+             is invalid: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn config_file_values_as_text() {
+        let data = Toml::string("A = 5\nB = true\nC = [\"a.json\", \"b.json\"]\nD = \"x\"")
+            .data()
+            .unwrap();
+        let dict = &data[&Profile::Default];
+        assert_eq!(text(&dict["A"]).unwrap(), "5");
+        assert_eq!(text(&dict["B"]).unwrap(), "true");
+        assert_eq!(text(&dict["C"]).unwrap(), "a.json,b.json");
+        assert_eq!(text(&dict["D"]).unwrap(), "x");
+    }
+
+    #[test]
+    fn template_is_complete() {
+        let known = known_settings(super::TEMPLATE);
+        for name in ["TICKET_ID_TO_CAR_MAP", "ACSM_JSON_FILE", "ADMIN_TOKEN"] {
+            assert!(known.contains(&name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn defaults_match_template() {
+        let template: HashMap<_, _> = super::TEMPLATE
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        for (name, value) in DEFAULTS {
+            assert_eq!(template.get(name), Some(value), "{}", name);
+        }
+    }
+
+    #[test]
+    fn secrets() {
+        let secrets = secret_settings();
+        assert!(secrets.contains(&"EVENTIX_OAUTH2_CLIENT_SECRET"));
+        assert!(secrets.contains(&"ADMIN_TOKEN"));
+        assert!(!secrets.contains(&"EVENTIX_OAUTH2_TOKEN_URL"));
+        assert!(!secrets.contains(&"TICKET_MAP_FILE"));
+    }
+}
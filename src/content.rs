@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::{collections::HashSet, path::PathBuf};
+
+use crate::http::HttpPolicy;
+
+/// Where to find the car models installed on the server
+pub enum CarContent {
+    /// AC's `content` directory, with a directory per car in `cars`
+    Dir(PathBuf),
+    /// A JSON list of cars from ACSM
+    Url(String),
+}
+
+fn var(name: &str) -> Option<String> {
+    dotenv::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl CarContent {
+    /// From ACSM_CONTENT_DIR or ACSM_CARS_URL
+    pub fn from_env() -> Result<Option<CarContent>> {
+        match (var("ACSM_CONTENT_DIR"), var("ACSM_CARS_URL")) {
+            (None, None) => Ok(None),
+            (Some(dir), None) => Ok(Some(CarContent::Dir(PathBuf::from(dir)))),
+            (None, Some(url)) => Ok(Some(CarContent::Url(url))),
+            (Some(_), Some(_)) => Err(anyhow!(
+                "Set either ACSM_CONTENT_DIR or ACSM_CARS_URL, not both"
+            )),
+        }
+    }
+
+    pub async fn car_models(&self, http: &HttpPolicy) -> Result<HashSet<String>> {
+        match self {
+            CarContent::Dir(dir) => {
+                let cars = dir.join("cars");
+                let mut entries = tokio::fs::read_dir(&cars)
+                    .await
+                    .with_context(|| format!("Failed to list {}", cars.display()))?;
+                let mut models = HashSet::new();
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.file_type().await?.is_dir() {
+                        models.insert(entry.file_name().to_string_lossy().into_owned());
+                    }
+                }
+                Ok(models)
+            }
+            CarContent::Url(url) => {
+                let response: Value = http
+                    .send(http.client().get(url))
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .context("Getting the car list from ACSM failed")?
+                    .json()
+                    .await
+                    .context("ACSM returned bad JSON")?;
+                car_models(&response)
+            }
+        }
+    }
+}
+
+/// Car models from a list of names, or of objects with the name in `Name`
+fn car_models(response: &Value) -> Result<HashSet<String>> {
+    response
+        .as_array()
+        .context("Car list is not a JSON array")?
+        .iter()
+        .map(|car| {
+            car.as_str()
+                .or_else(|| car["Name"].as_str())
+                .map(str::to_string)
+                .with_context(|| format!("Not a car in the car list: {}", car))
+        })
+        .collect()
+}
+
+/// The installed model closest to a missing one, to point out typos
+pub fn closest<'a>(models: &'a HashSet<String>, missing: &str) -> Option<&'a str> {
+    models
+        .iter()
+        .map(|model| (strsim::jaro_winkler(model, missing), model))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, model)| model.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use test_case::test_case;
+
+    #[test_case(json!(["ks_mclaren_650s_gt3", "bmw_m3_e30_gra"]); "names")]
+    #[test_case(json!([{"Name": "ks_mclaren_650s_gt3"}, {"Name": "bmw_m3_e30_gra"}]); "objects")]
+    fn parses_car_list(response: Value) {
+        assert_eq!(
+            car_models(&response).unwrap(),
+            HashSet::from([
+                "ks_mclaren_650s_gt3".to_string(),
+                "bmw_m3_e30_gra".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn suggests_typos() {
+        let models = HashSet::from([
+            "ks_mclaren_650s_gt3".to_string(),
+            "bmw_m3_e30_gra".to_string(),
+        ]);
+        assert_eq!(
+            closest(&models, "ks_mclaren_650_gt3"),
+            Some("ks_mclaren_650s_gt3")
+        );
+        assert_eq!(closest(&models, "rss_formula_hybrid"), None);
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+use crate::{
+    acsm::BasicDriver,
+    http::HttpPolicy,
+    report::{ProblemKind, Report},
+    source::RegistrationSource,
+    tickets::{self, MetaDataIDs, TicketContext},
+    State,
+};
+
+/// Registrations kept in a CSV file, like the responses of a Google Form
+/// published as CSV. Columns are matched by their header.
+#[derive(Debug)]
+pub struct CsvSource {
+    /// Local path, or an http(s) URL
+    pub location: String,
+    /// Column whose values TICKET_ID_TO_CAR_MAP maps to cars
+    pub car_column: String,
+    /// Optional column that identifies a registration, for IGNORED_GUIDS and
+    /// the report
+    pub id_column: Option<String>,
+    pub column_ids: MetaDataIDs,
+}
+
+impl CsvSource {
+    pub fn from_env() -> Result<CsvSource> {
+        Ok(CsvSource {
+            location: dotenv::var("CSV_LOCATION").context("CSV_LOCATION not set")?,
+            car_column: dotenv::var("CSV_COLUMN_CAR").context("CSV_COLUMN_CAR not set")?,
+            id_column: dotenv::var("CSV_COLUMN_ID")
+                .ok()
+                .filter(|column| !column.is_empty()),
+            column_ids: MetaDataIDs::from_env("CSV_COLUMN")?,
+        })
+    }
+
+    async fn read(&self, http: &HttpPolicy) -> Result<Vec<u8>> {
+        if self.location.starts_with("http://") || self.location.starts_with("https://") {
+            let request = http.client().get(&self.location);
+            Ok(http
+                .send(request)
+                .await
+                .context("Getting registrations CSV failed")?
+                .error_for_status()
+                .context("Registrations CSV URL returned error")?
+                .bytes()
+                .await
+                .context("Failed to read registrations CSV")?
+                .to_vec())
+        } else {
+            tokio::fs::read(&self.location)
+                .await
+                .with_context(|| format!("Failed to read {}", self.location))
+        }
+    }
+
+    pub async fn get_registrations(
+        &self,
+        http: &HttpPolicy,
+        tickets: &TicketContext<'_>,
+        report: &mut Report,
+    ) -> Result<Vec<BasicDriver>> {
+        let data = self.read(http).await?;
+        let (drivers, bad_rows) = self.parse(&data, tickets, report)?;
+        tickets.policy.check_bad_fraction(drivers.len(), bad_rows)?;
+        Ok(drivers)
+    }
+
+    /// Drivers and the number of rows with bad values
+    fn parse(
+        &self,
+        data: &[u8],
+        tickets: &TicketContext,
+        report: &mut Report,
+    ) -> Result<(Vec<BasicDriver>, usize)> {
+        let column_ids = tickets.metadata_ids;
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(data);
+        let headers = reader
+            .headers()
+            .context("Registrations CSV has no header")?
+            .clone();
+        for column in [
+            &self.car_column,
+            &column_ids.first_name,
+            &column_ids.last_name,
+            &column_ids.steam_id,
+        ] {
+            if !headers.iter().any(|header| header == column) {
+                return Err(anyhow!("Registrations CSV has no column {:?}", column));
+            }
+        }
+        let mut drivers = Vec::new();
+        let mut bad_rows = 0;
+        for (index, record) in reader.records().enumerate() {
+            // The header is line 1
+            let line = index + 2;
+            let record = record.with_context(|| format!("Bad CSV on line {}", line))?;
+            if record.iter().all(str::is_empty) {
+                continue;
+            }
+            let row: HashMap<&str, &str> = headers.iter().zip(record.iter()).collect();
+            let id = match &self.id_column {
+                Some(id_column) => row.get(id_column.as_str()).map(|id| id.to_string()),
+                None => Some(format!("line {}", line)),
+            };
+            let car_value = row.get(self.car_column.as_str()).copied().unwrap_or("");
+            let Some(mapping) = tickets.mapping(car_value) else {
+                tickets.skip_unmapped(
+                    car_value,
+                    format!("No car found for {:?}", car_value),
+                    report,
+                    None,
+                    id.as_deref(),
+                )?;
+                continue;
+            };
+            match tickets::driver_from_metadata(
+                mapping,
+                row.iter().map(|(column, value)| (*column, Some(*value))),
+                tickets,
+                None,
+                id.as_deref(),
+            ) {
+                Ok(driver) => drivers.push(driver),
+                Err(e) => {
+                    report.add(
+                        ProblemKind::BadMetadata,
+                        None,
+                        id.as_deref(),
+                        format!("{:#}", e),
+                    );
+                    bad_rows += 1;
+                }
+            }
+        }
+        Ok((drivers, bad_rows))
+    }
+}
+
+#[async_trait]
+impl RegistrationSource for CsvSource {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    async fn fetch_all(
+        &self,
+        state: &State,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        self.get_registrations(
+            &state.http.csv,
+            &state.ticket_context(&ticket_map, &self.column_ids, &steam_id_overrides),
+            report,
+        )
+        .await
+        .map(Some)
+    }
+
+    async fn fetch_delta(
+        &self,
+        _state: &State,
+        _order_id: &str,
+        _report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        Err(anyhow!("CSV registrations have no orders"))
+    }
+
+    async fn event_start(&self, _state: &State) -> Result<DateTime<Utc>> {
+        Err(anyhow!(
+            "CSV registrations have no event start, use REGISTRATION_CUTOFF"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ticket_map::TicketMap,
+        tickets::{DuplicatePolicy, TicketPolicy, UnmappedTicketPolicy},
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    fn google_forms_export() {
+        let source = CsvSource {
+            location: "responses.csv".to_string(),
+            car_column: "Car".to_string(),
+            id_column: Some("Timestamp".to_string()),
+            column_ids: MetaDataIDs {
+                first_name: "First name".to_string(),
+                last_name: "Last name".to_string(),
+                team_name: "Team".to_string(),
+                steam_id: "Steam ID".to_string(),
+                co_driver_steam_ids: Vec::new(),
+                nationality: None,
+                skill: None,
+                pace: None,
+            },
+        };
+        let data = "Timestamp,First name,Last name,Team,Steam ID,Car\n\
+            2024/01/01 10:00:00,Max,Power,\"Power, Inc\",76561190000000001,BMW\n\
+            ,,,,,\n\
+            2024/01/01 11:00:00,No,Steam,,,BMW\n\
+            2024/01/01 12:00:00,Bike,Rider,,76561190000000002,Bike\n";
+        let ticket_map = TicketMap::from_car_map("BMW:bmw_m3_e30").unwrap();
+        let policy = TicketPolicy {
+            unmapped: UnmappedTicketPolicy::Skip,
+            max_bad_fraction: 1.0,
+            require_check_in: false,
+            duplicates: DuplicatePolicy::Earliest,
+        };
+        let tickets = TicketContext {
+            ticket_map: &ticket_map,
+            ticket_names: None,
+            add_on_tickets: &HashSet::new(),
+            metadata_ids: &source.column_ids,
+            policy: &policy,
+            steam_id_overrides: &HashMap::new(),
+        };
+        let mut report = Report::default();
+        let (drivers, bad_rows) = source
+            .parse(data.as_bytes(), &tickets, &mut report)
+            .unwrap();
+        assert_eq!(bad_rows, 1);
+        assert_eq!(drivers.len(), 1);
+        assert_eq!(drivers[0].name, "Max Power");
+        assert_eq!(drivers[0].team_name.as_deref(), Some("Power, Inc"));
+        assert_eq!(
+            drivers[0].ticket_guid.as_deref(),
+            Some("2024/01/01 10:00:00")
+        );
+        assert_eq!(report.problems.len(), 2);
+    }
+}
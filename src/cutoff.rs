@@ -0,0 +1,69 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use log::info;
+use std::future::Future;
+
+use crate::timezone;
+
+/// When the entry list freezes. After that new drivers only end up in the
+/// report, and drivers only get removed through the admin API.
+#[derive(Debug, Clone, Copy)]
+pub enum RegistrationCutoff {
+    At(DateTime<Utc>),
+    /// Relative to the start of the event in the ticket source
+    BeforeStart(Duration),
+}
+
+/// RFC3339, or `YYYY-MM-DD HH:MM` in TIMEZONE
+pub fn parse_at(at: &str) -> Result<DateTime<Utc>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(at) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    let at = NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M")
+        .with_context(|| format!("`{}` is not RFC3339 or YYYY-MM-DD HH:MM", at))?;
+    timezone::from_local(at)
+}
+
+impl RegistrationCutoff {
+    pub fn from_env() -> Result<Option<RegistrationCutoff>> {
+        let at = dotenv::var("REGISTRATION_CUTOFF")
+            .ok()
+            .filter(|at| !at.is_empty());
+        let hours = dotenv::var("REGISTRATION_CUTOFF_HOURS_BEFORE_START")
+            .ok()
+            .filter(|hours| !hours.is_empty());
+        match (at, hours) {
+            (Some(_), Some(_)) => Err(anyhow!(
+                "Set only one of REGISTRATION_CUTOFF and REGISTRATION_CUTOFF_HOURS_BEFORE_START"
+            )),
+            (Some(at), None) => Ok(Some(RegistrationCutoff::At(
+                parse_at(&at).context("Invalid REGISTRATION_CUTOFF")?,
+            ))),
+            (None, Some(hours)) => Ok(Some(RegistrationCutoff::BeforeStart(Duration::hours(
+                hours
+                    .parse()
+                    .context("REGISTRATION_CUTOFF_HOURS_BEFORE_START is not a number")?,
+            )))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The actual time, which may need the event start from the ticket source
+    pub async fn resolve(
+        &self,
+        event_start: impl Future<Output = Result<DateTime<Utc>>>,
+    ) -> Result<DateTime<Utc>> {
+        match self {
+            RegistrationCutoff::At(at) => Ok(*at),
+            RegistrationCutoff::BeforeStart(before) => {
+                let start = event_start.await?;
+                info!(
+                    "Event starts at {}, registration closes {} hours before",
+                    timezone::format(start),
+                    before.num_hours()
+                );
+                Ok(start - *before)
+            }
+        }
+    }
+}
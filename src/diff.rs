@@ -0,0 +1,288 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::fmt;
+
+use crate::{
+    acsm::{self, BasicDriver, Entrant},
+    fetch_all_drivers, oauth2,
+    report::{Problem, Report},
+    splits, State,
+};
+
+/// What a full update would do to one entrant
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    Added {
+        file: String,
+        entrant: Entrant,
+    },
+    Removed {
+        file: String,
+        entrant: Entrant,
+    },
+    /// To another file, and maybe with other details too
+    Moved {
+        from: String,
+        to: String,
+        old: Entrant,
+        new: Entrant,
+    },
+    Updated {
+        file: String,
+        old: Entrant,
+        new: Entrant,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+    /// Tickets the full update would skip or waitlist
+    pub problems: Vec<Problem>,
+}
+
+/// The entrants of one file, before and after the full update
+struct Preview {
+    file: String,
+    before: Vec<Entrant>,
+    after: Vec<Entrant>,
+}
+
+fn describe(entrant: &Entrant) -> String {
+    let mut description = format!(
+        "{} steam_id={} class={}",
+        entrant.name, entrant.guid, entrant.class
+    );
+    if !entrant.team.is_empty() {
+        description.push_str(&format!(" team_name={}", entrant.team));
+    }
+    description
+}
+
+/// What differs between two versions of an entrant
+fn differences(old: &Entrant, new: &Entrant) -> String {
+    [
+        ("name", &old.name, &new.name),
+        ("team_name", &old.team, &new.team),
+        ("class", &old.class, &new.class),
+        ("nation", &old.nation, &new.nation),
+        ("steam_id", &old.guid, &new.guid),
+    ]
+    .into_iter()
+    .filter(|(_, old, new)| old != new)
+    .map(|(field, old, new)| format!("{} {:?} -> {:?}", field, old, new))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { file, entrant } => write!(f, "+ {}: {}", file, describe(entrant)),
+            Change::Removed { file, entrant } => write!(f, "- {}: {}", file, describe(entrant)),
+            Change::Moved { from, to, old, new } => {
+                write!(f, "> {} -> {}: {}", from, to, describe(new))?;
+                if old != new {
+                    write!(f, " ({})", differences(old, new))?;
+                }
+                Ok(())
+            }
+            Change::Updated { file, old, new } => {
+                write!(
+                    f,
+                    "~ {}: {} ({})",
+                    file,
+                    describe(new),
+                    differences(old, new)
+                )
+            }
+        }
+    }
+}
+
+/// Compare the entrants of every file before and after, matching them by the
+/// main driver's Steam ID
+fn changes(previews: &[Preview]) -> Vec<Change> {
+    let find = |after: bool, guid: &str| {
+        previews.iter().find_map(|preview| {
+            if after {
+                &preview.after
+            } else {
+                &preview.before
+            }
+            .iter()
+            .find(|entrant| entrant.main_guid() == guid)
+            .map(|entrant| (&preview.file, entrant))
+        })
+    };
+    let mut changes = Vec::new();
+    for Preview { file, before, .. } in previews {
+        for old in before {
+            match find(true, old.main_guid()) {
+                None => changes.push(Change::Removed {
+                    file: file.clone(),
+                    entrant: old.clone(),
+                }),
+                Some((to, new)) if to != file => changes.push(Change::Moved {
+                    from: file.clone(),
+                    to: to.clone(),
+                    old: old.clone(),
+                    new: new.clone(),
+                }),
+                Some((_, new)) if new != old => changes.push(Change::Updated {
+                    file: file.clone(),
+                    old: old.clone(),
+                    new: new.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+    for Preview { file, after, .. } in previews {
+        for new in after {
+            if find(false, new.main_guid()).is_none() {
+                changes.push(Change::Added {
+                    file: file.clone(),
+                    entrant: new.clone(),
+                });
+            }
+        }
+    }
+    changes
+}
+
+/// Fetch everything like a full update does, and work out what it would
+/// change in the ACSM files without writing them
+pub async fn diff(state: &State) -> Result<Diff> {
+    let mut report = Report::default();
+    let Some(drivers) = fetch_all_drivers(state, &mut report).await? else {
+        return Err(anyhow!("No OAuth2 token, can't fetch the orders"));
+    };
+    diff_drivers(state, &drivers, true, report).await
+}
+
+/// What placing these drivers would change in the ACSM files, like a full
+/// update or a webhook does
+pub async fn diff_drivers(
+    state: &State,
+    drivers: &[BasicDriver],
+    full_update: bool,
+    mut report: Report,
+) -> Result<Diff> {
+    let drivers = state.entry_drivers(drivers, &mut report).await?;
+    let json_files = state.acsm_json_files.lock().await.clone();
+    let ignored_steam_ids = state.ignored_steam_ids().await;
+    let mut splits = Vec::new();
+    for json_file in &json_files {
+        splits.push(acsm::class_slots(json_file).await?);
+    }
+    let mut previews = Vec::new();
+    if state.registration_closed().await {
+        splits::report_registration_closed(&splits, &drivers, &ignored_steam_ids, &mut report);
+    } else {
+        let allocation = splits::allocate(
+            &splits,
+            &drivers,
+            state.split_policy,
+            full_update,
+            &ignored_steam_ids,
+            &state.reserved_slots,
+            &mut report,
+        );
+        for (json_file, drivers) in json_files.iter().zip(allocation) {
+            if drivers.is_empty() && !full_update {
+                continue;
+            }
+            let (before, after) = acsm::preview_drivers(
+                full_update,
+                json_file,
+                &drivers,
+                &ignored_steam_ids,
+                &state.entrant_defaults,
+            )
+            .await?;
+            previews.push(Preview {
+                file: json_file.display().to_string(),
+                before,
+                after,
+            });
+        }
+    }
+    Ok(Diff {
+        changes: changes(&previews),
+        problems: report.problems,
+    })
+}
+
+/// For `eventix2acsm diff`, as text or with `--json` as JSON
+pub async fn run(state: &State, json: bool) -> Result<()> {
+    oauth2::local_tokens(state).await?;
+    let diff = diff(state).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+    for change in &diff.changes {
+        println!("{}", change);
+    }
+    for problem in &diff.problems {
+        println!("! {}: {}", problem.kind, problem.message);
+    }
+    if diff.changes.is_empty() {
+        println!("No changes");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entrant(guid: &str, name: &str) -> Entrant {
+        Entrant {
+            class: "GT3".to_string(),
+            guid: guid.to_string(),
+            name: name.to_string(),
+            team: String::new(),
+            nation: String::new(),
+        }
+    }
+
+    #[test]
+    fn all_changes() {
+        let previews = [
+            Preview {
+                file: "a.json".to_string(),
+                before: vec![
+                    entrant("1", "Same"),
+                    entrant("2", "Old"),
+                    entrant("3", "Gone"),
+                ],
+                after: vec![
+                    entrant("1", "Same"),
+                    entrant("2", "New"),
+                    entrant("5", "Up"),
+                ],
+            },
+            Preview {
+                file: "b.json".to_string(),
+                before: vec![entrant("5", "Up")],
+                after: vec![entrant("4;6", "Added")],
+            },
+        ];
+        let changes = changes(&previews)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                r#"~ a.json: New steam_id=2 class=GT3 (name "Old" -> "New")"#,
+                "- a.json: Gone steam_id=3 class=GT3",
+                "> b.json -> a.json: Up steam_id=5 class=GT3",
+                "+ b.json: Added steam_id=4;6 class=GT3",
+            ]
+        );
+    }
+}
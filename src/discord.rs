@@ -0,0 +1,715 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    body::Bytes,
+    extract,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use axum_macros::debug_handler;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use itertools::Itertools;
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{
+    acsm, admin, full_update, redact::Secret, sandbox, self_service, status, timezone, State,
+};
+
+const API_URL: &str = "https://discord.com/api/v10";
+
+const SIGNATURE_HEADER: &str = "x-signature-ed25519";
+const TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+
+// Interaction and response types, see Discord's "Receiving and Responding"
+const PING: u64 = 1;
+const APPLICATION_COMMAND: u64 = 2;
+const PONG: u64 = 1;
+const CHANNEL_MESSAGE: u64 = 4;
+const DEFERRED_CHANNEL_MESSAGE: u64 = 5;
+/// Only the driver who ran the command sees the reply
+const EPHEMERAL: u64 = 1 << 6;
+/// In characters, Discord rejects longer messages
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// The bot for drivers to correct their own Steam ID, and for race control to
+/// check and run the sync, enabled by DISCORD_PUBLIC_KEY
+pub struct Discord {
+    public_key: VerifyingKey,
+    /// Only needed to register the commands at startup
+    application_id: Option<String>,
+    bot_token: Option<Secret<String>>,
+    /// Role IDs that may look at the grid. Empty for anyone in the server.
+    viewer_roles: Vec<String>,
+    /// Role IDs that may also sync and ignore. Empty for no one.
+    race_control_roles: Vec<String>,
+}
+
+fn role_ids(key: &str) -> Vec<String> {
+    dotenv::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|role| !role.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Discord {
+    pub fn from_env() -> Result<Option<Discord>> {
+        let Some(public_key) = dotenv::var("DISCORD_PUBLIC_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+        else {
+            return Ok(None);
+        };
+        let public_key: [u8; 32] = hex::decode(&public_key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .context("DISCORD_PUBLIC_KEY is not 32 bytes of hex")?;
+        let application_id = dotenv::var("DISCORD_APPLICATION_ID")
+            .ok()
+            .filter(|id| !id.is_empty());
+        let bot_token = dotenv::var("DISCORD_BOT_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+            .map(Secret::new);
+        if bot_token.is_some() && application_id.is_none() {
+            return Err(anyhow!(
+                "DISCORD_BOT_TOKEN is set, but DISCORD_APPLICATION_ID is not"
+            ));
+        }
+        Ok(Some(Discord {
+            public_key: VerifyingKey::from_bytes(&public_key)
+                .context("DISCORD_PUBLIC_KEY is not a valid Ed25519 key")?,
+            application_id,
+            bot_token,
+            viewer_roles: role_ids("DISCORD_VIEWER_ROLE_IDS"),
+            race_control_roles: role_ids("DISCORD_RACE_CONTROL_ROLE_IDS"),
+        }))
+    }
+
+    /// Whether the member has a role that may run commands needing `access`.
+    /// Only `/register` works in DMs, as there are no roles there.
+    fn allowed(&self, access: Access, member: Option<&Member>) -> bool {
+        let has_role = |roles: &[String]| {
+            member.is_some_and(|member| member.roles.iter().any(|role| roles.contains(role)))
+        };
+        match access {
+            Access::Anyone => true,
+            Access::Viewer => {
+                (self.viewer_roles.is_empty() && member.is_some())
+                    || has_role(&self.viewer_roles)
+                    || has_role(&self.race_control_roles)
+            }
+            Access::RaceControl => has_role(&self.race_control_roles),
+        }
+    }
+
+    /// Check the Ed25519 signature over the timestamp and the raw body
+    fn verify_signature(&self, headers: &HeaderMap, body: &[u8]) -> bool {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let (Some(signature), Some(timestamp)) =
+            (header(SIGNATURE_HEADER), header(TIMESTAMP_HEADER))
+        else {
+            return false;
+        };
+        let Some(signature) = hex::decode(signature)
+            .ok()
+            .and_then(|signature| <[u8; 64]>::try_from(signature).ok())
+        else {
+            return false;
+        };
+        let mut message = timestamp.as_bytes().to_vec();
+        message.extend_from_slice(body);
+        self.public_key
+            .verify(&message, &Signature::from_bytes(&signature))
+            .is_ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Interaction {
+    #[serde(rename = "type")]
+    kind: u64,
+    application_id: String,
+    token: String,
+    data: Option<CommandData>,
+    /// Set in servers
+    member: Option<Member>,
+    /// Set in DMs
+    user: Option<User>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandData {
+    name: String,
+    #[serde(default)]
+    options: Vec<CommandOption>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandOption {
+    name: String,
+    value: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Member {
+    user: User,
+    /// Role IDs
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    id: String,
+    username: String,
+}
+
+/// What a driver asked for with `/register`
+#[derive(Debug, PartialEq)]
+struct Registration {
+    order: String,
+    /// The ticket's access code, which picks the driver's own ticket
+    code: String,
+    steam_profile: String,
+}
+
+impl Registration {
+    fn from_options(options: &[CommandOption]) -> Result<Registration> {
+        let string = |name| -> Result<String> {
+            string_option(options, name)?.with_context(|| format!("Missing option {}", name))
+        };
+        Ok(Registration {
+            order: string("order")?,
+            code: string("code")?,
+            steam_profile: string("steam_profile")?,
+        })
+    }
+}
+
+/// The value of an optional string option
+fn string_option(options: &[CommandOption], name: &str) -> Result<Option<String>> {
+    options
+        .iter()
+        .find(|option| option.name == name)
+        .map(|option| {
+            Ok(option
+                .value
+                .as_str()
+                .with_context(|| format!("Option {} is not a string", name))?
+                .trim()
+                .to_string())
+        })
+        .transpose()
+}
+
+/// Who may run a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Anyone,
+    Viewer,
+    RaceControl,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Register(Registration),
+    /// The grid, of one class if given
+    Entries {
+        class: Option<String>,
+    },
+    Sync,
+    Ignore {
+        steam_profile: String,
+    },
+    Status,
+}
+
+impl Command {
+    fn from_data(data: &CommandData) -> Result<Command> {
+        Ok(match data.name.as_str() {
+            "register" => Command::Register(Registration::from_options(&data.options)?),
+            "entries" => Command::Entries {
+                class: string_option(&data.options, "class")?,
+            },
+            "sync" => Command::Sync,
+            "ignore" => Command::Ignore {
+                steam_profile: string_option(&data.options, "steam_profile")?
+                    .context("Missing option steam_profile")?,
+            },
+            "status" => Command::Status,
+            name => return Err(anyhow!("Unknown command /{}", name)),
+        })
+    }
+
+    fn access(&self) -> Access {
+        match self {
+            Command::Register(_) => Access::Anyone,
+            Command::Entries { .. } | Command::Status => Access::Viewer,
+            Command::Sync | Command::Ignore { .. } => Access::RaceControl,
+        }
+    }
+}
+
+/// Join the lines, leaving out what doesn't fit in one message
+fn fit_message(lines: &[String]) -> String {
+    // Room for the note about what was left out
+    let limit = MAX_MESSAGE_LENGTH - 30;
+    let mut content = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        if content.chars().count() + line.chars().count() + 1 > limit {
+            content.push_str(&format!("…and {} more lines", lines.len() - index));
+            break;
+        }
+        content.push_str(line);
+        content.push('\n');
+    }
+    content.trim_end().to_string()
+}
+
+fn ephemeral(content: &str) -> Json<Value> {
+    Json(json!({
+        "type": CHANNEL_MESSAGE,
+        "data": {"content": sandbox::mark(content), "flags": EPHEMERAL},
+    }))
+}
+
+#[debug_handler]
+pub async fn handle_interaction(
+    extract::State(state): extract::State<Arc<State>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, StatusCode> {
+    let Some(discord) = &state.discord else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    // Discord checks that we reject these before it accepts the endpoint
+    if !discord.verify_signature(&headers, &body) {
+        warn!("Rejected Discord interaction with missing or bad signature");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let interaction: Interaction = serde_json::from_slice(&body).map_err(|e| {
+        warn!("Bad Discord interaction: {:?}", e);
+        StatusCode::BAD_REQUEST
+    })?;
+    if interaction.kind == PING {
+        return Ok(Json(json!({"type": PONG})));
+    }
+    let Some(data) = interaction
+        .data
+        .as_ref()
+        .filter(|_| interaction.kind == APPLICATION_COMMAND)
+    else {
+        warn!("Ignoring Discord interaction of type {}", interaction.kind);
+        return Ok(ephemeral("Unknown command"));
+    };
+    let command = match Command::from_data(data) {
+        Ok(command) => command,
+        Err(e) => return Ok(ephemeral(&e.to_string())),
+    };
+    let user = interaction
+        .member
+        .as_ref()
+        .map(|member| &member.user)
+        .or(interaction.user.as_ref())
+        .map(|user| format!("{} ({})", user.username, user.id))
+        .unwrap_or_else(|| "unknown user".to_string());
+    if !discord.allowed(command.access(), interaction.member.as_ref()) {
+        warn!("Discord user {} may not run /{}", user, data.name);
+        return Ok(ephemeral("You don't have a role that may run this command"));
+    }
+    // Looking up the order and the profile, or a full update, can take longer
+    // than the three seconds Discord waits for an answer, so the answer comes
+    // as an edit
+    tokio::spawn(async move {
+        let content = match command {
+            Command::Register(registration) => register(&state, &user, &registration).await,
+            Command::Entries { class } => entries(&state, class.as_deref()).await,
+            Command::Sync => sync(&state, &user).await,
+            Command::Ignore { steam_profile } => ignore(&state, &user, &steam_profile).await,
+            Command::Status => sync_status(&state).await,
+        };
+        if let Err(e) = edit_reply(&state, &interaction, &content).await {
+            error!("Failed to answer Discord interaction: {:?}", e);
+        }
+    });
+    Ok(Json(json!({
+        "type": DEFERRED_CHANNEL_MESSAGE,
+        "data": {"flags": EPHEMERAL},
+    })))
+}
+
+/// Check the order and the profile, and store the Steam ID for the ticket.
+/// Returns the reply for the driver.
+async fn register(state: &Arc<State>, user: &str, registration: &Registration) -> String {
+    let ticket =
+        match self_service::own_ticket(state, &registration.order, &registration.code).await {
+            Ok(Some(ticket)) => ticket,
+            Ok(None) => return "Registration is not open yet, try again later".to_string(),
+            Err(e) => {
+                warn!(
+                    "Discord user {} gave order {:?}: {:?}",
+                    user, registration.order, e
+                );
+                return format!(
+                    "Could not find a ticket with that access code in paid order {}",
+                    registration.order
+                );
+            }
+        };
+    let steam_id = match state
+        .steam
+        .resolve(&state.http.steam, &registration.steam_profile)
+        .await
+    {
+        Ok(steam_id) => steam_id,
+        Err(e) => {
+            warn!(
+                "Discord user {} gave Steam profile {:?}: {:?}",
+                user, registration.steam_profile, e
+            );
+            return format!("That Steam profile didn't check out: {}", e);
+        }
+    };
+    let by = format!("Discord user {}", user);
+    if let Err(e) = self_service::set_steam_id(state, &ticket, steam_id, &by).await {
+        error!("Failed to store Steam ID override: {:?}", e);
+        return "Something went wrong on our side, try again later".to_string();
+    }
+    format!(
+        "Steam ID {} registered for ticket {} ({})",
+        steam_id, ticket.guid, ticket.description
+    )
+}
+
+/// The drivers on the grid, by class
+async fn entries(state: &State, class: Option<&str>) -> String {
+    let mut lines = Vec::new();
+    for json_file in state.acsm_json_files.lock().await.iter() {
+        let entrants = match acsm::read_entrants(json_file).await {
+            Ok(entrants) => entrants,
+            Err(e) => {
+                error!("Failed to read {}: {:?}", json_file.display(), e);
+                return format!("Could not read {}", json_file.display());
+            }
+        };
+        let entrants = entrants
+            .iter()
+            .filter(|entrant| class.is_none_or(|class| entrant.class.eq_ignore_ascii_case(class)));
+        for (class, entrants) in &entrants.group_by(|entrant| &entrant.class) {
+            lines.push(format!("**{}** ({})", class, json_file.display()));
+            lines.extend(entrants.map(|entrant| {
+                if entrant.team.is_empty() {
+                    format!("{} `{}`", entrant.name, entrant.main_guid())
+                } else {
+                    format!(
+                        "{} ({}) `{}`",
+                        entrant.name,
+                        entrant.team,
+                        entrant.main_guid()
+                    )
+                }
+            }));
+        }
+    }
+    if lines.is_empty() {
+        return match class {
+            Some(class) => format!("No drivers in class {}", class),
+            None => "No drivers on the grid".to_string(),
+        };
+    }
+    fit_message(&lines)
+}
+
+/// Run a full update now
+async fn sync(state: &Arc<State>, user: &str) -> String {
+    info!("Full update requested by Discord user {}", user);
+    match full_update(state.clone()).await {
+        Ok(()) => "Full update done".to_string(),
+        Err(e) => format!("Full update failed: {}", e),
+    }
+}
+
+/// Ignore a Steam ID like the admin API does
+async fn ignore(state: &State, user: &str, steam_profile: &str) -> String {
+    let steam_id = match state.steam.resolve(&state.http.steam, steam_profile).await {
+        Ok(steam_id) => steam_id,
+        Err(e) => return format!("That Steam profile didn't check out: {}", e),
+    };
+    info!("Discord user {} ignores steam_id={}", user, steam_id);
+    if let Err(e) = admin::ignore_steam_id(state, steam_id).await {
+        error!("Failed to store ignored Steam ID: {:?}", e);
+        return "Something went wrong on our side, try again later".to_string();
+    }
+    format!(
+        "Steam ID {} is ignored from now on, and taken off the grid if they're \
+         on it.",
+        steam_id
+    )
+}
+
+/// What `/status` has, as text
+async fn sync_status(state: &State) -> String {
+    let sync = state.sync_status.lock().await.clone();
+    let outcome = |outcome: Option<status::Outcome>| match outcome {
+        Some(outcome) if outcome.success => format!("{}, ok", timezone::format(outcome.time)),
+        Some(outcome) => format!("{}, {}", timezone::format(outcome.time), outcome.message),
+        None => "never".to_string(),
+    };
+    let mut lines = vec![
+        format!("Last full update: {}", outcome(sync.last_full_update)),
+        format!(
+            "Next full update: {}",
+            sync.next_full_update
+                .map_or_else(|| "not planned".to_string(), timezone::format)
+        ),
+        format!("Last webhook: {}", outcome(sync.last_webhook)),
+        format!("Waitlist: {}", status::waitlist(state).await),
+    ];
+    lines.extend(
+        status::class_statuses(state)
+            .await
+            .into_iter()
+            .map(|class| {
+                format!(
+                    "**{}** ({}): {}/{} filled, {} ignored, {} waitlisted",
+                    class.class,
+                    class.file,
+                    class.filled,
+                    class.slots,
+                    class.ignored,
+                    class.waitlisted
+                )
+            }),
+    );
+    fit_message(&lines)
+}
+
+/// Replace the deferred answer. Needs no bot token, the interaction token
+/// is good for 15 minutes.
+async fn edit_reply(state: &State, interaction: &Interaction, content: &str) -> Result<()> {
+    let url = format!(
+        "{}/webhooks/{}/{}/messages/@original",
+        API_URL, interaction.application_id, interaction.token
+    );
+    let http = &state.http.discord;
+    http.send(
+        http.client()
+            .patch(url)
+            .json(&json!({"content": sandbox::mark(content)})),
+    )
+    .await
+    .and_then(|response| response.error_for_status())
+    // The URL has the interaction token in it
+    .map_err(|e| e.without_url())
+    .context("Failed to edit Discord reply")?;
+    Ok(())
+}
+
+/// Create or update the commands, if we have a bot token
+pub async fn register_commands(state: &State) -> Result<()> {
+    let Some(Discord {
+        application_id: Some(application_id),
+        bot_token: Some(bot_token),
+        ..
+    }) = &state.discord
+    else {
+        return Ok(());
+    };
+    let register = json!({
+        "name": "register",
+        "description": "Register the Steam account to race with for your ticket",
+        "options": [
+            {
+                "type": 3,
+                "name": "order",
+                "description": "The order number from your ticket email",
+                "required": true,
+            },
+            {
+                "type": 3,
+                "name": "code",
+                "description": "The access code for your ticket",
+                "required": true,
+            },
+            {
+                "type": 3,
+                "name": "steam_profile",
+                "description": "Your Steam profile URL or SteamID64",
+                "required": true,
+            },
+        ],
+    });
+    // Roles are checked when they're run, these only need a server
+    let entries = json!({
+        "name": "entries",
+        "description": "List the drivers on the grid",
+        "dm_permission": false,
+        "options": [
+            {
+                "type": 3,
+                "name": "class",
+                "description": "Only this class",
+            },
+        ],
+    });
+    let sync = json!({
+        "name": "sync",
+        "description": "Run a full update now",
+        "dm_permission": false,
+    });
+    let ignore = json!({
+        "name": "ignore",
+        "description": "Never add this driver, and never remove them in a full update",
+        "dm_permission": false,
+        "options": [
+            {
+                "type": 3,
+                "name": "steam_profile",
+                "description": "Steam profile URL or SteamID64",
+                "required": true,
+            },
+        ],
+    });
+    let status = json!({
+        "name": "status",
+        "description": "Show the last and next full update and how full each class is",
+        "dm_permission": false,
+    });
+    let http = &state.http.discord;
+    for command in [register, entries, sync, ignore, status] {
+        http.send(
+            http.client()
+                .post(format!(
+                    "{}/applications/{}/commands",
+                    API_URL, application_id
+                ))
+                .header("Authorization", format!("Bot {}", bot_token.expose()))
+                .json(&command),
+        )
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to register Discord command /{}", command["name"]))?;
+        info!("Registered Discord command /{}", command["name"]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use test_case::test_case;
+
+    fn signed(signing_key: &SigningKey, timestamp: &str, body: &[u8]) -> HeaderMap {
+        let mut message = timestamp.as_bytes().to_vec();
+        message.extend_from_slice(body);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SIGNATURE_HEADER,
+            hex::encode(signing_key.sign(&message).to_bytes())
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(TIMESTAMP_HEADER, timestamp.parse().unwrap());
+        headers
+    }
+
+    #[test_case([1; 32], b"{}", true; "good")]
+    #[test_case([2; 32], b"{}", false; "wrong key")]
+    #[test_case([1; 32], b"{ }", false; "tampered body")]
+    fn signature(signing_key: [u8; 32], body: &[u8], expected: bool) {
+        let discord = Discord {
+            public_key: SigningKey::from_bytes(&[1; 32]).verifying_key(),
+            application_id: None,
+            bot_token: None,
+            viewer_roles: Vec::new(),
+            race_control_roles: Vec::new(),
+        };
+        let headers = signed(&SigningKey::from_bytes(&signing_key), "1700000000", b"{}");
+        assert_eq!(discord.verify_signature(&headers, body), expected);
+    }
+
+    #[test]
+    fn missing_signature() {
+        let discord = Discord {
+            public_key: SigningKey::from_bytes(&[1; 32]).verifying_key(),
+            application_id: None,
+            bot_token: None,
+            viewer_roles: Vec::new(),
+            race_control_roles: Vec::new(),
+        };
+        assert!(!discord.verify_signature(&HeaderMap::new(), b"{}"));
+    }
+
+    #[test_case(json!([{"name": "order", "value": " 1234 "}, {"name": "code", "value": "0123456789abcdef"}, {"name": "steam_profile", "value": "76561197960287930"}]), true; "all")]
+    #[test_case(json!([{"name": "order", "value": "1234"}, {"name": "steam_profile", "value": "76561197960287930"}]), false; "missing code")]
+    #[test_case(json!([{"name": "order", "value": "1234"}, {"name": "code", "value": "0123456789abcdef"}]), false; "missing profile")]
+    fn options(options: Value, valid: bool) {
+        let options: Vec<CommandOption> = serde_json::from_value(options).unwrap();
+        match (Registration::from_options(&options), valid) {
+            (Ok(registration), true) => assert_eq!(
+                registration,
+                Registration {
+                    order: "1234".to_string(),
+                    code: "0123456789abcdef".to_string(),
+                    steam_profile: "76561197960287930".to_string(),
+                }
+            ),
+            (Err(_), false) => {}
+            (result, _) => panic!("Unexpected {:?}", result),
+        }
+    }
+
+    #[test_case(Access::Anyone, None, &[], true; "register in DM")]
+    #[test_case(Access::Viewer, None, &[], false; "entries in DM")]
+    #[test_case(Access::Viewer, Some(&[]), &[], true; "entries without viewer roles")]
+    #[test_case(Access::Viewer, Some(&[]), &["1"], false; "entries without role")]
+    #[test_case(Access::Viewer, Some(&["1"]), &["1"], true; "entries with viewer role")]
+    #[test_case(Access::Viewer, Some(&["2"]), &["1"], true; "entries with race control role")]
+    #[test_case(Access::RaceControl, Some(&["1"]), &["1"], false; "sync with viewer role")]
+    #[test_case(Access::RaceControl, Some(&["2", "3"]), &[], true; "sync with race control role")]
+    fn allowed(access: Access, roles: Option<&[&str]>, viewer_roles: &[&str], expected: bool) {
+        let to_strings = |roles: &[&str]| roles.iter().map(|role| role.to_string()).collect();
+        let discord = Discord {
+            public_key: SigningKey::from_bytes(&[1; 32]).verifying_key(),
+            application_id: None,
+            bot_token: None,
+            viewer_roles: to_strings(viewer_roles),
+            race_control_roles: vec!["2".to_string()],
+        };
+        let member = roles.map(|roles| Member {
+            user: User {
+                id: "1".to_string(),
+                username: "race_control".to_string(),
+            },
+            roles: to_strings(roles),
+        });
+        assert_eq!(discord.allowed(access, member.as_ref()), expected);
+    }
+
+    #[test_case(json!({"name": "entries"}), Some(Command::Entries { class: None }))]
+    #[test_case(json!({"name": "entries", "options": [{"name": "class", "value": "GT3 "}]}), Some(Command::Entries { class: Some("GT3".to_string()) }))]
+    #[test_case(json!({"name": "ignore", "options": [{"name": "steam_profile", "value": "76561197960287930"}]}), Some(Command::Ignore { steam_profile: "76561197960287930".to_string() }))]
+    #[test_case(json!({"name": "ignore"}), None; "ignore without profile")]
+    #[test_case(json!({"name": "sync"}), Some(Command::Sync))]
+    #[test_case(json!({"name": "kick"}), None; "unknown")]
+    fn command(data: Value, expected: Option<Command>) {
+        let data: CommandData = serde_json::from_value(data).unwrap();
+        assert_eq!(Command::from_data(&data).ok(), expected);
+    }
+
+    #[test]
+    fn long_message() {
+        let lines: Vec<_> = (0..200).map(|n| format!("Driver {:03}", n)).collect();
+        let content = fit_message(&lines);
+        assert!(content.chars().count() <= MAX_MESSAGE_LENGTH);
+        assert!(content.starts_with("Driver 000\nDriver 001"));
+        assert!(content.ends_with("more lines"));
+        assert_eq!(fit_message(&lines[..2]), "Driver 000\nDriver 001");
+    }
+}
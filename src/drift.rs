@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+
+use crate::{acsm::BasicDriver, diff, events::EventKind, report::Report, State};
+
+/// From DRIFT_CHECK_MINUTES and DRIFT_THRESHOLD
+#[derive(Debug)]
+pub struct DriftCheck {
+    interval: Duration,
+    /// Changes a full update would make that are still fine, e.g. for a
+    /// webhook that didn't get through
+    threshold: usize,
+}
+
+impl DriftCheck {
+    pub fn from_env() -> Result<Option<DriftCheck>> {
+        let Some(minutes) = dotenv::var("DRIFT_CHECK_MINUTES")
+            .ok()
+            .filter(|minutes| !minutes.is_empty())
+        else {
+            return Ok(None);
+        };
+        let minutes: u64 = minutes
+            .parse()
+            .context("DRIFT_CHECK_MINUTES is not a number")?;
+        if minutes == 0 {
+            return Ok(None);
+        }
+        let threshold = dotenv::var("DRIFT_THRESHOLD")
+            .ok()
+            .filter(|threshold| !threshold.is_empty())
+            .map(|threshold| threshold.parse())
+            .transpose()
+            .context("DRIFT_THRESHOLD is not a number")?
+            .unwrap_or(0);
+        Ok(Some(DriftCheck {
+            interval: Duration::from_secs(minutes * 60),
+            threshold,
+        }))
+    }
+}
+
+/// The drivers from the last full update and the webhooks since, prepared
+/// like a full update does. None before the first full update.
+async fn cached_drivers(state: &State, report: &mut Report) -> Option<Vec<BasicDriver>> {
+    let cached_orders = state.cached_orders.lock().await;
+    let mut drivers = Vec::new();
+    for source in &state.sources {
+        let (source_drivers, source_report) = cached_orders.get(source.name())?;
+        drivers.extend(source_drivers.iter().cloned());
+        report
+            .problems
+            .extend(source_report.problems.iter().cloned());
+        report
+            .chargebacks
+            .extend(source_report.chargebacks.iter().cloned());
+    }
+    drop(cached_orders);
+    state.prepare_drivers(&mut drivers, &[], report).await;
+    drivers.extend(state.store.lock().await.data().manual_drivers.clone());
+    Some(drivers)
+}
+
+/// How many changes a full update with the cached drivers would make, or None
+/// if there's nothing to compare against yet or writes are held back
+async fn measure(state: &State) -> Result<Option<usize>> {
+    // Not while something is being written, and queued changes aren't drift
+    let write_gate = state.write_gate.lock().await;
+    if write_gate.is_held() {
+        return Ok(None);
+    }
+    let mut report = Report::default();
+    let Some(drivers) = cached_drivers(state, &mut report).await else {
+        return Ok(None);
+    };
+    let diff = diff::diff_drivers(state, &drivers, true, report).await?;
+    drop(write_gate);
+    for change in &diff.changes {
+        info!("Drift: {}", change);
+    }
+    Ok(Some(diff.changes.len()))
+}
+
+/// Compare the ACSM files against the cached drivers every
+/// DRIFT_CHECK_MINUTES, without writing, and notify once when they drift
+/// apart by more than DRIFT_THRESHOLD changes
+pub async fn check_task(state: Arc<State>) {
+    let Some(check) = &state.drift_check else {
+        return;
+    };
+    let mut drifted = false;
+    loop {
+        sleep(check.interval).await;
+        let changes = match measure(&state).await {
+            Ok(Some(changes)) => changes,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Drift check failed: {:?}", e);
+                continue;
+            }
+        };
+        let over = changes > check.threshold;
+        if over && !drifted {
+            warn!(
+                "ACSM files drifted from the tickets: a full update would make {} changes",
+                changes
+            );
+            state.events.emit(EventKind::Drift { changes });
+        } else if drifted && !over {
+            info!("ACSM files match the tickets again");
+            state.events.emit(EventKind::Drift { changes });
+        }
+        drifted = over;
+    }
+}
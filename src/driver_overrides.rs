@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+use crate::acsm::BasicDriver;
+
+/// What an admin sets for one driver, over what the ticket gives them
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriverOverride {
+    pub name: Option<String>,
+    /// In kg
+    pub ballast: Option<u32>,
+    /// In percent
+    pub restrictor: Option<u32>,
+    pub skin: Option<String>,
+    pub fixed_setup: Option<String>,
+}
+
+/// DRIVER_OVERRIDES_FILE, if set
+pub fn path_from_env() -> Option<PathBuf> {
+    dotenv::var("DRIVER_OVERRIDES_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// By Steam ID of the main driver. Read on every write, so changes to the file
+/// apply on the next sync without a restart.
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct DriverOverrides(HashMap<u64, DriverOverride>);
+
+impl DriverOverrides {
+    pub async fn load(path: &Path) -> Result<DriverOverrides> {
+        let text = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read DRIVER_OVERRIDES_FILE {}", path.display()))?;
+        serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse DRIVER_OVERRIDES_FILE {}", path.display()))
+    }
+
+    /// Put the overrides over what came from the tickets and the ticket map
+    pub fn apply(&self, drivers: &mut [BasicDriver]) {
+        for driver in drivers {
+            let Some(driver_override) = self.0.get(&driver.steam_id) else {
+                continue;
+            };
+            if let Some(name) = &driver_override.name {
+                driver.name = name.clone();
+            }
+            if let Some(ballast) = driver_override.ballast {
+                driver.entry.ballast = Some(ballast);
+            }
+            if let Some(restrictor) = driver_override.restrictor {
+                driver.entry.restrictor = Some(restrictor);
+            }
+            if let Some(skin) = &driver_override.skin {
+                // A pool of one is that skin, even if someone else has it
+                driver.entry.skins = vec![skin.clone()];
+            }
+            if let Some(fixed_setup) = &driver_override.fixed_setup {
+                driver.entry.fixed_setup = Some(fixed_setup.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acsm::EntrySettings;
+
+    fn driver(steam_id: u64) -> BasicDriver {
+        BasicDriver {
+            name: "From Ticket".to_string(),
+            entry: EntrySettings {
+                ballast: Some(10),
+                skins: vec!["red".to_string(), "blue".to_string()],
+                ..EntrySettings::default()
+            },
+            ..BasicDriver::test(steam_id, "bmw_m6_gt3")
+        }
+    }
+
+    #[test]
+    fn apply() {
+        let overrides: DriverOverrides = serde_json::from_str(
+            r#"{
+                "76561190000000001": {
+                    "name": "Fixed Name",
+                    "ballast": 30,
+                    "skin": "gold",
+                    "fixed_setup": "gt3/monza.ini"
+                },
+                "76561190000000002": {"restrictor": 5}
+            }"#,
+        )
+        .unwrap();
+        let mut drivers = [
+            driver(76561190000000001),
+            driver(76561190000000002),
+            driver(76561190000000003),
+        ];
+        overrides.apply(&mut drivers);
+        assert_eq!(drivers[0].name, "Fixed Name");
+        assert_eq!(
+            drivers[0].entry,
+            EntrySettings {
+                ballast: Some(30),
+                skins: vec!["gold".to_string()],
+                fixed_setup: Some("gt3/monza.ini".to_string()),
+                ..EntrySettings::default()
+            }
+        );
+        assert_eq!(drivers[1].name, "From Ticket");
+        assert_eq!(drivers[1].entry.ballast, Some(10));
+        assert_eq!(drivers[1].entry.restrictor, Some(5));
+        assert_eq!(drivers[2].entry, driver(76561190000000003).entry);
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(serde_json::from_str::<DriverOverrides>(r#"{"1": {"balast": 30}}"#).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::sandbox;
+
+/// Sends plain text email through SMTP_URL
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+fn var(name: &str) -> Option<String> {
+    dotenv::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl Mailer {
+    /// From SMTP_URL, like `smtps://smtp.example.com` or
+    /// `smtp://smtp.example.com:587?tls=required`, SMTP_USERNAME,
+    /// SMTP_PASSWORD and EMAIL_FROM
+    pub fn from_env() -> Result<Option<Mailer>> {
+        let Some(url) = var("SMTP_URL") else {
+            return Ok(None);
+        };
+        let mut builder =
+            AsyncSmtpTransport::<Tokio1Executor>::from_url(&url).context("Invalid SMTP_URL")?;
+        if let Some(username) = var("SMTP_USERNAME") {
+            builder = builder.credentials(Credentials::new(
+                username,
+                var("SMTP_PASSWORD").unwrap_or_default(),
+            ));
+        }
+        Ok(Some(Mailer {
+            transport: builder.build(),
+            from: var("EMAIL_FROM")
+                .context("SMTP_URL is set, but EMAIL_FROM is not")?
+                .parse()
+                .context("EMAIL_FROM is not an email address")?,
+        }))
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to
+                .parse()
+                .with_context(|| format!("{} is not an email address", to))?)
+            .subject(sandbox::mark(subject))
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+        self.transport
+            .send(message)
+            .await
+            .with_context(|| format!("Failed to email {}", to))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+#[cfg(feature = "sentry")]
+use log::info;
+use log::warn;
+
+/// Keeps the client alive until exiting, so the last errors still get sent
+pub struct Guard {
+    #[cfg(feature = "sentry")]
+    _client: Option<sentry::ClientInitGuard>,
+}
+
+fn dsn_from_env() -> Option<String> {
+    dotenv::var("SENTRY_DSN").ok().filter(|dsn| !dsn.is_empty())
+}
+
+/// Report panics and captured errors to SENTRY_DSN, if set. Works with
+/// anything that takes a Sentry DSN.
+#[cfg(feature = "sentry")]
+pub fn init() -> Guard {
+    let Some(dsn) = dsn_from_env() else {
+        return Guard { _client: None };
+    };
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: dotenv::var("SENTRY_ENVIRONMENT")
+                .ok()
+                .filter(|environment| !environment.is_empty())
+                .map(Into::into),
+            ..Default::default()
+        },
+    ));
+    if guard.is_enabled() {
+        info!("Reporting errors to Sentry");
+    } else {
+        warn!("SENTRY_DSN is not a valid DSN, not reporting errors");
+    }
+    Guard {
+        _client: Some(guard),
+    }
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init() -> Guard {
+    if dsn_from_env().is_some() {
+        warn!("SENTRY_DSN is set, but this build doesn't have the sentry feature");
+    }
+    Guard {}
+}
+
+/// Report the error, tagged with what it was about, like `order_guid` or
+/// `file`
+pub fn capture(error: &anyhow::Error, tags: &[(&str, &str)]) {
+    #[cfg(feature = "sentry")]
+    sentry::with_scope(
+        |scope| {
+            for (key, value) in tags {
+                scope.set_tag(key, value);
+            }
+        },
+        || sentry::integrations::anyhow::capture_anyhow(error),
+    );
+    #[cfg(not(feature = "sentry"))]
+    let _ = (error, tags);
+}
@@ -0,0 +1,392 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    acsm::BasicDriver,
+    http::HttpPolicy,
+    oauth2::{self, setup_oauth2_client, OAuth2State},
+    report::{ProblemKind, Report},
+    source::RegistrationSource,
+    tickets::{self, MetaDataIDs, TicketContext},
+    State, WebhookPayload,
+};
+
+const API_URL: &str = "https://www.eventbriteapi.com/v3";
+
+/// Tickets sold through Eventbrite
+pub struct Eventbrite {
+    pub event_id: String,
+    pub question_ids: MetaDataIDs,
+    pub oauth2: Mutex<OAuth2State>,
+}
+
+impl Eventbrite {
+    pub async fn from_env() -> Result<Eventbrite> {
+        Ok(Eventbrite {
+            event_id: dotenv::var("EVENTBRITE_EVENT_ID").context("EVENTBRITE_EVENT_ID not set")?,
+            question_ids: MetaDataIDs::from_env("EVENTBRITE_QUESTION")?,
+            oauth2: Mutex::new(setup_oauth2_client("EVENTBRITE_OAUTH2").await?),
+        })
+    }
+}
+
+/// What's needed to make calls to the Eventbrite API
+#[derive(Debug, Clone, Copy)]
+pub struct Api<'a> {
+    pub http: &'a HttpPolicy,
+    pub token: &'a str,
+}
+
+async fn get_json(api: Api<'_>, url: String, what: &str) -> Result<serde_json::Value> {
+    let request = api.http.client().get(url).bearer_auth(api.token);
+    api.http
+        .send(request)
+        .await
+        .with_context(|| format!("Getting {} from Eventbrite API failed", what))?
+        .error_for_status()
+        .context("Eventbrite API returned error")?
+        .json()
+        .await
+        .context("Eventbrite API returned bad JSON")
+}
+
+pub async fn get_event_start(api: Api<'_>, event_id: &str) -> Result<DateTime<Utc>> {
+    let event = get_json(api, format!("{}/events/{}/", API_URL, event_id), "event").await?;
+    let start = event["start"]["utc"]
+        .as_str()
+        .context("Event start is not a string")?;
+    Ok(DateTime::parse_from_rfc3339(start)
+        .context("Event start is not an RFC3339 timestamp")?
+        .with_timezone(&Utc))
+}
+
+/// All attending attendees, following the continuation tokens
+pub async fn get_attendees(
+    api: Api<'_>,
+    event_id: &str,
+    tickets: &TicketContext<'_>,
+    report: &mut Report,
+) -> Result<Vec<BasicDriver>> {
+    let mut drivers = Vec::new();
+    let mut bad_tickets = 0_usize;
+    let mut continuation: Option<String> = None;
+    loop {
+        let mut url = format!(
+            "{}/events/{}/attendees/?status=attending",
+            API_URL, event_id
+        );
+        if let Some(continuation) = &continuation {
+            url.push_str(&format!("&continuation={}", continuation));
+        }
+        let page = get_json(api, url, "attendees").await?;
+        bad_tickets += attendees_to_drivers(&page["attendees"], tickets, report, &mut drivers)?;
+        if page["pagination"]["has_more_items"].as_bool() != Some(true) {
+            break;
+        }
+        continuation = Some(
+            page["pagination"]["continuation"]
+                .as_str()
+                .context("More attendees, but no continuation")?
+                .to_string(),
+        );
+    }
+    tickets
+        .policy
+        .check_bad_fraction(drivers.len(), bad_tickets)?;
+    Ok(drivers)
+}
+
+pub async fn get_single_order(
+    api: Api<'_>,
+    event_id: &str,
+    tickets: &TicketContext<'_>,
+    order_id: &str,
+    report: &mut Report,
+) -> Result<Vec<BasicDriver>> {
+    let order = get_json(
+        api,
+        format!("{}/orders/{}/?expand=attendees", API_URL, order_id),
+        "order",
+    )
+    .await?;
+    let mut drivers = Vec::new();
+    if order["event_id"] != event_id {
+        warn!(
+            "Order {} is for another event: {}",
+            order_id, order["event_id"]
+        );
+        return Ok(drivers);
+    }
+    if order["status"] != "placed" {
+        debug!(
+            "Skipping order {} with status: {}",
+            order_id, order["status"]
+        );
+        return Ok(drivers);
+    }
+    attendees_to_drivers(&order["attendees"], tickets, report, &mut drivers)?;
+    Ok(drivers)
+}
+
+/// Add the drivers among the attendees, returning how many had bad answers
+fn attendees_to_drivers(
+    attendees: &serde_json::Value,
+    tickets: &TicketContext,
+    report: &mut Report,
+    drivers: &mut Vec<BasicDriver>,
+) -> Result<usize> {
+    let mut bad_tickets = 0;
+    for attendee in attendees.as_array().context("Attendees is not an array")? {
+        if attendee["cancelled"].as_bool() == Some(true)
+            || attendee["refunded"].as_bool() == Some(true)
+        {
+            continue;
+        }
+        let order_id = attendee["order_id"].as_str();
+        let attendee_id = attendee["id"].as_str();
+        if tickets.policy.require_check_in && attendee["checked_in"].as_bool() != Some(true) {
+            debug!("Skipping attendee {:?} that isn't checked in", attendee_id);
+            continue;
+        }
+        let ticket_class = attendee["ticket_class_id"]
+            .as_str()
+            .context("Attendee ticket_class_id is not a string")?;
+        let Some(mapping) = tickets.mapping(ticket_class) else {
+            tickets.skip_unmapped(
+                ticket_class,
+                format!("No car found for ticket class: {}", ticket_class),
+                report,
+                order_id,
+                attendee_id,
+            )?;
+            continue;
+        };
+        // The attendee's name isn't a custom question, offer it as if it were
+        let profile = [
+            ("first_name", attendee["profile"]["first_name"].as_str()),
+            ("last_name", attendee["profile"]["last_name"].as_str()),
+        ];
+        let answers = attendee["answers"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|answer| {
+                Some((answer["question_id"].as_str()?, answer["answer"].as_str()))
+            });
+        match tickets::driver_from_metadata(
+            mapping,
+            profile.into_iter().chain(answers),
+            tickets,
+            order_id,
+            attendee_id,
+        ) {
+            // Attendees are created when the order is placed
+            Ok(driver) => drivers.push(BasicDriver {
+                paid_at: tickets::parse_time(&attendee["created"]),
+                email: attendee["profile"]["email"].as_str().map(str::to_string),
+                ..driver
+            }),
+            Err(e) => {
+                report.add(
+                    ProblemKind::BadMetadata,
+                    order_id,
+                    attendee_id,
+                    format!("{:#}", e),
+                );
+                bad_tickets += 1;
+            }
+        }
+    }
+    Ok(bad_tickets)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventbriteWebhookConfig {
+    pub action: String,
+    pub webhook_id: String,
+}
+
+/// Eventbrite webhooks only point at the API object that changed
+#[derive(Debug, Deserialize)]
+pub struct EventbriteWebhookPayload {
+    pub config: EventbriteWebhookConfig,
+    pub api_url: String,
+}
+
+impl EventbriteWebhookPayload {
+    /// From `https://www.eventbriteapi.com/v3/orders/<id>/`
+    fn order_id(&self) -> Option<&str> {
+        let path = self.api_url.split_once("/orders/")?.1;
+        let order_id = path.trim_end_matches('/');
+        (!order_id.is_empty() && !order_id.contains('/')).then_some(order_id)
+    }
+}
+
+#[async_trait]
+impl RegistrationSource for Eventbrite {
+    fn name(&self) -> &'static str {
+        "eventbrite"
+    }
+
+    async fn fetch_all(
+        &self,
+        state: &State,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        let Some(api_token) = oauth2::token(&self.oauth2).await else {
+            return Ok(None);
+        };
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        get_attendees(
+            state.eventbrite_api(&api_token),
+            &self.event_id,
+            &state.ticket_context(&ticket_map, &self.question_ids, &steam_id_overrides),
+            report,
+        )
+        .await
+        .map(Some)
+    }
+
+    async fn fetch_delta(
+        &self,
+        state: &State,
+        order_id: &str,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        let Some(api_token) = oauth2::token(&self.oauth2).await else {
+            return Ok(None);
+        };
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        get_single_order(
+            state.eventbrite_api(&api_token),
+            &self.event_id,
+            &state.ticket_context(&ticket_map, &self.question_ids, &steam_id_overrides),
+            order_id,
+            report,
+        )
+        .await
+        .map(Some)
+    }
+
+    fn has_webhook(&self) -> bool {
+        true
+    }
+
+    fn parse_webhook(&self, payload: serde_json::Value) -> Result<Option<WebhookPayload>> {
+        let payload: EventbriteWebhookPayload =
+            serde_json::from_value(payload).context("Not an Eventbrite webhook")?;
+        // Answers can be edited after the order was placed
+        if payload.config.action != "order.placed" && payload.config.action != "order.updated" {
+            info!(
+                "Ignoring Eventbrite {} for {}",
+                payload.config.action, payload.api_url
+            );
+            return Ok(None);
+        }
+        let order_id = payload
+            .order_id()
+            .ok_or_else(|| anyhow!("Eventbrite webhook without order: {}", payload.api_url))?;
+        Ok(Some(WebhookPayload {
+            // Eventbrite doesn't say when, but sends right away
+            date_time: Utc::now().to_rfc3339(),
+            event: "order-paid".to_string(),
+            event_key: payload.config.webhook_id.clone(),
+            guid: order_id.to_string(),
+        }))
+    }
+
+    async fn event_start(&self, state: &State) -> Result<DateTime<Utc>> {
+        let api_token = oauth2::token(&self.oauth2)
+            .await
+            .context("No OAuth2 token")?;
+        get_event_start(state.eventbrite_api(&api_token), &self.event_id).await
+    }
+
+    fn oauth2(&self) -> Option<&Mutex<OAuth2State>> {
+        Some(&self.oauth2)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ticket_map::TicketMap,
+        tickets::{DuplicatePolicy, TicketPolicy, UnmappedTicketPolicy},
+    };
+    use serde_json::json;
+    use std::collections::{HashMap, HashSet};
+    use test_case::test_case;
+
+    #[test_case("https://www.eventbriteapi.com/v3/orders/123/", Some("123"); "order")]
+    #[test_case("https://www.eventbriteapi.com/v3/orders/123", Some("123"); "no trailing slash")]
+    #[test_case("https://www.eventbriteapi.com/v3/events/9/", None; "event")]
+    fn webhook_order_id(api_url: &str, expected: Option<&str>) {
+        let payload = EventbriteWebhookPayload {
+            config: EventbriteWebhookConfig {
+                action: "order.placed".to_string(),
+                webhook_id: "1".to_string(),
+            },
+            api_url: api_url.to_string(),
+        };
+        assert_eq!(payload.order_id(), expected);
+    }
+
+    #[test]
+    fn attendees() {
+        let attendees = json!([
+            {
+                "id": "a1",
+                "order_id": "o1",
+                "ticket_class_id": "10",
+                "profile": {"first_name": "Max", "last_name": "Power"},
+                "answers": [
+                    {"question_id": "100", "answer": "76561190000000001"},
+                    {"question_id": "101"},
+                ],
+            },
+            {"id": "a2", "order_id": "o1", "ticket_class_id": "10", "refunded": true},
+            {"id": "a3", "order_id": "o2", "ticket_class_id": "10", "profile": {}},
+        ]);
+        let question_ids = MetaDataIDs {
+            first_name: "first_name".to_string(),
+            last_name: "last_name".to_string(),
+            team_name: "101".to_string(),
+            steam_id: "100".to_string(),
+            co_driver_steam_ids: Vec::new(),
+            nationality: None,
+            skill: None,
+            pace: None,
+        };
+        let map = TicketMap::from_car_map("10:gt3").unwrap();
+        let policy = TicketPolicy {
+            unmapped: UnmappedTicketPolicy::Skip,
+            max_bad_fraction: 1.0,
+            require_check_in: false,
+            duplicates: DuplicatePolicy::Earliest,
+        };
+        let tickets = TicketContext {
+            ticket_map: &map,
+            ticket_names: None,
+            add_on_tickets: &HashSet::new(),
+            metadata_ids: &question_ids,
+            policy: &policy,
+            steam_id_overrides: &HashMap::new(),
+        };
+        let mut report = Report::default();
+        let mut drivers = Vec::new();
+        let bad = attendees_to_drivers(&attendees, &tickets, &mut report, &mut drivers).unwrap();
+        assert_eq!(bad, 1);
+        assert_eq!(drivers.len(), 1);
+        assert_eq!(drivers[0].name, "Max Power");
+        assert_eq!(drivers[0].steam_id, 76561190000000001);
+        assert_eq!(drivers[0].team_name, None);
+    }
+}
@@ -1,49 +1,432 @@
 use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use log::debug;
+use log::{debug, info};
 use std::collections::HashMap;
+use tokio::sync::Mutex;
 
-use crate::acsm::BasicDriver;
+use crate::{
+    acsm::BasicDriver,
+    chargebacks,
+    http::HttpPolicy,
+    oauth2::{self, setup_oauth2_client, OAuth2State},
+    order_status::{self, Action, OrderStatus},
+    report::{ProblemKind, Report},
+    source::RegistrationSource,
+    ticket_map::TicketMap,
+    tickets::{self, MetaDataIDs, TicketContext},
+    validate, State,
+};
 
-pub struct MetaDataIDs {
-    pub first_name: String,
-    pub last_name: String,
-    pub team_name: String,
-    pub steam_id: String,
+/// Tickets sold through Eventix
+pub struct Eventix {
+    /// The Event the tickets are sold under, CALENDAR_FILE switches it
+    event_guid: Mutex<String>,
+    pub metadata_ids: MetaDataIDs,
+    pub oauth2: Mutex<OAuth2State>,
+    /// By order GUID, the last status we saw, to tell what changed
+    pub statuses: Mutex<HashMap<String, OrderStatus>>,
 }
 
-pub async fn get_single_order(
-    api_token: &str,
-    event_guid: &str,
-    ticket_to_car_map: &HashMap<String, String>,
-    metadata_ids: &MetaDataIDs,
-    order_id: &str,
-) -> Result<Vec<BasicDriver>> {
-    let client = reqwest::Client::new();
-    let url = format!("https://api.eventix.io/3.0.0/order/{}", order_id);
-    let request = client.get(url).bearer_auth(api_token);
-    let response: serde_json::Value = request
-        .send()
+impl Eventix {
+    pub async fn from_env() -> Result<Eventix> {
+        Ok(Eventix {
+            event_guid: Mutex::new(
+                dotenv::var("EVENTIX_EVENT_GUID").context("EVENTIX_EVENT_GUID not set")?,
+            ),
+            metadata_ids: MetaDataIDs::from_env("EVENTIX_METADATA")?,
+            oauth2: Mutex::new(setup_oauth2_client("EVENTIX_OAUTH2").await?),
+            statuses: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Eventix {
+    async fn event_guid(&self) -> String {
+        self.event_guid.lock().await.clone()
+    }
+
+    /// Ticket type names by GUID, if the ticket map has name patterns. Orders
+    /// only have the GUIDs.
+    async fn ticket_names(
+        &self,
+        ticket_map: &TicketMap,
+        api: Api<'_>,
+    ) -> Result<Option<HashMap<String, String>>> {
+        if !ticket_map.has_patterns() {
+            return Ok(None);
+        }
+        let ticket_types = get_ticket_types(api, &self.event_guid().await).await?;
+        Ok(Some(
+            ticket_types
+                .into_iter()
+                .map(|ticket_type| (ticket_type.guid, ticket_type.name))
+                .collect(),
+        ))
+    }
+}
+
+#[async_trait]
+impl RegistrationSource for Eventix {
+    fn name(&self) -> &'static str {
+        "eventix"
+    }
+
+    async fn fetch_all(
+        &self,
+        state: &State,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        let Some(api_token) = oauth2::token(&self.oauth2).await else {
+            return Ok(None);
+        };
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        let api = state.eventix_api(&api_token);
+        state
+            .eventix_call(async {
+                let ticket_names = self.ticket_names(&ticket_map, api).await?;
+                let mut tickets =
+                    state.ticket_context(&ticket_map, &self.metadata_ids, &steam_id_overrides);
+                tickets.ticket_names = ticket_names.as_ref();
+                let mut statuses = self.statuses.lock().await;
+                get_orders(
+                    api,
+                    &self.event_guid().await,
+                    &tickets,
+                    &mut statuses,
+                    report,
+                )
+                .await
+            })
+            .await
+            .map(Some)
+    }
+
+    async fn fetch_delta(
+        &self,
+        state: &State,
+        order_id: &str,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        let Some(api_token) = oauth2::token(&self.oauth2).await else {
+            return Ok(None);
+        };
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        let api = state.eventix_api(&api_token);
+        state
+            .eventix_call(async {
+                let ticket_names = self.ticket_names(&ticket_map, api).await?;
+                let mut tickets =
+                    state.ticket_context(&ticket_map, &self.metadata_ids, &steam_id_overrides);
+                tickets.ticket_names = ticket_names.as_ref();
+                let mut statuses = self.statuses.lock().await;
+                get_single_order(
+                    api,
+                    &self.event_guid().await,
+                    &tickets,
+                    order_id,
+                    &mut statuses,
+                    report,
+                )
+                .await
+            })
+            .await
+            .map(Some)
+    }
+
+    async fn parse_order(
+        &self,
+        state: &State,
+        order: &serde_json::Value,
+        report: &mut Report,
+    ) -> Result<Vec<BasicDriver>> {
+        let order_id = order["guid"].as_str().context("Order has no guid")?;
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        let ticket_names = match oauth2::token(&self.oauth2).await {
+            Some(api_token) => {
+                self.ticket_names(&ticket_map, state.eventix_api(&api_token))
+                    .await?
+            }
+            None if ticket_map.has_patterns() => {
+                return Err(anyhow!("No OAuth2 token to look up ticket type names"))
+            }
+            None => None,
+        };
+        let mut tickets =
+            state.ticket_context(&ticket_map, &self.metadata_ids, &steam_id_overrides);
+        tickets.ticket_names = ticket_names.as_ref();
+        // Simulated orders don't change what we know about the real ones
+        let mut statuses = self.statuses.lock().await.clone();
+        order_drivers(
+            order,
+            &self.event_guid().await,
+            &tickets,
+            order_id,
+            &mut statuses,
+            report,
+        )
+    }
+
+    async fn event_start(&self, state: &State) -> Result<DateTime<Utc>> {
+        let api_token = oauth2::token(&self.oauth2)
+            .await
+            .context("No OAuth2 token")?;
+        get_event_start(state.eventix_api(&api_token), &self.event_guid().await).await
+    }
+
+    async fn validate(&self, state: &State, ticket_map: &TicketMap) -> Result<()> {
+        let api_token = oauth2::token(&self.oauth2)
+            .await
+            .context("No OAuth2 token")?;
+        validate::validate_eventix_tickets(
+            state.eventix_api(&api_token),
+            &self.event_guid().await,
+            ticket_map,
+            &state.add_on_ticket_ids,
+        )
         .await
-        .context("getting single order from Eventix API failed")?
+    }
+
+    fn oauth2(&self) -> Option<&Mutex<OAuth2State>> {
+        Some(&self.oauth2)
+    }
+
+    /// While the circuit breaker keeps us from calling Eventix
+    async fn is_unavailable(&self, state: &State) -> bool {
+        state.eventix_breaker.lock().await.is_open()
+    }
+
+    async fn ticket_types(&self, state: &State) -> Result<Option<Vec<TicketType>>> {
+        let Some(api_token) = oauth2::token(&self.oauth2).await else {
+            return Ok(None);
+        };
+        let api = state.eventix_api(&api_token);
+        state
+            .eventix_call(get_ticket_types(api, &self.event_guid().await))
+            .await
+            .map(Some)
+    }
+
+    async fn set_sales_open(&self, state: &State, ticket_type: &str, open: bool) -> Result<()> {
+        let api_token = oauth2::token(&self.oauth2)
+            .await
+            .context("No OAuth2 token")?;
+        let api = state.eventix_api(&api_token);
+        state
+            .eventix_call(set_ticket_sales_open(api, ticket_type, open))
+            .await
+    }
+
+    async fn set_available(
+        &self,
+        state: &State,
+        ticket_type: &str,
+        available: usize,
+    ) -> Result<()> {
+        let api_token = oauth2::token(&self.oauth2)
+            .await
+            .context("No OAuth2 token")?;
+        let api = state.eventix_api(&api_token);
+        state
+            .eventix_call(set_ticket_stock(api, ticket_type, available))
+            .await
+    }
+
+    async fn switch_event(&self, event_guid: &str) -> Result<()> {
+        *self.event_guid.lock().await = event_guid.to_string();
+        // Orders of the other event
+        self.statuses.lock().await.clear();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct TicketType {
+    pub guid: String,
+    pub name: String,
+}
+
+/// What's needed to make calls to the Eventix API
+#[derive(Debug, Clone, Copy)]
+pub struct Api<'a> {
+    pub http: &'a HttpPolicy,
+    pub token: &'a str,
+}
+
+async fn get_json(api: Api<'_>, url: String, what: &str) -> Result<serde_json::Value> {
+    let request = api.http.client().get(url).bearer_auth(api.token);
+    api.http
+        .send(request)
+        .await
+        .with_context(|| format!("Getting {} from Eventix API failed", what))?
         .error_for_status()
         .context("Eventix API returned error")?
         .json()
         .await
-        .context("Eventix API returned bad JSON")?;
-    if response
+        .context("Eventix API returned bad JSON")
+}
+
+async fn update_ticket(api: Api<'_>, ticket_guid: &str, body: serde_json::Value) -> Result<()> {
+    let url = format!("https://api.eventix.io/3.0.0/ticket/{}", ticket_guid);
+    let request = api
+        .http
+        .client()
+        .put(url)
+        .bearer_auth(api.token)
+        .json(&body);
+    api.http
+        .send(request)
+        .await
+        .context("Updating ticket type in Eventix API failed")?
+        .error_for_status()
+        .context("Eventix API returned error")?;
+    Ok(())
+}
+
+/// Mark the ticket type as sold out in the shop, or let its own stock decide
+/// again
+pub async fn set_ticket_sales_open(api: Api<'_>, ticket_guid: &str, open: bool) -> Result<()> {
+    let status_overrule = if open { "auto" } else { "sold_out" };
+    update_ticket(
+        api,
+        ticket_guid,
+        serde_json::json!({ "status_overrule": status_overrule }),
+    )
+    .await
+}
+
+/// How many more of the ticket type the shop can sell
+pub async fn set_ticket_stock(api: Api<'_>, ticket_guid: &str, available: usize) -> Result<()> {
+    update_ticket(
+        api,
+        ticket_guid,
+        serde_json::json!({ "available_stock": available }),
+    )
+    .await
+}
+
+pub async fn get_ticket_types(api: Api<'_>, event_guid: &str) -> Result<Vec<TicketType>> {
+    let url = format!("https://api.eventix.io/3.0.0/event/{}/ticket", event_guid);
+    let response = get_json(api, url, "ticket types").await?;
+    response
+        .as_array()
+        .context("Ticket types is not an array")?
+        .iter()
+        .map(|ticket| {
+            Ok(TicketType {
+                guid: ticket["guid"]
+                    .as_str()
+                    .context("Ticket type guid is not a string")?
+                    .to_string(),
+                name: ticket["name"].as_str().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// An event of the company the token is for
+#[derive(Debug)]
+pub struct EventSummary {
+    pub guid: String,
+    pub name: String,
+}
+
+pub async fn get_events(api: Api<'_>) -> Result<Vec<EventSummary>> {
+    let url = "https://api.eventix.io/3.0.0/event".to_string();
+    let response = get_json(api, url, "events").await?;
+    response
+        .as_array()
+        .context("Events is not an array")?
+        .iter()
+        .map(|event| {
+            Ok(EventSummary {
+                guid: event["guid"]
+                    .as_str()
+                    .context("Event guid is not a string")?
+                    .to_string(),
+                name: event["name"].as_str().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// A metadata field the buyer fills in for a ticket
+#[derive(Debug)]
+pub struct MetadataField {
+    pub guid: String,
+    pub name: String,
+}
+
+pub async fn get_metadata_fields(api: Api<'_>, ticket_guid: &str) -> Result<Vec<MetadataField>> {
+    let url = format!(
+        "https://api.eventix.io/3.0.0/ticket/{}/metadata",
+        ticket_guid
+    );
+    let response = get_json(api, url, "metadata fields").await?;
+    response
+        .as_array()
+        .context("Metadata fields is not an array")?
+        .iter()
+        .map(|field| {
+            Ok(MetadataField {
+                guid: field["guid"]
+                    .as_str()
+                    .context("Metadata field guid is not a string")?
+                    .to_string(),
+                name: field["name"].as_str().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+pub async fn get_event_start(api: Api<'_>, event_guid: &str) -> Result<DateTime<Utc>> {
+    let url = format!("https://api.eventix.io/3.0.0/event/{}", event_guid);
+    let response = get_json(api, url, "event").await?;
+    let start = response["start"]
+        .as_str()
+        .context("Event start is not a string")?;
+    Ok(DateTime::parse_from_rfc3339(start)
+        .with_context(|| format!("Unrecognized event start: {}", start))?
+        .with_timezone(&Utc))
+}
+
+pub async fn get_single_order(
+    api: Api<'_>,
+    event_guid: &str,
+    tickets: &TicketContext<'_>,
+    order_id: &str,
+    statuses: &mut HashMap<String, OrderStatus>,
+    report: &mut Report,
+) -> Result<Vec<BasicDriver>> {
+    let url = format!("https://api.eventix.io/3.0.0/order/{}", order_id);
+    let response = get_json(api, url, "single order").await?;
+    order_drivers(&response, event_guid, tickets, order_id, statuses, report)
+}
+
+/// The drivers in an order as `/order/:guid` returns it
+pub fn order_drivers(
+    response: &serde_json::Value,
+    event_guid: &str,
+    tickets: &TicketContext<'_>,
+    order_id: &str,
+    statuses: &mut HashMap<String, OrderStatus>,
+    report: &mut Report,
+) -> Result<Vec<BasicDriver>> {
+    response
         .get("status")
         .context("Order is missing status field")?
         .as_str()
-        .context("Order status is not a string")?
-        != "paid"
-    {
-        return Err(anyhow!("Order is not paid, this should not happen"));
+        .context("Order status is not a string")?;
+    if !should_add(tickets, statuses, report, order_id, response) {
+        return Ok(Vec::new());
     }
-    let tickets = response["tickets"]
+    response["tickets"]
         .as_array()
-        .context("tickets is not an array")?;
-    tickets
+        .context("tickets is not an array")?
         .iter()
         .map(|ticket| {
             if ticket
@@ -57,10 +440,24 @@ pub async fn get_single_order(
             {
                 debug!("Skipping ticket [{}] with wrong event_id", ticket["guid"]);
                 Ok(None)
+            } else if !is_mapped(tickets, ticket) {
+                skip_unmapped(tickets, report, order_id, ticket)?;
+                Ok(None)
+            } else if tickets.policy.require_check_in && !is_checked_in(ticket) {
+                debug!("Skipping ticket [{}] that isn't checked in", ticket["guid"]);
+                Ok(None)
             } else {
-                Ok(Some(ticket_to_driver(ticket_to_car_map, metadata_ids)(
-                    ticket,
-                )?))
+                match ticket_to_driver(tickets)(ticket) {
+                    Ok(driver) => Ok(Some(BasicDriver {
+                        paid_at: paid_at(response),
+                        email: email(response),
+                        ..driver
+                    })),
+                    Err(e) => {
+                        skip_bad_metadata(report, order_id, ticket, e);
+                        Ok(None)
+                    }
+                }
             }
         })
         .filter_map_ok(|x| x)
@@ -68,26 +465,17 @@ pub async fn get_single_order(
 }
 
 pub async fn get_orders(
-    api_token: &str,
+    api: Api<'_>,
     event_guid: &str,
-    ticket_id_to_car_map: &HashMap<String, String>,
-    metadata_ids: &MetaDataIDs,
+    tickets: &TicketContext<'_>,
+    statuses: &mut HashMap<String, OrderStatus>,
+    report: &mut Report,
 ) -> Result<Vec<BasicDriver>> {
-    let client = reqwest::Client::new();
     let url = format!(
         "https://api.eventix.io/3.0.0/statistics/event/{}",
         event_guid
     );
-    let request = client.get(url).bearer_auth(api_token);
-    let response: serde_json::Value = request
-        .send()
-        .await
-        .context("Getting orders from Eventix API failed")?
-        .error_for_status()
-        .context("Eventix API returned error")?
-        .json()
-        .await
-        .context("Eventix API returned bad JSON")?;
+    let response = get_json(api, url, "orders").await?;
     let hits = response
         .get("hits")
         .context("Missing hits field in JSON")?
@@ -95,81 +483,195 @@ pub async fn get_orders(
         .context("Missing hits->hits field in JSON")?
         .as_array()
         .context("hits->hits is not an array")?;
-    let drivers = hits.iter()
-        .filter_map(|hit| {
-            let source = hit["_source"].as_object().unwrap();
-            let status = source["status"].as_str().unwrap();
-            if status != "paid" {
-                debug!("Skipping order [{}] with status: {}", source["guid"], status);
-                return None;
+    let mut drivers = Vec::new();
+    let mut bad_tickets = 0_usize;
+    for hit in hits {
+        let source = hit["_source"].as_object().unwrap();
+        let order_guid = source["guid"].as_str().unwrap_or_default();
+        if !should_add(tickets, statuses, report, order_guid, &hit["_source"]) {
+            continue;
+        }
+        for ticket in source["tickets"].as_array().unwrap() {
+            if !is_mapped(tickets, ticket) {
+                skip_unmapped(tickets, report, order_guid, ticket)?;
+                continue;
             }
-            let tickets = source["tickets"].as_array().unwrap();
-            Some(tickets
-                    .iter()
-                    .filter_map(|ticket| {
-                        match ticket_to_driver(ticket_id_to_car_map, metadata_ids)(ticket) {
-                            Ok(driver) => Some(driver),
-                            Err(e) => {
-                                debug!("Skipping ticket [{}] with error: {}", ticket["guid"], e);
-                                None
-                            }
-                        }
-                    })
-            )
-        })
-        .flatten()
-        .collect();
+            if tickets.policy.require_check_in && !is_checked_in(ticket) {
+                debug!("Skipping ticket [{}] that isn't checked in", ticket["guid"]);
+                continue;
+            }
+            match ticket_to_driver(tickets)(ticket) {
+                Ok(driver) => drivers.push(BasicDriver {
+                    paid_at: paid_at(&hit["_source"]),
+                    email: email(&hit["_source"]),
+                    ..driver
+                }),
+                Err(e) => {
+                    skip_bad_metadata(report, order_guid, ticket, e);
+                    bad_tickets += 1;
+                }
+            }
+        }
+    }
+    tickets
+        .policy
+        .check_bad_fraction(drivers.len(), bad_tickets)?;
     Ok(drivers)
 }
 
+fn is_mapped(tickets: &TicketContext, ticket: &serde_json::Value) -> bool {
+    ticket["ticket_id"]
+        .as_str()
+        .is_some_and(|ticket_id| tickets.mapping(ticket_id).is_some())
+}
+
+/// When the payment went to `paid`, or else when the order was placed
+fn paid_at(order: &serde_json::Value) -> Option<DateTime<Utc>> {
+    order["payments"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|payment| payment["statii"].as_array().into_iter().flatten())
+        .filter(|status| status["status"] == "paid")
+        .filter_map(|status| tickets::parse_time(&status["created_at"]))
+        .min()
+        .or_else(|| tickets::parse_time(&order["created_at"]))
+}
+
+/// Whether to make drivers of the order's tickets, going by its status and
+/// the one it had the last time we saw it
+fn should_add(
+    tickets: &TicketContext,
+    statuses: &mut HashMap<String, OrderStatus>,
+    report: &mut Report,
+    order_guid: &str,
+    order: &serde_json::Value,
+) -> bool {
+    let status = OrderStatus::of(order);
+    let from = statuses.insert(order_guid.to_string(), status.clone());
+    match order_status::action(from.as_ref(), &status) {
+        Action::Add => return true,
+        Action::Hold => debug!("Holding order [{}] with status: {}", order_guid, status),
+        Action::Remove if from == Some(OrderStatus::Paid) => info!(
+            "Order [{}] went from paid to {}, leaving its drivers out",
+            order_guid, status
+        ),
+        Action::Remove => debug!("Skipping order [{}] with status: {}", order_guid, status),
+        Action::Report => report.add(
+            ProblemKind::UnknownOrderStatus,
+            Some(order_guid),
+            None,
+            format!("Unknown order status `{}`, leaving the order out", status),
+        ),
+    }
+    if status == OrderStatus::Disputed {
+        report_disputed(tickets, report, order_guid, order);
+    }
+    false
+}
+
+/// The drivers of a disputed order, for the review list
+fn report_disputed(
+    tickets: &TicketContext,
+    report: &mut Report,
+    order_guid: &str,
+    order: &serde_json::Value,
+) {
+    for ticket in order["tickets"].as_array().into_iter().flatten() {
+        if !is_mapped(tickets, ticket) {
+            continue;
+        }
+        if let Ok(driver) = ticket_to_driver(tickets)(ticket) {
+            chargebacks::report(report, order_guid, &driver);
+        }
+    }
+}
+
+/// Of the buyer, who gets the results
+fn email(order: &serde_json::Value) -> Option<String> {
+    order["email"]
+        .as_str()
+        .filter(|email| !email.is_empty())
+        .map(str::to_string)
+}
+
+/// Scanning a ticket at the venue counts on its products
+fn is_checked_in(ticket: &serde_json::Value) -> bool {
+    ticket["products"].as_array().is_some_and(|products| {
+        products
+            .iter()
+            .any(|product| product["scanned_amount"].as_u64().unwrap_or(0) > 0)
+    })
+}
+
+fn skip_unmapped(
+    tickets: &TicketContext,
+    report: &mut Report,
+    order_guid: &str,
+    ticket: &serde_json::Value,
+) -> Result<()> {
+    tickets.skip_unmapped(
+        ticket["ticket_id"].as_str().unwrap_or_default(),
+        format!("No car found for ticket type: {}", ticket["ticket_id"]),
+        report,
+        Some(order_guid),
+        ticket["guid"].as_str(),
+    )
+}
+
+fn skip_bad_metadata(
+    report: &mut Report,
+    order_guid: &str,
+    ticket: &serde_json::Value,
+    error: anyhow::Error,
+) {
+    report.add(
+        ProblemKind::BadMetadata,
+        Some(order_guid),
+        ticket["guid"].as_str(),
+        format!("{:#}", error),
+    );
+}
+
 fn ticket_to_driver<'a>(
-    ticket_to_car_map: &'a HashMap<String, String>,
-    metadata_ids: &'a MetaDataIDs,
+    tickets: &'a TicketContext,
 ) -> impl Fn(&serde_json::Value) -> Result<BasicDriver> + 'a {
-    move |ticket| match ticket_to_car_map.get(ticket["ticket_id"].as_str().unwrap()) {
-        Some(car) => {
-            let mut first_name = None;
-            let mut last_name = None;
-            let mut team_name = None;
-            let mut steam_id = None;
-            // So in https://api.eventix.io/3.0.0/order/:guid it's `metadata` but in
-            // https://api.eventix.io/3.0.0/statistics/event/:guid it's `meta_data`
-            let metadata_array = ticket["meta_data"].as_array();
-            let metadata_array = match metadata_array {
-                Some(metadata_array) => metadata_array,
-                None => ticket["metadata"].as_array().with_context(|| {
-                    format!(
-                        "Missing meta_data and metadata fields for ticket: {:?}",
-                        ticket.get("guid")
-                    )
-                })?,
-            };
-            for metadata_item in metadata_array {
-                let metadata_id = metadata_item["metadata_id"].as_str().unwrap();
-                if metadata_id == metadata_ids.first_name {
-                    first_name = Some(metadata_item["value"].as_str().unwrap().trim());
-                } else if metadata_id == metadata_ids.last_name {
-                    last_name = Some(metadata_item["value"].as_str().unwrap().trim());
-                } else if metadata_id == metadata_ids.team_name {
-                    team_name = Some(metadata_item["value"].as_str().unwrap().trim());
-                } else if metadata_id == metadata_ids.steam_id {
-                    steam_id = Some(metadata_item["value"].as_str().unwrap().trim());
-                }
-            }
-            if first_name.is_none() || last_name.is_none() || steam_id.is_none() {
-                return Err(anyhow!(
-                    "Missing metadata for ticket: {:?}",
+    move |ticket| {
+        let ticket_id = ticket["ticket_id"]
+            .as_str()
+            .context("ticket_id is not a string")?;
+        let mapping = tickets
+            .mapping(ticket_id)
+            .with_context(|| format!("No car found for ticket: {}", ticket_id))?;
+        // So in https://api.eventix.io/3.0.0/order/:guid it's `metadata` but in
+        // https://api.eventix.io/3.0.0/statistics/event/:guid it's `meta_data`
+        let metadata_array = ticket["meta_data"].as_array();
+        let metadata_array = match metadata_array {
+            Some(metadata_array) => metadata_array,
+            None => ticket["metadata"].as_array().with_context(|| {
+                format!(
+                    "Missing meta_data and metadata fields for ticket: {:?}",
                     ticket.get("guid")
-                ));
-            }
-
-            Ok(BasicDriver {
-                name: format!("{} {}", first_name.unwrap(), last_name.unwrap()),
-                car: car.clone(),
-                steam_id: steam_id.unwrap().parse().unwrap(),
-                team_name: team_name.map(|x| x.to_string()),
+                )
+            })?,
+        };
+        let metadata = metadata_array
+            .iter()
+            .map(|metadata_item| {
+                Ok((
+                    metadata_item["metadata_id"]
+                        .as_str()
+                        .context("metadata_id is not a string")?,
+                    metadata_item["value"].as_str(),
+                ))
             })
-        }
-        None => Err(anyhow!("No car found for ticket: {}", ticket["ticket_id"])),
+            .collect::<Result<Vec<_>>>()?;
+        tickets::driver_from_metadata(
+            mapping,
+            metadata,
+            tickets,
+            ticket["order_id"].as_str(),
+            ticket["guid"].as_str(),
+        )
     }
 }
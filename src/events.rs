@@ -0,0 +1,156 @@
+use axum::{
+    extract,
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
+};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use std::{convert::Infallible, sync::Arc};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::{capacity::CapacityLevel, timezone, State};
+
+/// Something that happened during syncing, for showing live activity
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    WebhookReceived {
+        order_guid: String,
+    },
+    DriverPlaced {
+        file: String,
+        name: String,
+        steam_id: u64,
+        car: String,
+    },
+    WriteCompleted {
+        file: String,
+        drivers: usize,
+    },
+    /// By the driver themselves, see `/admin/v1/audit-log`
+    DriverChanged {
+        ticket_guid: String,
+        by: String,
+        field: String,
+        new: Option<String>,
+    },
+    /// A class with CLASS_CAPACITY_THRESHOLDS crossed one, up or down
+    ClassCapacity {
+        class: String,
+        level: CapacityLevel,
+        filled: usize,
+        slots: usize,
+    },
+    /// Sales of a ticket type closed because its class filled up, or opened
+    /// again
+    SalesChanged {
+        ticket_type: String,
+        open: bool,
+    },
+    /// After a session, see `/admin/v1/attendance`
+    Attendance {
+        track: String,
+        no_shows: usize,
+        gatecrashers: usize,
+    },
+    /// The ACSM files differ from the tickets by more than DRIFT_THRESHOLD
+    /// changes, or no longer do
+    Drift {
+        changes: usize,
+    },
+    /// A driver went on the waitlist as their team has MAX_CARS_PER_TEAM cars
+    /// in the class already
+    TeamFull {
+        team: String,
+        class: String,
+        steam_id: u64,
+    },
+    /// A driver on the grid lost their slot to a priority ticket or an
+    /// earlier buyer
+    Bumped {
+        name: String,
+        steam_id: u64,
+        car: String,
+    },
+    /// A driver with a priority ticket got a slot ahead of the waitlist
+    PriorityPlaced {
+        name: String,
+        steam_id: u64,
+        car: String,
+    },
+    /// CALENDAR_FILE moved on to the next round
+    RoundStarted {
+        event_guid: String,
+        files: Vec<String>,
+    },
+    /// After EVENT_END, the final entry list is in ARCHIVE_DIR and nothing
+    /// is written anymore
+    Archived {
+        files: Vec<String>,
+    },
+    /// A payment was disputed, the driver is on `/admin/v1/chargebacks`
+    Chargeback {
+        order_guid: String,
+        steam_id: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    #[serde(serialize_with = "timezone::serialize")]
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Fan-out of sync activity to everyone listening on `/admin/v1/events`
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            sender: broadcast::channel(256).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn emit(&self, kind: EventKind) {
+        // Only fails when nobody is listening, which is fine
+        let _ = self.sender.send(Event {
+            time: Utc::now(),
+            kind,
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn error(&self, message: String) {
+        self.emit(EventKind::Error { message });
+    }
+}
+
+pub async fn handle_events(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let stream = BroadcastStream::new(state.events.sender.subscribe()).filter_map(|event| {
+        match event {
+            Ok(event) => SseEvent::default().json_data(event).ok().map(Ok),
+            // Slow listeners miss events rather than holding up syncing
+            Err(e) => {
+                warn!("Event listener lagging: {}", e);
+                None
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
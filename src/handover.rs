@@ -0,0 +1,316 @@
+use anyhow::Result;
+use axum::{extract, response::Html};
+use axum_server::Handle;
+use log::{error, info};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::{store::Store, writes, State};
+
+/// Listening sockets the previous process left open for us, comma separated,
+/// with `:admin` for those of the admin routes
+#[cfg(unix)]
+const LISTEN_FDS: &str = "EVENTIX2ACSM_HANDOVER_FDS";
+/// A socket the previous process keeps open until it exits
+const WAIT_FD: &str = "EVENTIX2ACSM_HANDOVER_WAIT_FD";
+/// How long requests in progress get to finish
+#[cfg(unix)]
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long the new process gets to start serving, it checks the ACSM files
+/// first
+#[cfg(unix)]
+const READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+/// Sent over the WAIT_FD socket once the new process serves
+#[cfg(unix)]
+const READY: u8 = b'!';
+
+/// The socket to the previous process, after a handover
+#[cfg(unix)]
+pub type Predecessor = tokio::net::UnixStream;
+#[cfg(not(unix))]
+pub type Predecessor = std::convert::Infallible;
+
+#[cfg(unix)]
+fn parse_fds(fds: &str) -> Result<Vec<(i32, bool)>> {
+    use anyhow::Context;
+
+    fds.split(',')
+        .map(|fd| {
+            let (fd, admin) = match fd.strip_suffix(":admin") {
+                Some(fd) => (fd, true),
+                None => (fd, false),
+            };
+            let fd = fd
+                .parse()
+                .with_context(|| format!("{} has a bad socket: {}", LISTEN_FDS, fd))?;
+            Ok((fd, admin))
+        })
+        .collect()
+}
+
+/// Whether the new process gets the socket when starting it
+#[cfg(unix)]
+fn set_inherited(fd: i32, inherited: bool) -> Result<()> {
+    // SAFETY: the socket stays open for as long as this borrow
+    let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+    socket2::SockRef::from(&fd).set_cloexec(!inherited)?;
+    Ok(())
+}
+
+/// Started by a handover, the socket to the previous process. Writes wait
+/// until it exits.
+#[cfg(unix)]
+pub fn predecessor() -> Result<Option<Predecessor>> {
+    use anyhow::Context;
+    use std::os::fd::FromRawFd;
+
+    let Ok(fd) = std::env::var(WAIT_FD) else {
+        return Ok(None);
+    };
+    std::env::remove_var(WAIT_FD);
+    let fd = fd
+        .parse()
+        .with_context(|| format!("{} has a bad socket: {}", WAIT_FD, fd))?;
+    set_inherited(fd, false)?;
+    // SAFETY: the previous process passed it for this only
+    let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+    stream.set_nonblocking(true)?;
+    Ok(Some(tokio::net::UnixStream::from_std(stream)?))
+}
+
+#[cfg(not(unix))]
+pub fn predecessor() -> Result<Option<Predecessor>> {
+    Ok(None)
+}
+
+/// The listening sockets from the previous process, split into the main ones
+/// and those for the admin routes like [`crate::systemd::listeners`]
+#[cfg(unix)]
+pub fn take_over() -> Result<Option<(Vec<TcpListener>, Vec<TcpListener>)>> {
+    use std::os::fd::FromRawFd;
+
+    let Ok(fds) = std::env::var(LISTEN_FDS) else {
+        return Ok(None);
+    };
+    std::env::remove_var(LISTEN_FDS);
+    let mut listeners = Vec::new();
+    let mut admin_listeners = Vec::new();
+    for (fd, admin) in parse_fds(&fds)? {
+        // Not for the processes we start
+        set_inherited(fd, false)?;
+        // SAFETY: the previous process left it open for us, and nothing else
+        // in this process uses it
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        info!("Took over socket {}", listener.local_addr()?);
+        if admin {
+            admin_listeners.push(listener);
+        } else {
+            listeners.push(listener);
+        }
+    }
+    Ok(Some((listeners, admin_listeners)))
+}
+
+#[cfg(not(unix))]
+pub fn take_over() -> Result<Option<(Vec<TcpListener>, Vec<TcpListener>)>> {
+    Ok(None)
+}
+
+/// The sockets to hand over, in the format of EVENTIX2ACSM_HANDOVER_FDS
+#[cfg(unix)]
+pub fn socket_list(listeners: &[TcpListener], admin_listeners: &[TcpListener]) -> String {
+    use itertools::Itertools;
+    use std::os::fd::AsRawFd;
+
+    listeners
+        .iter()
+        .map(|listener| listener.as_raw_fd().to_string())
+        .chain(
+            admin_listeners
+                .iter()
+                .map(|listener| format!("{}:admin", listener.as_raw_fd())),
+        )
+        .join(",")
+}
+
+#[cfg(not(unix))]
+pub fn socket_list(_listeners: &[TcpListener], _admin_listeners: &[TcpListener]) -> String {
+    String::new()
+}
+
+#[cfg(unix)]
+async fn wait(stream: &mut Predecessor) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Until now the previous process could still take over again
+    stream.write_all(&[READY]).await?;
+    // Nothing comes back, it ends when the previous process exits
+    let mut buffer = [0; 1];
+    while stream.read(&mut buffer).await? > 0 {}
+    Ok(())
+}
+
+/// After a handover and once serving, let the previous process stop, and
+/// start writing once it's gone, with what it stored until then
+pub async fn wait_for_predecessor(state: Arc<State>, predecessor: Option<Predecessor>) {
+    #[cfg(unix)]
+    {
+        let Some(mut stream) = predecessor else {
+            return;
+        };
+        info!("Serving, waiting for the previous process to finish");
+        if let Err(e) = wait(&mut stream).await {
+            error!(
+                "Failed to wait for the previous process, writing anyway: {:?}",
+                e
+            );
+        }
+        info!("Previous process exited, taking over writes");
+        match Store::from_env().await {
+            Ok(store) => *state.store.lock().await = store,
+            Err(e) => error!("Failed to load what the previous process stored: {:?}", e),
+        }
+        state.write_gate.lock().await.handover = false;
+        if let Err(e) = writes::release(state).await {
+            error!("Failed to apply queued changes: {:?}", e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (state, predecessor);
+}
+
+/// SIGUSR2 or `POST /admin/v1/handover`
+pub async fn requested(state: &State) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined2()) {
+            Ok(mut usr2) => tokio::select! {
+                _ = usr2.recv() => {}
+                () = state.handover.notified() => {}
+            },
+            Err(e) => {
+                error!("Failed to listen for SIGUSR2: {:?}", e);
+                state.handover.notified().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    state.handover.notified().await;
+}
+
+/// The binary at the path this one was started from, which is the new one
+/// after an upgrade
+#[cfg(unix)]
+fn executable() -> Result<std::path::PathBuf> {
+    let path = std::env::current_exe()?;
+    // Linux adds this once the file is replaced
+    Ok(path
+        .to_str()
+        .and_then(|path| path.strip_suffix(" (deleted)"))
+        .map(std::path::PathBuf::from)
+        .unwrap_or(path))
+}
+
+/// Under systemd, make the new process the service's main process, so our
+/// exit doesn't stop it. Needs `NotifyAccess=main` in the service.
+#[cfg(unix)]
+fn notify_main_pid(pid: u32) {
+    // This comes from systemd, not .env
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = std::os::unix::net::UnixDatagram::unbound()
+        .and_then(|socket| socket.send_to(format!("MAINPID={}\n", pid).as_bytes(), path));
+    if let Err(e) = result {
+        error!("Failed to tell systemd about process {}: {:?}", pid, e);
+    }
+}
+
+/// Start the binary again on the sockets in `fds`, from [`socket_list`], and
+/// once it serves stop taking requests, giving those in progress
+/// DRAIN_TIMEOUT to finish. The new process waits with writing until this one
+/// exits. If it fails to start, this one keeps serving.
+#[cfg(unix)]
+pub async fn hand_over(state: &State, fds: &str, handle: &Handle) -> Result<()> {
+    use anyhow::{anyhow, Context};
+    use std::os::fd::AsRawFd;
+    use tokio::io::AsyncReadExt;
+
+    let fds_to_pass = parse_fds(fds)?;
+    let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+    for (fd, _) in &fds_to_pass {
+        set_inherited(*fd, true)?;
+    }
+    set_inherited(theirs.as_raw_fd(), true)?;
+    // Keep writes paused if they are now
+    let paused = state.write_gate.lock().await.paused;
+    let child = tokio::process::Command::new(executable()?)
+        .args(std::env::args_os().skip(1).filter(|arg| arg != "--paused"))
+        .args(paused.then_some("--paused"))
+        .env(LISTEN_FDS, fds)
+        .env(WAIT_FD, theirs.as_raw_fd().to_string())
+        .spawn();
+    for (fd, _) in &fds_to_pass {
+        set_inherited(*fd, false)?;
+    }
+    // Only the new process has it now, so it ends if that one exits
+    drop(theirs);
+    let mut child = child.context("Failed to start the new process")?;
+    let pid = child.id().unwrap_or_default();
+    ours.set_nonblocking(true)?;
+    let mut ours = tokio::net::UnixStream::from_std(ours)?;
+    let mut ready = [0; 1];
+    match tokio::time::timeout(READY_TIMEOUT, ours.read(&mut ready)).await {
+        Ok(Ok(1)) if ready[0] == READY => {}
+        result => {
+            // Might still be starting, and must not take over writes later
+            let _ = child.kill().await;
+            return Err(match result {
+                Ok(Err(e)) => anyhow!(e),
+                Err(_) => anyhow!("not serving after {:?}", READY_TIMEOUT),
+                Ok(_) => anyhow!("exited before serving"),
+            })
+            .with_context(|| format!("New process {} failed, still serving here", pid));
+        }
+    }
+    // Closes when this process exits, which the new one waits for
+    std::mem::forget(ours.into_std()?);
+    notify_main_pid(pid);
+    info!(
+        "Handed over to process {}, finishing the requests in progress",
+        pid
+    );
+    handle.graceful_shutdown(Some(DRAIN_TIMEOUT));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn hand_over(_state: &State, _fds: &str, _handle: &Handle) -> Result<()> {
+    Err(anyhow::anyhow!("Handing over is only supported on Unix"))
+}
+
+/// Upgrade without a restart, like SIGUSR2
+pub async fn handle_handover(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Html<&'static str> {
+    state.handover.notify_one();
+    Html("handing over")
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_fds() {
+        assert_eq!(
+            parse_fds("7,8,9:admin").unwrap(),
+            vec![(7, false), (8, false), (9, true)]
+        );
+        assert!(parse_fds("").is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+
+use crate::{http::HttpPolicy, reload, results, writes, State};
+
+/// Whether ACSM's live timing says a session is running. Without a running
+/// session the session type is empty.
+pub async fn session_is_live(http: &HttpPolicy, url: &str) -> Result<bool> {
+    let response: serde_json::Value = http
+        .send(http.client().get(url))
+        .await
+        .context("Getting live timing from ACSM failed")?
+        .error_for_status()
+        .context("ACSM returned error")?
+        .json()
+        .await
+        .context("ACSM returned bad JSON")?;
+    Ok(response["Type"]
+        .as_str()
+        .is_some_and(|session_type| !session_type.is_empty()))
+}
+
+/// Hold back writes while a session is running on any of the servers, and
+/// apply what was queued once they're all done
+pub async fn session_watch_task(state: Arc<State>, urls: Vec<String>, interval: Duration) {
+    loop {
+        let mut live = false;
+        for url in &urls {
+            match session_is_live(&state.http.acsm, url).await {
+                Ok(session_live) => live |= session_live,
+                // Assume nothing is running, ACSM can't reload when it's down
+                Err(e) => warn!("Failed to check for a live session at {}: {:?}", url, e),
+            }
+        }
+        let mut write_gate = state.write_gate.lock().await;
+        let was_live = std::mem::replace(&mut write_gate.session_live, live);
+        drop(write_gate);
+        if live && !was_live {
+            info!("ACSM session live, holding back writes");
+        } else if was_live && !live {
+            info!("ACSM session over, applying queued changes");
+            tokio::spawn(results::after_session(state.clone()));
+            if let Err(e) = writes::release(state.clone()).await {
+                error!("Failed to apply queued changes: {:?}", e);
+            }
+        }
+        if !live {
+            reload::run_queued(state.clone()).await;
+        }
+        sleep(interval).await;
+    }
+}
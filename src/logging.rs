@@ -0,0 +1,272 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// Of the HTTP request being handled
+    static REQUEST_ID: String;
+}
+
+/// Tag what's logged while handling the request with an ID, the one in the
+/// request's `x-request-id` if there is one, and return it in the response
+pub async fn request_id(req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+    if let Ok(id) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    response
+}
+
+/// One line of the JSON log format
+fn json_line(record: &log::Record, time: DateTime<Utc>, request_id: Option<String>) -> Value {
+    let mut line = json!({
+        "timestamp": time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Some(request_id) = request_id {
+        line["request_id"] = request_id.into();
+    }
+    line
+}
+
+/// When to start a new log file, and how many old ones to keep
+#[derive(Debug, Clone, Copy)]
+struct Rotation {
+    /// In bytes
+    max_size: Option<u64>,
+    daily: bool,
+    /// Rotated files, as `<file>.1` for the newest up to `<file>.<keep>`
+    keep: usize,
+}
+
+/// A log file that moves itself aside when it gets too big or a day old
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// UTC, like the timestamps in the log
+    opened_on: NaiveDate,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Rotation) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            opened_on: Utc::now().date_naive(),
+            rotation,
+        })
+    }
+
+    fn rotated_path(&self, number: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_os_string();
+        path.push(format!(".{}", number));
+        PathBuf::from(path)
+    }
+
+    fn needs_rotation(&self, len: usize, today: NaiveDate) -> bool {
+        self.size > 0
+            && (self
+                .rotation
+                .max_size
+                .is_some_and(|max_size| self.size + len as u64 > max_size)
+                || (self.rotation.daily && today != self.opened_on))
+    }
+
+    /// Shift the old files up by one, dropping the oldest, and start afresh
+    fn rotate(&mut self) -> io::Result<()> {
+        for number in (1..self.rotation.keep).rev() {
+            match fs::rename(self.rotated_path(number), self.rotated_path(number + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.rotation.keep > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        *self = LogFile::open(&self.path, self.rotation)?;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len(), Utc::now().date_naive()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The log file, and stderr too unless turned off
+struct Output {
+    file: LogFile,
+    stderr: bool,
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stderr {
+            // Losing the copy on stderr shouldn't lose the file's
+            let _ = io::stderr().write_all(buf);
+        }
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn number(var: &str, default: u64) -> Result<u64> {
+    dotenv::var(var)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map_or(Ok(default), |value| value.parse())
+        .with_context(|| format!("{} is not a number", var))
+}
+
+/// Log at RUST_LOG to stderr, and to LOG_FILE if set, in LOG_FORMAT
+pub fn init() -> Result<()> {
+    // Set RUST_LOG from .env
+    let rust_log = dotenv::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+    std::env::set_var("RUST_LOG", rust_log);
+    let mut builder = env_logger::Builder::from_default_env();
+    match dotenv::var("LOG_FORMAT").as_deref() {
+        Ok("json") => {
+            builder.format(|buf, record| {
+                let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+                writeln!(buf, "{}", json_line(record, Utc::now(), request_id))
+            });
+        }
+        Ok("text" | "") | Err(_) => {}
+        Ok(format) => return Err(anyhow!("LOG_FORMAT {} is not text or json", format)),
+    }
+    if let Some(path) = dotenv::var("LOG_FILE").ok().filter(|path| !path.is_empty()) {
+        let max_size_mb = number("LOG_FILE_MAX_SIZE_MB", 10)?;
+        let rotation = Rotation {
+            max_size: (max_size_mb > 0).then_some(max_size_mb * 1024 * 1024),
+            daily: dotenv::var("LOG_FILE_ROTATE_DAILY").is_ok_and(|value| value == "true"),
+            keep: number("LOG_FILE_KEEP", 5)? as usize,
+        };
+        let file = LogFile::open(Path::new(&path), rotation)
+            .with_context(|| format!("Failed to open LOG_FILE {}", path))?;
+        builder.target(env_logger::Target::Pipe(Box::new(Output {
+            file,
+            stderr: dotenv::var("LOG_TO_STDERR").map_or(true, |value| value != "false"),
+        })));
+    }
+    builder.init();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn json_format() {
+        let time = Utc.with_ymd_and_hms(2026, 3, 1, 20, 0, 0).unwrap();
+        let line = |request_id| {
+            json_line(
+                &log::Record::builder()
+                    .level(log::Level::Warn)
+                    .target("eventix2acsm::http")
+                    .args(format_args!("Pretix API call failed:\n{}", 503))
+                    .build(),
+                time,
+                request_id,
+            )
+            .to_string()
+        };
+        assert_eq!(
+            line(None),
+            r#"{"level":"WARN","message":"Pretix API call failed:\n503","target":"eventix2acsm::http","timestamp":"2026-03-01T20:00:00.000Z"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&line(Some("abc".to_string()))).unwrap()["request_id"],
+            "abc"
+        );
+    }
+
+    fn log_file(dir: &Path, max_size: Option<u64>, daily: bool, keep: usize) -> LogFile {
+        LogFile::open(
+            &dir.join("eventix2acsm.log"),
+            Rotation {
+                max_size,
+                daily,
+                keep,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = log_file(dir.path(), Some(10), false, 2);
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("eventix2acsm.log"), "fourth\n");
+        assert_eq!(read("eventix2acsm.log.1"), "third\n");
+        assert_eq!(read("eventix2acsm.log.2"), "second\n");
+        assert!(!dir.path().join("eventix2acsm.log.3").exists());
+    }
+
+    #[test]
+    fn keeps_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        log_file(dir.path(), Some(100), false, 2)
+            .write_all(b"before restart\n")
+            .unwrap();
+        let mut file = log_file(dir.path(), Some(100), false, 2);
+        file.write_all(b"after restart\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("eventix2acsm.log")).unwrap(),
+            "before restart\nafter restart\n"
+        );
+    }
+
+    #[test]
+    fn daily() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = log_file(dir.path(), None, true, 2);
+        let today = file.opened_on;
+        assert!(!file.needs_rotation(1, today));
+        file.write_all(b"line\n").unwrap();
+        assert!(!file.needs_rotation(1, today));
+        assert!(file.needs_rotation(1, today.succ_opt().unwrap()));
+    }
+}
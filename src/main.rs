@@ -2,32 +2,561 @@ use anyhow::{anyhow, Context, Result};
 use axum::{
     extract::{self, Request},
     http::StatusCode,
-    middleware::Next,
+    middleware::{self, Next},
     response::{Html, IntoResponse},
-    routing::{get, post},
-    Json, Router,
+    routing::{delete, get, post},
+    Router,
 };
 use axum_macros::debug_handler;
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use itertools::Itertools;
 use log::{debug, error, info, warn};
 use serde::Deserialize;
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
-use tokio::{sync::Mutex, task::JoinHandle, time::sleep};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::Mutex,
+    task::{JoinHandle, JoinSet},
+    time::sleep,
+};
 
 mod acsm;
+mod admin;
+mod allowlist;
+mod archive;
+mod backups;
+mod breaker;
+mod calendar;
+mod capacity;
+mod chargebacks;
+mod classes;
+mod compression;
+mod config;
+mod content;
+mod csv_source;
+mod cutoff;
+mod diff;
+mod discord;
+mod drift;
+mod driver_overrides;
+mod email;
+mod error_reporting;
+mod eventbrite;
 mod eventix;
+mod events;
+mod handover;
+mod http;
+mod live;
+mod logging;
+mod maintenance;
+mod names;
+mod nation;
 mod oauth2;
+mod order_status;
+mod os_keyring;
+mod portal;
+mod pretix;
+mod priority;
+mod redact;
+mod reload;
+mod report;
+mod results;
+mod retention;
+mod s3;
+mod sales;
+mod sandbox;
+mod self_service;
+mod setup;
+mod sftp;
+mod simulate;
+mod snapshots;
+mod source;
+mod splits;
+mod status;
+mod status_webhook;
+mod steam;
+mod steam_group;
+mod store;
+mod systemd;
+mod team_cap;
+mod telemetry;
+mod ticket_map;
+mod tickets;
+mod timezone;
+mod tls;
+mod validate;
+mod vault;
+mod webhook;
+mod wizard;
+mod writes;
 
-use crate::oauth2::{handle_oauth2_callback, refresh_token_task, setup_oauth2_client, OAuth2State};
+use crate::oauth2::{handle_oauth2_callback, refresh_token_task, OAuth2State};
+use crate::redact::Secret;
+use crate::source::RegistrationSource;
 
 struct State {
-    acsm_json_file: Mutex<PathBuf>,
-    eventix_event_guid: String,
-    ticket_id_to_car_map: HashMap<String, String>,
-    metadata_ids: eventix::MetaDataIDs,
+    http: http::HttpPolicies,
+    /// One per split, usually just the one
+    acsm_json_files: Mutex<Vec<PathBuf>>,
+    split_policy: splits::SplitPolicy,
+    reserved_slots: splits::ReservedSlots,
+    /// From TICKET_SOURCE, all feeding the same grid
+    sources: Vec<Box<dyn RegistrationSource>>,
+    /// Eventix ticket types, Pretix items, Eventbrite ticket classes or the
+    /// values of the CSV car column
+    /// Swapped as a whole when replaced through the admin API
+    ticket_map: Mutex<Arc<ticket_map::TicketMap>>,
+    /// From ADD_ON_TICKET_IDS, for extra drivers in the buyer's entry
+    add_on_ticket_ids: HashSet<String>,
+    ticket_policy: tickets::TicketPolicy,
+    /// Instead of hourly full updates, with REQUIRE_CHECK_IN
+    check_in_poll_interval: Duration,
+    skill_classes: classes::SkillClasses,
+    name_filter: names::NameFilter,
+    name_lengths: names::NameLengths,
+    /// From EMPTY_SLOT_DEFAULTS
+    entrant_defaults: acsm::EntrantDefaults,
+    /// From DRIVER_OVERRIDES_FILE
+    driver_overrides_file: Option<PathBuf>,
+    /// From TRANSLITERATE_NAMES, write names in ASCII only
+    transliterate_names: bool,
+    /// From IGNORED_STEAM_IDS, the admin API adds to these at runtime
     ignored_steam_ids: Vec<u64>,
-    oauth2_state: Mutex<OAuth2State>,
+    /// From IGNORED_GUIDS, the admin API adds to these at runtime
+    ignored_guids: Vec<String>,
+    webhook_max_age: Option<chrono::Duration>,
+    registration_cutoff: Option<cutoff::RegistrationCutoff>,
+    /// Resolved from `registration_cutoff` once we can talk to the ticket
+    /// source
+    registration_closes: Mutex<Option<DateTime<Utc>>>,
     full_update_task: Mutex<Option<JoinHandle<()>>>,
+    last_report: Mutex<report::Report>,
+    sales_policy: sales::SalesPolicy,
+    /// The stock we last set per ticket type, to only update it on changes
+    ticket_availability: Mutex<HashMap<String, usize>>,
+    /// From CLASS_CAPACITY_THRESHOLDS, with the level each class is at
+    capacity_alerts: Mutex<capacity::CapacityAlerts>,
+    /// Per class name, drivers that didn't fit during the last full update
+    class_waitlist: Mutex<HashMap<String, usize>>,
+    eventix_breaker: Mutex<breaker::CircuitBreaker>,
+    /// Per source, the registrations from the last time it answered, for
+    /// while it doesn't. Webhooks keep it up to date between full updates.
+    cached_orders: Mutex<HashMap<&'static str, (Vec<acsm::BasicDriver>, report::Report)>>,
+    sync_status: Mutex<status::SyncStatus>,
+    /// From VAULT_ADDR, to check for rotated secrets
+    vault: Option<vault::Vault>,
+    /// From ACSM_CONTENT_DIR or ACSM_CARS_URL, to check the ticket map's cars
+    /// against
+    car_content: Option<content::CarContent>,
+    /// From RESULTS_PATH or RESULTS_URL, to read after every session
+    results_source: Option<results::ResultsSource>,
+    /// From SMTP_URL, to email drivers their results
+    mailer: Option<email::Mailer>,
+    /// From MAX_CARS_PER_TEAM
+    team_cap: Option<team_cap::TeamCap>,
+    /// From CHARGEBACK_EMAIL, the organizer to email about disputed payments
+    chargeback_email: Option<String>,
+    /// From PERSONAL_DATA_RETENTION_DAYS, how long to keep backups and audit
+    /// log entries
+    retention: Option<Duration>,
+    /// From ACSM_RELOAD_URL or ACSM_RELOAD_COMMAND
+    acsm_reload: Option<reload::AcsmReload>,
+    /// From STATUS_WEBHOOK_URL, to push the status to after every sync
+    status_webhook: Option<status_webhook::StatusWebhook>,
+    /// From MAINTENANCE_WINDOWS, when to hold back writes every week
+    maintenance_schedule: Option<maintenance::MaintenanceSchedule>,
+    /// From CALENDAR_FILE, which round is synced
+    calendar: Option<calendar::Calendar>,
+    /// From EVENT_END, when to stop writing and export the entry list
+    archive: Option<archive::Archive>,
+    /// `POST /admin/v1/handover` notifies this, like SIGUSR2
+    handover: tokio::sync::Notify,
+    /// From DRIFT_CHECK_MINUTES, to compare the ACSM files against the tickets
+    drift_check: Option<drift::DriftCheck>,
+    write_gate: Mutex<writes::WriteGate>,
+    events: events::Events,
+    admin_token: Option<Secret<String>>,
+    webhook_allowlist: allowlist::IpAllowlist,
+    /// For verifying the signature of v2 webhooks
+    webhook_secret: Option<Secret<String>>,
+    store: Mutex<store::Store>,
+    /// From DISCORD_PUBLIC_KEY, for drivers to correct their Steam ID
+    discord: Option<discord::Discord>,
+    /// From PORTAL_URL, for drivers to log in with Steam instead
+    portal: Option<portal::Portal>,
+    /// From ACCESS_CODE_SECRET, for drivers to show a ticket is theirs
+    access_codes: Option<self_service::AccessCodes>,
+    steam: steam::Steam,
+    /// From STEAM_REQUIRED_GROUP, to only place its members
+    steam_group: Option<steam_group::SteamGroup>,
+}
+
+impl State {
+    fn source(&self, name: &str) -> Option<&dyn RegistrationSource> {
+        self.sources
+            .iter()
+            .find(|source| source.name() == name)
+            .map(|source| source.as_ref())
+    }
+
+    /// Only for sources that use OAuth2
+    fn oauth2(&self, source: &str) -> &Mutex<OAuth2State> {
+        self.source(source)
+            .and_then(|source| source.oauth2())
+            .expect("OAuth2 is set up for this source")
+    }
+
+    fn eventix_api<'a>(&'a self, api_token: &'a Secret<String>) -> eventix::Api<'a> {
+        eventix::Api {
+            http: &self.http.eventix,
+            token: api_token.expose(),
+        }
+    }
+
+    fn eventbrite_api<'a>(&'a self, api_token: &'a Secret<String>) -> eventbrite::Api<'a> {
+        eventbrite::Api {
+            http: &self.http.eventbrite,
+            token: api_token.expose(),
+        }
+    }
+
+    /// Steam IDs corrected by the drivers themselves, by ticket GUID
+    async fn steam_id_overrides(&self) -> HashMap<String, u64> {
+        self.store.lock().await.data().steam_id_overrides.clone()
+    }
+
+    async fn ticket_map(&self) -> Arc<ticket_map::TicketMap> {
+        self.ticket_map.lock().await.clone()
+    }
+
+    fn ticket_context<'a>(
+        &'a self,
+        ticket_map: &'a ticket_map::TicketMap,
+        metadata_ids: &'a tickets::MetaDataIDs,
+        steam_id_overrides: &'a HashMap<String, u64>,
+    ) -> tickets::TicketContext<'a> {
+        tickets::TicketContext {
+            ticket_map,
+            ticket_names: None,
+            add_on_tickets: &self.add_on_ticket_ids,
+            metadata_ids,
+            policy: &self.ticket_policy,
+            steam_id_overrides,
+        }
+    }
+
+    /// Configured plus runtime ignored Steam IDs
+    async fn ignored_steam_ids(&self) -> Vec<u64> {
+        let store = self.store.lock().await;
+        self.ignored_steam_ids
+            .iter()
+            .chain(&store.data().ignored_steam_ids)
+            .copied()
+            .unique()
+            .collect()
+    }
+
+    /// Configured plus runtime ignored order and ticket GUIDs
+    async fn ignored_guids(&self) -> Vec<String> {
+        let store = self.store.lock().await;
+        self.ignored_guids
+            .iter()
+            .chain(&store.data().ignored_guids)
+            .unique()
+            .cloned()
+            .collect()
+    }
+
+    /// Check that an ACSM file has the cars and classes the settings name
+    async fn validate_acsm_file(
+        &self,
+        acsm_json_file: &Path,
+        ticket_map: &ticket_map::TicketMap,
+    ) -> Result<()> {
+        validate::validate_acsm_file(acsm_json_file, ticket_map)
+            .await
+            .context("ACSM file does not match the ticket map")?;
+        validate::validate_classes(acsm_json_file, self.skill_classes.class_names())
+            .await
+            .context("ACSM file does not match SKILL_TO_CLASS_MAP or SKILL_FALLBACK_CLASS")?;
+        validate::validate_skill_classes(acsm_json_file, &self.skill_classes, ticket_map)
+            .await
+            .context("ACSM file does not match SKILL_TO_CLASS_MAP or SKILL_FALLBACK_CLASS")?;
+        validate::validate_classes(acsm_json_file, self.entrant_defaults.class_names())
+            .await
+            .context("ACSM file does not match EMPTY_SLOT_DEFAULTS")?;
+        validate::validate_classes(
+            acsm_json_file,
+            self.capacity_alerts.lock().await.class_names(),
+        )
+        .await
+        .context("ACSM file does not match CLASS_CAPACITY_THRESHOLDS")
+    }
+
+    /// Call the Eventix API through the circuit breaker
+    async fn eventix_call<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        if !self.eventix_breaker.lock().await.allow() {
+            return Err(anyhow!("Eventix API circuit open, not calling"));
+        }
+        let result = call.await;
+        self.eventix_breaker.lock().await.record(result.is_ok());
+        result
+    }
+
+    /// The drivers as they go in the entry list, with the overrides from
+    /// DRIVER_OVERRIDES_FILE and names shortened to fit
+    async fn entry_drivers(
+        &self,
+        drivers: &[acsm::BasicDriver],
+        report: &mut report::Report,
+    ) -> Result<Vec<acsm::BasicDriver>> {
+        let mut drivers = drivers.to_vec();
+        if let Some(path) = &self.driver_overrides_file {
+            driver_overrides::DriverOverrides::load(path)
+                .await?
+                .apply(&mut drivers);
+        }
+        Ok(self.name_lengths.apply(&drivers, report))
+    }
+
+    /// Add/update the drivers in the ACSM files, or queue them if writes are
+    /// held back, returning whether they were written. With `full_update`
+    /// drivers that aren't in `drivers` are removed.
+    async fn place_drivers(
+        &self,
+        drivers: &[acsm::BasicDriver],
+        full_update: bool,
+        report: &mut report::Report,
+    ) -> Result<bool> {
+        // Before queueing, so queued drivers are reported too
+        let drivers = &self.entry_drivers(drivers, report).await?;
+        // Held for the whole write, so pausing waits for it to finish
+        let mut write_gate = telemetry::in_span(
+            "wait for other writes".to_string(),
+            vec![],
+            self.write_gate.lock(),
+        )
+        .await;
+        if write_gate.queue_if_held(drivers, full_update) {
+            return Ok(false);
+        }
+        let acsm_json_files = self.acsm_json_files.lock().await;
+        let mut changed = false;
+        let mut affected = priority::Affected::default();
+        if self.registration_closed().await {
+            info!("Registration closed, not changing the entry list");
+            let mut splits = Vec::new();
+            for json_file in acsm_json_files.iter() {
+                splits.push(acsm::class_slots(json_file).await?);
+            }
+            splits::report_registration_closed(
+                &splits,
+                drivers,
+                &self.ignored_steam_ids().await,
+                report,
+            );
+        } else {
+            (changed, affected) = splits::place_drivers(
+                &acsm_json_files,
+                drivers,
+                self.split_policy,
+                full_update,
+                &self.ignored_steam_ids().await,
+                &self.reserved_slots,
+                &self.entrant_defaults,
+                report,
+                &self.events,
+            )
+            .await?;
+        }
+        if changed {
+            reload::after_change(self, &mut write_gate).await;
+        }
+        priority::notify(self, affected).await;
+        if let Err(e) = self
+            .after_write(&acsm_json_files, drivers, full_update)
+            .await
+        {
+            error!("Failed to act on the new entry list: {:?}", e);
+            error_reporting::capture(&e, &[("sync", "after_write")]);
+        }
+        Ok(true)
+    }
+
+    /// Keep what depends on how full the classes are up to date. With
+    /// `full_update`, `drivers` are all of them, so the waitlist can be
+    /// counted too.
+    async fn after_write(
+        &self,
+        acsm_json_files: &[PathBuf],
+        drivers: &[acsm::BasicDriver],
+        full_update: bool,
+    ) -> Result<()> {
+        let mut splits = Vec::new();
+        for json_file in acsm_json_files {
+            splits.push(acsm::class_slots(json_file).await?);
+        }
+        if full_update {
+            *self.class_waitlist.lock().await =
+                splits::waitlist_by_class(&splits, drivers, &self.ignored_steam_ids().await);
+        }
+        self.check_capacity(&splits).await;
+        sales::update(self, &splits).await
+    }
+
+    /// Notify about classes that crossed a CLASS_CAPACITY_THRESHOLDS threshold
+    async fn check_capacity(&self, splits: &[Vec<acsm::ClassSlots>]) {
+        for change in self.capacity_alerts.lock().await.check(splits) {
+            let message = format!(
+                "Class {} is at {} capacity: {}/{} slots filled",
+                change.class, change.level, change.filled, change.slots
+            );
+            if change.level == capacity::CapacityLevel::Normal {
+                info!("{}", message);
+            } else {
+                warn!("{}", message);
+            }
+            self.events.emit(events::EventKind::ClassCapacity {
+                class: change.class,
+                level: change.level,
+                filled: change.filled,
+                slots: change.slots,
+            });
+        }
+    }
+
+    /// When the entry list freezes, if there is a cutoff and it's known yet
+    async fn registration_closes(&self) -> Option<DateTime<Utc>> {
+        let registration_cutoff = self.registration_cutoff?;
+        let mut registration_closes = self.registration_closes.lock().await;
+        if registration_closes.is_none() {
+            // The first source is the main one
+            let source = &self.sources[0];
+            if let Some(oauth2_state) = source.oauth2() {
+                oauth2::token(oauth2_state).await?;
+            }
+            match registration_cutoff.resolve(source.event_start(self)).await {
+                Ok(closes) => {
+                    info!("Registration closes at {}", closes);
+                    *registration_closes = Some(closes);
+                }
+                Err(e) => error!("Failed to determine registration cutoff: {:?}", e),
+            }
+        }
+        *registration_closes
+    }
+
+    /// Without a known cutoff registration stays open
+    async fn registration_closed(&self) -> bool {
+        self.registration_closes()
+            .await
+            .is_some_and(|closes| Utc::now() >= closes)
+    }
+
+    /// The classes of all ACSM files, with those that can't be read left out
+    async fn classes(&self) -> Vec<acsm::ClassSlots> {
+        let mut classes = Vec::new();
+        for json_file in self.acsm_json_files.lock().await.iter() {
+            match acsm::class_slots(json_file).await {
+                Ok(slots) => classes.extend(slots),
+                Err(e) => warn!(
+                    "Failed to read the classes of {}: {:?}",
+                    json_file.display(),
+                    e
+                ),
+            }
+        }
+        classes
+    }
+
+    /// Everything that happens to drivers between getting them from Eventix
+    /// and placing them. `others` are the drivers from other orders, to find
+    /// duplicate tickets when `drivers` is a single order.
+    async fn prepare_drivers(
+        &self,
+        drivers: &mut Vec<acsm::BasicDriver>,
+        others: &[acsm::BasicDriver],
+        report: &mut report::Report,
+    ) {
+        self.remove_ignored_guids(drivers).await;
+        chargebacks::hold_under_review(self, drivers, report).await;
+        self_service::apply_edits(&self.store.lock().await.data().driver_edits, drivers);
+        tickets::attach_add_ons(drivers, report);
+        let classes = self.classes().await;
+        let left_out = tickets::resolve_duplicates(
+            drivers,
+            others,
+            &classes,
+            self.ticket_policy.duplicates,
+            report,
+        );
+        self_service::audit_once(self, left_out).await;
+        names::hold_flagged(self, drivers, report).await;
+        steam::check_ownership(self, drivers, report).await;
+        steam_group::hold_non_members(self, drivers, report).await;
+        team_cap::hold_over_cap(self, drivers, others, &classes, report).await;
+        steam::check_names(self, drivers, report).await;
+        if self.transliterate_names {
+            names::transliterate(self, drivers).await;
+        }
+        self.skill_classes.assign(drivers, &classes);
+    }
+
+    /// Replace the drivers of an order in the cache with those from its
+    /// webhook. Only once a full update filled the cache, as a cache with just
+    /// this order would empty the grid while the source is down.
+    async fn cache_order(&self, source: &str, order: &str, drivers: &[acsm::BasicDriver]) {
+        if let Some((cached, _)) = self.cached_orders.lock().await.get_mut(source) {
+            cached.retain(|driver| driver.order_guid.as_deref() != Some(order));
+            cached.extend(drivers.iter().cloned());
+        }
+    }
+
+    /// The drivers as of the last full update and the webhooks since, except
+    /// the order and ignored ones
+    async fn other_drivers(&self, order: &str) -> Vec<acsm::BasicDriver> {
+        let ignored_guids = self.ignored_guids().await;
+        self.cached_orders
+            .lock()
+            .await
+            .values()
+            .flat_map(|(drivers, _)| drivers)
+            .filter(|driver| {
+                driver.order_guid.as_deref() != Some(order)
+                    && ![&driver.order_guid, &driver.ticket_guid]
+                        .into_iter()
+                        .flatten()
+                        .any(|guid| ignored_guids.contains(guid))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Leave out drivers from ignored orders or tickets
+    async fn remove_ignored_guids(&self, drivers: &mut Vec<acsm::BasicDriver>) {
+        let ignored_guids = self.ignored_guids().await;
+        drivers.retain(|driver| {
+            let ignored = [&driver.order_guid, &driver.ticket_guid]
+                .into_iter()
+                .flatten()
+                .any(|guid| ignored_guids.contains(guid));
+            if ignored {
+                info!(
+                    "Ignoring {} steam_id={} from order {:?} ticket {:?}",
+                    driver.name, driver.steam_id, driver.order_guid, driver.ticket_guid
+                );
+            }
+            !ignored
+        });
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -39,109 +568,677 @@ struct WebhookPayload {
     guid: String,
 }
 
+impl WebhookPayload {
+    /// Eventix sends RFC3339 timestamps, but a plain `YYYY-MM-DD HH:MM:SS` has
+    /// also been seen, which we take to be UTC.
+    fn parsed_date_time(&self) -> Result<DateTime<Utc>> {
+        if let Ok(date_time) = DateTime::parse_from_rfc3339(&self.date_time) {
+            return Ok(date_time.with_timezone(&Utc));
+        }
+        let date_time = NaiveDateTime::parse_from_str(&self.date_time, "%Y-%m-%d %H:%M:%S")
+            .with_context(|| format!("Unrecognized date_time format: {}", self.date_time))?;
+        Ok(date_time.and_utc())
+    }
+}
+
 async fn full_update(state: Arc<State>) -> Result<()> {
-    let oauth2_state = state.oauth2_state.lock().await;
-    if oauth2_state.token.is_none() {
-        error!("No OAuth2 token, skipping full update");
+    let result = telemetry::in_span(
+        "full update".to_string(),
+        vec![],
+        update_all_drivers(&state),
+    )
+    .await;
+    if let Err(e) = &result {
+        state.events.error(format!("Full update failed: {:?}", e));
+        error_reporting::capture(e, &[("sync", "full_update")]);
+    }
+    state.sync_status.lock().await.last_full_update =
+        Some(status::Outcome::new("full update", &result));
+    status_webhook::send(&state, "full_update", None).await;
+    result
+}
+
+async fn update_all_drivers(state: &State) -> Result<()> {
+    let mut report = report::Report::default();
+    let Some(all_drivers) = fetch_all_drivers(state, &mut report).await? else {
         return Ok(());
+    };
+    if let Err(e) = chargebacks::review(state, &report).await {
+        error!(
+            "Failed to put disputed payments on the review list: {:?}",
+            e
+        );
+    }
+    let result = state
+        .place_drivers(&all_drivers, true, &mut report)
+        .await
+        .map(|_| ())
+        .context("Failed to update drivers");
+    if let Err(e) = steam_group::notify_non_members(state).await {
+        error!("Failed to email drivers about the Steam group: {:?}", e);
+    }
+    report.log();
+    *state.last_report.lock().await = report;
+    result
+}
+
+/// Every driver that should be on the grid, from all sources plus the manual
+/// ones. None if a source has no OAuth2 token yet.
+async fn fetch_all_drivers(
+    state: &State,
+    report: &mut report::Report,
+) -> Result<Option<Vec<acsm::BasicDriver>>> {
+    let mut all_drivers = Vec::new();
+    for source in &state.sources {
+        let mut source_report = report::Report::default();
+        let drivers = match source.fetch_all(state, &mut source_report).await {
+            Ok(None) => {
+                // Going ahead would remove the drivers from this source
+                error!(
+                    "No OAuth2 token for {}, skipping full update",
+                    source.name()
+                );
+                return Ok(None);
+            }
+            Ok(Some(drivers)) => {
+                state
+                    .cached_orders
+                    .lock()
+                    .await
+                    .insert(source.name(), (drivers.clone(), source_report.clone()));
+                drivers
+            }
+            Err(e) if source.is_unavailable(state).await => {
+                let cached = state.cached_orders.lock().await.get(source.name()).cloned();
+                let Some((drivers, cached_report)) = cached else {
+                    return Err(e.context(format!(
+                        "Failed to get orders from {}, and none cached",
+                        source.name()
+                    )));
+                };
+                warn!(
+                    "{} unavailable, using {} cached drivers",
+                    source.name(),
+                    drivers.len()
+                );
+                source_report = cached_report;
+                drivers
+            }
+            Err(e) => return Err(e.context(format!("Failed to get orders from {}", source.name()))),
+        };
+        all_drivers.extend(drivers);
+        report.problems.extend(source_report.problems);
+        report.chargebacks.extend(source_report.chargebacks);
+    }
+    state.prepare_drivers(&mut all_drivers, &[], report).await;
+    all_drivers.extend(state.store.lock().await.data().manual_drivers.clone());
+    Ok(Some(all_drivers))
+}
+
+/// Check the configuration against the sources that support it
+async fn validate_sources(state: &State) {
+    let ticket_map = state.ticket_map().await;
+    for source in &state.sources {
+        if let Err(e) = source.validate(state, &ticket_map).await {
+            error!(
+                "Ticket map validation against {} failed: {:?}",
+                source.name(),
+                e
+            );
+        }
     }
-    let api_token = oauth2_state.token.as_ref().unwrap().secret().clone();
-    drop(oauth2_state);
-    let all_drivers = eventix::get_orders(
-        &api_token,
-        &state.eventix_event_guid,
-        &state.ticket_id_to_car_map,
-        &state.metadata_ids,
-    )
-    .await
-    .context("Failed to get orders")?;
-    let acsm_json_file = state.acsm_json_file.lock().await;
-    acsm::update_drivers(
-        true,
-        &acsm_json_file,
-        &all_drivers,
-        &state.ignored_steam_ids,
-    )
-    .await
-    .context("Failed to update drivers")?;
-    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Set RUST_LOG from .env
-    let rust_log = dotenv::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
-    std::env::set_var("RUST_LOG", rust_log);
-    env_logger::init();
+    // Before anything loads .env into the environment
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("config") && args.next().as_deref() == Some("show") {
+        return config::show();
+    }
+    let config = config::Config::load(&config::Flags::parse(std::env::args().skip(1))?)?;
+    logging::init()?;
+    if std::env::args().nth(1).as_deref() == Some("keyring") {
+        return os_keyring::run(&std::env::args().skip(2).collect::<Vec<_>>()).await;
+    }
+    config::load_secret_files()?;
+    let vault = vault::Vault::from_env()?;
+    if let Some(vault) = &vault {
+        vault.load().await?;
+    }
+    os_keyring::load_secrets().await?;
+    let _error_reporting = error_reporting::init();
+    let _telemetry = telemetry::init()?;
+    match std::env::args().nth(1).as_deref() {
+        Some("setup") if std::env::args().skip(2).any(|arg| arg == "--browser") => {
+            return wizard::run().await
+        }
+        Some("setup") => return setup::run().await,
+        // Nothing set up yet, walk through it in the browser
+        None if wizard::is_needed(&config.acsm_json_file) => return wizard::run().await,
+        Some("list-tickets") => return setup::list_tickets().await,
+        _ => {}
+    }
+    // Only load and check the configuration, for CI and systemd ExecStartPre
+    let check_only = std::env::args().skip(1).any(|arg| arg == "--check");
+    // Start with writes paused, resume through the admin API
+    let start_paused = std::env::args().skip(1).any(|arg| arg == "--paused");
+    // Print what a full update would change, without writing
+    let diff_only = std::env::args().nth(1).as_deref() == Some("diff");
+    let json_output = std::env::args().skip(1).any(|arg| arg == "--json");
+    // Authorize the OAuth2 sources, or the one named, and exit
+    let auth_only = std::env::args().nth(1).as_deref() == Some("auth");
+    compression::Compression::init_from_env()?;
+    backups::BackupLocation::init_from_env()?;
+    timezone::init_from_env()?;
+    sandbox::init_from_env()?;
+    if sandbox::is_on() {
+        warn!("SANDBOX is on, nothing will be written");
+    }
+    let steam = steam::Steam::from_env()?;
+    let maintenance_schedule = maintenance::MaintenanceSchedule::from_env()?;
+    let in_maintenance = maintenance_schedule
+        .as_ref()
+        .is_some_and(|schedule| schedule.is_open());
+    let calendar = calendar::Calendar::from_env()?;
+    let round = calendar.as_ref().map(calendar::Calendar::current);
+    if let Some(round) = round {
+        info!("Syncing the round of {}", round);
+        // Before the Eventix source reads it
+        std::env::set_var("EVENTIX_EVENT_GUID", &round.event_guid);
+    }
+    let store = store::Store::from_env().await?;
+    let archive = archive::Archive::from_env()?;
+    // Already over before a restart, so nothing gets written at all
+    let archived = archive
+        .as_ref()
+        .is_some_and(|archive| archive.is_done(store.data()));
+    if archived {
+        warn!("EVENT_END has passed and the event is archived, not writing");
+    }
+    let predecessor = handover::predecessor()?;
     let state = State {
-        acsm_json_file: Mutex::new(
-            dotenv::var("ACSM_JSON_FILE")
-                .context("ACSM_JSON_FILE not set")?
-                .into(),
-        ),
-        eventix_event_guid: dotenv::var("EVENTIX_EVENT_GUID")
-            .context("EVENTIX_EVENT_GUID not set")?,
-        ticket_id_to_car_map: dotenv::var("TICKET_ID_TO_CAR_MAP")
-            .context("TICKET_ID_TO_CAR_MAP not set")?
-            .split(',')
-            .map(|pair| {
-                let pair = pair
-                    .split_once(':')
-                    .context("Missing : separator in TICKET_ID_TO_CAR_MAP")?;
-                Ok((pair.0.to_string(), pair.1.to_string()))
-            })
-            .collect::<Result<_>>()?,
-        metadata_ids: eventix::MetaDataIDs {
-            first_name: dotenv::var("EVENTIX_METADATA_FIRST_NAME")
-                .context("EVENTIX_METADATA_FIRST_NAME not set")?,
-            last_name: dotenv::var("EVENTIX_METADATA_LAST_NAME")
-                .context("EVENTIX_METADATA_LAST_NAME not set")?,
-            team_name: dotenv::var("EVENTIX_METADATA_TEAM_NAME")
-                .context("EVENTIX_METADATA_TEAM_NAME not set")?,
-            steam_id: dotenv::var("EVENTIX_METADATA_STEAM_ID")
-                .context("EVENTIX_METADATA_STEAM_ID not set")?,
+        http: http::HttpPolicies::from_env()?,
+        acsm_json_files: Mutex::new(match round {
+            Some(round) => round.acsm_json_file.clone(),
+            None if config.acsm_json_file.is_empty() => {
+                return Err(anyhow!("ACSM_JSON_FILE not set"));
+            }
+            None => config.acsm_json_file,
+        }),
+        split_policy: config.split_policy,
+        reserved_slots: splits::ReservedSlots::from_env()?,
+        ticket_map: Mutex::new(Arc::new(
+            match round
+                .map(calendar::Round::ticket_map)
+                .transpose()?
+                .flatten()
+            {
+                Some(ticket_map) => ticket_map,
+                None => ticket_map::TicketMap::from_env()?,
+            },
+        )),
+        add_on_ticket_ids: config.add_on_ticket_ids.into_iter().collect(),
+        skill_classes: classes::SkillClasses::from_env()?,
+        name_filter: names::NameFilter::from_env()?,
+        name_lengths: names::NameLengths::from_env()?,
+        driver_overrides_file: driver_overrides::path_from_env(),
+        entrant_defaults: acsm::EntrantDefaults::from_env()?,
+        transliterate_names: config.transliterate_names,
+        ticket_policy: tickets::TicketPolicy {
+            unmapped: config.unmapped_ticket_policy,
+            max_bad_fraction: config.max_bad_ticket_fraction,
+            require_check_in: config.require_check_in,
+            duplicates: config.duplicate_steam_id_policy,
         },
-        ignored_steam_ids: dotenv::var("IGNORED_STEAM_IDS")
-            .unwrap_or_else(|_| "".to_string())
-            .split(',')
-            .map(|id| {
-                if id.is_empty() {
-                    Ok(None)
-                } else {
-                    Ok(Some(id.parse::<u64>()?))
-                }
-            })
-            .filter_map_ok(|id| id)
-            .collect::<Result<Vec<_>>>()?,
-        oauth2_state: Mutex::new(setup_oauth2_client().await?),
+        check_in_poll_interval: Duration::from_secs(config.check_in_poll_seconds),
+        ignored_steam_ids: config.ignored_steam_ids,
+        ignored_guids: config.ignored_guids,
+        webhook_max_age: config
+            .webhook_max_age_seconds
+            .map(chrono::Duration::seconds),
+        registration_cutoff: cutoff::RegistrationCutoff::from_env()?,
+        registration_closes: Mutex::new(None),
         full_update_task: Mutex::new(None),
+        last_report: Mutex::new(report::Report::default()),
+        capacity_alerts: Mutex::new(capacity::CapacityAlerts::from_env()?),
+        sales_policy: sales::SalesPolicy::from_env()?,
+        ticket_availability: Mutex::new(HashMap::new()),
+        class_waitlist: Mutex::new(HashMap::new()),
+        eventix_breaker: Mutex::new(breaker::CircuitBreaker::from_env()?),
+        cached_orders: Mutex::new(HashMap::new()),
+        sync_status: Mutex::new(status::SyncStatus::default()),
+        vault,
+        car_content: content::CarContent::from_env()?,
+        results_source: results::ResultsSource::from_env()?,
+        mailer: email::Mailer::from_env()?,
+        team_cap: team_cap::TeamCap::from_env()?,
+        chargeback_email: dotenv::var("CHARGEBACK_EMAIL")
+            .ok()
+            .filter(|email| !email.is_empty()),
+        retention: retention::from_env()?,
+        acsm_reload: reload::AcsmReload::from_env(&config.acsm_live_timing_url)?,
+        status_webhook: status_webhook::StatusWebhook::from_env(),
+        maintenance_schedule,
+        calendar,
+        archive,
+        handover: tokio::sync::Notify::new(),
+        drift_check: drift::DriftCheck::from_env()?,
+        write_gate: Mutex::new(writes::WriteGate {
+            paused: start_paused,
+            maintenance: in_maintenance,
+            handover: predecessor.is_some(),
+            archived,
+            ..Default::default()
+        }),
+        events: events::Events::default(),
+        admin_token: dotenv::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+            .map(Secret::new),
+        webhook_allowlist: allowlist::IpAllowlist::from_env()?,
+        webhook_secret: dotenv::var("EVENTIX_WEBHOOK_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .map(Secret::new),
+        sources: source::from_env().await?,
+        store: Mutex::new(store),
+        discord: discord::Discord::from_env()?,
+        portal: portal::Portal::from_env(),
+        access_codes: self_service::AccessCodes::from_env(),
+        steam_group: steam_group::SteamGroup::from_env(steam.has_api_key())?,
+        steam,
     };
-    if state.ticket_id_to_car_map.is_empty() {
-        return Err(anyhow!("TICKET_ID_TO_CAR_MAP is empty"));
+    if let Some(car_content) = &state.car_content {
+        let models = car_content.car_models(&state.http.acsm).await?;
+        validate::validate_car_models(&*state.ticket_map().await, &models)
+            .context("Ticket map does not match the cars on the server")?;
+    }
+    let ticket_map = state.ticket_map().await;
+    for acsm_json_file in state.acsm_json_files.lock().await.iter() {
+        state
+            .validate_acsm_file(acsm_json_file, &ticket_map)
+            .await?;
     }
     let state = Arc::new(state);
-    let app = Router::new()
+    let admin_routes = Router::new()
+        .route("/status", get(status::handle_status))
+        .route("/metrics", get(status::handle_metrics))
+        .route("/admin/v1/drivers", post(admin::handle_add_driver))
+        .route(
+            "/admin/v1/drivers/:steam_id",
+            delete(admin::handle_remove_driver),
+        )
+        .route("/admin/v1/events", get(events::handle_events))
+        .route("/admin/v1/pause", post(writes::handle_pause))
+        .route("/admin/v1/resume", post(writes::handle_resume))
+        .route(
+            "/admin/v1/ignored-steam-ids",
+            get(admin::handle_list_ignored_steam_ids),
+        )
+        .route(
+            "/admin/v1/ignored-steam-ids/:steam_id",
+            post(admin::handle_add_ignored_steam_id).delete(admin::handle_remove_ignored_steam_id),
+        )
+        .route("/admin/v1/audit-log", get(admin::handle_audit_log))
+        .route("/admin/v1/results", post(results::handle_ingest))
+        .route(
+            "/admin/v1/simulate/order-paid",
+            post(simulate::handle_order_paid),
+        )
+        .route("/admin/v1/attendance", get(results::handle_attendance))
+        .route(
+            "/admin/v1/snapshots",
+            get(snapshots::handle_list).post(snapshots::handle_take),
+        )
+        .route("/admin/v1/snapshots/:id", delete(snapshots::handle_delete))
+        .route(
+            "/admin/v1/backups",
+            get(backups::handle_list).delete(backups::handle_delete),
+        )
+        .route(
+            "/admin/v1/snapshots/:id/restore",
+            post(snapshots::handle_restore),
+        )
+        .route("/admin/v1/chargebacks", get(chargebacks::handle_list))
+        .route(
+            "/admin/v1/chargebacks/:steam_id",
+            delete(chargebacks::handle_clear),
+        )
+        .route(
+            "/admin/v1/oauth2/authorize-url",
+            get(oauth2::handle_authorize_url),
+        )
+        .route(
+            "/admin/v1/personal-data/:steam_id",
+            delete(retention::handle_purge),
+        )
+        .route(
+            "/admin/v1/ticket-map",
+            get(admin::handle_get_ticket_map).put(admin::handle_replace_ticket_map),
+        )
+        .route(
+            "/admin/v1/acsm-json-files",
+            get(admin::handle_get_acsm_json_files).put(admin::handle_replace_acsm_json_files),
+        )
+        .route("/admin/v1/access-codes", get(admin::handle_access_codes))
+        .route("/admin/v1/handover", post(handover::handle_handover))
+        .route(
+            "/admin/v1/name-approvals/:ticket_guid",
+            post(admin::handle_approve_name),
+        )
+        .route(
+            "/admin/v1/ignored-guids",
+            get(admin::handle_list_ignored_guids),
+        )
+        .route(
+            "/admin/v1/ignored-guids/:guid",
+            post(admin::handle_add_ignored_guid).delete(admin::handle_remove_ignored_guid),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            admin::require_admin_token,
+        ));
+    let mut webhook_routes = Router::new()
         .route(
             "/eventix/webhook-old/v1/order-paid",
-            post(handle_order_paid),
+            post(webhook::handle_order_paid),
         )
-        .route("/eventix/oauth2/v1/callback", get(handle_oauth2_callback))
-        .route("/control/v1/full_update", post(handle_full_update))
-        .fallback(handler)
-        .with_state(state.clone());
-
-    let listen_address = dotenv::var("LISTEN_ADDRESS").context("LISTEN_ADDRESS not set")?;
-    let listener = tokio::net::TcpListener::bind(&listen_address)
-        .await
-        .with_context(|| format!("Failed to bind to {}", listen_address))?;
-    info!("listening on {}", listener.local_addr().unwrap());
-    refresh_token_task(state).await;
-    axum::serve(listener, app)
-        .await
-        .context("Failed to start Axum server")?;
+        .route(
+            "/eventix/webhook/v2/order-paid",
+            post(webhook::handle_order_paid_v2),
+        );
+    let mut oauth2_routes = Router::new();
+    for source in &state.sources {
+        let name = source.name();
+        if source.has_webhook() {
+            webhook_routes = webhook_routes.route(
+                &format!("/{}/webhook/v1", name),
+                post(
+                    move |extract::State(state), extract::Json(payload)| async move {
+                        webhook::handle_source_webhook(state, name, payload).await
+                    },
+                ),
+            );
+        }
+        if source.oauth2().is_some() {
+            oauth2_routes = oauth2_routes.route(
+                &format!("/{}/oauth2/v1/callback", name),
+                get(
+                    move |extract::State(state), extract::Query(query)| async move {
+                        handle_oauth2_callback(state, name, query).await
+                    },
+                ),
+            );
+        }
+    }
+    let webhook_routes = webhook_routes.route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        allowlist::require_allowed_ip,
+    ));
+    let log_requests = config.log_requests;
+    let inherited = match handover::take_over()? {
+        Some(listeners) => Some(listeners),
+        None => systemd::listeners()?,
+    };
+    let (listeners, admin_listeners) = match inherited {
+        Some(listeners) => listeners,
+        None => {
+            let listen_address = config
+                .listen_address
+                .as_deref()
+                .context("LISTEN_ADDRESS not set")?;
+            // Admin routes can get a listener of their own, e.g. to only expose
+            // that one with client certificates
+            let admin_listeners = match &config.admin_listen_address {
+                Some(admin_listen_address) => bind_all(admin_listen_address).await?,
+                None => Vec::new(),
+            };
+            (bind_all(listen_address).await?, admin_listeners)
+        }
+    };
+    let mut app = Router::new()
+        .merge(webhook_routes)
+        .merge(oauth2_routes)
+        .route("/control/v1/full_update", post(handle_full_update));
+    if state.discord.is_some() {
+        if state.access_codes.is_none() {
+            warn!("DISCORD_PUBLIC_KEY is set without ACCESS_CODE_SECRET, so /register won't work");
+        }
+        // Not behind the webhook allowlist, Discord signs its requests
+        app = app.route("/discord/interactions", post(discord::handle_interaction));
+    }
+    if state.portal.is_some() {
+        if state.access_codes.is_none() {
+            warn!("PORTAL_URL is set without ACCESS_CODE_SECRET, so drivers can't log in");
+        }
+        app = app
+            .route("/portal/v1", get(portal::handle_form))
+            .route("/portal/v1/login", get(portal::handle_login))
+            .route("/portal/v1/verify", get(portal::handle_verify))
+            .route(
+                "/portal/v1/edit",
+                get(portal::handle_edit_form).post(portal::handle_edit),
+            );
+    }
+    let mut admin_app = None;
+    if admin_listeners.is_empty() {
+        app = app.merge(admin_routes);
+    } else {
+        admin_app = Some(with_common_layers(admin_routes, &state, log_requests));
+    }
+    let app = with_common_layers(app, &state, log_requests);
+    let tls_config = tls::TlsSettings::from_env()?
+        .map(|tls_settings| tls_settings.rustls_config())
+        .transpose()?;
+    let acsm_live_urls = config.acsm_live_timing_url;
+    let acsm_live_poll_interval = Duration::from_secs(config.acsm_live_poll_seconds);
+    if check_only {
+        for acsm_json_file in state.acsm_json_files.lock().await.iter() {
+            validate::check_writable(acsm_json_file).await?;
+        }
+        info!("Configuration OK");
+        return Ok(());
+    }
+    if diff_only {
+        return diff::run(&state, json_output).await;
+    }
+    if auth_only {
+        let listen_address = oauth2::local_callback_address()?;
+        let name = std::env::args()
+            .nth(2)
+            .filter(|name| !name.starts_with("--"));
+        let sources: Vec<_> = state
+            .sources
+            .iter()
+            .filter(|source| source.oauth2().is_some())
+            .map(|source| source.name())
+            .filter(|source| name.as_deref().is_none_or(|name| name == *source))
+            .collect();
+        if sources.is_empty() {
+            return Err(anyhow!("No OAuth2 source to authorize"));
+        }
+        for source in sources {
+            let client = state.oauth2(source).lock().await.client.clone();
+            oauth2::authorize_locally(
+                client,
+                &state.http.oauth2,
+                &state.store,
+                source,
+                listen_address,
+            )
+            .await?;
+            info!("Stored refresh token for {}", source);
+        }
+        return Ok(());
+    }
+    tokio::spawn(status_webhook::event_task(state.clone()));
+    tokio::spawn(vault::refresh_task(state.clone()));
+    tokio::spawn(retention::expire_task(state.clone()));
+    tokio::spawn(maintenance::window_task(state.clone()));
+    tokio::spawn(calendar::switch_task(state.clone()));
+    tokio::spawn(archive::task(state.clone()));
+    tokio::spawn(drift::check_task(state.clone()));
+    if !acsm_live_urls.is_empty() {
+        tokio::spawn(live::session_watch_task(
+            state.clone(),
+            acsm_live_urls,
+            acsm_live_poll_interval,
+        ));
+    }
+    if let Err(e) = discord::register_commands(&state).await {
+        error!("{:?}", e);
+    }
+    let oauth2_sources: Vec<_> = state
+        .sources
+        .iter()
+        .filter(|source| source.oauth2().is_some())
+        .map(|source| source.name())
+        .collect();
+    if oauth2_sources.is_empty() {
+        // No token to wait for
+        full_update_task(state.clone()).await;
+    }
+    for source in oauth2_sources {
+        oauth2::load_refresh_token(&state, source).await;
+        refresh_token_task(state.clone(), source).await;
+    }
+    let handover_fds = handover::socket_list(&listeners, &admin_listeners);
+    let handle = axum_server::Handle::new();
+    let mut servers = JoinSet::new();
+    match admin_app {
+        Some(admin_app) => {
+            for listener in listeners {
+                servers.spawn(serve(listener, app.clone(), None, handle.clone()));
+            }
+            for listener in admin_listeners {
+                servers.spawn(serve(
+                    listener,
+                    admin_app.clone(),
+                    tls_config.clone(),
+                    handle.clone(),
+                ));
+            }
+        }
+        None => {
+            for listener in listeners {
+                servers.spawn(serve(
+                    listener,
+                    app.clone(),
+                    tls_config.clone(),
+                    handle.clone(),
+                ));
+            }
+        }
+    }
+    tokio::spawn(handover::wait_for_predecessor(state.clone(), predecessor));
+    // Servers only stop on errors, or after handing over
+    let mut handed_over = false;
+    loop {
+        tokio::select! {
+            result = servers.join_next() => match result {
+                Some(result) => result.context("Server task failed")??,
+                None => break,
+            },
+            () = handover::requested(&state), if !handed_over => {
+                match handover::hand_over(&state, &handover_fds, &handle).await {
+                    Ok(()) => handed_over = true,
+                    Err(e) => error!("Failed to hand over: {:?}", e),
+                }
+            }
+        }
+    }
+    // Held until we exit, so the new process doesn't write along with us
+    let _write_gate = state.write_gate.lock().await;
+    info!("Requests finished, exiting");
     Ok(())
 }
 
+/// The fallback and request logging, for every listener
+fn with_common_layers(
+    router: Router<Arc<State>>,
+    state: &Arc<State>,
+    log_requests: bool,
+) -> Router {
+    let router = router.fallback(handler).with_state(state.clone());
+    let router = if log_requests {
+        router.layer(middleware::from_fn(log_request))
+    } else {
+        router
+    };
+    // Outside the request logging, so that has the ID too
+    router.layer(middleware::from_fn(logging::request_id))
+}
+
+/// Bind every address in a comma separated list, hostnames to all their
+/// addresses
+async fn bind_all(listen_addresses: &str) -> Result<Vec<tokio::net::TcpListener>> {
+    let mut listeners = Vec::new();
+    for listen_address in listen_addresses
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+    {
+        let addresses = tokio::net::lookup_host(listen_address)
+            .await
+            .with_context(|| format!("Failed to resolve {}", listen_address))?;
+        for address in addresses {
+            listeners
+                .push(bind(address).with_context(|| format!("Failed to bind to {}", address))?);
+        }
+    }
+    if listeners.is_empty() {
+        return Err(anyhow!("No addresses to listen on in {}", listen_addresses));
+    }
+    Ok(listeners)
+}
+
+/// IPv6 sockets are IPv6 only, so `0.0.0.0:8888,[::]:8888` works for dual
+/// stack
+fn bind(address: SocketAddr) -> Result<tokio::net::TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
+}
+
+/// Until an error, or `handle` shuts it down
+async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    tls_config: Option<RustlsConfig>,
+    handle: axum_server::Handle,
+) -> Result<()> {
+    let local_addr = listener.local_addr()?;
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_config {
+        Some(tls_config) => {
+            info!("listening on {} with TLS", local_addr);
+            axum_server::from_tcp_rustls(listener.into_std()?, tls_config)
+                .handle(handle)
+                .serve(service)
+                .await
+        }
+        None => {
+            info!("listening on {}", local_addr);
+            axum_server::from_tcp(listener.into_std()?)
+                .handle(handle)
+                .serve(service)
+                .await
+        }
+    }
+    .context("Failed to start Axum server")
+}
+
 async fn full_update_task(state: Arc<State>) {
     let state_clone = state.clone();
     let mut full_update_task = state.full_update_task.lock().await;
@@ -149,77 +1246,176 @@ async fn full_update_task(state: Arc<State>) {
         return;
     }
     full_update_task.replace(tokio::spawn(async move {
+        // With OAuth2 this only runs once we have a token, which is the
+        // first time we can talk to the ticket source at all
+        validate_sources(&state_clone).await;
         loop {
             let result = full_update(state_clone.clone()).await;
             if let Err(e) = result {
                 error!("Full update failed: {:?}", e);
             }
-            sleep(Duration::from_secs(60 * 60)).await;
+            // Probe sooner while Eventix is down, the cached drivers may be
+            // out of date
+            let breaker = state_clone.eventix_breaker.lock().await;
+            let interval = if breaker.is_open() {
+                breaker.probe_interval()
+            } else if state_clone.ticket_policy.require_check_in {
+                // Drivers are arriving, and scanning doesn't send webhooks
+                // for every source
+                state_clone.check_in_poll_interval
+            } else {
+                Duration::from_secs(60 * 60)
+            };
+            drop(breaker);
+            state_clone.sync_status.lock().await.next_full_update = Some(Utc::now() + interval);
+            sleep(interval).await;
         }
     }));
 }
 
-async fn _log_request(
+async fn log_request(
     req: Request,
     next: Next,
 ) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    info!("request: {:?}", req);
+    info!(
+        "request: {} {} {:?}",
+        req.method(),
+        redact::redact_uri(req.uri()),
+        redact::redact_headers(req.headers())
+    );
     let res = next.run(req).await;
-    info!("response: {:?}", res);
+    info!(
+        "response: {} {:?}",
+        res.status(),
+        redact::redact_headers(res.headers())
+    );
     Ok(res)
 }
 
-async fn handler(extract::Json(payload): extract::Json<serde_json::Value>) -> Html<&'static str> {
+async fn handler(
+    extract::Json(mut payload): extract::Json<serde_json::Value>,
+) -> Html<&'static str> {
+    redact::redact_json(&mut payload);
     info!("payload: {:?}", payload.to_string());
     Html("received")
 }
 
-#[debug_handler]
-async fn handle_order_paid(
-    extract::State(state): extract::State<Arc<State>>,
-    Json(payload): Json<WebhookPayload>,
+/// Common to all webhook formats
+async fn receive_order_paid(
+    state: &State,
+    source: &str,
+    payload: WebhookPayload,
+) -> Result<Html<&'static str>, StatusCode> {
+    let Some(source) = state.source(source) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let order_guid = payload.guid.clone();
+    state.events.emit(events::EventKind::WebhookReceived {
+        order_guid: order_guid.clone(),
+    });
+    let result = telemetry::in_span(
+        "order-paid webhook".to_string(),
+        vec![("order_guid", order_guid.clone())],
+        process_order_paid(state, source, payload),
+    )
+    .await;
+    if let Err(status) = &result {
+        state
+            .events
+            .error(format!("order-paid {} failed: {}", order_guid, status));
+    }
+    state.sync_status.lock().await.last_webhook = Some(status::Outcome::new(
+        &format!("order-paid {}", order_guid),
+        &result,
+    ));
+    status_webhook::send(state, "webhook", None).await;
+    result
+}
+
+async fn process_order_paid(
+    state: &State,
+    source: &dyn RegistrationSource,
+    payload: WebhookPayload,
 ) -> Result<Html<&'static str>, StatusCode> {
     debug!(
         "order-paid payload: guid={} event={} event_key={} date_time={}",
         payload.guid, payload.event, payload.event_key, payload.date_time
     );
-    if payload.event != "order-paid" {
+    if payload.event != "order-paid"
+        && !chargebacks::WEBHOOK_EVENTS.contains(&payload.event.as_str())
+    {
         warn!("Received event {} instead of order-paid", payload.event);
         return Err(StatusCode::BAD_REQUEST);
     }
-    let oauth2_state = state.oauth2_state.lock().await;
-    if oauth2_state.token.is_none() {
-        error!("No OAuth2 token, skipping order update");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    match payload.parsed_date_time() {
+        Ok(date_time) => {
+            let age = Utc::now() - date_time;
+            debug!(
+                "order-paid for {} is {}s old",
+                payload.guid,
+                age.num_seconds()
+            );
+            if let Some(max_age) = state.webhook_max_age {
+                if age > max_age {
+                    // Acknowledge, otherwise Eventix keeps redelivering it. The
+                    // next full update picks up the order if it still matters.
+                    warn!(
+                        "Ignoring stale order-paid for {} from {}",
+                        payload.guid, date_time
+                    );
+                    return Ok(Html("stale, ignored"));
+                }
+            }
+        }
+        Err(e) => warn!("Failed to parse webhook date_time: {:?}", e),
     }
-    let api_token = oauth2_state.token.as_ref().unwrap().secret().clone();
-    drop(oauth2_state);
-    let new_drivers = eventix::get_single_order(
-        &api_token,
-        &state.eventix_event_guid,
-        &state.ticket_id_to_car_map,
-        &state.metadata_ids,
-        &payload.guid,
-    )
-    .await;
-    if let Err(e) = new_drivers {
-        error!("Failed to get order: {:?}", e);
+    let mut report = report::Report::default();
+    let new_drivers = source.fetch_delta(state, &payload.guid, &mut report).await;
+    let mut new_drivers = match new_drivers {
+        Ok(Some(new_drivers)) => new_drivers,
+        Ok(None) => {
+            error!("No OAuth2 token, skipping order update");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Err(e) => {
+            report.log();
+            error!("Failed to get order: {:?}", e);
+            error_reporting::capture(&e, &[("order_guid", &payload.guid)]);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    state
+        .cache_order(source.name(), &payload.guid, &new_drivers)
+        .await;
+    if let Err(e) = chargebacks::review(state, &report).await {
+        error!(
+            "Failed to put disputed payments on the review list: {:?}",
+            e
+        );
+    }
+    if let Err(e) = chargebacks::remove(state, &report.chargebacks).await {
+        error!("Failed to remove drivers of a disputed order: {:?}", e);
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
-    let new_drivers = new_drivers.unwrap();
+    let others = state.other_drivers(&payload.guid).await;
+    state
+        .prepare_drivers(&mut new_drivers, &others, &mut report)
+        .await;
     if !new_drivers.is_empty() {
-        let acsm_json_file = state.acsm_json_file.lock().await;
-        acsm::update_drivers(
-            false,
-            &acsm_json_file,
-            &new_drivers,
-            &state.ignored_steam_ids,
-        )
-        .await
-        .unwrap();
+        let result = state.place_drivers(&new_drivers, false, &mut report).await;
+        report.log();
+        if let Err(e) = result {
+            error!("Failed to place drivers of order {}: {:?}", payload.guid, e);
+            error_reporting::capture(&e, &[("order_guid", &payload.guid)]);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
     } else {
+        report.log();
         warn!("No drivers found in order {}", payload.guid);
     }
+    if let Err(e) = steam_group::notify_non_members(state).await {
+        error!("Failed to email drivers about the Steam group: {:?}", e);
+    }
     Ok(Html("received"))
 }
 
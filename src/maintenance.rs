@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use log::{error, info};
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+
+use crate::{reload, timezone, writes, State};
+
+/// Windows have minute resolution, so this is often enough
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A weekly window without writes, ending the next day if it ends before it
+/// starts
+#[derive(Debug, Clone, PartialEq)]
+struct MaintenanceWindow {
+    day: Weekday,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    /// Like `Sat 19:00-23:00`
+    fn parse(text: &str) -> Result<MaintenanceWindow> {
+        let (day, times) = text
+            .trim()
+            .split_once(' ')
+            .with_context(|| format!("Maintenance window `{}` has no day", text))?;
+        let (start, end) = times
+            .split_once(['-', '–'])
+            .with_context(|| format!("Maintenance window `{}` has no end", text))?;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .with_context(|| format!("Maintenance window `{}` has a bad time", text))
+        };
+        let window = MaintenanceWindow {
+            day: day
+                .parse()
+                .map_err(|_| anyhow!("Maintenance window `{}` has a bad day", text))?,
+            start: time(start)?,
+            end: time(end)?,
+        };
+        if window.start == window.end {
+            return Err(anyhow!("Maintenance window `{}` is empty", text));
+        }
+        Ok(window)
+    }
+
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let (day, time) = (now.weekday(), now.time());
+        if self.start < self.end {
+            day == self.day && self.start <= time && time < self.end
+        } else {
+            (day == self.day && self.start <= time) || (day == self.day.succ() && time < self.end)
+        }
+    }
+}
+
+/// From MAINTENANCE_WINDOWS, in TIMEZONE
+#[derive(Debug)]
+pub struct MaintenanceSchedule {
+    windows: Vec<MaintenanceWindow>,
+}
+
+impl MaintenanceSchedule {
+    pub fn from_env() -> Result<Option<MaintenanceSchedule>> {
+        let Some(windows) = dotenv::var("MAINTENANCE_WINDOWS")
+            .ok()
+            .filter(|windows| !windows.is_empty())
+        else {
+            return Ok(None);
+        };
+        Ok(Some(MaintenanceSchedule {
+            windows: windows
+                .split(',')
+                .map(MaintenanceWindow::parse)
+                .collect::<Result<_>>()
+                .context("Invalid MAINTENANCE_WINDOWS")?,
+        }))
+    }
+
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        self.windows.iter().any(|window| window.contains(now))
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.contains(timezone::now())
+    }
+}
+
+/// Hold back writes during the maintenance windows, and apply what was queued
+/// after each
+pub async fn window_task(state: Arc<State>) {
+    let Some(schedule) = &state.maintenance_schedule else {
+        return;
+    };
+    if state.write_gate.lock().await.maintenance {
+        info!("In a maintenance window, holding back writes");
+    }
+    loop {
+        let open = schedule.is_open();
+        let mut write_gate = state.write_gate.lock().await;
+        let was_open = std::mem::replace(&mut write_gate.maintenance, open);
+        drop(write_gate);
+        if open && !was_open {
+            info!("Maintenance window started, holding back writes");
+        } else if was_open && !open {
+            info!("Maintenance window over, applying queued changes");
+            if let Err(e) = writes::release(state.clone()).await {
+                error!("Failed to apply queued changes: {:?}", e);
+            }
+            reload::run_queued(state.clone()).await;
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+    use test_case::test_case;
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        // 2024-06-01 is a Saturday
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test_case("Sat 19:00-23:00", at(1, "19:00"), true; "start")]
+    #[test_case("Sat 19:00-23:00", at(1, "22:59"), true; "inside")]
+    #[test_case("Sat 19:00-23:00", at(1, "23:00"), false; "end")]
+    #[test_case("Sat 19:00-23:00", at(8, "20:00"), true; "next week")]
+    #[test_case("Sat 19:00-23:00", at(2, "20:00"), false; "other day")]
+    #[test_case("Sat 22:00–02:00", at(2, "01:30"), true; "past midnight")]
+    #[test_case("sunday 22:00-02:00", at(2, "01:30"), false; "before start")]
+    fn contains(window: &str, now: NaiveDateTime, expected: bool) {
+        assert_eq!(
+            MaintenanceWindow::parse(window).unwrap().contains(now),
+            expected
+        );
+    }
+
+    #[test_case("Sat"; "no times")]
+    #[test_case("Sat 19:00"; "no end")]
+    #[test_case("Someday 19:00-23:00"; "bad day")]
+    #[test_case("Sat 19:00-25:00"; "bad time")]
+    #[test_case("Sat 19:00-19:00"; "empty")]
+    fn rejects(window: &str) {
+        assert!(MaintenanceWindow::parse(window).is_err());
+    }
+}
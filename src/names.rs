@@ -0,0 +1,343 @@
+use any_ascii::any_ascii;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use log::warn;
+use std::str::FromStr;
+
+use crate::{
+    acsm::BasicDriver,
+    report::{ProblemKind, Report},
+    self_service::{self, AuditEntry},
+    State,
+};
+
+/// Who made the change, in the audit log
+const TRANSLITERATION: &str = "transliteration";
+
+/// Replace the names with ASCII, for servers and timing screens that mangle
+/// anything else. The originals go in the audit log, once.
+pub async fn transliterate(state: &State, drivers: &mut [BasicDriver]) {
+    let mut changes = Vec::new();
+    for driver in drivers {
+        let name = any_ascii(&driver.name);
+        let team_name = driver.team_name.as_deref().map(any_ascii);
+        if let Some(ticket_guid) = &driver.ticket_guid {
+            for (field, old, new) in [
+                ("name", Some(&driver.name), Some(&name)),
+                ("team_name", driver.team_name.as_ref(), team_name.as_ref()),
+            ] {
+                if old != new {
+                    changes.push(AuditEntry {
+                        time: Utc::now(),
+                        ticket_guid: ticket_guid.clone(),
+                        by: TRANSLITERATION.to_string(),
+                        field: field.to_string(),
+                        old: old.cloned(),
+                        new: new.cloned(),
+                    });
+                }
+            }
+        }
+        driver.name = name;
+        driver.team_name = team_name;
+    }
+    self_service::audit_once(state, changes).await;
+}
+
+/// Lowercase ASCII words, so `Ćrap-Racing` matches `crap`
+fn words(text: &str) -> Vec<String> {
+    any_ascii(text)
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Words and phrases that keep a driver off the grid until an admin approves
+/// the name, like profanity or the names of real drivers
+#[derive(Debug, Default)]
+pub struct NameFilter {
+    /// Each as words, where a trailing `*` matches any word starting with the
+    /// rest
+    denylist: Vec<Vec<String>>,
+}
+
+impl NameFilter {
+    /// From NAME_DENYLIST, comma separated, and NAME_DENYLIST_FILE, one per
+    /// line
+    pub fn from_env() -> Result<NameFilter> {
+        let mut entries = dotenv::var("NAME_DENYLIST")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if let Some(path) = dotenv::var("NAME_DENYLIST_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+        {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read NAME_DENYLIST_FILE {}", path))?;
+            entries.extend(
+                text.lines()
+                    .filter(|line| !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        Ok(NameFilter::new(&entries))
+    }
+
+    fn new(entries: &[String]) -> NameFilter {
+        NameFilter {
+            denylist: entries
+                .iter()
+                .map(|entry| {
+                    let wildcard = entry.trim().ends_with('*');
+                    let mut words = words(entry);
+                    if let Some(last) = words.last_mut().filter(|_| wildcard) {
+                        last.push('*');
+                    }
+                    words
+                })
+                .filter(|words| !words.is_empty())
+                .collect(),
+        }
+    }
+
+    /// The denylist entry the text contains, if any
+    pub fn check(&self, text: &str) -> Option<String> {
+        let words = words(text);
+        self.denylist
+            .iter()
+            .find(|entry| {
+                words.windows(entry.len()).any(|window| {
+                    window.iter().zip(entry.iter()).all(|(word, pattern)| {
+                        match pattern.strip_suffix('*') {
+                            Some(prefix) => word.starts_with(prefix),
+                            None => word == pattern,
+                        }
+                    })
+                })
+            })
+            .map(|entry| entry.join(" "))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.denylist.is_empty()
+    }
+}
+
+/// Leave out and report drivers whose name or team name is on the denylist,
+/// unless an admin approved exactly those names for the ticket
+pub async fn hold_flagged(state: &State, drivers: &mut Vec<BasicDriver>, report: &mut Report) {
+    if state.name_filter.is_empty() {
+        return;
+    }
+    let store = state.store.lock().await;
+    let approved_names = &store.data().approved_names;
+    drivers.retain(|driver| {
+        let Some(entry) = [Some(&driver.name), driver.team_name.as_ref()]
+            .into_iter()
+            .flatten()
+            .find_map(|text| state.name_filter.check(text))
+        else {
+            return true;
+        };
+        let approved = driver
+            .ticket_guid
+            .as_ref()
+            .and_then(|ticket_guid| approved_names.get(ticket_guid))
+            .is_some_and(|approved| {
+                approved.name == driver.name && approved.team_name == driver.team_name
+            });
+        if approved {
+            return true;
+        }
+        warn!(
+            "Holding back {} steam_id={} team_name={:?}, matches {:?}",
+            driver.name, driver.steam_id, driver.team_name, entry
+        );
+        report.add(
+            ProblemKind::FlaggedName,
+            driver.order_guid.as_deref(),
+            driver.ticket_guid.as_deref(),
+            format!(
+                "Name {:?} or team name {:?} matches {:?}, needs approval",
+                driver.name,
+                driver.team_name.as_deref().unwrap_or_default(),
+                entry
+            ),
+        );
+        false
+    });
+}
+
+/// How to shorten names that are too long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truncation {
+    /// Cut off at the maximum length
+    Cut,
+    /// Cut off and end in `...`
+    Ellipsis,
+    /// Drop whole words from the end, like `Racing Team`, and only cut a
+    /// single word that is too long
+    DropSuffix,
+}
+
+impl FromStr for Truncation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cut" => Ok(Truncation::Cut),
+            "ellipsis" => Ok(Truncation::Ellipsis),
+            "drop-suffix" => Ok(Truncation::DropSuffix),
+            _ => Err(anyhow!("Unknown name truncation: {}", s)),
+        }
+    }
+}
+
+fn cut(text: &str, max_length: usize) -> String {
+    text.chars()
+        .take(max_length)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// The text in at most `max_length` characters
+fn truncate(text: &str, max_length: usize, truncation: Truncation) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    match truncation {
+        Truncation::Cut => cut(text, max_length),
+        Truncation::Ellipsis if max_length > 3 => format!("{}...", cut(text, max_length - 3)),
+        Truncation::Ellipsis => cut(text, max_length),
+        Truncation::DropSuffix => {
+            let mut text = text.trim_end();
+            while text.chars().count() > max_length {
+                match text.rfind(char::is_whitespace) {
+                    Some(end) => text = text[..end].trim_end(),
+                    None => return cut(text, max_length),
+                }
+            }
+            text.to_string()
+        }
+    }
+}
+
+/// Longest names and team names to write, since AC cuts off or misrenders
+/// longer ones
+#[derive(Debug)]
+pub struct NameLengths {
+    max_name_length: Option<usize>,
+    max_team_name_length: Option<usize>,
+    truncation: Truncation,
+}
+
+impl NameLengths {
+    /// From MAX_NAME_LENGTH, MAX_TEAM_NAME_LENGTH and NAME_TRUNCATION
+    pub fn from_env() -> Result<NameLengths> {
+        let length = |var: &str| -> Result<Option<usize>> {
+            match dotenv::var(var) {
+                Ok(length) if !length.is_empty() => Ok(Some(
+                    length
+                        .parse()
+                        .with_context(|| format!("{} is not a number", var))?,
+                )),
+                _ => Ok(None),
+            }
+        };
+        Ok(NameLengths {
+            max_name_length: length("MAX_NAME_LENGTH")?,
+            max_team_name_length: length("MAX_TEAM_NAME_LENGTH")?,
+            truncation: dotenv::var("NAME_TRUNCATION")
+                .unwrap_or_else(|_| "cut".to_string())
+                .parse()
+                .context("Invalid NAME_TRUNCATION")?,
+        })
+    }
+
+    /// The drivers with their names shortened to fit, reporting every one
+    /// that was
+    pub fn apply(&self, drivers: &[BasicDriver], report: &mut Report) -> Vec<BasicDriver> {
+        let mut drivers = drivers.to_vec();
+        for driver in &mut drivers {
+            let name = self.max_name_length.map_or_else(
+                || driver.name.clone(),
+                |max_length| truncate(&driver.name, max_length, self.truncation),
+            );
+            let team_name = driver.team_name.as_ref().map(|team_name| {
+                self.max_team_name_length.map_or_else(
+                    || team_name.clone(),
+                    |max_length| truncate(team_name, max_length, self.truncation),
+                )
+            });
+            if name == driver.name && team_name == driver.team_name {
+                continue;
+            }
+            warn!(
+                "Truncating {:?} team_name={:?} to {:?} team_name={:?}",
+                driver.name, driver.team_name, name, team_name
+            );
+            report.add(
+                ProblemKind::TruncatedName,
+                driver.order_guid.as_deref(),
+                driver.ticket_guid.as_deref(),
+                format!(
+                    "Name {:?} or team name {:?} too long, written as {:?} and {:?}",
+                    driver.name,
+                    driver.team_name.as_deref().unwrap_or_default(),
+                    name,
+                    team_name.as_deref().unwrap_or_default()
+                ),
+            );
+            driver.name = name;
+            driver.team_name = team_name;
+        }
+        drivers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("Max Power", None; "clean")]
+    #[test_case("Max VERSTAPPEN", Some("max verstappen"); "impersonation")]
+    #[test_case("Max  Verstappen-Fan", Some("max verstappen"); "inside other words")]
+    #[test_case("Maxime Verstappen", None; "only whole words")]
+    #[test_case("Crap Racing", Some("crap"); "profanity")]
+    #[test_case("Scrapyard Racing", None; "scunthorpe")]
+    #[test_case("Crappy Racing", Some("crap*"); "wildcard")]
+    #[test_case("Ćrap Racing", Some("crap"); "transliterated")]
+    fn filter(text: &str, expected: Option<&str>) {
+        let filter = NameFilter::new(&[
+            "Max Verstappen".to_string(),
+            " crap ".to_string(),
+            "crap*".to_string(),
+            "".to_string(),
+        ]);
+        assert_eq!(filter.check(text).as_deref(), expected);
+    }
+
+    #[test_case("Max Power", "Max Power"; "already ASCII")]
+    #[test_case("Jérôme Ünal", "Jerome Unal"; "accents")]
+    #[test_case("Михаил Шумахер", "Mikhail Shumakher"; "cyrillic")]
+    fn transliterate(name: &str, expected: &str) {
+        assert_eq!(any_ascii::any_ascii(name), expected);
+    }
+
+    #[test_case("Max Power", Truncation::Cut, "Max Power"; "fits")]
+    #[test_case("Power Racing Team", Truncation::Cut, "Power Racin"; "cut")]
+    #[test_case("Power Racing Team", Truncation::Ellipsis, "Power Ra..."; "ellipsis")]
+    #[test_case("Power Racing Team", Truncation::DropSuffix, "Power"; "drop suffix")]
+    #[test_case("Superpowerracing", Truncation::DropSuffix, "Superpowerr"; "drop suffix of one word")]
+    #[test_case("Powér Rácing", Truncation::Cut, "Powér Rácin"; "characters")]
+    #[test_case("Power      Racing", Truncation::Cut, "Power"; "no trailing space")]
+    fn truncation(text: &str, truncation: Truncation, expected: &str) {
+        assert_eq!(truncate(text, 11, truncation), expected);
+    }
+}
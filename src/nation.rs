@@ -0,0 +1,62 @@
+use isocountry::CountryCode;
+
+/// Common names that aren't the ISO 3166-1 short name
+const ALIASES: &[(&str, CountryCode)] = &[
+    ("uk", CountryCode::GBR),
+    ("united kingdom", CountryCode::GBR),
+    ("great britain", CountryCode::GBR),
+    ("england", CountryCode::GBR),
+    ("scotland", CountryCode::GBR),
+    ("wales", CountryCode::GBR),
+    ("northern ireland", CountryCode::GBR),
+    ("usa", CountryCode::USA),
+    ("us", CountryCode::USA),
+    ("united states", CountryCode::USA),
+    ("america", CountryCode::USA),
+    ("holland", CountryCode::NLD),
+    ("the netherlands", CountryCode::NLD),
+    ("nederland", CountryCode::NLD),
+    ("deutschland", CountryCode::DEU),
+    ("belgië", CountryCode::BEL),
+    ("belgique", CountryCode::BEL),
+    ("russia", CountryCode::RUS),
+    ("south korea", CountryCode::KOR),
+    ("czech republic", CountryCode::CZE),
+];
+
+/// Turn whatever the buyer entered as their nationality into the ISO 3166-1
+/// alpha-3 code ACSM uses for the entrant's Nation, if we recognize it
+pub fn normalize(nationality: &str) -> Option<&'static str> {
+    let nationality = nationality.trim();
+    let lowercase = nationality.to_lowercase();
+    let country = CountryCode::for_alpha3_caseless(nationality)
+        .or_else(|_| CountryCode::for_alpha2_caseless(nationality))
+        .ok()
+        .or_else(|| {
+            ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == lowercase)
+                .map(|(_, country)| *country)
+        })
+        .or_else(|| {
+            CountryCode::iter()
+                .find(|country| country.name().to_lowercase() == lowercase)
+                .copied()
+        })?;
+    Some(country.alpha3())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("NLD", Some("NLD"); "alpha3")]
+    #[test_case("nl", Some("NLD"); "alpha2 lowercase")]
+    #[test_case(" Germany ", Some("DEU"); "name with whitespace")]
+    #[test_case("UK", Some("GBR"); "alias")]
+    #[test_case("Atlantis", None; "unknown")]
+    fn normalize_nationality(nationality: &str, expected: Option<&str>) {
+        assert_eq!(normalize(nationality), expected);
+    }
+}
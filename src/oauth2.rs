@@ -1,17 +1,25 @@
-use anyhow::{Context, Result};
-use axum::{extract, http::StatusCode, response::Html};
+use anyhow::{anyhow, Context, Result};
+use axum::{extract, http::StatusCode, response::Html, Json};
 use axum_macros::debug_handler;
-use log::{error, info};
+use log::{error, info, warn};
 use oauth2::{
-    basic::BasicClient, reqwest::async_http_client, AccessToken, AuthUrl, AuthorizationCode,
-    ClientId, ClientSecret, CsrfToken, ExtraTokenFields, RedirectUrl, RefreshToken,
-    StandardTokenResponse, TokenResponse, TokenType, TokenUrl,
+    basic::BasicClient, url::Url, AccessToken, AuthType, AuthUrl, AuthorizationCode, ClientId,
+    ClientSecret, CsrfToken, ExtraTokenFields, RedirectUrl, RefreshToken, StandardTokenResponse,
+    TokenResponse, TokenType, TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, sleep_until, Instant},
 };
-use serde::Deserialize;
-use std::{sync::Arc, time::Duration};
-use tokio::time::{Instant, sleep_until, sleep};
 
-use crate::State;
+use crate::{
+    http::{self, HttpPolicy},
+    redact::Secret,
+    store::Store,
+    State,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct OAuth2CallbackParameters {
@@ -19,73 +27,173 @@ pub struct OAuth2CallbackParameters {
     pub state: String,
 }
 
+/// How long an authorization URL stays usable
+const CSRF_TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
 #[derive(Debug)]
 pub struct OAuth2State {
     pub client: BasicClient,
-    pub csrf_token: CsrfToken,
+    /// Outstanding authorization attempts, CSRF token to expiry
+    pub pending_csrf_tokens: HashMap<String, Instant>,
+    /// The last authorization URL handed out, with its CSRF token
+    current_authorization: Option<(Url, String)>,
     pub token: Option<AccessToken>,
     pub token_expires: Option<Instant>,
     pub refresh_token: Option<RefreshToken>,
 }
 
-pub async fn setup_oauth2_client() -> Result<OAuth2State> {
-    let client_id = ClientId::new(
-        dotenv::var("EVENTIX_OAUTH2_CLIENT_ID").context("EVENTIX_OAUTH2_CLIENT_ID not set")?,
-    );
-    let client_secret = ClientSecret::new(
-        dotenv::var("EVENTIX_OAUTH2_CLIENT_SECRET")
-            .context("EVENTIX_OAUTH2_CLIENT_SECRET not set")?,
-    );
-    let auth_url = AuthUrl::new(
-        dotenv::var("EVENTIX_OAUTH2_AUTH_URL")
-            .context("EVENTIX_OAUTH2_AUTH_URL not set")?
-            .to_string(),
-    )
-    .context("Failed to create OAuth2 AuthURL")?;
-    let token_url = TokenUrl::new(
-        dotenv::var("EVENTIX_OAUTH2_TOKEN_URL")
-            .context("EVENTIX_OAUTH2_TOKEN_URL not set")?
-            .to_string(),
-    )
-    .context("Failed to create OAuth2 TokenURL")?;
-    let redirect_url = RedirectUrl::new(
-        dotenv::var("EVENTIX_OAUTH2_REDIRECT_URL")
-            .context("EVENTIX_OAUTH2_REDIRECT_URL not set")?
-            .to_string(),
-    )
-    .context("Failed to create OAuth2 RedirectURL")?;
+impl OAuth2State {
+    /// Start a new authorization attempt, without invalidating earlier ones
+    pub fn authorize_url(&mut self) -> Url {
+        self.prune_csrf_tokens();
+        let (auth_url, csrf_token) = self.client.authorize_url(CsrfToken::new_random).url();
+        self.pending_csrf_tokens.insert(
+            csrf_token.secret().clone(),
+            Instant::now() + CSRF_TOKEN_LIFETIME,
+        );
+        self.current_authorization = Some((auth_url.clone(), csrf_token.secret().clone()));
+        auth_url
+    }
+
+    /// The last authorization URL handed out while it's still usable, or a
+    /// new one, with how long it stays usable
+    pub fn current_authorize_url(&mut self) -> (Url, Duration) {
+        self.prune_csrf_tokens();
+        if let Some((url, csrf_token)) = &self.current_authorization {
+            if let Some(expires) = self.pending_csrf_tokens.get(csrf_token) {
+                return (
+                    url.clone(),
+                    expires.saturating_duration_since(Instant::now()),
+                );
+            }
+        }
+        (self.authorize_url(), CSRF_TOKEN_LIFETIME)
+    }
+
+    pub fn has_pending_authorization(&mut self) -> bool {
+        self.prune_csrf_tokens();
+        !self.pending_csrf_tokens.is_empty()
+    }
+
+    /// Consume a CSRF token, returning whether it belonged to an outstanding
+    /// authorization attempt
+    fn take_csrf_token(&mut self, csrf_token: &str) -> bool {
+        self.prune_csrf_tokens();
+        self.pending_csrf_tokens.remove(csrf_token).is_some()
+    }
+
+    fn prune_csrf_tokens(&mut self) {
+        let now = Instant::now();
+        self.pending_csrf_tokens.retain(|_, expires| *expires > now);
+    }
+}
+
+/// Read `<prefix>_CLIENT_ID` and so on
+pub async fn setup_oauth2_client(prefix: &str) -> Result<OAuth2State> {
+    let var = |name: &str| {
+        let var = format!("{}_{}", prefix, name);
+        dotenv::var(&var).with_context(|| format!("{} not set", var))
+    };
+    let client_id = ClientId::new(var("CLIENT_ID")?);
+    let client_secret = ClientSecret::new(var("CLIENT_SECRET")?);
+    let auth_url = AuthUrl::new(var("AUTH_URL")?).context("Failed to create OAuth2 AuthURL")?;
+    let token_url = TokenUrl::new(var("TOKEN_URL")?).context("Failed to create OAuth2 TokenURL")?;
+    let redirect_url =
+        RedirectUrl::new(var("REDIRECT_URL")?).context("Failed to create OAuth2 RedirectURL")?;
+    // Not every provider takes the client credentials as HTTP Basic auth
+    let auth_type = match dotenv::var(format!("{}_CLIENT_AUTH", prefix)).as_deref() {
+        Ok("request-body") => AuthType::RequestBody,
+        Ok("basic") | Ok("") | Err(_) => AuthType::BasicAuth,
+        Ok(other) => return Err(anyhow!("Unknown {}_CLIENT_AUTH: {}", prefix, other)),
+    };
     let client = BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
+        .set_auth_type(auth_type)
         .set_redirect_uri(redirect_url);
-    let (auth_url, csrf_token) = client.authorize_url(CsrfToken::new_random).url();
-    println!("Browse to: {}", auth_url);
+    // The refresh task offers an authorization URL, unless there's a stored
+    // refresh token
     Ok(OAuth2State {
         client,
-        csrf_token,
+        pending_csrf_tokens: HashMap::new(),
+        current_authorization: None,
         token: None,
         token_expires: None,
         refresh_token: None,
     })
 }
 
+/// The current access token, if any
+pub async fn token(oauth2_state: &Mutex<OAuth2State>) -> Option<Secret<String>> {
+    oauth2_state
+        .lock()
+        .await
+        .token
+        .as_ref()
+        .map(|token| Secret::new(token.secret().clone()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeUrlQuery {
+    /// The first source that uses OAuth2 if not given
+    source: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizeUrl {
+    source: &'static str,
+    url: String,
+    expires_in_seconds: u64,
+    /// Authorizing again replaces the token
+    has_token: bool,
+}
+
+/// `GET /admin/v1/oauth2/authorize-url`, for when nobody sees stdout, like
+/// under Docker or systemd
 #[debug_handler]
-pub async fn handle_oauth2_callback(
+pub async fn handle_authorize_url(
     extract::State(state): extract::State<Arc<State>>,
-    extract::Query(query): extract::Query<OAuth2CallbackParameters>,
+    extract::Query(query): extract::Query<AuthorizeUrlQuery>,
+) -> Result<Json<AuthorizeUrl>, StatusCode> {
+    let source = state
+        .sources
+        .iter()
+        .filter(|source| source.oauth2().is_some())
+        .find(|source| {
+            query
+                .source
+                .as_deref()
+                .is_none_or(|name| source.name() == name)
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let mut oauth2_state = state.oauth2(source.name()).lock().await;
+    let (url, expires_in) = oauth2_state.current_authorize_url();
+    Ok(Json(AuthorizeUrl {
+        source: source.name(),
+        url: url.to_string(),
+        expires_in_seconds: expires_in.as_secs(),
+        has_token: oauth2_state.token.is_some(),
+    }))
+}
+
+/// At `/<source>/oauth2/v1/callback`
+pub async fn handle_oauth2_callback(
+    state: Arc<State>,
+    source: &'static str,
+    query: OAuth2CallbackParameters,
 ) -> Result<Html<&'static str>, StatusCode> {
-    info!("oauth2 callback received");
-    let oauth2_state = state.oauth2_state.lock().await;
-    if &query.state != oauth2_state.csrf_token.secret() {
+    info!("oauth2 callback received for {}", source);
+    let mut oauth2_state = state.oauth2(source).lock().await;
+    if !oauth2_state.take_csrf_token(&query.state) {
         return Err(StatusCode::UNAUTHORIZED);
     }
     let token_result = oauth2_state
         .client
         .exchange_code(AuthorizationCode::new(query.code))
-        .request_async(async_http_client)
+        .request_async(|request| http::oauth2_request(&state.http.oauth2, request))
         .await;
     drop(oauth2_state);
     match token_result {
         Ok(token_result) => {
-            update_token_in_state(state.clone(), token_result).await;
+            update_token_in_state(state.clone(), source, token_result).await;
         }
         Err(e) => {
             error!("Failed to exchange code for token: {}", e);
@@ -97,30 +205,51 @@ pub async fn handle_oauth2_callback(
 
 async fn update_token_in_state<EF, TT>(
     state: Arc<State>,
+    source: &'static str,
     token_result: StandardTokenResponse<EF, TT>,
 ) where
     EF: ExtraTokenFields,
     TT: TokenType,
 {
-    info!("Received token");
-    let mut oauth2_state = state.oauth2_state.lock().await;
+    info!("Received token for {}", source);
+    let mut oauth2_state = state.oauth2(source).lock().await;
+    let first_token = oauth2_state.token.is_none();
     let token = token_result.access_token().clone();
     let refresh_token = token_result.refresh_token().cloned();
     let token_expires = token_result
         .expires_in()
         .map(|expires_in| Instant::now() + expires_in - Duration::from_secs(60));
     oauth2_state.token = Some(token);
-    oauth2_state.refresh_token = refresh_token;
+    oauth2_state.refresh_token = refresh_token.clone();
     oauth2_state.token_expires = token_expires;
-    info!("Refresh token: {:?}", oauth2_state.refresh_token);
+    info!(
+        "Refresh token: {:?}",
+        oauth2_state
+            .refresh_token
+            .as_ref()
+            .map(|token| Secret::new(token.secret()))
+    );
     info!("Token expires: {:?}", oauth2_state.token_expires);
     info!("Now: {:?}", Instant::now());
     drop(oauth2_state);
+    if let Some(refresh_token) = refresh_token {
+        store_refresh_token(&state.store, source, &refresh_token).await;
+    }
+    // Full updates may have started before, skipped while this source had no
+    // token
+    if first_token && state.full_update_task.lock().await.is_some() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::full_update(state).await {
+                error!("Full update failed: {:?}", e);
+            }
+        });
+    }
     crate::full_update_task(state).await;
 }
 
-async fn refresh_token(state: Arc<State>) {
-    let oauth2_state = state.oauth2_state.lock().await;
+async fn refresh_token(state: Arc<State>, source: &'static str) {
+    let oauth2_state = state.oauth2(source).lock().await;
     if oauth2_state.refresh_token.is_none() {
         error!("No OAuth2 refresh token, should not happen");
         return;
@@ -129,23 +258,171 @@ async fn refresh_token(state: Arc<State>) {
     let result = oauth2_state
         .client
         .exchange_refresh_token(&refresh_token)
-        .request_async(async_http_client)
+        .request_async(|request| http::oauth2_request(&state.http.oauth2, request))
         .await;
     drop(oauth2_state);
     match result {
         Ok(token_result) => {
-            update_token_in_state(state.clone(), token_result).await;
+            update_token_in_state(state.clone(), source, token_result).await;
         }
         Err(e) => {
             error!("Failed to refresh token: {}", e);
+            // Don't retry it right away forever, wait for an authorization
+            let mut oauth2_state = state.oauth2(source).lock().await;
+            oauth2_state.refresh_token = None;
+            oauth2_state.token_expires = None;
         }
     }
 }
 
-pub async fn refresh_token_task(state: Arc<State>) {
+async fn store_refresh_token(store: &Mutex<Store>, source: &str, refresh_token: &RefreshToken) {
+    let result = store
+        .lock()
+        .await
+        .set_refresh_token(source, Secret::new(refresh_token.secret().clone()))
+        .await;
+    if let Err(e) = result {
+        error!("Failed to store refresh token for {}: {:?}", source, e);
+    }
+}
+
+/// Pick up the refresh token from an earlier run or `eventix2acsm auth`, so
+/// the refresh task gets a token right away
+pub async fn load_refresh_token(state: &State, source: &'static str) {
+    let refresh_token = match state.store.lock().await.refresh_token(source).await {
+        Ok(Some(refresh_token)) => refresh_token,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Failed to load refresh token for {}: {:?}", source, e);
+            return;
+        }
+    };
+    info!("Using stored refresh token for {}", source);
+    let mut oauth2_state = state.oauth2(source).lock().await;
+    oauth2_state.refresh_token = Some(RefreshToken::new(refresh_token.expose().clone()));
+    oauth2_state.token_expires = Some(Instant::now());
+}
+
+/// Where `eventix2acsm diff`, `auth` and `setup` wait for the OAuth2 callback
+pub fn local_callback_address() -> Result<SocketAddr> {
+    dotenv::var("OAUTH2_LOCAL_CALLBACK_ADDRESS")
+        .unwrap_or_else(|_| "127.0.0.1:8765".to_string())
+        .parse()
+        .context("Invalid OAUTH2_LOCAL_CALLBACK_ADDRESS")
+}
+
+/// For `eventix2acsm auth` and `diff`: authorize through a temporary listener
+/// on this machine instead of the server's redirect URL, store the refresh
+/// token and return the access token. The OAuth2 client has to accept the
+/// local redirect URL.
+pub async fn authorize_locally(
+    client: BasicClient,
+    http: &HttpPolicy,
+    store: &Mutex<Store>,
+    source: &'static str,
+    listen_address: SocketAddr,
+) -> Result<Secret<String>> {
+    let listener = tokio::net::TcpListener::bind(listen_address)
+        .await
+        .with_context(|| format!("Failed to listen on {}", listen_address))?;
+    let callback_path = format!("/{}/oauth2/v1/callback", source);
+    let redirect_url = RedirectUrl::new(format!(
+        "http://{}{}",
+        listener.local_addr()?,
+        callback_path
+    ))
+    .context("Failed to create OAuth2 RedirectURL")?;
+    let client = client.set_redirect_uri(redirect_url);
+    let (auth_url, csrf_token) = client.authorize_url(CsrfToken::new_random).url();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+    let app = axum::Router::new().route(
+        &callback_path,
+        axum::routing::get(
+            |axum::extract::Query(query): axum::extract::Query<OAuth2CallbackParameters>| async move {
+                let _ = sender.send(query).await;
+                Html("authentication received, you can close this page")
+            },
+        ),
+    );
+    let server = tokio::spawn(async move { axum::serve(listener, app).await });
+    println!("Browse to: {}", auth_url);
+    let query = loop {
+        let query = receiver.recv().await.context("Callback listener stopped")?;
+        if query.state == *csrf_token.secret() {
+            break query;
+        }
+        warn!("Ignoring {} callback with unknown state", source);
+    };
+    server.abort();
+    let token_result = client
+        .exchange_code(AuthorizationCode::new(query.code))
+        .request_async(|request| http::oauth2_request(http, request))
+        .await
+        .map_err(|e| anyhow!("Failed to exchange code for token: {}", e))?;
+    let refresh_token = token_result
+        .refresh_token()
+        .with_context(|| format!("{} gave no refresh token", source))?;
+    store_refresh_token(store, source, refresh_token).await;
+    Ok(Secret::new(token_result.access_token().secret().clone()))
+}
+
+/// For command line tools like `eventix2acsm setup`: an access token from the
+/// stored refresh token, or else by authorizing locally
+pub async fn local_token(
+    client: BasicClient,
+    http: &HttpPolicy,
+    store: &Mutex<Store>,
+    source: &'static str,
+    listen_address: SocketAddr,
+) -> Result<Secret<String>> {
+    let stored = store.lock().await.refresh_token(source).await?;
+    if let Some(refresh_token) = stored {
+        let result = client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token.expose().clone()))
+            .request_async(|request| http::oauth2_request(http, request))
+            .await;
+        match result {
+            Ok(token_result) => {
+                // Providers may hand out a new refresh token every time
+                if let Some(refresh_token) = token_result.refresh_token() {
+                    store_refresh_token(store, source, refresh_token).await;
+                }
+                return Ok(Secret::new(token_result.access_token().secret().clone()));
+            }
+            Err(e) => warn!("Stored refresh token for {} failed: {}", source, e),
+        }
+    }
+    authorize_locally(client, http, store, source, listen_address).await
+}
+
+/// For commands that run without the server, like `eventix2acsm diff`: get a
+/// token for every OAuth2 source like `local_token` does
+pub async fn local_tokens(state: &State) -> Result<()> {
+    let listen_address = local_callback_address()?;
+    for source in &state.sources {
+        if source.oauth2().is_none() {
+            continue;
+        }
+        let source = source.name();
+        let client = state.oauth2(source).lock().await.client.clone();
+        let token = local_token(
+            client,
+            &state.http.oauth2,
+            &state.store,
+            source,
+            listen_address,
+        )
+        .await
+        .with_context(|| format!("Failed to get an OAuth2 token for {}", source))?;
+        state.oauth2(source).lock().await.token = Some(AccessToken::new(token.expose().clone()));
+    }
+    Ok(())
+}
+
+pub async fn refresh_token_task(state: Arc<State>, source: &'static str) {
     tokio::spawn(async move {
         loop {
-            let mut oauth2_state = state.oauth2_state.lock().await;
+            let mut oauth2_state = state.oauth2(source).lock().await;
             if let Some(token_expires) = oauth2_state.token_expires {
                 if token_expires > Instant::now() {
                     drop(oauth2_state);
@@ -155,11 +432,15 @@ pub async fn refresh_token_task(state: Arc<State>) {
                 }
                 if oauth2_state.refresh_token.is_some() {
                     drop(oauth2_state);
-                    refresh_token(state.clone()).await;
+                    refresh_token(state.clone(), source).await;
                 } else {
                     oauth2_state.token_expires = None;
                 }
             } else {
+                // Offer a fresh URL once the previous ones expire unused
+                if oauth2_state.token.is_none() && !oauth2_state.has_pending_authorization() {
+                    println!("Browse to: {}", oauth2_state.authorize_url());
+                }
                 drop(oauth2_state);
                 info!("No token expiration, sleeping for 1 minute");
                 sleep(Duration::from_secs(60)).await;
@@ -0,0 +1,138 @@
+use std::fmt;
+
+use crate::chargebacks;
+
+/// The status of an Eventix order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderStatus {
+    Pending,
+    Paid,
+    Cancelled,
+    Refunded,
+    Expired,
+    /// Charged back or disputed, the order's or that of its last payment
+    Disputed,
+    /// One Eventix added after this was written
+    Unknown(String),
+}
+
+/// What to do with the drivers of an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Put them on the grid
+    Add,
+    /// Leave them out, they may have been on the grid before
+    Remove,
+    /// Leave them out for now, they may come later
+    Hold,
+    /// Leave them out and report the order, as we don't know what it means
+    Report,
+}
+
+impl OrderStatus {
+    fn parse(status: &str) -> OrderStatus {
+        match status {
+            "pending" | "open" => OrderStatus::Pending,
+            "paid" => OrderStatus::Paid,
+            "cancelled" | "canceled" => OrderStatus::Cancelled,
+            "refunded" => OrderStatus::Refunded,
+            "expired" => OrderStatus::Expired,
+            status if chargebacks::is_disputed(status) => OrderStatus::Disputed,
+            status => OrderStatus::Unknown(status.to_string()),
+        }
+    }
+
+    /// Of an order as the API returns it. A dispute of a payment is different
+    /// from a refund: the buyer got their money back without asking us.
+    pub fn of(order: &serde_json::Value) -> OrderStatus {
+        let payment_disputed = order["payments"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|payment| payment["statii"].as_array()?.last())
+            .any(|status| {
+                status["status"]
+                    .as_str()
+                    .is_some_and(chargebacks::is_disputed)
+            });
+        if payment_disputed {
+            return OrderStatus::Disputed;
+        }
+        OrderStatus::parse(order["status"].as_str().unwrap_or_default())
+    }
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderStatus::Pending => write!(f, "pending"),
+            OrderStatus::Paid => write!(f, "paid"),
+            OrderStatus::Cancelled => write!(f, "cancelled"),
+            OrderStatus::Refunded => write!(f, "refunded"),
+            OrderStatus::Expired => write!(f, "expired"),
+            OrderStatus::Disputed => write!(f, "disputed"),
+            OrderStatus::Unknown(status) => write!(f, "{}", status),
+        }
+    }
+}
+
+/// The transition table, from the status an order had the last time we saw
+/// it, if we did, to the one it has now
+pub fn action(from: Option<&OrderStatus>, to: &OrderStatus) -> Action {
+    match (from, to) {
+        (_, OrderStatus::Paid) => Action::Add,
+        // The payment was reversed
+        (Some(OrderStatus::Paid), OrderStatus::Pending) => Action::Remove,
+        (_, OrderStatus::Pending) => Action::Hold,
+        (
+            _,
+            OrderStatus::Cancelled
+            | OrderStatus::Refunded
+            | OrderStatus::Expired
+            | OrderStatus::Disputed,
+        ) => Action::Remove,
+        (_, OrderStatus::Unknown(_)) => Action::Report,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use test_case::test_case;
+
+    #[test_case(json!({"status": "paid"}), OrderStatus::Paid; "paid")]
+    #[test_case(json!({"status": "canceled"}), OrderStatus::Cancelled; "american")]
+    #[test_case(json!({"status": "chargeback"}), OrderStatus::Disputed; "chargeback")]
+    #[test_case(
+        json!({"status": "paid", "payments": [{"statii": [{"status": "paid"}, {"status": "disputed"}]}]}),
+        OrderStatus::Disputed;
+        "disputed payment"
+    )]
+    #[test_case(
+        json!({"status": "paid", "payments": [{"statii": [{"status": "disputed"}, {"status": "paid"}]}]}),
+        OrderStatus::Paid;
+        "dispute won"
+    )]
+    #[test_case(json!({"status": "on_hold"}), OrderStatus::Unknown("on_hold".to_string()); "unknown")]
+    #[test_case(json!({}), OrderStatus::Unknown(String::new()); "missing")]
+    fn parses(order: serde_json::Value, expected: OrderStatus) {
+        assert_eq!(OrderStatus::of(&order), expected);
+    }
+
+    #[test_case(None, OrderStatus::Paid, Action::Add; "new paid")]
+    #[test_case(Some(OrderStatus::Pending), OrderStatus::Paid, Action::Add; "paid after all")]
+    #[test_case(None, OrderStatus::Pending, Action::Hold; "new pending")]
+    #[test_case(Some(OrderStatus::Paid), OrderStatus::Pending, Action::Remove; "reversed")]
+    #[test_case(Some(OrderStatus::Paid), OrderStatus::Refunded, Action::Remove; "refunded")]
+    #[test_case(None, OrderStatus::Disputed, Action::Remove; "disputed")]
+    #[test_case(
+        Some(OrderStatus::Paid),
+        OrderStatus::Unknown("on_hold".to_string()),
+        Action::Report;
+        "unknown"
+    )]
+    fn transitions(from: Option<OrderStatus>, to: OrderStatus, expected: Action) {
+        assert_eq!(action(from.as_ref(), &to), expected);
+    }
+}
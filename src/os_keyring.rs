@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use std::io::BufRead;
+
+use crate::{config, redact::Secret};
+
+/// Service name of our entries in the keyring
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "eventix2acsm";
+
+/// Whether CREDENTIAL_STORE is `keyring`, to keep secrets and refresh tokens
+/// in the OS keyring instead of `.env` and STATE_FILE
+pub fn enabled() -> Result<bool> {
+    match dotenv::var("CREDENTIAL_STORE").as_deref() {
+        Ok("file" | "") | Err(_) => Ok(false),
+        Ok("keyring") if cfg!(feature = "keyring") => Ok(true),
+        Ok("keyring") => Err(anyhow!(
+            "CREDENTIAL_STORE is keyring, but this build doesn't have the keyring feature"
+        )),
+        Ok(other) => Err(anyhow!("Unknown CREDENTIAL_STORE: {}", other)),
+    }
+}
+
+/// The entry's value, if there is one
+#[cfg(feature = "keyring")]
+fn get_blocking(name: &str) -> Result<Option<String>> {
+    let entry = keyring::Entry::new(SERVICE, name)?;
+    match entry.get_password() {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {} from the keyring", name)),
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn set_blocking(name: &str, value: &str) -> Result<()> {
+    keyring::Entry::new(SERVICE, name)?
+        .set_password(value)
+        .with_context(|| format!("Failed to write {} to the keyring", name))
+}
+
+/// Remove the entry, returning whether there was one
+#[cfg(feature = "keyring")]
+fn delete_blocking(name: &str) -> Result<bool> {
+    match keyring::Entry::new(SERVICE, name)?.delete_credential() {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to delete {} from the keyring", name)),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn get_blocking(_name: &str) -> Result<Option<String>> {
+    Err(anyhow!("This build doesn't have the keyring feature"))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn set_blocking(_name: &str, _value: &str) -> Result<()> {
+    Err(anyhow!("This build doesn't have the keyring feature"))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn delete_blocking(_name: &str) -> Result<bool> {
+    Err(anyhow!("This build doesn't have the keyring feature"))
+}
+
+// The keyring blocks, and on Linux starts a runtime of its own, so it can't be
+// used from async code directly
+
+async fn get(name: &str) -> Result<Option<String>> {
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || get_blocking(&name)).await?
+}
+
+async fn set(name: &str, value: Secret<String>) -> Result<()> {
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || set_blocking(&name, value.expose())).await?
+}
+
+async fn delete(name: &str) -> Result<bool> {
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || delete_blocking(&name)).await?
+}
+
+/// Name of the entry of an OAuth2 source's refresh token
+fn refresh_token_entry(source: &str) -> String {
+    format!("{}_REFRESH_TOKEN", source.to_uppercase())
+}
+
+pub async fn refresh_token(source: &str) -> Result<Option<Secret<String>>> {
+    Ok(get(&refresh_token_entry(source)).await?.map(Secret::new))
+}
+
+pub async fn set_refresh_token(source: &str, refresh_token: Secret<String>) -> Result<()> {
+    set(&refresh_token_entry(source), refresh_token).await
+}
+
+/// Put the secret settings that aren't set otherwise in the environment from
+/// the keyring, where the settings are read from
+pub async fn load_secrets() -> Result<()> {
+    if !enabled()? {
+        return Ok(());
+    }
+    for name in config::secret_settings() {
+        if dotenv::var(name).is_ok_and(|value| !value.is_empty()) {
+            continue;
+        }
+        if let Some(value) = get(name).await? {
+            info!("Using {} from the keyring", name);
+            std::env::set_var(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// `eventix2acsm keyring set <NAME>` with the value on stdin, so it stays out
+/// of the shell history, or `eventix2acsm keyring delete <NAME>`
+pub async fn run(args: &[String]) -> Result<()> {
+    if !cfg!(feature = "keyring") {
+        return Err(anyhow!("This build doesn't have the keyring feature"));
+    }
+    let (command, name) = match args {
+        [command, name] => (command.as_str(), name.as_str()),
+        _ => return Err(anyhow!("Usage: eventix2acsm keyring set|delete <NAME>")),
+    };
+    if !config::secret_settings().contains(&name) && !name.ends_with("_REFRESH_TOKEN") {
+        return Err(anyhow!("{} is not a secret setting", name));
+    }
+    match command {
+        "set" => {
+            eprintln!("Value for {}:", name);
+            let mut value = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut value)
+                .context("Failed to read the value")?;
+            let value = value.trim_end_matches(['\r', '\n']);
+            if value.is_empty() {
+                return Err(anyhow!("No value given for {}", name));
+            }
+            set(name, Secret::new(value.to_string())).await?;
+            println!("Stored {} in the keyring", name);
+        }
+        "delete" => {
+            if delete(name).await? {
+                println!("Deleted {} from the keyring", name);
+            } else {
+                println!("{} is not in the keyring", name);
+            }
+        }
+        other => return Err(anyhow!("Unknown keyring command: {}", other)),
+    }
+    Ok(())
+}
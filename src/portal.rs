@@ -0,0 +1,422 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract,
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_macros::debug_handler;
+use log::{error, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    acsm::BasicDriver,
+    http::HttpPolicy,
+    self_service::{self, OrderTicket},
+    State,
+};
+
+const STEAM_OPENID_URL: &str = "https://steamcommunity.com/openid/login";
+const OPENID_NS: &str = "http://specs.openid.net/auth/2.0";
+const IDENTIFIER_SELECT: &str = "http://specs.openid.net/auth/2.0/identifier_select";
+const CLAIMED_ID_PREFIX: &str = "https://steamcommunity.com/openid/id/";
+
+/// How long a driver has to log in with Steam
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Web pages for drivers to prove their Steam ID by logging in with Steam,
+/// enabled by PORTAL_URL
+pub struct Portal {
+    /// Where drivers reach us, without trailing slash
+    url: String,
+    /// Logins in progress, by the nonce in the return URL, so Steam can't be
+    /// sent back with a ticket the driver didn't look up
+    pending: Mutex<HashMap<String, (OrderTicket, Instant)>>,
+}
+
+impl Portal {
+    pub fn from_env() -> Option<Portal> {
+        let url = dotenv::var("PORTAL_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        Some(Portal {
+            url: url.trim_end_matches('/').to_string(),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The edit page with the access code filled in
+    pub fn edit_url(&self, ticket_guid: &str, code: &str) -> String {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("ticket", ticket_guid)
+            .append_pair("code", code)
+            .finish();
+        format!("{}/portal/v1/edit?{}", self.url, query)
+    }
+
+    fn return_to(&self, nonce: &str) -> String {
+        format!("{}/portal/v1/verify?state={}", self.url, nonce)
+    }
+
+    /// Where to send the driver to log in with Steam for this ticket
+    async fn start_login(&self, ticket: OrderTicket) -> String {
+        let nonce = format!("{:032x}", rand::random::<u128>());
+        let mut pending = self.pending.lock().await;
+        pending.retain(|_, (_, started)| started.elapsed() < LOGIN_TIMEOUT);
+        pending.insert(nonce.clone(), (ticket, Instant::now()));
+        login_url(&self.return_to(&nonce), &self.url)
+    }
+}
+
+fn login_url(return_to: &str, realm: &str) -> String {
+    url::Url::parse_with_params(
+        STEAM_OPENID_URL,
+        [
+            ("openid.ns", OPENID_NS),
+            ("openid.mode", "checkid_setup"),
+            ("openid.return_to", return_to),
+            ("openid.realm", realm),
+            ("openid.identity", IDENTIFIER_SELECT),
+            ("openid.claimed_id", IDENTIFIER_SELECT),
+        ],
+    )
+    .expect("Steam OpenID URL is valid")
+    .to_string()
+}
+
+/// Check that the assertion is from Steam and meant for us, and take the
+/// SteamID64 from it. Whether Steam signed it is checked separately.
+fn parse_assertion(params: &HashMap<String, String>, return_to: &str) -> Result<u64> {
+    let param = |name: &str| {
+        params
+            .get(name)
+            .map(String::as_str)
+            .with_context(|| format!("Missing {}", name))
+    };
+    match param("openid.mode")? {
+        "id_res" => {}
+        "cancel" => return Err(anyhow!("Login was cancelled")),
+        mode => return Err(anyhow!("Unexpected openid.mode {:?}", mode)),
+    }
+    if param("openid.op_endpoint")? != STEAM_OPENID_URL {
+        return Err(anyhow!("Assertion is not from Steam"));
+    }
+    if param("openid.return_to")? != return_to {
+        return Err(anyhow!("Assertion is for another return URL"));
+    }
+    let claimed_id = param("openid.claimed_id")?;
+    if param("openid.identity")? != claimed_id {
+        return Err(anyhow!("Identity and claimed ID differ"));
+    }
+    claimed_id
+        .strip_prefix(CLAIMED_ID_PREFIX)
+        .context("Claimed ID is not a Steam ID")?
+        .parse()
+        .context("Claimed Steam ID is not a number")
+}
+
+/// Have Steam check its own signature on the assertion
+async fn verify_with_steam(http: &HttpPolicy, params: &HashMap<String, String>) -> Result<()> {
+    let form: Vec<(&str, &str)> = params
+        .iter()
+        .filter(|(name, _)| name.starts_with("openid.") && *name != "openid.mode")
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .chain([("openid.mode", "check_authentication")])
+        .collect();
+    let body = http
+        .send(http.client().post(STEAM_OPENID_URL).form(&form))
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("Failed to verify Steam login")?
+        .text()
+        .await
+        .context("Failed to read Steam login verification")?;
+    if !body.lines().any(|line| line.trim() == "is_valid:true") {
+        return Err(anyhow!("Steam did not confirm the login"));
+    }
+    Ok(())
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn page(body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width\">\
+        <title>Steam ID registration</title></head>\n<body>\n{}\n</body></html>\n",
+        body
+    ))
+}
+
+fn form_page(message: Option<&str>) -> Html<String> {
+    page(&format!(
+        "<h1>Steam ID registration</h1>\n{}\
+        <p>Enter the order number and the access code for your ticket, then log \
+        in with Steam to register the account you'll race with.</p>\n\
+        <form action=\"/portal/v1/login\" method=\"get\">\
+        <input name=\"order\" required placeholder=\"Order number\"> \
+        <input name=\"code\" required placeholder=\"Access code\"> \
+        <button>Log in with Steam</button></form>",
+        message
+            .map(|message| format!("<p><strong>{}</strong></p>\n", escape(message)))
+            .unwrap_or_default()
+    ))
+}
+
+#[debug_handler]
+pub async fn handle_form() -> Html<String> {
+    form_page(None)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    order: String,
+    /// The ticket's access code, which picks the driver's own ticket
+    code: String,
+}
+
+#[debug_handler]
+pub async fn handle_login(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Query(query): extract::Query<LoginQuery>,
+) -> Result<Response, StatusCode> {
+    let Some(portal) = &state.portal else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let order = query.order.trim();
+    let ticket = match self_service::own_ticket(&state, order, query.code.trim()).await {
+        Ok(Some(ticket)) => ticket,
+        Ok(None) => {
+            return Ok(
+                form_page(Some("Registration is not open yet, try again later")).into_response(),
+            )
+        }
+        Err(e) => {
+            warn!("Portal visitor gave order {:?}: {:?}", order, e);
+            let message = format!(
+                "Could not find a ticket with that access code in paid order {}",
+                order
+            );
+            return Ok(form_page(Some(&message)).into_response());
+        }
+    };
+    Ok(Redirect::to(&portal.start_login(ticket).await).into_response())
+}
+
+#[debug_handler]
+pub async fn handle_verify(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Query(params): extract::Query<HashMap<String, String>>,
+) -> Result<Html<String>, StatusCode> {
+    let Some(portal) = &state.portal else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let nonce = params.get("state").ok_or(StatusCode::BAD_REQUEST)?;
+    let Some((ticket, started)) = portal.pending.lock().await.remove(nonce) else {
+        return Ok(form_page(Some("That login expired, please start again")));
+    };
+    if started.elapsed() >= LOGIN_TIMEOUT {
+        return Ok(form_page(Some("That login expired, please start again")));
+    }
+    let steam_id = match parse_assertion(&params, &portal.return_to(nonce)) {
+        Ok(steam_id) => steam_id,
+        Err(e) => {
+            warn!("Bad Steam login for ticket {}: {:?}", ticket.guid, e);
+            return Ok(form_page(Some(
+                "Logging in with Steam didn't work, please try again",
+            )));
+        }
+    };
+    if let Err(e) = verify_with_steam(&state.http.steam, &params).await {
+        warn!("Unverified Steam login for ticket {}: {:?}", ticket.guid, e);
+        return Ok(form_page(Some(
+            "Logging in with Steam didn't work, please try again",
+        )));
+    }
+    if let Err(e) = self_service::set_steam_id(&state, &ticket, steam_id, "Steam login").await {
+        error!("Failed to store Steam ID override: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(page(&format!(
+        "<h1>Steam ID registration</h1>\n<p>Steam ID {} is now registered for {}.</p>",
+        steam_id,
+        escape(&ticket.description)
+    )))
+}
+
+fn edit_page(
+    ticket_guid: &str,
+    code: &str,
+    driver: &BasicDriver,
+    message: Option<&str>,
+) -> Html<String> {
+    page(&format!(
+        "<h1>Your entry</h1>\n{}\
+        <p>This is how you'll show up in the entry list and on the server.</p>\n\
+        <form action=\"/portal/v1/edit\" method=\"post\">\
+        <input type=\"hidden\" name=\"ticket\" value=\"{}\">\
+        <input type=\"hidden\" name=\"code\" value=\"{}\">\
+        <p><label>Name <input name=\"name\" required value=\"{}\"></label></p>\
+        <p><label>Team <input name=\"team_name\" value=\"{}\"></label></p>\
+        <button>Save</button></form>",
+        message
+            .map(|message| format!("<p><strong>{}</strong></p>\n", escape(message)))
+            .unwrap_or_default(),
+        escape(ticket_guid),
+        escape(code),
+        escape(&driver.name),
+        escape(driver.team_name.as_deref().unwrap_or_default())
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditQuery {
+    ticket: String,
+    code: String,
+}
+
+/// The driver for a ticket, if the access code is right
+async fn editable_driver(
+    state: &State,
+    ticket_guid: &str,
+    code: &str,
+) -> Result<BasicDriver, StatusCode> {
+    let Some(access_codes) = &state.access_codes else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    if !access_codes.check(ticket_guid, code) {
+        warn!("Wrong access code for ticket {}", ticket_guid);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    // Tickets that haven't made it into a full update yet can't be edited
+    self_service::find_driver(state, ticket_guid)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[debug_handler]
+pub async fn handle_edit_form(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Query(query): extract::Query<EditQuery>,
+) -> Result<Html<String>, StatusCode> {
+    let driver = editable_driver(&state, &query.ticket, &query.code).await?;
+    Ok(edit_page(&query.ticket, &query.code, &driver, None))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditForm {
+    ticket: String,
+    code: String,
+    name: String,
+    #[serde(default)]
+    team_name: String,
+}
+
+#[debug_handler]
+pub async fn handle_edit(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Form(form): extract::Form<EditForm>,
+) -> Result<Html<String>, StatusCode> {
+    let driver = editable_driver(&state, &form.ticket, &form.code).await?;
+    let edit = match self_service::clean_edit(&form.name, &form.team_name) {
+        Ok(edit) => edit,
+        Err(e) => {
+            return Ok(edit_page(
+                &form.ticket,
+                &form.code,
+                &driver,
+                Some(&e.to_string()),
+            ))
+        }
+    };
+    if let Err(e) = self_service::edit_driver(&state, &driver, edit.clone(), "Edit portal").await {
+        error!("Failed to store driver edit: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let driver = BasicDriver {
+        name: edit.name,
+        team_name: edit.team_name,
+        ..driver
+    };
+    Ok(edit_page(
+        &form.ticket,
+        &form.code,
+        &driver,
+        Some("Saved, the entry list will be updated shortly"),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    const RETURN_TO: &str = "https://example.com/portal/v1/verify?state=abc";
+
+    fn assertion(changes: &[(&str, &str)]) -> HashMap<String, String> {
+        let mut params: HashMap<String, String> = [
+            ("openid.mode", "id_res"),
+            ("openid.op_endpoint", STEAM_OPENID_URL),
+            ("openid.return_to", RETURN_TO),
+            (
+                "openid.claimed_id",
+                "https://steamcommunity.com/openid/id/76561197960287930",
+            ),
+            (
+                "openid.identity",
+                "https://steamcommunity.com/openid/id/76561197960287930",
+            ),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        for (name, value) in changes {
+            params.insert(name.to_string(), value.to_string());
+        }
+        params
+    }
+
+    #[test]
+    fn good_assertion() {
+        assert_eq!(
+            parse_assertion(&assertion(&[]), RETURN_TO).unwrap(),
+            76561197960287930
+        );
+    }
+
+    #[test_case(&[("openid.mode", "cancel")]; "cancelled")]
+    #[test_case(&[("openid.op_endpoint", "https://evil.example.com/openid/login")]; "other provider")]
+    #[test_case(&[("openid.return_to", "https://example.com/portal/v1/verify?state=other")]; "other nonce")]
+    #[test_case(&[("openid.claimed_id", "https://evil.example.com/76561197960287930")]; "other claimed id")]
+    #[test_case(&[("openid.identity", "https://steamcommunity.com/openid/id/76561197960287931")]; "identity differs")]
+    fn bad_assertion(changes: &[(&str, &str)]) {
+        assert!(parse_assertion(&assertion(changes), RETURN_TO).is_err());
+    }
+
+    #[test]
+    fn login() {
+        let url = url::Url::parse(&login_url(RETURN_TO, "https://example.com")).unwrap();
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["openid.return_to"], RETURN_TO);
+        assert_eq!(params["openid.mode"], "checkid_setup");
+    }
+
+    #[test]
+    fn escaped() {
+        assert_eq!(
+            escape("<a href=\"x\">Tom & 'Jerry'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+    }
+}
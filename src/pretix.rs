@@ -0,0 +1,423 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    acsm::BasicDriver,
+    http::HttpPolicy,
+    redact::Secret,
+    report::{ProblemKind, Report},
+    source::RegistrationSource,
+    tickets::{self, MetaDataIDs, TicketContext},
+    State, WebhookPayload,
+};
+
+/// A self-hosted or hosted Pretix, and the event the tickets are sold under.
+/// Products (items) map to cars like Eventix ticket types do, and question
+/// identifiers take the place of Eventix metadata IDs.
+#[derive(Debug)]
+pub struct Pretix {
+    /// Like `https://pretix.eu`
+    pub url: String,
+    pub organizer: String,
+    pub event: String,
+    pub api_token: Secret<String>,
+    pub question_ids: MetaDataIDs,
+}
+
+impl Pretix {
+    pub fn from_env() -> Result<Pretix> {
+        Ok(Pretix {
+            url: dotenv::var("PRETIX_URL")
+                .context("PRETIX_URL not set")?
+                .trim_end_matches('/')
+                .to_string(),
+            organizer: dotenv::var("PRETIX_ORGANIZER").context("PRETIX_ORGANIZER not set")?,
+            event: dotenv::var("PRETIX_EVENT").context("PRETIX_EVENT not set")?,
+            api_token: Secret::new(
+                dotenv::var("PRETIX_API_TOKEN").context("PRETIX_API_TOKEN not set")?,
+            ),
+            question_ids: MetaDataIDs::from_env("PRETIX_QUESTION")?,
+        })
+    }
+
+    fn event_url(&self) -> String {
+        format!(
+            "{}/api/v1/organizers/{}/events/{}/",
+            self.url, self.organizer, self.event
+        )
+    }
+
+    fn orders_url(&self) -> String {
+        format!("{}orders/", self.event_url())
+    }
+
+    async fn get_json(&self, http: &HttpPolicy, url: &str) -> Result<serde_json::Value> {
+        let request = http.client().get(url).header(
+            "Authorization",
+            format!("Token {}", self.api_token.expose()),
+        );
+        http.send(request)
+            .await
+            .context("Pretix API request failed")?
+            .error_for_status()
+            .context("Pretix API returned error")?
+            .json()
+            .await
+            .context("Pretix API returned bad JSON")
+    }
+
+    pub async fn get_event_start(&self, http: &HttpPolicy) -> Result<DateTime<Utc>> {
+        let event = self.get_json(http, &self.event_url()).await?;
+        let date_from = event["date_from"]
+            .as_str()
+            .context("Event date_from is not a string")?;
+        Ok(DateTime::parse_from_rfc3339(date_from)
+            .context("Event date_from is not an RFC3339 timestamp")?
+            .with_timezone(&Utc))
+    }
+
+    /// All paid orders, following the pagination
+    pub async fn get_orders(
+        &self,
+        http: &HttpPolicy,
+        tickets: &TicketContext<'_>,
+        report: &mut Report,
+    ) -> Result<Vec<BasicDriver>> {
+        let mut drivers = Vec::new();
+        let mut bad_tickets = 0_usize;
+        let mut next_url = Some(format!("{}?status=p", self.orders_url()));
+        while let Some(url) = next_url {
+            let page = self.get_json(http, &url).await?;
+            for order in page["results"]
+                .as_array()
+                .context("Orders results is not an array")?
+            {
+                bad_tickets += order_to_drivers(order, tickets, report, &mut drivers)?;
+            }
+            next_url = page["next"].as_str().map(str::to_string);
+        }
+        tickets
+            .policy
+            .check_bad_fraction(drivers.len(), bad_tickets)?;
+        Ok(drivers)
+    }
+
+    pub async fn get_single_order(
+        &self,
+        http: &HttpPolicy,
+        tickets: &TicketContext<'_>,
+        code: &str,
+        report: &mut Report,
+    ) -> Result<Vec<BasicDriver>> {
+        let url = format!("{}{}/", self.orders_url(), code);
+        let order = self.get_json(http, &url).await?;
+        let mut drivers = Vec::new();
+        order_to_drivers(&order, tickets, report, &mut drivers)?;
+        Ok(drivers)
+    }
+}
+
+/// When the first payment was confirmed, or else when the order was placed
+fn paid_at(order: &serde_json::Value) -> Option<DateTime<Utc>> {
+    order["payments"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|payment| payment["state"] == "confirmed")
+        .filter_map(|payment| tickets::parse_time(&payment["payment_date"]))
+        .min()
+        .or_else(|| tickets::parse_time(&order["datetime"]))
+}
+
+/// Add the drivers of a paid order, returning how many positions had bad
+/// answers
+fn order_to_drivers(
+    order: &serde_json::Value,
+    tickets: &TicketContext,
+    report: &mut Report,
+    drivers: &mut Vec<BasicDriver>,
+) -> Result<usize> {
+    let code = order["code"]
+        .as_str()
+        .context("Order code is not a string")?;
+    // n(ew), p(aid), e(xpired), c(anceled)
+    if order["status"] != "p" {
+        debug!("Skipping order [{}] with status: {}", code, order["status"]);
+        return Ok(0);
+    }
+    let mut bad_tickets = 0;
+    for position in order["positions"]
+        .as_array()
+        .context("Order positions is not an array")?
+    {
+        if position["canceled"].as_bool() == Some(true) {
+            continue;
+        }
+        let position_id = position["id"].to_string();
+        if tickets.policy.require_check_in
+            && position["checkins"]
+                .as_array()
+                .is_none_or(|checkins| checkins.is_empty())
+        {
+            debug!("Skipping position {} that isn't checked in", position_id);
+            continue;
+        }
+        let item = position["item"].to_string();
+        let Some(mapping) = tickets.mapping(&item) else {
+            tickets.skip_unmapped(
+                &item,
+                format!("No car found for item: {}", item),
+                report,
+                Some(code),
+                Some(&position_id),
+            )?;
+            continue;
+        };
+        let answers = position["answers"]
+            .as_array()
+            .context("Position answers is not an array")?
+            .iter()
+            .filter_map(|answer| {
+                Some((
+                    answer["question_identifier"].as_str()?,
+                    answer["answer"].as_str(),
+                ))
+            });
+        match tickets::driver_from_metadata(
+            mapping,
+            answers,
+            tickets,
+            Some(code),
+            Some(&position_id),
+        ) {
+            Ok(driver) => drivers.push(BasicDriver {
+                paid_at: paid_at(order),
+                email: order["email"].as_str().map(str::to_string),
+                ..driver
+            }),
+            Err(e) => {
+                report.add(
+                    ProblemKind::BadMetadata,
+                    Some(code),
+                    Some(&position_id),
+                    format!("{:#}", e),
+                );
+                bad_tickets += 1;
+            }
+        }
+    }
+    Ok(bad_tickets)
+}
+
+/// Pretix webhooks only say what happened to which order
+#[derive(Debug, Deserialize)]
+pub struct PretixWebhookPayload {
+    pub notification_id: u64,
+    pub organizer: String,
+    pub event: String,
+    pub code: String,
+    pub action: String,
+}
+
+#[async_trait]
+impl RegistrationSource for Pretix {
+    fn name(&self) -> &'static str {
+        "pretix"
+    }
+
+    async fn fetch_all(
+        &self,
+        state: &State,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        self.get_orders(
+            &state.http.pretix,
+            &state.ticket_context(&ticket_map, &self.question_ids, &steam_id_overrides),
+            report,
+        )
+        .await
+        .map(Some)
+    }
+
+    async fn fetch_delta(
+        &self,
+        state: &State,
+        order_id: &str,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>> {
+        let steam_id_overrides = state.steam_id_overrides().await;
+        let ticket_map = state.ticket_map().await;
+        self.get_single_order(
+            &state.http.pretix,
+            &state.ticket_context(&ticket_map, &self.question_ids, &steam_id_overrides),
+            order_id,
+            report,
+        )
+        .await
+        .map(Some)
+    }
+
+    fn has_webhook(&self) -> bool {
+        true
+    }
+
+    fn parse_webhook(&self, payload: serde_json::Value) -> Result<Option<WebhookPayload>> {
+        let payload: PretixWebhookPayload =
+            serde_json::from_value(payload).context("Not a Pretix webhook")?;
+        if payload.organizer != self.organizer || payload.event != self.event {
+            warn!(
+                "Ignoring Pretix webhook for {}/{}",
+                payload.organizer, payload.event
+            );
+            return Ok(None);
+        }
+        // Check-ins only matter with REQUIRE_CHECK_IN, but fetching the order
+        // again is harmless otherwise
+        if payload.action != "pretix.event.order.paid" && payload.action != "pretix.event.checkin" {
+            info!(
+                "Ignoring Pretix {} for order {}",
+                payload.action, payload.code
+            );
+            return Ok(None);
+        }
+        Ok(Some(WebhookPayload {
+            // Pretix doesn't say when, but sends right away
+            date_time: Utc::now().to_rfc3339(),
+            event: "order-paid".to_string(),
+            event_key: payload.notification_id.to_string(),
+            guid: payload.code,
+        }))
+    }
+
+    async fn event_start(&self, state: &State) -> Result<DateTime<Utc>> {
+        self.get_event_start(&state.http.pretix).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ticket_map::TicketMap,
+        tickets::{DuplicatePolicy, TicketPolicy, UnmappedTicketPolicy},
+    };
+    use serde_json::json;
+    use std::collections::{HashMap, HashSet};
+
+    fn question_ids() -> MetaDataIDs {
+        MetaDataIDs {
+            first_name: "FIRST".to_string(),
+            last_name: "LAST".to_string(),
+            team_name: "TEAM".to_string(),
+            steam_id: "STEAM".to_string(),
+            co_driver_steam_ids: Vec::new(),
+            nationality: None,
+            skill: None,
+            pace: None,
+        }
+    }
+
+    #[test]
+    fn paid_order() {
+        let order = json!({
+            "code": "ABC12",
+            "status": "p",
+            "positions": [
+                {
+                    "id": 1,
+                    "item": 10,
+                    "answers": [
+                        {"question_identifier": "FIRST", "answer": "Max"},
+                        {"question_identifier": "LAST", "answer": "Power"},
+                        {"question_identifier": "STEAM", "answer": "76561190000000001"},
+                    ],
+                },
+                {"id": 2, "item": 99, "answers": []},
+                {"id": 3, "item": 10, "canceled": true, "answers": []},
+                {"id": 4, "item": 10, "answers": []},
+                {
+                    "id": 5,
+                    "item": 10,
+                    "answers": [
+                        {"question_identifier": "FIRST", "answer": "Typo"},
+                        {"question_identifier": "LAST", "answer": "Fixed"},
+                        {"question_identifier": "STEAM", "answer": "7656119OOOOOOOOO2"},
+                    ],
+                },
+            ],
+        });
+        let map = TicketMap::from_car_map("10:gt3").unwrap();
+        let policy = TicketPolicy {
+            unmapped: UnmappedTicketPolicy::Skip,
+            max_bad_fraction: 1.0,
+            require_check_in: false,
+            duplicates: DuplicatePolicy::Earliest,
+        };
+        let steam_id_overrides = HashMap::from([("5".to_string(), 76561190000000002)]);
+        let tickets = TicketContext {
+            ticket_map: &map,
+            ticket_names: None,
+            add_on_tickets: &HashSet::new(),
+            metadata_ids: &question_ids(),
+            policy: &policy,
+            steam_id_overrides: &steam_id_overrides,
+        };
+        let mut report = Report::default();
+        let mut drivers = Vec::new();
+        let bad = order_to_drivers(&order, &tickets, &mut report, &mut drivers).unwrap();
+        assert_eq!(bad, 1);
+        assert_eq!(drivers.len(), 2);
+        assert_eq!(drivers[0].name, "Max Power");
+        assert_eq!(drivers[0].order_guid.as_deref(), Some("ABC12"));
+        assert_eq!(drivers[1].steam_id, 76561190000000002);
+        assert_eq!(report.problems.len(), 2);
+    }
+
+    #[test]
+    fn check_in() {
+        let answers = json!([
+            {"question_identifier": "FIRST", "answer": "Max"},
+            {"question_identifier": "LAST", "answer": "Power"},
+            {"question_identifier": "STEAM", "answer": "76561190000000001"},
+        ]);
+        let order = json!({
+            "code": "ABC12",
+            "status": "p",
+            "positions": [
+                {"id": 1, "item": 10, "answers": answers, "checkins": []},
+                {"id": 2, "item": 10, "answers": answers},
+                {
+                    "id": 3,
+                    "item": 10,
+                    "answers": answers,
+                    "checkins": [{"list": 1, "datetime": "2024-01-28T12:00:00Z"}],
+                },
+            ],
+        });
+        let map = TicketMap::from_car_map("10:gt3").unwrap();
+        let policy = TicketPolicy {
+            unmapped: UnmappedTicketPolicy::Skip,
+            max_bad_fraction: 1.0,
+            require_check_in: true,
+            duplicates: DuplicatePolicy::Earliest,
+        };
+        let tickets = TicketContext {
+            ticket_map: &map,
+            ticket_names: None,
+            add_on_tickets: &HashSet::new(),
+            metadata_ids: &question_ids(),
+            policy: &policy,
+            steam_id_overrides: &HashMap::new(),
+        };
+        let mut report = Report::default();
+        let mut drivers = Vec::new();
+        order_to_drivers(&order, &tickets, &mut report, &mut drivers).unwrap();
+        assert_eq!(drivers.len(), 1);
+        assert_eq!(drivers[0].ticket_guid.as_deref(), Some("3"));
+    }
+}
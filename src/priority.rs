@@ -0,0 +1,164 @@
+use log::{info, warn};
+
+use crate::{
+    acsm::{BasicDriver, ClassSlots},
+    events::EventKind,
+    State,
+};
+
+/// The drivers a full update moved past each other
+#[derive(Debug, Default)]
+pub struct Affected {
+    /// Were on the grid, but their slot went to a priority ticket or an
+    /// earlier buyer
+    pub bumped: Vec<BasicDriver>,
+    /// Got a slot with a priority ticket, while drivers without one who paid
+    /// earlier are on the waitlist
+    pub jumped: Vec<BasicDriver>,
+}
+
+/// Compare the splits before placing with the allocation
+pub fn affected(
+    splits: &[Vec<ClassSlots>],
+    drivers: &[BasicDriver],
+    allocation: &[Vec<BasicDriver>],
+    ignored_steam_ids: &[u64],
+) -> Affected {
+    let on_grid = |steam_id: u64| {
+        let steam_id = steam_id.to_string();
+        splits
+            .iter()
+            .flatten()
+            .any(|class| class.guids.contains(&steam_id))
+    };
+    let placed = |steam_id: u64| {
+        allocation
+            .iter()
+            .flatten()
+            .any(|driver| driver.steam_id == steam_id)
+    };
+    let left_out: Vec<&BasicDriver> = drivers
+        .iter()
+        .filter(|driver| !placed(driver.steam_id) && !ignored_steam_ids.contains(&driver.steam_id))
+        .collect();
+    let bumped = left_out
+        .iter()
+        .filter(|driver| on_grid(driver.steam_id))
+        .map(|driver| (*driver).clone())
+        .collect();
+    let jumped = allocation
+        .iter()
+        .flatten()
+        .filter(|driver| driver.priority && !on_grid(driver.steam_id))
+        .filter(|driver| {
+            left_out.iter().any(|waiting| {
+                !waiting.priority
+                    && waiting.paid_at.is_some()
+                    && (driver.paid_at.is_none() || waiting.paid_at < driver.paid_at)
+            })
+        })
+        .cloned()
+        .collect();
+    Affected { bumped, jumped }
+}
+
+/// Let the drivers know through the events, the status webhook and, with
+/// SMTP_URL, an email to the buyer
+pub async fn notify(state: &State, affected: Affected) {
+    for driver in affected.bumped {
+        info!(
+            "{} steam_id={} lost their slot and is on the waitlist",
+            driver.name, driver.steam_id
+        );
+        state.events.emit(EventKind::Bumped {
+            name: driver.name.clone(),
+            steam_id: driver.steam_id,
+            car: driver.car.clone(),
+        });
+        let body = format!(
+            "Hi {},\n\nAn entry that goes before yours took your slot on the grid, so you're on \
+             the waitlist now. You get a slot back automatically as soon as one frees up.\n",
+            driver.name
+        );
+        email(state, &driver, "You're on the waitlist", body).await;
+    }
+    for driver in affected.jumped {
+        info!(
+            "{} steam_id={} got a slot ahead of the waitlist with a priority ticket",
+            driver.name, driver.steam_id
+        );
+        state.events.emit(EventKind::PriorityPlaced {
+            name: driver.name.clone(),
+            steam_id: driver.steam_id,
+            car: driver.car.clone(),
+        });
+        let body = format!(
+            "Hi {},\n\nThe grid was full, but your priority ticket got you a slot ahead of \
+             the waitlist. See you on track!\n",
+            driver.name
+        );
+        email(state, &driver, "You're on the grid", body).await;
+    }
+}
+
+async fn email(state: &State, driver: &BasicDriver, subject: &str, body: String) {
+    let (Some(mailer), Some(email)) = (&state.mailer, &driver.email) else {
+        return;
+    };
+    if let Err(e) = mailer.send(email, subject, body).await {
+        warn!(
+            "Failed to email steam_id={} about their slot: {:?}",
+            driver.steam_id, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn driver(steam_id: u64, priority: bool, minute: u32) -> BasicDriver {
+        BasicDriver {
+            paid_at: Some(format!("2024-01-01T12:{:02}:00Z", minute).parse().unwrap()),
+            priority,
+            ..BasicDriver::test(steam_id, "gt3")
+        }
+    }
+
+    fn steam_ids(drivers: &[BasicDriver]) -> Vec<u64> {
+        drivers.iter().map(|driver| driver.steam_id).collect()
+    }
+
+    #[test]
+    fn bumped_and_jumped() {
+        let splits = [vec![ClassSlots {
+            name: "GT3".to_string(),
+            cars: vec!["gt3".to_string()],
+            guids: vec!["1".to_string(), "2".to_string()],
+        }]];
+        let drivers = [
+            driver(1, false, 0),
+            driver(2, false, 10),
+            driver(3, false, 20),
+            driver(4, true, 30),
+        ];
+        let allocation = [vec![drivers[0].clone(), drivers[3].clone()]];
+        let affected = affected(&splits, &drivers, &allocation, &[]);
+        assert_eq!(steam_ids(&affected.bumped), vec![2]);
+        assert_eq!(steam_ids(&affected.jumped), vec![4]);
+    }
+
+    #[test]
+    fn nobody_waiting() {
+        let splits = [vec![ClassSlots {
+            name: "GT3".to_string(),
+            cars: vec!["gt3".to_string()],
+            guids: vec!["1".to_string(), String::new()],
+        }]];
+        let drivers = [driver(1, false, 0), driver(2, true, 10)];
+        let allocation = [drivers.to_vec()];
+        let affected = affected(&splits, &drivers, &allocation, &[]);
+        assert!(affected.bumped.is_empty());
+        assert!(affected.jumped.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use std::sync::Arc;
+use tokio::process::Command;
+
+use crate::{live, redact::Secret, sandbox, writes::WriteGate, State};
+
+/// How to make ACSM pick up the new entry list
+enum Action {
+    /// POST to ACSM's API, like reloading the championship
+    Url {
+        url: String,
+        token: Option<Secret<String>>,
+    },
+    /// Run through the shell, like restarting the event
+    Command(String),
+}
+
+/// Tell ACSM about entry list changes, but never while a session is running
+pub struct AcsmReload {
+    action: Action,
+    /// ACSM_LIVE_TIMING_URL, checked right before reloading
+    live_timing_urls: Vec<String>,
+}
+
+fn var(name: &str) -> Option<String> {
+    dotenv::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl AcsmReload {
+    /// From ACSM_RELOAD_URL and ACSM_RELOAD_TOKEN, or ACSM_RELOAD_COMMAND
+    pub fn from_env(live_timing_urls: &[String]) -> Result<Option<AcsmReload>> {
+        let action = match (var("ACSM_RELOAD_URL"), var("ACSM_RELOAD_COMMAND")) {
+            (None, None) => return Ok(None),
+            (Some(url), None) => Action::Url {
+                url,
+                token: var("ACSM_RELOAD_TOKEN").map(Secret::new),
+            },
+            (None, Some(command)) => Action::Command(command),
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "Set either ACSM_RELOAD_URL or ACSM_RELOAD_COMMAND, not both"
+                ))
+            }
+        };
+        if live_timing_urls.is_empty() {
+            return Err(anyhow!(
+                "Reloading ACSM needs ACSM_LIVE_TIMING_URL, to never do it during a session"
+            ));
+        }
+        Ok(Some(AcsmReload {
+            action,
+            live_timing_urls: live_timing_urls.to_vec(),
+        }))
+    }
+
+    /// Whether every server answered that no session is running
+    async fn all_idle(&self, state: &State) -> bool {
+        for url in &self.live_timing_urls {
+            match live::session_is_live(&state.http.acsm, url).await {
+                Ok(false) => {}
+                Ok(true) => return false,
+                Err(e) => {
+                    warn!("Failed to check for a live session at {}: {:?}", url, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    async fn run(&self, state: &State) -> Result<()> {
+        match &self.action {
+            Action::Url { url, token } => {
+                let mut request = state.http.acsm.client().post(url);
+                if let Some(token) = token {
+                    request = request.bearer_auth(token.expose());
+                }
+                state
+                    .http
+                    .acsm
+                    .send(request)
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .context("ACSM reload call failed")?;
+            }
+            Action::Command(command) => {
+                let status = shell(command)
+                    .status()
+                    .await
+                    .with_context(|| format!("Failed to run {}", command))?;
+                if !status.success() {
+                    return Err(anyhow!("{} failed: {}", command, status));
+                }
+            }
+        }
+        info!("Reloaded ACSM");
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// After the entry list changed, reload ACSM if no session is running, or
+/// once it's over. Called with the write gate held, so no write happens in
+/// between.
+pub async fn after_change(state: &State, write_gate: &mut WriteGate) {
+    let Some(reload) = &state.acsm_reload else {
+        return;
+    };
+    if sandbox::is_on() {
+        info!("[SANDBOX] Not reloading ACSM");
+        return;
+    }
+    if write_gate.maintenance {
+        info!("In a maintenance window, reloading ACSM once it's over");
+        write_gate.reload_queued = true;
+        return;
+    }
+    if write_gate.session_live || !reload.all_idle(state).await {
+        info!("ACSM session live, reloading once it's over");
+        write_gate.reload_queued = true;
+        return;
+    }
+    write_gate.reload_queued = false;
+    if let Err(e) = reload.run(state).await {
+        error!("Failed to reload ACSM: {:?}", e);
+    }
+}
+
+/// Do a reload that was held back, if no session or maintenance window is
+/// running now
+pub async fn run_queued(state: Arc<State>) {
+    let mut write_gate = state.write_gate.lock().await;
+    if write_gate.reload_queued && !write_gate.session_live && !write_gate.maintenance {
+        after_change(&state, &mut write_gate).await;
+    }
+}
@@ -0,0 +1,109 @@
+use itertools::Itertools;
+use log::warn;
+use serde::Serialize;
+use std::fmt;
+
+use crate::chargebacks::Chargeback;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    UnmappedTicket,
+    BadMetadata,
+    NoFreeSlot,
+    RegistrationClosed,
+    AddOnWithoutEntry,
+    FlaggedName,
+    TruncatedName,
+    DuplicateSteamId,
+    SteamProfile,
+    NotInSteamGroup,
+    SteamNameMismatch,
+    Chargeback,
+    UnknownOrderStatus,
+    TeamFull,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProblemKind::UnmappedTicket => write!(f, "unmapped ticket"),
+            ProblemKind::BadMetadata => write!(f, "bad metadata"),
+            ProblemKind::NoFreeSlot => write!(f, "no free slot"),
+            ProblemKind::RegistrationClosed => write!(f, "registration closed"),
+            ProblemKind::AddOnWithoutEntry => write!(f, "add-on without entry"),
+            ProblemKind::FlaggedName => write!(f, "flagged name"),
+            ProblemKind::TruncatedName => write!(f, "truncated name"),
+            ProblemKind::DuplicateSteamId => write!(f, "duplicate Steam ID"),
+            ProblemKind::SteamProfile => write!(f, "Steam profile"),
+            ProblemKind::NotInSteamGroup => write!(f, "not in Steam group"),
+            ProblemKind::SteamNameMismatch => write!(f, "Steam name mismatch"),
+            ProblemKind::Chargeback => write!(f, "chargeback"),
+            ProblemKind::UnknownOrderStatus => write!(f, "unknown order status"),
+            ProblemKind::TeamFull => write!(f, "team full"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub order_guid: Option<String>,
+    pub ticket_guid: Option<String>,
+    pub message: String,
+}
+
+/// Everything that went wrong with individual tickets during a sync, but was
+/// not bad enough to abort it
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Drivers in orders with a disputed payment, for the review list
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chargebacks: Vec<Chargeback>,
+}
+
+impl Report {
+    pub fn add(
+        &mut self,
+        kind: ProblemKind,
+        order_guid: Option<&str>,
+        ticket_guid: Option<&str>,
+        message: String,
+    ) {
+        self.problems.push(Problem {
+            kind,
+            order_guid: order_guid.map(str::to_string),
+            ticket_guid: ticket_guid.map(str::to_string),
+            message,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn log(&self) {
+        for problem in &self.problems {
+            warn!(
+                "Problem: {} order={} ticket={}: {}",
+                problem.kind,
+                problem.order_guid.as_deref().unwrap_or("-"),
+                problem.ticket_guid.as_deref().unwrap_or("-"),
+                problem.message
+            );
+        }
+        if !self.is_empty() {
+            warn!(
+                "Problem report: {}",
+                self.problems
+                    .iter()
+                    .counts_by(|problem| problem.kind)
+                    .into_iter()
+                    .sorted()
+                    .map(|(kind, count)| format!("{} {}", count, kind))
+                    .join(", ")
+            );
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{extract, http::StatusCode, Json};
+use axum_macros::debug_handler;
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{acsm::BasicDriver, events::EventKind, State};
+
+/// ACSM's marker for a driver without a valid lap
+const NO_LAP_MS: u64 = 999999999;
+
+/// Where ACSM's results JSON is
+pub enum ResultsSource {
+    /// A results file, or ACSM's results directory to take the newest from
+    Path(PathBuf),
+    /// ACSM's API for a results file
+    Url(String),
+}
+
+fn var(name: &str) -> Option<String> {
+    dotenv::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl ResultsSource {
+    /// From RESULTS_PATH or RESULTS_URL
+    pub fn from_env() -> Result<Option<ResultsSource>> {
+        match (var("RESULTS_PATH"), var("RESULTS_URL")) {
+            (None, None) => Ok(None),
+            (Some(path), None) => Ok(Some(ResultsSource::Path(PathBuf::from(path)))),
+            (None, Some(url)) => Ok(Some(ResultsSource::Url(url))),
+            (Some(_), Some(_)) => Err(anyhow!("Set either RESULTS_PATH or RESULTS_URL, not both")),
+        }
+    }
+
+    async fn read(&self, state: &State) -> Result<String> {
+        match self {
+            ResultsSource::Path(path) => {
+                let path = if tokio::fs::metadata(path).await?.is_dir() {
+                    newest_json_file(path).await?
+                } else {
+                    path.clone()
+                };
+                info!("Reading results from {}", path.display());
+                tokio::fs::read_to_string(&path)
+                    .await
+                    .with_context(|| format!("Failed to read {}", path.display()))
+            }
+            ResultsSource::Url(url) => {
+                let http = &state.http.acsm;
+                http.send(http.client().get(url))
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .context("Getting results from ACSM failed")?
+                    .text()
+                    .await
+                    .context("Getting results from ACSM failed")
+            }
+        }
+    }
+}
+
+async fn newest_json_file(dir: &PathBuf) -> Result<PathBuf> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to list {}", dir.display()))?;
+    let mut newest = None;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            let modified = entry.metadata().await?.modified()?;
+            if newest.as_ref().is_none_or(|(newest, _)| modified > *newest) {
+                newest = Some((modified, path));
+            }
+        }
+    }
+    newest
+        .map(|(_, path)| path)
+        .with_context(|| format!("No results in {}", dir.display()))
+}
+
+#[derive(Debug, PartialEq)]
+struct Finisher {
+    position: usize,
+    steam_id: u64,
+    name: String,
+    car: String,
+    laps: usize,
+    best_lap: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct SessionResult {
+    track: String,
+    /// `RACE`, `QUALIFY` or `PRACTICE`
+    session_type: String,
+    finishers: Vec<Finisher>,
+}
+
+/// The drivers in the order they finished. Empty slots and drivers that never
+/// completed a lap are left out.
+fn parse(results: &Value) -> Result<SessionResult> {
+    let laps = results["Laps"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut finishers = Vec::new();
+    for result in results["Result"]
+        .as_array()
+        .context("Result is not an array")?
+    {
+        let Ok(steam_id) = result["DriverGuid"].as_str().unwrap_or_default().parse() else {
+            continue;
+        };
+        let guid = result["DriverGuid"].as_str();
+        let laps = laps
+            .iter()
+            .filter(|lap| lap["DriverGuid"].as_str() == guid)
+            .count();
+        if laps == 0 {
+            continue;
+        }
+        finishers.push(Finisher {
+            position: finishers.len() + 1,
+            steam_id,
+            name: result["DriverName"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            car: result["CarModel"].as_str().unwrap_or_default().to_string(),
+            laps,
+            best_lap: result["BestLap"]
+                .as_u64()
+                .filter(|best_lap| *best_lap > 0 && *best_lap < NO_LAP_MS)
+                .map(Duration::from_millis),
+        });
+    }
+    Ok(SessionResult {
+        track: match results["TrackConfig"].as_str() {
+            Some(config) if !config.is_empty() => {
+                format!(
+                    "{} ({})",
+                    results["TrackName"].as_str().unwrap_or_default(),
+                    config
+                )
+            }
+            _ => results["TrackName"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        },
+        session_type: results["Type"].as_str().unwrap_or_default().to_string(),
+        finishers,
+    })
+}
+
+/// Like `1:42.337`
+fn lap_time(lap: Duration) -> String {
+    let millis = lap.as_millis();
+    format!(
+        "{}:{:02}.{:03}",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn email_body(finisher: &Finisher, session: &SessionResult) -> String {
+    let best_lap = match finisher.best_lap {
+        Some(best_lap) => format!(", with a best lap of {}", lap_time(best_lap)),
+        None => String::new(),
+    };
+    format!(
+        "Hi {},\n\nThanks for racing at {}. You finished P{} of {} in the {}, \
+         completing {} laps{}.\n",
+        finisher.name,
+        session.track,
+        finisher.position,
+        session.finishers.len(),
+        finisher.car,
+        finisher.laps,
+        best_lap
+    )
+}
+
+/// The latest results, with the SHA-256 of their text to tell them apart
+async fn latest(state: &State) -> Result<(String, Value)> {
+    let source = state
+        .results_source
+        .as_ref()
+        .context("Neither RESULTS_PATH nor RESULTS_URL is set")?;
+    let text = source.read(state).await?;
+    let id = hex::encode(Sha256::digest(text.as_bytes()));
+    Ok((
+        id,
+        serde_json::from_str(&text).context("Results are not JSON")?,
+    ))
+}
+
+/// The drivers with a ticket, as of the last full update
+async fn paid_drivers(state: &State) -> Vec<BasicDriver> {
+    state
+        .cached_orders
+        .lock()
+        .await
+        .values()
+        .flat_map(|(drivers, _)| drivers.iter().cloned())
+        .collect()
+}
+
+/// The ticket holder who drove, as the main driver or a co-driver
+fn ticket_holder(drivers: &[BasicDriver], steam_id: u64) -> Option<&BasicDriver> {
+    drivers
+        .iter()
+        .find(|driver| driver.steam_id == steam_id || driver.co_drivers.contains(&steam_id))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    track: String,
+    session_type: String,
+    finishers: usize,
+    /// Finishers with a ticket
+    ticket_holders: usize,
+    emailed: usize,
+    /// These results were emailed about before
+    already_emailed: bool,
+}
+
+/// Read the latest results, match the finishers to ticket holders, and email
+/// those their result if SMTP_URL is set. Only race results count, and each
+/// only once.
+pub async fn ingest(state: &State) -> Result<Summary> {
+    let (id, results) = latest(state).await?;
+    let session = parse(&results)?;
+    let drivers = paid_drivers(state).await;
+    let ticket_holders: Vec<_> = session
+        .finishers
+        .iter()
+        .filter_map(|finisher| Some((finisher, ticket_holder(&drivers, finisher.steam_id)?)))
+        .collect();
+    let mut summary = Summary {
+        track: session.track.clone(),
+        session_type: session.session_type.clone(),
+        finishers: session.finishers.len(),
+        ticket_holders: ticket_holders.len(),
+        emailed: 0,
+        already_emailed: state
+            .store
+            .lock()
+            .await
+            .data()
+            .emailed_results
+            .contains(&id),
+    };
+    info!(
+        "{} results at {}: {} finishers, {} with a ticket",
+        session.session_type, session.track, summary.finishers, summary.ticket_holders
+    );
+    let Some(mailer) = &state.mailer else {
+        return Ok(summary);
+    };
+    if session.session_type != "RACE" || summary.already_emailed {
+        return Ok(summary);
+    }
+    for (finisher, driver) in ticket_holders {
+        let Some(email) = &driver.email else {
+            continue;
+        };
+        let subject = format!("Your result at {}", session.track);
+        match mailer
+            .send(email, &subject, email_body(finisher, &session))
+            .await
+        {
+            Ok(()) => summary.emailed += 1,
+            Err(e) => warn!("Failed to email steam_id={}: {:?}", finisher.steam_id, e),
+        }
+    }
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| data.emailed_results.push(id))
+        .await?;
+    info!("Emailed {} drivers their result", summary.emailed);
+    Ok(summary)
+}
+
+/// Everyone who drove in the session by Steam ID, with their name, also when
+/// they left before the end
+fn joined(results: &Value, session: &SessionResult) -> BTreeMap<u64, String> {
+    let mut joined: BTreeMap<u64, String> = results["Laps"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|lap| {
+            Some((
+                lap["DriverGuid"].as_str()?.parse().ok()?,
+                lap["DriverName"].as_str().unwrap_or_default().to_string(),
+            ))
+        })
+        .collect();
+    for finisher in &session.finishers {
+        joined.insert(finisher.steam_id, finisher.name.clone());
+    }
+    joined
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct NoShow {
+    steam_id: u64,
+    name: String,
+    order_guid: Option<String>,
+    ticket_guid: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Gatecrasher {
+    steam_id: u64,
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Attendance {
+    track: String,
+    session_type: String,
+    /// Paid, but neither they nor a co-driver completed a lap
+    no_shows: Vec<NoShow>,
+    /// Drove without a ticket, and aren't manual drivers or ignored
+    gatecrashers: Vec<Gatecrasher>,
+}
+
+/// Compare who drove with who paid. `allowed` are the Steam IDs that can drive
+/// without a ticket.
+fn reconcile(
+    joined: &BTreeMap<u64, String>,
+    paid: &[BasicDriver],
+    allowed: &[u64],
+) -> (Vec<NoShow>, Vec<Gatecrasher>) {
+    let no_shows = paid
+        .iter()
+        .filter(|driver| {
+            !std::iter::once(&driver.steam_id)
+                .chain(&driver.co_drivers)
+                .any(|steam_id| joined.contains_key(steam_id))
+        })
+        .map(|driver| NoShow {
+            steam_id: driver.steam_id,
+            name: driver.name.clone(),
+            order_guid: driver.order_guid.clone(),
+            ticket_guid: driver.ticket_guid.clone(),
+        })
+        .collect();
+    let gatecrashers = joined
+        .iter()
+        .filter(|(steam_id, _)| {
+            !allowed.contains(steam_id) && ticket_holder(paid, **steam_id).is_none()
+        })
+        .map(|(steam_id, name)| Gatecrasher {
+            steam_id: *steam_id,
+            name: name.clone(),
+        })
+        .collect();
+    (no_shows, gatecrashers)
+}
+
+/// Who paid but didn't show up, and who drove without paying, in the latest
+/// results
+pub async fn attendance(state: &State) -> Result<Attendance> {
+    let (_, results) = latest(state).await?;
+    let session = parse(&results)?;
+    let mut allowed = state.ignored_steam_ids().await;
+    allowed.extend(
+        state
+            .store
+            .lock()
+            .await
+            .data()
+            .manual_drivers
+            .iter()
+            .map(|driver| driver.steam_id),
+    );
+    let (no_shows, gatecrashers) = reconcile(
+        &joined(&results, &session),
+        &paid_drivers(state).await,
+        &allowed,
+    );
+    Ok(Attendance {
+        track: session.track,
+        session_type: session.session_type,
+        no_shows,
+        gatecrashers,
+    })
+}
+
+/// Right after a session, when ACSM has written its results
+pub async fn after_session(state: Arc<State>) {
+    if state.results_source.is_none() {
+        return;
+    }
+    if let Err(e) = ingest(&state).await {
+        error!("Failed to process the results: {:?}", e);
+    }
+    match attendance(&state).await {
+        Ok(attendance) => {
+            for gatecrasher in &attendance.gatecrashers {
+                warn!(
+                    "{} steam_id={} drove without a ticket",
+                    gatecrasher.name, gatecrasher.steam_id
+                );
+            }
+            state.events.emit(EventKind::Attendance {
+                track: attendance.track,
+                no_shows: attendance.no_shows.len(),
+                gatecrashers: attendance.gatecrashers.len(),
+            });
+        }
+        Err(e) => error!("Failed to check attendance: {:?}", e),
+    }
+}
+
+#[debug_handler]
+pub async fn handle_attendance(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Result<Json<Attendance>, StatusCode> {
+    attendance(&state).await.map(Json).map_err(|e| {
+        error!("Failed to check attendance: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[debug_handler]
+pub async fn handle_ingest(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Result<Json<Summary>, StatusCode> {
+    ingest(&state).await.map(Json).map_err(|e| {
+        error!("Failed to process the results: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn results() -> Value {
+        json!({
+            "TrackName": "ks_nordschleife",
+            "TrackConfig": "endurance",
+            "Type": "RACE",
+            "Result": [
+                {"DriverName": "Fast", "DriverGuid": "76561198000000002", "CarModel": "bmw_m3_e30_gra", "BestLap": 481337, "TotalTime": 1500000},
+                {"DriverName": "", "DriverGuid": "", "CarModel": "bmw_m3_e30_gra", "BestLap": 999999999, "TotalTime": 0},
+                {"DriverName": "Slow", "DriverGuid": "76561198000000001", "CarModel": "bmw_m3_e30_gra", "BestLap": 999999999, "TotalTime": 1600000},
+                {"DriverName": "No show", "DriverGuid": "76561198000000003", "CarModel": "bmw_m3_e30_gra", "BestLap": 999999999, "TotalTime": 0}
+            ],
+            "Laps": [
+                {"DriverGuid": "76561198000000002", "LapTime": 481337},
+                {"DriverGuid": "76561198000000002", "LapTime": 490000},
+                {"DriverGuid": "76561198000000001", "LapTime": 520000}
+            ]
+        })
+    }
+
+    #[test]
+    fn parses_results() {
+        let session = parse(&results()).unwrap();
+        assert_eq!(session.track, "ks_nordschleife (endurance)");
+        assert_eq!(
+            session.finishers,
+            vec![
+                Finisher {
+                    position: 1,
+                    steam_id: 76561198000000002,
+                    name: "Fast".to_string(),
+                    car: "bmw_m3_e30_gra".to_string(),
+                    laps: 2,
+                    best_lap: Some(Duration::from_millis(481337)),
+                },
+                Finisher {
+                    position: 2,
+                    steam_id: 76561198000000001,
+                    name: "Slow".to_string(),
+                    car: "bmw_m3_e30_gra".to_string(),
+                    laps: 1,
+                    best_lap: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn attendance() {
+        let results = results();
+        let joined = joined(&results, &parse(&results).unwrap());
+        let paid = |steam_id: u64, co_drivers| BasicDriver {
+            co_drivers,
+            order_guid: Some("order".to_string()),
+            ..BasicDriver::test(steam_id, "bmw_m3_e30_gra")
+        };
+        let (no_shows, gatecrashers) = reconcile(
+            &joined,
+            &[
+                paid(76561198000000001, vec![]),
+                paid(76561198000000003, vec![]),
+                paid(76561198000000004, vec![76561198000000002]),
+            ],
+            &[],
+        );
+        assert_eq!(
+            no_shows,
+            vec![NoShow {
+                steam_id: 76561198000000003,
+                name: "Driver 76561198000000003".to_string(),
+                order_guid: Some("order".to_string()),
+                ticket_guid: None,
+            }]
+        );
+        assert!(gatecrashers.is_empty());
+        let (_, gatecrashers) = reconcile(&joined, &[], &[76561198000000001]);
+        assert_eq!(
+            gatecrashers,
+            vec![Gatecrasher {
+                steam_id: 76561198000000002,
+                name: "Fast".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn email() {
+        let session = parse(&results()).unwrap();
+        assert_eq!(
+            email_body(&session.finishers[0], &session),
+            "Hi Fast,\n\nThanks for racing at ks_nordschleife (endurance). You finished P1 \
+             of 2 in the bmw_m3_e30_gra, completing 2 laps, with a best lap of 8:01.337.\n"
+        );
+    }
+}
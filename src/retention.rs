@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use axum::{extract, http::StatusCode, Json};
+use axum_macros::debug_handler;
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::time::sleep;
+
+use crate::{acsm, backups, reload, sandbox, snapshots, store::StoreData, State};
+
+/// How often old personal data is looked for
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// From PERSONAL_DATA_RETENTION_DAYS, forever if not set
+pub fn from_env() -> Result<Option<Duration>> {
+    let Some(days) = dotenv::var("PERSONAL_DATA_RETENTION_DAYS")
+        .ok()
+        .filter(|days| !days.is_empty())
+    else {
+        return Ok(None);
+    };
+    let days: u64 = days
+        .parse()
+        .context("PERSONAL_DATA_RETENTION_DAYS is not a number")?;
+    Ok(Some(Duration::from_secs(days * 24 * 60 * 60)))
+}
+
+/// Delete backups and snapshots of the ACSM files and audit log entries older
+/// than the retention period
+async fn expire(state: &State, retention: Duration) -> Result<()> {
+    let cutoff = SystemTime::now() - retention;
+    let mut backups = 0;
+    // The backups belong to whoever writes the ACSM files
+    let json_files = if sandbox::is_on() {
+        Vec::new()
+    } else {
+        state.acsm_json_files.lock().await.clone()
+    };
+    for json_file in json_files.iter() {
+        for (backup, taken) in backups::list(json_file).await? {
+            if taken < cutoff {
+                tokio::fs::remove_file(&backup)
+                    .await
+                    .with_context(|| format!("Failed to delete {}", backup.display()))?;
+                backups += 1;
+            }
+        }
+    }
+    let cutoff = Utc::now() - chrono::Duration::from_std(retention)?;
+    let mut audit_entries = 0;
+    let mut snapshots = 0;
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            let before = data.audit_log.len();
+            data.audit_log.retain(|entry| entry.time >= cutoff);
+            audit_entries = before - data.audit_log.len();
+            let before = data.snapshots.len();
+            data.snapshots.retain(|snapshot| snapshot.time >= cutoff);
+            snapshots = before - data.snapshots.len();
+        })
+        .await?;
+    if backups > 0 || audit_entries > 0 || snapshots > 0 {
+        info!(
+            "Deleted {} backups, {} audit log entries and {} snapshots past their retention",
+            backups, audit_entries, snapshots
+        );
+    }
+    Ok(())
+}
+
+/// Expire old personal data once a day, for as long as the service runs
+pub async fn expire_task(state: Arc<State>) {
+    let Some(retention) = state.retention else {
+        return;
+    };
+    loop {
+        if let Err(e) = expire(&state, retention).await {
+            error!("Failed to delete old personal data: {:?}", e);
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+/// Take everything about the Steam ID out of the stored data, for the tickets
+/// it has. Returns how many entries that removed.
+fn purge_store(data: &mut StoreData, steam_id: u64, tickets: &HashSet<String>) -> usize {
+    let before = data.manual_drivers.len()
+        + data.steam_id_overrides.len()
+        + data.driver_edits.len()
+        + data.approved_names.len()
+        + data.audit_log.len();
+    let steam_id_text = steam_id.to_string();
+    data.manual_drivers
+        .retain(|driver| driver.steam_id != steam_id);
+    data.steam_id_overrides
+        .retain(|_, overridden| *overridden != steam_id);
+    data.driver_edits
+        .retain(|ticket, _| !tickets.contains(ticket));
+    data.approved_names
+        .retain(|ticket, _| !tickets.contains(ticket));
+    data.audit_log.retain(|entry| {
+        !tickets.contains(&entry.ticket_guid)
+            && entry.old.as_ref() != Some(&steam_id_text)
+            && entry.new.as_ref() != Some(&steam_id_text)
+    });
+    before
+        - (data.manual_drivers.len()
+            + data.steam_id_overrides.len()
+            + data.driver_edits.len()
+            + data.approved_names.len()
+            + data.audit_log.len())
+}
+
+#[derive(Debug, Serialize)]
+pub struct Purged {
+    /// Manual drivers, Steam ID corrections, name edits and approvals, and
+    /// audit log entries
+    stored_entries: usize,
+    /// Whether the driver was taken off the grid
+    entry_list: bool,
+    backups: usize,
+    snapshots: usize,
+}
+
+/// Delete everything we have on a driver, on request. A ticket holder comes
+/// back with the next full update, unless the ticket is cancelled or the
+/// Steam ID ignored first.
+async fn purge(state: &State, steam_id: u64) -> Result<Option<Purged>> {
+    let mut tickets: HashSet<String> = state
+        .cached_orders
+        .lock()
+        .await
+        .values()
+        .flat_map(|(drivers, _)| drivers)
+        .filter(|driver| driver.steam_id == steam_id || driver.co_drivers.contains(&steam_id))
+        .filter_map(|driver| driver.ticket_guid.clone())
+        .collect();
+    let mut store = state.store.lock().await;
+    tickets.extend(
+        store
+            .data()
+            .steam_id_overrides
+            .iter()
+            .filter(|(_, overridden)| **overridden == steam_id)
+            .map(|(ticket, _)| ticket.clone()),
+    );
+    let mut stored_entries = 0;
+    let mut snapshots = 0;
+    store
+        .update(|data| {
+            stored_entries = purge_store(data, steam_id, &tickets);
+            snapshots = snapshots::scrub(&mut data.snapshots, steam_id);
+        })
+        .await?;
+    drop(store);
+    for (drivers, _) in state.cached_orders.lock().await.values_mut() {
+        drivers.retain(|driver| driver.steam_id != steam_id);
+        for driver in drivers {
+            driver.co_drivers.retain(|co_driver| *co_driver != steam_id);
+        }
+    }
+    let mut write_gate = state.write_gate.lock().await;
+    if write_gate.is_held() {
+        return Ok(None);
+    }
+    let mut purged = Purged {
+        stored_entries,
+        entry_list: false,
+        backups: 0,
+        snapshots,
+    };
+    for json_file in state.acsm_json_files.lock().await.iter() {
+        purged.entry_list |= acsm::scrub_driver(json_file, steam_id).await?;
+        for (backup, _) in backups::list(json_file).await? {
+            if acsm::scrub_backup(&backup, steam_id).await? {
+                purged.backups += 1;
+            }
+        }
+    }
+    if purged.entry_list {
+        reload::after_change(state, &mut write_gate).await;
+    }
+    drop(write_gate);
+    info!(
+        "Purged personal data of steam_id={}: {:?}",
+        steam_id, purged
+    );
+    Ok(Some(purged))
+}
+
+#[debug_handler]
+pub async fn handle_purge(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(steam_id): extract::Path<u64>,
+) -> Result<Json<Purged>, StatusCode> {
+    match purge(&state, steam_id).await {
+        Ok(Some(purged)) => Ok(Json(purged)),
+        Ok(None) => {
+            warn!(
+                "Writes held back, not purging steam_id={} from the ACSM files",
+                steam_id
+            );
+            Err(StatusCode::CONFLICT)
+        }
+        Err(e) => {
+            error!("Failed to purge personal data: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::self_service::{AuditEntry, DriverEdit};
+
+    fn audit_entry(ticket_guid: &str, new: Option<&str>) -> AuditEntry {
+        AuditEntry {
+            time: Utc::now(),
+            ticket_guid: ticket_guid.to_string(),
+            by: "Steam login".to_string(),
+            field: "steam_id".to_string(),
+            old: None,
+            new: new.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn purges_store() {
+        let edit = DriverEdit {
+            name: "Name".to_string(),
+            team_name: None,
+        };
+        let mut data = StoreData::default();
+        data.steam_id_overrides.insert("t1".to_string(), 1);
+        data.steam_id_overrides.insert("t2".to_string(), 2);
+        data.driver_edits.insert("t1".to_string(), edit.clone());
+        data.driver_edits.insert("t2".to_string(), edit);
+        data.audit_log = vec![
+            audit_entry("t1", None),
+            audit_entry("t3", Some("1")),
+            audit_entry("t2", Some("2")),
+        ];
+        let tickets = HashSet::from(["t1".to_string()]);
+        assert_eq!(purge_store(&mut data, 1, &tickets), 4);
+        assert_eq!(data.steam_id_overrides.keys().collect::<Vec<_>>(), ["t2"]);
+        assert_eq!(data.driver_edits.keys().collect::<Vec<_>>(), ["t2"]);
+        assert_eq!(data.audit_log.len(), 1);
+        assert_eq!(data.audit_log[0].ticket_guid, "t2");
+    }
+}
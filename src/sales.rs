@@ -0,0 +1,298 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    acsm::ClassSlots, capacity::usage_by_class, eventix::TicketType, events::EventKind, sandbox,
+    source::RegistrationSource, ticket_map::TicketMapping, State,
+};
+
+#[derive(Debug, Default)]
+pub struct SalesPolicy {
+    /// Close the sales of ticket types whose classes are full, and reopen them
+    /// when a slot frees up
+    close_when_full: bool,
+    /// Drivers a class can have on the waitlist before it counts as full
+    waitlist_depth: usize,
+    /// Keep the stock of ticket types at the free slots of their classes
+    sync_availability: bool,
+    /// Slots to keep out of the stock, for tickets sold while we update it
+    safety_margin: usize,
+}
+
+impl SalesPolicy {
+    /// From CLOSE_SALES_WHEN_FULL and SALES_WAITLIST_DEPTH
+    pub fn from_env() -> Result<SalesPolicy> {
+        Ok(SalesPolicy {
+            close_when_full: dotenv::var("CLOSE_SALES_WHEN_FULL")
+                .is_ok_and(|value| value == "true"),
+            waitlist_depth: dotenv::var("SALES_WAITLIST_DEPTH")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .context("SALES_WAITLIST_DEPTH is not a number")?,
+            sync_availability: dotenv::var("SYNC_TICKET_AVAILABILITY")
+                .is_ok_and(|value| value == "true"),
+            safety_margin: dotenv::var("AVAILABILITY_SAFETY_MARGIN")
+                .unwrap_or_else(|_| "0".to_string())
+                .parse()
+                .context("AVAILABILITY_SAFETY_MARGIN is not a number")?,
+        })
+    }
+}
+
+/// Classes whose slots and waitlist are all taken
+fn full_classes<'a>(
+    splits: &'a [Vec<ClassSlots>],
+    waitlist: &HashMap<String, usize>,
+    waitlist_depth: usize,
+) -> HashSet<&'a str> {
+    usage_by_class(splits)
+        .into_iter()
+        .filter(|(class, usage)| {
+            let waitlisted = waitlist.get(*class).copied().unwrap_or_default();
+            usage.filled + waitlisted >= usage.slots + waitlist_depth
+        })
+        .map(|(class, _)| class)
+        .collect()
+}
+
+/// The classes drivers with the ticket type could go in. Without a class
+/// name that's every class with the car, since skill routing may pick any of
+/// them.
+fn class_names<'a>(mapping: &TicketMapping, splits: &'a [Vec<ClassSlots>]) -> HashSet<&'a str> {
+    splits
+        .iter()
+        .flatten()
+        .filter(|class| {
+            class.cars.contains(&mapping.car)
+                && mapping
+                    .class
+                    .as_ref()
+                    .is_none_or(|name| name == &class.name)
+        })
+        .map(|class| class.name.as_str())
+        .collect()
+}
+
+/// Whether every class drivers with the ticket type could go in is full
+fn is_sold_out(class_names: &HashSet<&str>, full_classes: &HashSet<&str>) -> bool {
+    !class_names.is_empty() && class_names.is_subset(full_classes)
+}
+
+/// Free slots in the classes, minus the drivers already waiting for one and
+/// the safety margin
+fn available(
+    class_names: &HashSet<&str>,
+    splits: &[Vec<ClassSlots>],
+    waitlist: &HashMap<String, usize>,
+    safety_margin: usize,
+) -> usize {
+    let usage = usage_by_class(splits);
+    class_names
+        .iter()
+        .map(|class| {
+            let usage = usage.get(class).copied().unwrap_or_default();
+            let waitlisted = waitlist.get(*class).copied().unwrap_or_default();
+            usage.slots.saturating_sub(usage.filled + waitlisted)
+        })
+        .sum::<usize>()
+        .saturating_sub(safety_margin)
+}
+
+/// Close the sales of the ticket type if it can't get a slot anymore, or
+/// reopen it if we closed it and it can again
+async fn close_or_reopen(
+    state: &State,
+    source: &dyn RegistrationSource,
+    ticket_type: &TicketType,
+    sold_out: bool,
+) -> Result<()> {
+    let closed = state
+        .store
+        .lock()
+        .await
+        .data()
+        .closed_ticket_types
+        .contains(&ticket_type.guid);
+    if sold_out == closed {
+        return Ok(());
+    }
+    if sandbox::is_on() {
+        info!(
+            "[SANDBOX] Not {} sales of {} ({})",
+            if sold_out { "closing" } else { "reopening" },
+            ticket_type.name,
+            ticket_type.guid
+        );
+        return Ok(());
+    }
+    source
+        .set_sales_open(state, &ticket_type.guid, !sold_out)
+        .await
+        .with_context(|| format!("Failed to open or close sales of {}", ticket_type.name))?;
+    info!(
+        "{} sales of {} ({})",
+        if sold_out { "Closed" } else { "Reopened" },
+        ticket_type.name,
+        ticket_type.guid
+    );
+    state.events.emit(EventKind::SalesChanged {
+        ticket_type: ticket_type.guid.clone(),
+        open: !sold_out,
+    });
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            if sold_out {
+                data.closed_ticket_types.push(ticket_type.guid.clone());
+            } else {
+                data.closed_ticket_types
+                    .retain(|guid| guid != &ticket_type.guid);
+            }
+        })
+        .await
+}
+
+/// Set the stock of the ticket type, unless that's what we set last time
+async fn update_availability(
+    state: &State,
+    source: &dyn RegistrationSource,
+    ticket_type: &TicketType,
+    available: usize,
+) -> Result<()> {
+    let mut ticket_availability = state.ticket_availability.lock().await;
+    if ticket_availability.get(&ticket_type.guid) == Some(&available) {
+        return Ok(());
+    }
+    if sandbox::is_on() {
+        info!(
+            "[SANDBOX] Not setting the stock of {} ({}) to {}",
+            ticket_type.name, ticket_type.guid, available
+        );
+        return Ok(());
+    }
+    source
+        .set_available(state, &ticket_type.guid, available)
+        .await
+        .with_context(|| format!("Failed to set the stock of {}", ticket_type.name))?;
+    info!(
+        "Set the stock of {} ({}) to {}",
+        ticket_type.name, ticket_type.guid, available
+    );
+    ticket_availability.insert(ticket_type.guid.clone(), available);
+    Ok(())
+}
+
+/// Bring the sales of every mapped ticket type in line with the free slots
+pub async fn update(state: &State, splits: &[Vec<ClassSlots>]) -> Result<()> {
+    let policy = &state.sales_policy;
+    if !policy.close_when_full && !policy.sync_availability {
+        return Ok(());
+    }
+    let waitlist = state.class_waitlist.lock().await.clone();
+    let full_classes = full_classes(splits, &waitlist, policy.waitlist_depth);
+    let ticket_map = state.ticket_map().await;
+    for source in &state.sources {
+        let Some(ticket_types) = source.ticket_types(state).await? else {
+            continue;
+        };
+        for ticket_type in ticket_types {
+            if state.add_on_ticket_ids.contains(&ticket_type.guid) {
+                continue;
+            }
+            let Some(mapping) = ticket_map.get(&ticket_type.guid, Some(&ticket_type.name)) else {
+                continue;
+            };
+            let class_names = class_names(mapping, splits);
+            if policy.close_when_full {
+                let sold_out = is_sold_out(&class_names, &full_classes);
+                close_or_reopen(state, source.as_ref(), &ticket_type, sold_out).await?;
+            }
+            if policy.sync_availability && !class_names.is_empty() {
+                let available = available(&class_names, splits, &waitlist, policy.safety_margin);
+                update_availability(state, source.as_ref(), &ticket_type, available).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn class(name: &str, car: &str, filled: usize, slots: usize) -> ClassSlots {
+        ClassSlots {
+            name: name.to_string(),
+            cars: vec![car.to_string()],
+            guids: (0..slots)
+                .map(|slot| {
+                    if slot < filled {
+                        (slot + 1).to_string()
+                    } else {
+                        String::new()
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test_case(2, 0, 0, true; "grid full")]
+    #[test_case(1, 0, 0, false; "free slot")]
+    #[test_case(2, 1, 2, false; "room on the waitlist")]
+    #[test_case(2, 2, 2, true; "waitlist full")]
+    #[test_case(1, 1, 0, true; "free slot but waitlisted")]
+    fn full(filled: usize, waitlisted: usize, waitlist_depth: usize, expected: bool) {
+        let splits = [vec![class("GT3", "gt3", filled, 2)]];
+        let waitlist = HashMap::from([("GT3".to_string(), waitlisted)]);
+        assert_eq!(
+            full_classes(&splits, &waitlist, waitlist_depth).contains("GT3"),
+            expected
+        );
+    }
+
+    #[test]
+    fn sold_out() {
+        // The Pro class is full in both splits, Am only in the first
+        let splits = [
+            vec![class("Pro", "gt3", 2, 2), class("Am", "gt3", 2, 2)],
+            vec![class("Pro", "gt3", 2, 2), class("Am", "gt3", 1, 2)],
+        ];
+        let full_classes = full_classes(&splits, &HashMap::new(), 0);
+        let sold_out = |class: Option<&str>, car: &str| {
+            let mapping = TicketMapping {
+                class: class.map(str::to_string),
+                ..TicketMapping::new(car.to_string())
+            };
+            is_sold_out(&class_names(&mapping, &splits), &full_classes)
+        };
+        assert!(sold_out(Some("Pro"), "gt3"));
+        assert!(!sold_out(Some("Am"), "gt3"));
+        assert!(!sold_out(None, "gt3"));
+        assert!(!sold_out(None, "gt4"));
+    }
+
+    #[test_case(Some("Pro"), 0, 0, 1; "one free")]
+    #[test_case(Some("Pro"), 1, 0, 0; "taken by the waitlist")]
+    #[test_case(None, 0, 0, 4; "both classes")]
+    #[test_case(None, 0, 2, 2; "safety margin")]
+    #[test_case(None, 0, 9, 0; "margin larger than free slots")]
+    fn availability(class_name: Option<&str>, waitlisted: usize, margin: usize, expected: usize) {
+        let splits = [
+            vec![class("Pro", "gt3", 1, 2), class("Am", "gt3", 0, 2)],
+            vec![class("Am", "gt3", 1, 2)],
+        ];
+        let mapping = TicketMapping {
+            class: class_name.map(str::to_string),
+            ..TicketMapping::new("gt3".to_string())
+        };
+        let waitlist = HashMap::from([("Pro".to_string(), waitlisted)]);
+        assert_eq!(
+            available(&class_names(&mapping, &splits), &splits, &waitlist, margin),
+            expected
+        );
+    }
+}
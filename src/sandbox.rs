@@ -0,0 +1,62 @@
+use anyhow::{anyhow, Result};
+use std::sync::OnceLock;
+
+/// From SANDBOX, set once at startup
+static SANDBOX: OnceLock<bool> = OnceLock::new();
+
+fn parse(value: &str) -> Result<bool> {
+    match value {
+        "" | "false" => Ok(false),
+        "true" => Ok(true),
+        _ => Err(anyhow!(
+            "SANDBOX must be `true` or `false`, not `{}`",
+            value
+        )),
+    }
+}
+
+/// Read SANDBOX, for [`is_on`] to return from now on
+pub fn init_from_env() -> Result<()> {
+    let sandbox = parse(&dotenv::var("SANDBOX").unwrap_or_default())?;
+    SANDBOX.get_or_init(|| sandbox);
+    Ok(())
+}
+
+/// Everything runs as usual, but nothing is written to the ACSM files, the
+/// ticket shop or ACSM itself
+pub fn is_on() -> bool {
+    SANDBOX.get().copied().unwrap_or(false)
+}
+
+/// Start notifications with `[SANDBOX]`, so nobody mistakes them for the
+/// real thing
+pub fn mark(text: &str) -> String {
+    mark_if(is_on(), text)
+}
+
+fn mark_if(sandbox: bool, text: &str) -> String {
+    if sandbox {
+        format!("[SANDBOX] {}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses() {
+        assert!(!parse("").unwrap());
+        assert!(!parse("false").unwrap());
+        assert!(parse("true").unwrap());
+        assert!(parse("yes").is_err());
+    }
+
+    #[test]
+    fn marks() {
+        assert_eq!(mark_if(true, "Synced"), "[SANDBOX] Synced");
+        assert_eq!(mark_if(false, "Synced"), "Synced");
+    }
+}
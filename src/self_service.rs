@@ -0,0 +1,355 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    acsm::BasicDriver, events::EventKind, full_update, redact::Secret, report, store::StoreData,
+    State,
+};
+
+/// Bytes of the HMAC in an access code, so 16 hex digits
+const ACCESS_CODE_BYTES: usize = 8;
+
+/// Longest name or team name a driver can enter
+const MAX_NAME_LENGTH: usize = 50;
+
+/// Per-ticket codes for drivers to show a ticket is theirs, enabled by
+/// ACCESS_CODE_SECRET
+pub struct AccessCodes {
+    secret: Secret<String>,
+}
+
+impl AccessCodes {
+    pub fn from_env() -> Option<AccessCodes> {
+        dotenv::var("ACCESS_CODE_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| AccessCodes {
+                secret: Secret::new(secret),
+            })
+    }
+
+    fn mac(&self, ticket_guid: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose().as_bytes())
+            .expect("HMAC takes keys of any size");
+        mac.update(ticket_guid.as_bytes());
+        mac
+    }
+
+    /// The code for the ticket, to email the driver. Derived from the ticket
+    /// GUID, so there's nothing to store.
+    pub fn code(&self, ticket_guid: &str) -> String {
+        hex::encode(&self.mac(ticket_guid).finalize().into_bytes()[..ACCESS_CODE_BYTES])
+    }
+
+    pub fn check(&self, ticket_guid: &str, code: &str) -> bool {
+        let Ok(code) = hex::decode(code.trim()) else {
+            return false;
+        };
+        code.len() == ACCESS_CODE_BYTES
+            && self.mac(ticket_guid).verify_truncated_left(&code).is_ok()
+    }
+}
+
+/// A ticket in a driver's order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderTicket {
+    pub guid: String,
+    /// The driver's name, if we could read the ticket
+    pub description: String,
+}
+
+/// Name and team name as corrected by the driver, replacing the ones from
+/// the ticket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DriverEdit {
+    pub name: String,
+    pub team_name: Option<String>,
+}
+
+/// A change to a driver's details after the ticket, usually by the driver
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub ticket_guid: String,
+    /// Who made the change, like `Steam login`, `Discord user name (id)` or
+    /// `transliteration`
+    pub by: String,
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// The tickets of a paid order in the first ticket source. Includes the
+/// tickets we couldn't make a driver of, since a bad Steam ID is what drivers
+/// usually come to fix. `None` while there's no OAuth2 token yet.
+async fn order_tickets(state: &State, order: &str) -> Result<Option<Vec<OrderTicket>>> {
+    let mut report = report::Report::default();
+    let Some(drivers) = state.sources[0]
+        .fetch_delta(state, order, &mut report)
+        .await?
+    else {
+        return Ok(None);
+    };
+    Ok(Some(
+        drivers
+            .into_iter()
+            .filter_map(|driver| {
+                Some(OrderTicket {
+                    guid: driver.ticket_guid?,
+                    description: driver.name,
+                })
+            })
+            .chain(report.problems.into_iter().filter_map(|problem| {
+                if problem.kind != report::ProblemKind::BadMetadata {
+                    return None;
+                }
+                Some(OrderTicket {
+                    guid: problem.ticket_guid?,
+                    description: "no valid Steam ID".to_string(),
+                })
+            }))
+            .collect(),
+    ))
+}
+
+/// The ticket of a paid order that the access code is for. The order number
+/// is shared with everyone in the order, so the code is what makes it the
+/// driver's own, and they don't get to see the other tickets. `None` while
+/// there's no OAuth2 token yet.
+pub async fn own_ticket(state: &State, order: &str, code: &str) -> Result<Option<OrderTicket>> {
+    let access_codes = state
+        .access_codes
+        .as_ref()
+        .ok_or_else(|| anyhow!("Access codes need ACCESS_CODE_SECRET"))?;
+    let Some(tickets) = order_tickets(state, order).await? else {
+        return Ok(None);
+    };
+    tickets
+        .into_iter()
+        .find(|ticket| access_codes.check(&ticket.guid, code))
+        .map(Some)
+        .ok_or_else(|| anyhow!("No ticket in order {} has that access code", order))
+}
+
+/// Record the change in the store's audit log and on the events stream
+fn audit(state: &State, data: &mut StoreData, entry: AuditEntry) {
+    info!(
+        "{} changed {} for ticket {} from {:?} to {:?}",
+        entry.by, entry.field, entry.ticket_guid, entry.old, entry.new
+    );
+    state.events.emit(EventKind::DriverChanged {
+        ticket_guid: entry.ticket_guid.clone(),
+        by: entry.by.clone(),
+        field: entry.field.clone(),
+        new: entry.new.clone(),
+    });
+    data.audit_log.push(entry);
+}
+
+/// Record changes we make on every sync, like transliteration, only the first
+/// time
+pub async fn audit_once(state: &State, mut entries: Vec<AuditEntry>) {
+    let mut store = state.store.lock().await;
+    entries.retain(|entry| {
+        !store.data().audit_log.iter().any(|logged| {
+            logged.by == entry.by
+                && logged.ticket_guid == entry.ticket_guid
+                && logged.field == entry.field
+                && logged.old == entry.old
+                && logged.new == entry.new
+        })
+    });
+    if entries.is_empty() {
+        return;
+    }
+    let result = store
+        .update(|data| {
+            for entry in entries {
+                audit(state, data, entry);
+            }
+        })
+        .await;
+    if let Err(e) = result {
+        error!("Failed to store audit log: {:?}", e);
+    }
+}
+
+/// Store the Steam ID for the ticket and run a full update, which replaces the
+/// driver if they were already placed under the old one
+pub async fn set_steam_id(
+    state: &Arc<State>,
+    ticket: &OrderTicket,
+    steam_id: u64,
+    by: &str,
+) -> Result<()> {
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            let old = data
+                .steam_id_overrides
+                .insert(ticket.guid.clone(), steam_id);
+            audit(
+                state,
+                data,
+                AuditEntry {
+                    time: Utc::now(),
+                    ticket_guid: ticket.guid.clone(),
+                    by: by.to_string(),
+                    field: "steam_id".to_string(),
+                    old: old.map(|old| old.to_string()),
+                    new: Some(steam_id.to_string()),
+                },
+            );
+        })
+        .await?;
+    tokio::spawn(full_update(state.clone()));
+    Ok(())
+}
+
+/// Trim the names, and refuse ones that don't fit in an entry list
+pub fn clean_edit(name: &str, team_name: &str) -> Result<DriverEdit> {
+    let name = name.trim();
+    let team_name = team_name.trim();
+    if name.is_empty() {
+        return Err(anyhow!("The name can't be empty"));
+    }
+    for text in [name, team_name] {
+        if text.chars().count() > MAX_NAME_LENGTH {
+            return Err(anyhow!(
+                "Names can be at most {} characters",
+                MAX_NAME_LENGTH
+            ));
+        }
+        if text.chars().any(char::is_control) {
+            return Err(anyhow!("Names can't contain control characters"));
+        }
+    }
+    Ok(DriverEdit {
+        name: name.to_string(),
+        team_name: Some(team_name.to_string()).filter(|team_name| !team_name.is_empty()),
+    })
+}
+
+/// Store the names for the driver's ticket and run a full update to write
+/// them out
+pub async fn edit_driver(
+    state: &Arc<State>,
+    driver: &BasicDriver,
+    edit: DriverEdit,
+    by: &str,
+) -> Result<()> {
+    let ticket_guid = driver
+        .ticket_guid
+        .clone()
+        .ok_or_else(|| anyhow!("Driver has no ticket"))?;
+    state
+        .store
+        .lock()
+        .await
+        .update(|data| {
+            for (field, old, new) in [
+                ("name", Some(&driver.name), Some(&edit.name)),
+                (
+                    "team_name",
+                    driver.team_name.as_ref(),
+                    edit.team_name.as_ref(),
+                ),
+            ] {
+                if old != new {
+                    audit(
+                        state,
+                        data,
+                        AuditEntry {
+                            time: Utc::now(),
+                            ticket_guid: ticket_guid.clone(),
+                            by: by.to_string(),
+                            field: field.to_string(),
+                            old: old.cloned(),
+                            new: new.cloned(),
+                        },
+                    );
+                }
+            }
+            data.driver_edits.insert(ticket_guid.clone(), edit);
+        })
+        .await?;
+    tokio::spawn(full_update(state.clone()));
+    Ok(())
+}
+
+/// Put the drivers' own corrections over what came from the tickets
+pub fn apply_edits(edits: &HashMap<String, DriverEdit>, drivers: &mut [BasicDriver]) {
+    for driver in drivers {
+        let Some(edit) = driver
+            .ticket_guid
+            .as_ref()
+            .and_then(|ticket_guid| edits.get(ticket_guid))
+        else {
+            continue;
+        };
+        driver.name = edit.name.clone();
+        driver.team_name = edit.team_name.clone();
+    }
+}
+
+/// The driver for the ticket as of the last full update, with any edits
+pub async fn find_driver(state: &State, ticket_guid: &str) -> Option<BasicDriver> {
+    let mut driver = state
+        .cached_orders
+        .lock()
+        .await
+        .values()
+        .flat_map(|(drivers, _)| drivers)
+        .find(|driver| driver.ticket_guid.as_deref() == Some(ticket_guid))
+        .cloned()?;
+    apply_edits(
+        &state.store.lock().await.data().driver_edits,
+        std::slice::from_mut(&mut driver),
+    );
+    Some(driver)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn access_codes() {
+        let access_codes = AccessCodes {
+            secret: Secret::new("secret".to_string()),
+        };
+        let code = access_codes.code("ticket-1");
+        assert_eq!(code.len(), ACCESS_CODE_BYTES * 2);
+        assert!(access_codes.check("ticket-1", &code));
+        assert!(access_codes.check("ticket-1", &code.to_uppercase()));
+        assert!(!access_codes.check("ticket-2", &code));
+        assert!(!access_codes.check("ticket-1", &code[..8]));
+        assert!(!access_codes.check("ticket-1", ""));
+        let other = AccessCodes {
+            secret: Secret::new("other".to_string()),
+        };
+        assert!(!other.check("ticket-1", &code));
+    }
+
+    #[test_case(" Max Power ", "", Some(("Max Power", None)); "no team")]
+    #[test_case("Max Power", " Power, Inc ", Some(("Max Power", Some("Power, Inc"))); "team")]
+    #[test_case("  ", "Power, Inc", None; "empty name")]
+    #[test_case("Max\nPower", "", None; "newline")]
+    #[test_case(&"x".repeat(51), "", None; "too long")]
+    fn edit(name: &str, team_name: &str, expected: Option<(&str, Option<&str>)>) {
+        let edit = clean_edit(name, team_name).ok();
+        assert_eq!(
+            edit.as_ref()
+                .map(|edit| (edit.name.as_str(), edit.team_name.as_deref())),
+            expected
+        );
+    }
+}
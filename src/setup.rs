@@ -0,0 +1,483 @@
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+use tokio::{fs, sync::Mutex};
+
+use crate::{
+    acsm, config, eventix, http, oauth2,
+    redact::Secret,
+    store,
+    ticket_map::{self, TicketMap, TicketMapping},
+    tickets,
+};
+
+/// The EVENTIX_METADATA_ settings, with the start of a word that the field's
+/// name usually has and whether it's required
+pub const METADATA_SETTINGS: [(&str, &str, bool); 7] = [
+    ("FIRST_NAME", "first", true),
+    ("LAST_NAME", "last", true),
+    ("TEAM_NAME", "team", true),
+    ("STEAM_ID", "steam", true),
+    ("NATIONALITY", "nation", false),
+    ("SKILL", "skill", false),
+    ("PACE", "pace", false),
+];
+
+/// What a ticket type is for
+#[derive(Debug, PartialEq)]
+enum TicketChoice {
+    Car(String),
+    AddOn,
+    /// Not a driver's ticket, like merch
+    Skip,
+}
+
+/// An answer to the car question: the number or name of a car, `a` for an
+/// add-on, or nothing. `None` if it's none of those.
+fn parse_ticket_choice(answer: &str, cars: &[String]) -> Option<TicketChoice> {
+    match answer {
+        "" => Some(TicketChoice::Skip),
+        "a" => Some(TicketChoice::AddOn),
+        _ => match answer.parse::<usize>() {
+            Ok(number) => cars
+                .get(number.checked_sub(1)?)
+                .map(|car| TicketChoice::Car(car.clone())),
+            Err(_) => cars
+                .iter()
+                .find(|car| *car == answer)
+                .map(|car| TicketChoice::Car(car.clone())),
+        },
+    }
+}
+
+/// The first field with a word in its name that starts with this one, so
+/// `team` doesn't pick `Steam ID`
+pub fn guess_field(fields: &[eventix::MetadataField], word: &str) -> Option<usize> {
+    fields.iter().position(|field| {
+        field
+            .name
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|name_word| name_word.starts_with(word))
+    })
+}
+
+/// Set the settings in the text of a `.env` file, replacing their lines if
+/// they're there and adding them at the end if not. Everything else is kept.
+fn update_env(text: &str, settings: &[(String, String)]) -> String {
+    let mut done = vec![false; settings.len()];
+    let mut lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            let name = line.split_once('=').map(|(name, _)| name.trim());
+            match settings
+                .iter()
+                .position(|(setting, _)| Some(setting.as_str()) == name)
+            {
+                Some(index) => {
+                    done[index] = true;
+                    format!("{}={}", settings[index].0, settings[index].1)
+                }
+                None => line.to_string(),
+            }
+        })
+        .collect();
+    for ((name, value), done) in settings.iter().zip(done) {
+        if !done {
+            lines.push(format!("{}={}", name, value));
+        }
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+fn prompt(question: &str) -> Result<String> {
+    print!("{}: ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Err(anyhow!("Setup aborted"));
+    }
+    Ok(answer.trim().to_string())
+}
+
+/// The setting's current value, or ask for it. The second value is whether it
+/// was asked, and so needs writing out.
+fn setting_or_prompt(name: &str, question: &str) -> Result<(String, bool)> {
+    if let Ok(value) = dotenv::var(name) {
+        if !value.is_empty() {
+            return Ok((value, false));
+        }
+    }
+    loop {
+        let value = prompt(question)?;
+        if !value.is_empty() {
+            return Ok((value, true));
+        }
+    }
+}
+
+/// Ask for a metadata field by number, offering the guess as default
+fn choose_field(
+    setting: &str,
+    fields: &[eventix::MetadataField],
+    guess: Option<usize>,
+    required: bool,
+) -> Result<Option<usize>> {
+    let mut question = format!("Field for {}", setting);
+    if let Some(guess) = guess {
+        question.push_str(&format!(" [{}]", guess + 1));
+    }
+    if !required {
+        question.push_str(", `-` for none");
+    }
+    loop {
+        let answer = prompt(&question)?;
+        match answer.as_str() {
+            "" if guess.is_some() => return Ok(guess),
+            "-" if !required => return Ok(None),
+            _ => {
+                if let Some(index) = answer
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|number| number.checked_sub(1))
+                    .filter(|index| *index < fields.len())
+                {
+                    return Ok(Some(index));
+                }
+            }
+        }
+        println!("Enter one of the numbers above");
+    }
+}
+
+/// An Eventix access token without the server, from the stored refresh token
+/// or by authorizing
+async fn eventix_token(http: &http::HttpPolicies) -> Result<Secret<String>> {
+    let store = Mutex::new(store::Store::from_env().await?);
+    let client = oauth2::setup_oauth2_client("EVENTIX_OAUTH2").await?.client;
+    oauth2::local_token(
+        client,
+        &http.oauth2,
+        &store,
+        "eventix",
+        oauth2::local_callback_address()?,
+    )
+    .await
+}
+
+/// For `eventix2acsm setup`: list the event's ticket types and the cars in the
+/// ACSM files, ask which car goes with each ticket type and which metadata
+/// fields hold the driver's details, and write the answers to `.env`
+pub async fn run() -> Result<()> {
+    let env_file = env_file();
+    let mut settings = Vec::new();
+    let (event_guid, asked) = setting_or_prompt("EVENTIX_EVENT_GUID", "Eventix event GUID")?;
+    if asked {
+        settings.push(("EVENTIX_EVENT_GUID".to_string(), event_guid.clone()));
+    }
+    let (json_files, asked) = setting_or_prompt(
+        "ACSM_JSON_FILE",
+        "Path to the Championship JSON file, comma separated for multiple",
+    )?;
+    if asked {
+        settings.push(("ACSM_JSON_FILE".to_string(), json_files.clone()));
+    }
+    let cars = cars(&json_files).await?;
+
+    let http = http::HttpPolicies::from_env()?;
+    let token = eventix_token(&http).await?;
+    let api = eventix::Api {
+        http: &http.eventix,
+        token: token.expose(),
+    };
+    let ticket_types = eventix::get_ticket_types(api, &event_guid).await?;
+    if ticket_types.is_empty() {
+        return Err(anyhow!("Event {} has no ticket types", event_guid));
+    }
+
+    println!("Cars:");
+    for (number, (car, class)) in cars.iter().enumerate() {
+        println!("{:3}. {} ({})", number + 1, car, class);
+    }
+    let car_names: Vec<_> = cars.into_iter().map(|(car, _)| car).collect();
+    // Since it's what's being set up, it may well not be valid yet
+    let mut current = TicketMap::from_env().unwrap_or_default();
+    // Name patterns and the ignore list can only be set in TICKET_MAP_FILE,
+    // so keep them
+    let mut ticket_map = TicketMap {
+        tickets: BTreeMap::new(),
+        patterns: current.patterns.clone(),
+        ignore: current.ignore.clone(),
+    };
+    let mut add_ons = Vec::new();
+    for ticket_type in &ticket_types {
+        let current = current.tickets.remove(&ticket_type.guid);
+        let mut question = format!(
+            "Car for ticket `{}`: number, `a` for an add-on, ",
+            ticket_type.name
+        );
+        match ticket_map.get(&ticket_type.guid, Some(&ticket_type.name)) {
+            Some(pattern) => question.push_str(&format!(
+                "empty to leave it to the name pattern for {}",
+                pattern.car
+            )),
+            None => question.push_str("empty to skip"),
+        }
+        if let Some(current) = &current {
+            question.push_str(&format!(", `=` for {}", current.car));
+        }
+        let choice = loop {
+            let answer = prompt(&question)?;
+            if let (Some(current), "=") = (&current, answer.as_str()) {
+                break TicketChoice::Car(current.car.clone());
+            }
+            if let Some(choice) = parse_ticket_choice(&answer, &car_names) {
+                break choice;
+            }
+            println!("Enter one of the numbers above");
+        };
+        match choice {
+            // Keeping any other settings from TICKET_MAP_FILE
+            TicketChoice::Car(car) => {
+                let mapping = match current {
+                    Some(current) => TicketMapping { car, ..current },
+                    None => TicketMapping::new(car),
+                };
+                ticket_map.tickets.insert(ticket_type.guid.clone(), mapping);
+            }
+            TicketChoice::AddOn => add_ons.push(ticket_type.guid.clone()),
+            TicketChoice::Skip => {}
+        }
+    }
+    let is_mapped = |ticket_type: &eventix::TicketType| {
+        ticket_map
+            .get(&ticket_type.guid, Some(&ticket_type.name))
+            .is_some()
+    };
+    if !ticket_types.iter().any(is_mapped) {
+        return Err(anyhow!("No ticket type has a car, nothing to set up"));
+    }
+    settings.push(("ADD_ON_TICKET_IDS".to_string(), add_ons.join(",")));
+
+    let fields = metadata_fields(api, ticket_types.iter().filter(|t| is_mapped(t))).await?;
+    println!("Metadata fields:");
+    for (number, field) in fields.iter().enumerate() {
+        println!("{:3}. {}", number + 1, field.name);
+    }
+    for (setting, word, required) in METADATA_SETTINGS {
+        let index = choose_field(setting, &fields, guess_field(&fields, word), required)?;
+        settings.push((
+            format!("EVENTIX_METADATA_{}", setting),
+            index
+                .map(|index| fields[index].guid.clone())
+                .unwrap_or_default(),
+        ));
+    }
+
+    save(&env_file, settings, &ticket_map).await
+}
+
+/// Every car in the ACSM files, given comma separated, once with the first
+/// class it's in
+pub async fn cars(json_files: &str) -> Result<Vec<(String, String)>> {
+    let mut cars: Vec<(String, String)> = Vec::new();
+    for json_file in json_files.split(',') {
+        for class in acsm::class_slots(Path::new(json_file)).await? {
+            for car in class.cars {
+                if !cars.iter().any(|(known, _)| *known == car) {
+                    cars.push((car, class.name.clone()));
+                }
+            }
+        }
+    }
+    if cars.is_empty() {
+        return Err(anyhow!("No cars in the Championship's classes"));
+    }
+    Ok(cars)
+}
+
+/// The metadata fields of the ticket types, each once
+pub async fn metadata_fields(
+    api: eventix::Api<'_>,
+    ticket_types: impl IntoIterator<Item = &eventix::TicketType>,
+) -> Result<Vec<eventix::MetadataField>> {
+    let mut fields: Vec<eventix::MetadataField> = Vec::new();
+    for ticket_type in ticket_types {
+        for field in eventix::get_metadata_fields(api, &ticket_type.guid)
+            .await
+            .with_context(|| format!("Failed to get metadata of ticket {}", ticket_type.name))?
+        {
+            if !fields.iter().any(|known| known.guid == field.guid) {
+                fields.push(field);
+            }
+        }
+    }
+    if fields.is_empty() {
+        return Err(anyhow!("The tickets have no metadata fields"));
+    }
+    Ok(fields)
+}
+
+/// The `.env` file that was loaded, or the one to create
+pub fn env_file() -> PathBuf {
+    dotenv::dotenv().unwrap_or_else(|_| PathBuf::from(".env"))
+}
+
+/// Write the ticket map to TICKET_MAP_FILE, or else to TICKET_ID_TO_CAR_MAP,
+/// and the settings to `.env`. Without a `.env` yet, it starts out as a copy
+/// of `.env-template`.
+pub async fn save(
+    env_file: &Path,
+    mut settings: Vec<(String, String)>,
+    ticket_map: &TicketMap,
+) -> Result<()> {
+    match ticket_map::path_from_env() {
+        Some(path) => {
+            ticket_map.save(&path).await?;
+            println!("Wrote the ticket map to {}", path.display());
+        }
+        None => settings.push((
+            "TICKET_ID_TO_CAR_MAP".to_string(),
+            ticket_map
+                .tickets
+                .iter()
+                .map(|(guid, mapping)| format!("{}:{}", guid, mapping.car))
+                .join(","),
+        )),
+    }
+    let text = match fs::read_to_string(env_file).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => config::TEMPLATE.to_string(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", env_file.display())),
+    };
+    fs::write(env_file, update_env(&text, &settings))
+        .await
+        .with_context(|| format!("Failed to write {}", env_file.display()))?;
+    println!(
+        "Wrote {} settings to {}",
+        settings.len(),
+        env_file.display()
+    );
+    Ok(())
+}
+
+/// For `eventix2acsm list-tickets`: print the event's ticket types and their
+/// metadata fields with GUIDs, and what the configuration does with them
+pub async fn list_tickets() -> Result<()> {
+    let event_guid = dotenv::var("EVENTIX_EVENT_GUID").context("EVENTIX_EVENT_GUID not set")?;
+    let http = http::HttpPolicies::from_env()?;
+    let token = eventix_token(&http).await?;
+    let api = eventix::Api {
+        http: &http.eventix,
+        token: token.expose(),
+    };
+    let ticket_map = TicketMap::from_env().ok();
+    let add_ons = dotenv::var("ADD_ON_TICKET_IDS").unwrap_or_default();
+    let add_ons: Vec<_> = add_ons.split(',').collect();
+    let metadata_ids = tickets::MetaDataIDs::from_env("EVENTIX_METADATA").ok();
+    for ticket_type in eventix::get_ticket_types(api, &event_guid).await? {
+        let mapping = ticket_map
+            .as_ref()
+            .and_then(|ticket_map| ticket_map.get(&ticket_type.guid, Some(&ticket_type.name)));
+        let mapping = match mapping {
+            Some(TicketMapping {
+                car,
+                class: Some(class),
+                ..
+            }) => format!("car {} in class {}", car, class),
+            Some(mapping) => format!("car {}", mapping.car),
+            None if add_ons.contains(&ticket_type.guid.as_str()) => "add-on".to_string(),
+            None => "not mapped".to_string(),
+        };
+        println!("{} {} ({})", ticket_type.guid, ticket_type.name, mapping);
+        for field in eventix::get_metadata_fields(api, &ticket_type.guid).await? {
+            let setting = metadata_ids
+                .as_ref()
+                .and_then(|ids| metadata_setting(ids, &field.guid));
+            match setting {
+                Some(setting) => println!(
+                    "    {} {} (EVENTIX_METADATA_{})",
+                    field.guid, field.name, setting
+                ),
+                None => println!("    {} {}", field.guid, field.name),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Which of the EVENTIX_METADATA_ settings has this field
+fn metadata_setting(ids: &tickets::MetaDataIDs, guid: &str) -> Option<&'static str> {
+    [
+        ("FIRST_NAME", Some(&ids.first_name)),
+        ("LAST_NAME", Some(&ids.last_name)),
+        ("TEAM_NAME", Some(&ids.team_name)),
+        ("STEAM_ID", Some(&ids.steam_id)),
+        ("NATIONALITY", ids.nationality.as_ref()),
+        ("SKILL", ids.skill.as_ref()),
+        ("PACE", ids.pace.as_ref()),
+    ]
+    .into_iter()
+    .find(|(_, id)| id.is_some_and(|id| id == guid))
+    .map(|(setting, _)| setting)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("", Some(TicketChoice::Skip); "skip")]
+    #[test_case("a", Some(TicketChoice::AddOn); "add-on")]
+    #[test_case("2", Some(TicketChoice::Car("ks_porsche_911_gt3_r_2016".to_string())); "number")]
+    #[test_case("bmw_m6_gt3", Some(TicketChoice::Car("bmw_m6_gt3".to_string())); "name")]
+    #[test_case("0", None; "zero")]
+    #[test_case("3", None; "out of range")]
+    #[test_case("audi", None; "unknown car")]
+    fn ticket_choice(answer: &str, expected: Option<TicketChoice>) {
+        let cars = [
+            "bmw_m6_gt3".to_string(),
+            "ks_porsche_911_gt3_r_2016".to_string(),
+        ];
+        assert_eq!(parse_ticket_choice(answer, &cars), expected);
+    }
+
+    #[test]
+    fn guess() {
+        let fields: Vec<_> = ["First name", "Last name", "Steam ID", "Nationality"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| eventix::MetadataField {
+                guid: index.to_string(),
+                name: name.to_string(),
+            })
+            .collect();
+        assert_eq!(guess_field(&fields, "steam"), Some(2));
+        assert_eq!(guess_field(&fields, "team"), None);
+        assert_eq!(guess_field(&fields, "nation"), Some(3));
+        assert_eq!(guess_field(&fields, "pace"), None);
+    }
+
+    #[test]
+    fn env_text() {
+        let text = "# The map\nTICKET_ID_TO_CAR_MAP=old:car\nSPLIT_POLICY=overflow\n";
+        let settings = [
+            (
+                "TICKET_ID_TO_CAR_MAP".to_string(),
+                "1:bmw_m6_gt3".to_string(),
+            ),
+            ("ADD_ON_TICKET_IDS".to_string(), "2".to_string()),
+        ];
+        assert_eq!(
+            update_env(text, &settings),
+            "# The map\nTICKET_ID_TO_CAR_MAP=1:bmw_m6_gt3\nSPLIT_POLICY=overflow\n\
+             ADD_ON_TICKET_IDS=2\n"
+        );
+    }
+}
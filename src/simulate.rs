@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use axum::{extract, http::StatusCode, Json};
+use axum_macros::debug_handler;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    acsm::BasicDriver,
+    diff::{self, Change},
+    report::{Problem, Report},
+    State,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SimulateQuery {
+    /// As in TICKET_SOURCE, the first one if not given
+    source: Option<String>,
+    /// Only show what would change, unless this is `false`
+    dry_run: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Simulation {
+    dry_run: bool,
+    /// From the order, after everything a webhook does to them
+    drivers: Vec<BasicDriver>,
+    changes: Vec<Change>,
+    problems: Vec<Problem>,
+}
+
+/// Run an order through everything an order-paid webhook does with it, and
+/// unless it's a dry run place its drivers too
+async fn simulate(
+    state: &State,
+    source: Option<&str>,
+    dry_run: bool,
+    order: &serde_json::Value,
+) -> Result<Simulation> {
+    let source = match source {
+        Some(name) => state
+            .source(name)
+            .with_context(|| format!("No ticket source {}", name))?,
+        None => state.sources[0].as_ref(),
+    };
+    let mut report = Report::default();
+    let mut drivers = source.parse_order(state, order, &mut report).await?;
+    let order_guid = order["guid"].as_str().unwrap_or_default();
+    let others = state.other_drivers(order_guid).await;
+    state
+        .prepare_drivers(&mut drivers, &others, &mut report)
+        .await;
+    let diff = diff::diff_drivers(state, &drivers, false, report.clone()).await?;
+    if !dry_run {
+        info!(
+            "Placing {} drivers of simulated order {}",
+            drivers.len(),
+            order_guid
+        );
+        state
+            .place_drivers(&drivers, false, &mut report)
+            .await
+            .context("Failed to place the drivers")?;
+    }
+    Ok(Simulation {
+        dry_run,
+        drivers,
+        changes: diff.changes,
+        problems: diff.problems,
+    })
+}
+
+/// `POST /admin/v1/simulate/order-paid` with an order as the source's API
+/// returns it, to test the whole pipeline without buying a ticket
+#[debug_handler]
+pub async fn handle_order_paid(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Query(query): extract::Query<SimulateQuery>,
+    Json(order): Json<serde_json::Value>,
+) -> Result<Json<Simulation>, (StatusCode, String)> {
+    simulate(
+        &state,
+        query.source.as_deref(),
+        query.dry_run.unwrap_or(true),
+        &order,
+    )
+    .await
+    .map(Json)
+    .map_err(|e| {
+        error!("Simulated order-paid failed: {:?}", e);
+        (StatusCode::BAD_REQUEST, format!("{:#}", e))
+    })
+}
@@ -0,0 +1,233 @@
+use anyhow::Result;
+use axum::{extract, http::StatusCode, response::Html, Json};
+use axum_macros::debug_handler;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use crate::{acsm, reload, timezone, State};
+
+/// Copies of all ACSM files, taken through the admin API to go back to later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: u64,
+    pub label: String,
+    pub time: DateTime<Utc>,
+    /// The contents of every ACSM file, by ACSM_JSON_FILE entry
+    pub files: BTreeMap<PathBuf, Value>,
+}
+
+/// A snapshot without the files, for listing
+#[derive(Debug, Serialize)]
+pub struct SnapshotSummary {
+    id: u64,
+    label: String,
+    #[serde(serialize_with = "timezone::serialize")]
+    time: DateTime<Utc>,
+    files: Vec<PathBuf>,
+}
+
+impl From<&Snapshot> for SnapshotSummary {
+    fn from(snapshot: &Snapshot) -> Self {
+        SnapshotSummary {
+            id: snapshot.id,
+            label: snapshot.label.clone(),
+            time: snapshot.time,
+            files: snapshot.files.keys().cloned().collect(),
+        }
+    }
+}
+
+/// Take the Steam ID out of every snapshot, returning how many had it
+pub fn scrub(snapshots: &mut [Snapshot], steam_id: u64) -> usize {
+    let steam_id = steam_id.to_string();
+    let mut scrubbed = 0;
+    for snapshot in snapshots {
+        let mut found = false;
+        for data in snapshot.files.values_mut() {
+            found |= acsm::scrub_steam_id(data, &steam_id).unwrap_or(false);
+        }
+        if found {
+            scrubbed += 1;
+        }
+    }
+    scrubbed
+}
+
+async fn take(state: &State, label: String) -> Result<Snapshot> {
+    let mut files = BTreeMap::new();
+    for json_file in state.acsm_json_files.lock().await.iter() {
+        files.insert(json_file.clone(), acsm::read_grid(json_file).await?);
+    }
+    let mut store = state.store.lock().await;
+    let snapshot = Snapshot {
+        id: store
+            .data()
+            .snapshots
+            .iter()
+            .map(|snapshot| snapshot.id + 1)
+            .max()
+            .unwrap_or(1),
+        label,
+        time: Utc::now(),
+        files,
+    };
+    store
+        .update(|data| data.snapshots.push(snapshot.clone()))
+        .await?;
+    info!("Took snapshot {}: {}", snapshot.id, snapshot.label);
+    Ok(snapshot)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TakeSnapshotRequest {
+    label: String,
+}
+
+#[debug_handler]
+pub async fn handle_take(
+    extract::State(state): extract::State<Arc<State>>,
+    Json(request): Json<TakeSnapshotRequest>,
+) -> Result<Json<SnapshotSummary>, StatusCode> {
+    if request.label.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match take(&state, request.label).await {
+        Ok(snapshot) => Ok(Json(SnapshotSummary::from(&snapshot))),
+        Err(e) => {
+            error!("Failed to take snapshot: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[debug_handler]
+pub async fn handle_list(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Json<Vec<SnapshotSummary>> {
+    Json(
+        state
+            .store
+            .lock()
+            .await
+            .data()
+            .snapshots
+            .iter()
+            .map(SnapshotSummary::from)
+            .collect(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct Restored {
+    /// Files that were different from the snapshot
+    changed: Vec<PathBuf>,
+}
+
+/// Write the snapshot back to the ACSM files. They all have to still be in
+/// ACSM_JSON_FILE, or nothing is written.
+#[debug_handler]
+pub async fn handle_restore(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(id): extract::Path<u64>,
+) -> Result<Json<Restored>, StatusCode> {
+    let snapshot = state
+        .store
+        .lock()
+        .await
+        .data()
+        .snapshots
+        .iter()
+        .find(|snapshot| snapshot.id == id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let json_files = state.acsm_json_files.lock().await.clone();
+    if let Some(json_file) = snapshot
+        .files
+        .keys()
+        .find(|json_file| !json_files.contains(json_file))
+    {
+        warn!(
+            "Can't restore snapshot {}, {} is no longer an ACSM file",
+            id,
+            json_file.display()
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let mut write_gate = state.write_gate.lock().await;
+    if write_gate.is_held() {
+        warn!("Writes held back, not restoring snapshot {}", id);
+        return Err(StatusCode::CONFLICT);
+    }
+    info!("Restoring snapshot {}: {}", id, snapshot.label);
+    let mut changed = Vec::new();
+    for (json_file, data) in &snapshot.files {
+        match acsm::restore_grid(json_file, data).await {
+            Ok(true) => changed.push(json_file.clone()),
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to restore snapshot {}: {:?}", id, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+    if !changed.is_empty() {
+        reload::after_change(&state, &mut write_gate).await;
+    }
+    Ok(Json(Restored { changed }))
+}
+
+#[debug_handler]
+pub async fn handle_delete(
+    extract::State(state): extract::State<Arc<State>>,
+    extract::Path(id): extract::Path<u64>,
+) -> Result<Html<&'static str>, StatusCode> {
+    let mut store = state.store.lock().await;
+    if !store
+        .data()
+        .snapshots
+        .iter()
+        .any(|snapshot| snapshot.id == id)
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    store
+        .update(|data| data.snapshots.retain(|snapshot| snapshot.id != id))
+        .await
+        .map_err(|e| {
+            error!("Failed to delete snapshot: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    info!("Deleted snapshot {}", id);
+    Ok(Html("snapshot deleted"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn scrubs_snapshots() {
+        let snapshot = |guid: &str| Snapshot {
+            id: 1,
+            label: "pre-quali grid".to_string(),
+            time: Utc::now(),
+            files: BTreeMap::from([(
+                PathBuf::from("c.json"),
+                json!({"Classes": [{"Entrants": {"CAR_0": {"Name": "A", "GUID": guid}}}]}),
+            )]),
+        };
+        let mut snapshots = vec![snapshot("1"), snapshot("2;1"), snapshot("3")];
+        assert_eq!(scrub(&mut snapshots, 1), 2);
+        let guid = |snapshot: &Snapshot| {
+            snapshot.files[&PathBuf::from("c.json")]["Classes"][0]["Entrants"]["CAR_0"]["GUID"]
+                .clone()
+        };
+        assert_eq!(guid(&snapshots[0]), "");
+        assert_eq!(guid(&snapshots[1]), "2");
+        assert_eq!(guid(&snapshots[2]), "3");
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use tokio::sync::Mutex;
+
+use crate::{
+    acsm::BasicDriver,
+    csv_source::CsvSource,
+    eventbrite::Eventbrite,
+    eventix::{Eventix, TicketType},
+    oauth2::OAuth2State,
+    pretix::Pretix,
+    report::Report,
+    ticket_map::TicketMap,
+    State, WebhookPayload,
+};
+
+/// Somewhere registrations come from. Several can feed the same grid, e.g.
+/// tickets plus a CSV of invited drivers.
+#[async_trait]
+pub trait RegistrationSource: Send + Sync {
+    /// As in TICKET_SOURCE and the URL paths
+    fn name(&self) -> &'static str;
+
+    /// Everything currently registered, None while waiting for an OAuth2
+    /// token
+    async fn fetch_all(
+        &self,
+        state: &State,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>>;
+
+    /// The drivers in one order, after a webhook
+    async fn fetch_delta(
+        &self,
+        state: &State,
+        order_id: &str,
+        report: &mut Report,
+    ) -> Result<Option<Vec<BasicDriver>>>;
+
+    /// Whether to take webhooks at `/<name>/webhook/v1`
+    fn has_webhook(&self) -> bool {
+        false
+    }
+
+    /// The order such a webhook is about, None to acknowledge and ignore it
+    fn parse_webhook(&self, _payload: serde_json::Value) -> Result<Option<WebhookPayload>> {
+        Err(anyhow!("{} has no webhooks", self.name()))
+    }
+
+    /// The drivers in an order document like the source's API returns, for
+    /// simulating webhooks
+    async fn parse_order(
+        &self,
+        _state: &State,
+        _order: &serde_json::Value,
+        _report: &mut Report,
+    ) -> Result<Vec<BasicDriver>> {
+        Err(anyhow!("{} orders can't be simulated", self.name()))
+    }
+
+    async fn event_start(&self, state: &State) -> Result<DateTime<Utc>>;
+
+    /// Check the configuration against the source, once it can be reached
+    async fn validate(&self, _state: &State, _ticket_map: &TicketMap) -> Result<()> {
+        Ok(())
+    }
+
+    fn oauth2(&self) -> Option<&Mutex<OAuth2State>> {
+        None
+    }
+
+    /// Whether failing calls should fall back to the registrations fetched
+    /// last time
+    async fn is_unavailable(&self, _state: &State) -> bool {
+        false
+    }
+
+    /// The ticket types on sale, for sources whose sales can be opened and
+    /// closed with `set_sales_open`
+    async fn ticket_types(&self, _state: &State) -> Result<Option<Vec<TicketType>>> {
+        Ok(None)
+    }
+
+    async fn set_sales_open(&self, _state: &State, _ticket_type: &str, _open: bool) -> Result<()> {
+        Err(anyhow!("{} can't open or close sales", self.name()))
+    }
+
+    /// How many more tickets of the type can be sold
+    async fn set_available(
+        &self,
+        _state: &State,
+        _ticket_type: &str,
+        _available: usize,
+    ) -> Result<()> {
+        Err(anyhow!(
+            "{} can't set the stock of ticket types",
+            self.name()
+        ))
+    }
+
+    /// Sync another event from now on, for CALENDAR_FILE
+    async fn switch_event(&self, _event_guid: &str) -> Result<()> {
+        Err(anyhow!("{} can't switch events", self.name()))
+    }
+}
+
+/// From TICKET_SOURCE, a comma separated list. The first one's event start
+/// counts for REGISTRATION_CUTOFF_HOURS_BEFORE_START.
+pub async fn from_env() -> Result<Vec<Box<dyn RegistrationSource>>> {
+    let names = dotenv::var("TICKET_SOURCE").unwrap_or_default();
+    let names = match names.as_str() {
+        "" => vec!["eventix"],
+        names => names.split(',').map(str::trim).collect(),
+    };
+    if names.iter().collect::<HashSet<_>>().len() != names.len() {
+        return Err(anyhow!("TICKET_SOURCE lists a source twice"));
+    }
+    let mut sources: Vec<Box<dyn RegistrationSource>> = Vec::new();
+    for name in names {
+        sources.push(match name {
+            "eventix" => Box::new(Eventix::from_env().await?),
+            "pretix" => Box::new(Pretix::from_env()?),
+            "eventbrite" => Box::new(Eventbrite::from_env().await?),
+            "csv" => Box::new(CsvSource::from_env()?),
+            other => return Err(anyhow!("Unknown TICKET_SOURCE: {}", other)),
+        });
+    }
+    Ok(sources)
+}
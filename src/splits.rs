@@ -0,0 +1,684 @@
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use log::warn;
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use crate::{
+    acsm::{self, BasicDriver, ClassSlots, EntrantDefaults},
+    events::{EventKind, Events},
+    priority::{self, Affected},
+    report::{ProblemKind, Report},
+};
+
+/// How to spread new drivers over multiple ACSM files, e.g. one per server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitPolicy {
+    /// Fill up the first file before using the next one
+    FillFirst,
+    /// Take turns
+    RoundRobin,
+    /// Take turns in order of pace, so every split gets a similar spread
+    PaceBalanced,
+    /// Like fill-first, but full updates also move drivers up to earlier files
+    /// when slots free up there, so later files only hold the overflow
+    Overflow,
+}
+
+impl FromStr for SplitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fill-first" => Ok(SplitPolicy::FillFirst),
+            "round-robin" => Ok(SplitPolicy::RoundRobin),
+            "pace-balanced" => Ok(SplitPolicy::PaceBalanced),
+            "overflow" => Ok(SplitPolicy::Overflow),
+            _ => Err(anyhow!("Unknown split policy: {}", s)),
+        }
+    }
+}
+
+/// From RESERVED_SLOTS, by class name how many slots of the class to keep
+/// free in each ACSM file for drivers that may take them
+#[derive(Debug, Default)]
+pub struct ReservedSlots(HashMap<String, usize>);
+
+impl ReservedSlots {
+    pub fn from_env() -> Result<ReservedSlots> {
+        dotenv::var("RESERVED_SLOTS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.is_empty())
+            .map(parse_reserved)
+            .collect::<Result<_>>()
+            .map(ReservedSlots)
+    }
+
+    fn of(&self, class: &ClassSlots) -> usize {
+        self.0.get(&class.name).copied().unwrap_or_default()
+    }
+}
+
+/// `class:count`, where the class name may contain `:` itself
+fn parse_reserved(entry: &str) -> Result<(String, usize)> {
+    let (class, count) = entry
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("RESERVED_SLOTS entry {} is not class:count", entry))?;
+    let count = count
+        .trim()
+        .parse()
+        .with_context(|| format!("Reserved slots for {} is not a number", class))?;
+    Ok((class.trim().to_string(), count))
+}
+
+/// Free slots per split and class, and how many of them are reserved
+struct Slots {
+    free: Vec<Vec<usize>>,
+    reserved: Vec<Vec<usize>>,
+}
+
+impl Slots {
+    fn has_room(&self, split: usize, class: usize, driver: &BasicDriver) -> bool {
+        if driver.reserved {
+            self.free[split][class] > 0
+        } else {
+            self.free[split][class] > self.reserved[split][class]
+        }
+    }
+
+    /// Returns whether the driver took a reserved slot, which they do first
+    fn take(&mut self, split: usize, class: usize, driver: &BasicDriver) -> bool {
+        self.free[split][class] -= 1;
+        let reserved = driver.reserved && self.reserved[split][class] > 0;
+        if reserved {
+            self.reserved[split][class] -= 1;
+        }
+        reserved
+    }
+
+    fn give_back(&mut self, split: usize, class: usize, reserved: bool) {
+        self.free[split][class] += 1;
+        if reserved {
+            self.reserved[split][class] += 1;
+        }
+    }
+}
+
+/// Decide which split each driver goes in: manual drivers first, then
+/// priority tickets, then the rest by earliest payment. Drivers that are
+/// already in a split stay there, unless they get promoted with the overflow
+/// policy or someone who goes before them took their slot. The rest go where
+/// the policy says there's room. During a full update every slot not taken by
+/// an ignored entrant is up for grabs, otherwise only empty slots are. Only
+/// drivers marked as reserved get the reserved slots. Outside of full updates
+/// those count as still free, as we can't tell who took them.
+pub fn allocate(
+    splits: &[Vec<ClassSlots>],
+    drivers: &[BasicDriver],
+    policy: SplitPolicy,
+    full_update: bool,
+    ignored_steam_ids: &[u64],
+    reserved_slots: &ReservedSlots,
+    report: &mut Report,
+) -> Vec<Vec<BasicDriver>> {
+    let free = splits
+        .iter()
+        .map(|classes| {
+            classes
+                .iter()
+                .map(|class| {
+                    if full_update {
+                        let ignored = class
+                            .guids
+                            .iter()
+                            .filter(|guid| {
+                                guid.parse()
+                                    .is_ok_and(|steam_id| ignored_steam_ids.contains(&steam_id))
+                            })
+                            .count();
+                        class.guids.len() - ignored
+                    } else {
+                        class.free_slots()
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let reserved = splits
+        .iter()
+        .map(|classes| {
+            classes
+                .iter()
+                .map(|class| reserved_slots.of(class))
+                .collect()
+        })
+        .collect();
+    let mut slots = Slots { free, reserved };
+    // Placing picks the first class the driver fits in, so we have to as well
+    let class_index = |split: usize, driver: &BasicDriver| {
+        splits[split].iter().position(|class| class.fits(driver))
+    };
+    let promote = policy == SplitPolicy::Overflow && full_update;
+    let mut drivers = drivers
+        .iter()
+        .filter(|driver| !ignored_steam_ids.contains(&driver.steam_id))
+        .map(|driver| {
+            let steam_id = driver.steam_id.to_string();
+            let current = (0..splits.len()).find_map(|split| {
+                class_index(split, driver)
+                    .filter(|&class| splits[split][class].guids.contains(&steam_id))
+                    .map(|class| (split, class))
+            });
+            (driver, current)
+        })
+        .collect::<Vec<_>>();
+    // Drivers without a payment time, like manual ones, go first, then those
+    // with priority tickets. Between equals, drivers on the grid go before new
+    // ones, and promotions come from the next file first.
+    drivers.sort_by_key(|(driver, current)| {
+        (
+            driver.paid_at.is_some(),
+            !driver.priority,
+            driver.paid_at,
+            current.map_or(usize::MAX, |(split, _)| split),
+        )
+    });
+    let mut allocation = vec![Vec::new(); splits.len()];
+    // With pace-balanced, purchase order decides who gets in and pace decides
+    // where they go
+    let mut deferred = Vec::new();
+    let mut next_split = 0;
+    for (driver, current) in drivers {
+        if let Some((split, class)) = current.filter(|_| !promote) {
+            if !full_update {
+                allocation[split].push(driver.clone());
+                continue;
+            }
+            if slots.has_room(split, class, driver) {
+                slots.take(split, class, driver);
+                allocation[split].push(driver.clone());
+                continue;
+            }
+        }
+        match room(
+            splits.len(),
+            policy,
+            next_split,
+            |split| class_index(split, driver),
+            |split, class| slots.has_room(split, class, driver),
+        ) {
+            Some((split, class)) => {
+                let took_reserved = slots.take(split, class, driver);
+                if policy == SplitPolicy::PaceBalanced && current.is_none() {
+                    deferred.push((split, class, took_reserved, driver));
+                } else {
+                    allocation[split].push(driver.clone());
+                    next_split = (split + 1) % splits.len();
+                }
+            }
+            None => no_free_slot(driver, report),
+        }
+    }
+    for &(split, class, took_reserved, _) in &deferred {
+        slots.give_back(split, class, took_reserved);
+    }
+    let mut deferred = deferred
+        .into_iter()
+        .map(|(_, _, _, driver)| driver)
+        .collect::<Vec<_>>();
+    deferred.sort_by(|a, b| {
+        a.pace
+            .unwrap_or(f64::MAX)
+            .total_cmp(&b.pace.unwrap_or(f64::MAX))
+    });
+    for driver in deferred {
+        match room(
+            splits.len(),
+            policy,
+            next_split,
+            |split| class_index(split, driver),
+            |split, class| slots.has_room(split, class, driver),
+        ) {
+            Some((split, class)) => {
+                slots.take(split, class, driver);
+                allocation[split].push(driver.clone());
+                next_split = (split + 1) % splits.len();
+            }
+            None => no_free_slot(driver, report),
+        }
+    }
+    allocation
+}
+
+/// The first split and class with room for a driver, in the order the policy
+/// tries the splits
+fn room(
+    split_count: usize,
+    policy: SplitPolicy,
+    next_split: usize,
+    class_index: impl Fn(usize) -> Option<usize>,
+    has_room: impl Fn(usize, usize) -> bool,
+) -> Option<(usize, usize)> {
+    (0..split_count)
+        .map(|offset| match policy {
+            SplitPolicy::FillFirst | SplitPolicy::Overflow => offset,
+            SplitPolicy::RoundRobin | SplitPolicy::PaceBalanced => {
+                (next_split + offset) % split_count
+            }
+        })
+        .filter_map(|split| class_index(split).map(|class| (split, class)))
+        .find(|&(split, class)| has_room(split, class))
+}
+
+fn no_free_slot(driver: &BasicDriver, report: &mut Report) {
+    warn!(
+        "No free slot for {} steam_id={} car={}",
+        driver.name, driver.steam_id, driver.car
+    );
+    report.add(
+        ProblemKind::NoFreeSlot,
+        driver.order_guid.as_deref(),
+        driver.ticket_guid.as_deref(),
+        format!(
+            "No free slot for {} steam_id={} car={}",
+            driver.name, driver.steam_id, driver.car
+        ),
+    );
+}
+
+/// The drivers that aren't in any of the splits, except ignored ones
+fn off_grid<'a>(
+    splits: &'a [Vec<ClassSlots>],
+    drivers: &'a [BasicDriver],
+    ignored_steam_ids: &'a [u64],
+) -> impl Iterator<Item = &'a BasicDriver> {
+    drivers.iter().filter(move |driver| {
+        let steam_id = driver.steam_id.to_string();
+        let on_grid = splits
+            .iter()
+            .flatten()
+            .any(|class| class.guids.contains(&steam_id));
+        !on_grid && !ignored_steam_ids.contains(&driver.steam_id)
+    })
+}
+
+/// Report drivers that aren't on the grid yet, for when the entry list is
+/// frozen and nothing gets written
+pub fn report_registration_closed(
+    splits: &[Vec<ClassSlots>],
+    drivers: &[BasicDriver],
+    ignored_steam_ids: &[u64],
+    report: &mut Report,
+) {
+    for driver in off_grid(splits, drivers, ignored_steam_ids) {
+        report.add(
+            ProblemKind::RegistrationClosed,
+            driver.order_guid.as_deref(),
+            driver.ticket_guid.as_deref(),
+            format!(
+                "Registration closed for {} steam_id={} car={}",
+                driver.name, driver.steam_id, driver.car
+            ),
+        );
+    }
+}
+
+/// The drivers allocated to a split that weren't on it before
+fn newly_placed<'a>(
+    split: &'a [ClassSlots],
+    drivers: &'a [BasicDriver],
+) -> impl Iterator<Item = &'a BasicDriver> {
+    drivers.iter().filter(move |driver| {
+        let steam_id = driver.steam_id.to_string();
+        !split.iter().any(|class| class.guids.contains(&steam_id))
+    })
+}
+
+/// Per class name, the drivers that are still off the grid after placing them
+/// and would go in that class. Drivers whose class isn't in any split aren't
+/// counted.
+pub fn waitlist_by_class(
+    splits: &[Vec<ClassSlots>],
+    drivers: &[BasicDriver],
+    ignored_steam_ids: &[u64],
+) -> HashMap<String, usize> {
+    off_grid(splits, drivers, ignored_steam_ids)
+        .filter_map(|driver| {
+            splits
+                .iter()
+                .flatten()
+                .find(|class| class.fits(driver))
+                .map(|class| class.name.clone())
+        })
+        .counts()
+}
+
+/// Allocate the drivers over the splits and add/update them in each file,
+/// returning whether any entry list changed and who moved past whom
+#[allow(clippy::too_many_arguments)]
+pub async fn place_drivers(
+    json_files: &[PathBuf],
+    drivers: &[BasicDriver],
+    policy: SplitPolicy,
+    delete_missing: bool,
+    ignored_steam_ids: &[u64],
+    reserved_slots: &ReservedSlots,
+    entrant_defaults: &EntrantDefaults,
+    report: &mut Report,
+    events: &Events,
+) -> Result<(bool, Affected)> {
+    let mut splits = Vec::new();
+    for json_file in json_files {
+        splits.push(acsm::class_slots(json_file).await?);
+    }
+    let allocation = allocate(
+        &splits,
+        drivers,
+        policy,
+        delete_missing,
+        ignored_steam_ids,
+        reserved_slots,
+        report,
+    );
+    let affected = priority::affected(&splits, drivers, &allocation, ignored_steam_ids);
+    let mut changed = false;
+    for ((json_file, split), drivers) in json_files.iter().zip(&splits).zip(allocation) {
+        if drivers.is_empty() && !delete_missing {
+            continue;
+        }
+        changed |= acsm::update_drivers(
+            delete_missing,
+            json_file,
+            &drivers,
+            ignored_steam_ids,
+            entrant_defaults,
+        )
+        .await?;
+        let file = json_file.display().to_string();
+        // Full updates allocate everyone again, only announce who's new
+        for driver in newly_placed(split, &drivers) {
+            events.emit(EventKind::DriverPlaced {
+                file: file.clone(),
+                name: driver.name.clone(),
+                steam_id: driver.steam_id,
+                car: driver.car.clone(),
+            });
+        }
+        events.emit(EventKind::WriteCompleted {
+            file,
+            drivers: drivers.len(),
+        });
+    }
+    Ok((changed, affected))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn split(guids: &[&str]) -> Vec<ClassSlots> {
+        vec![ClassSlots {
+            name: "GT3".to_string(),
+            cars: vec!["gt3".to_string()],
+            guids: guids.iter().map(|guid| guid.to_string()).collect(),
+        }]
+    }
+
+    fn driver(steam_id: u64, pace: f64) -> BasicDriver {
+        BasicDriver {
+            pace: Some(pace),
+            ..BasicDriver::test(steam_id, "gt3")
+        }
+    }
+
+    fn steam_ids(allocation: &[Vec<BasicDriver>]) -> Vec<Vec<u64>> {
+        allocation
+            .iter()
+            .map(|drivers| drivers.iter().map(|driver| driver.steam_id).collect())
+            .collect()
+    }
+
+    #[test_case(SplitPolicy::FillFirst, vec![vec![1, 2], vec![3]]; "fill first")]
+    #[test_case(SplitPolicy::RoundRobin, vec![vec![1, 3], vec![2]]; "round robin")]
+    #[test_case(SplitPolicy::PaceBalanced, vec![vec![3, 2], vec![1]]; "pace balanced")]
+    fn new_drivers(policy: SplitPolicy, expected: Vec<Vec<u64>>) {
+        let splits = [split(&["", ""]), split(&["", ""])];
+        let drivers = [driver(1, 2.0), driver(2, 3.0), driver(3, 1.0)];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            policy,
+            false,
+            &[],
+            &ReservedSlots::default(),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), expected);
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn existing_drivers_stay() {
+        let splits = [split(&["", ""]), split(&["2", ""])];
+        let drivers = [driver(1, 0.0), driver(2, 0.0)];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            SplitPolicy::FillFirst,
+            true,
+            &[],
+            &ReservedSlots::default(),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), vec![vec![1], vec![2]]);
+    }
+
+    fn paid(steam_id: u64, minute: u32) -> BasicDriver {
+        BasicDriver {
+            paid_at: Some(
+                chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+                    .unwrap()
+                    .and_hms_opt(12, minute, 0)
+                    .unwrap()
+                    .and_utc(),
+            ),
+            ..driver(steam_id, 0.0)
+        }
+    }
+
+    #[test_case(false, vec![vec![1, 3]], 1; "new drivers")]
+    #[test_case(true, vec![vec![1, 3]], 1; "earlier buyer takes slot of placed driver")]
+    fn purchase_order(full_update: bool, expected: Vec<Vec<u64>>, waitlisted: usize) {
+        let splits = [split(if full_update { &["2", "3"] } else { &["", ""] })];
+        let drivers = [paid(2, 30), paid(3, 10), paid(1, 0)];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            SplitPolicy::FillFirst,
+            full_update,
+            &[],
+            &ReservedSlots::default(),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), expected);
+        assert_eq!(report.problems.len(), waitlisted);
+        assert_eq!(report.problems[0].kind, ProblemKind::NoFreeSlot);
+        assert!(report.problems[0].message.contains("steam_id=2"));
+    }
+
+    #[test]
+    fn priority_bumps_latest_buyer() {
+        let splits = [split(&["2", "3"])];
+        let drivers = [
+            paid(2, 0),
+            paid(3, 10),
+            BasicDriver {
+                priority: true,
+                ..paid(1, 20)
+            },
+        ];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            SplitPolicy::FillFirst,
+            true,
+            &[],
+            &ReservedSlots::default(),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), vec![vec![1, 2]]);
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].message.contains("steam_id=3"));
+    }
+
+    #[test]
+    fn overflow_promotes_in_purchase_order() {
+        let splits = [split(&["1", ""]), split(&["3", "2"])];
+        let drivers = [paid(1, 0), paid(2, 10), paid(3, 20)];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            SplitPolicy::Overflow,
+            true,
+            &[],
+            &ReservedSlots::default(),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), vec![vec![1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn overflow_promotes() {
+        let splits = [split(&["1", ""]), split(&["3", "2"])];
+        let drivers = [
+            driver(4, 0.0),
+            driver(3, 0.0),
+            driver(2, 0.0),
+            driver(1, 0.0),
+        ];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            SplitPolicy::Overflow,
+            true,
+            &[],
+            &ReservedSlots::default(),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), vec![vec![1, 3], vec![2, 4]]);
+    }
+
+    #[test]
+    fn only_new_drivers_are_reported_when_closed() {
+        let splits = vec![split(&["1", ""])];
+        let drivers = vec![driver(1, 0.0), driver(2, 0.0), driver(3, 0.0)];
+        let mut report = Report::default();
+        report_registration_closed(&splits, &drivers, &[3], &mut report);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].kind, ProblemKind::RegistrationClosed);
+    }
+
+    #[test]
+    fn only_new_drivers_are_announced() {
+        let drivers = [driver(1, 0.0), driver(2, 0.0)];
+        let placed: Vec<u64> = newly_placed(&split(&["1", ""]), &drivers)
+            .map(|driver| driver.steam_id)
+            .collect();
+        assert_eq!(placed, vec![2]);
+    }
+
+    #[test]
+    fn full_grid_is_reported() {
+        let splits = [split(&["9"])];
+        let drivers = [driver(1, 0.0)];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            SplitPolicy::FillFirst,
+            true,
+            &[9],
+            &ReservedSlots::default(),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), vec![Vec::<u64>::new()]);
+        assert_eq!(report.problems[0].kind, ProblemKind::NoFreeSlot);
+    }
+
+    #[test_case(false, vec![vec![1, 2]], 1; "new drivers")]
+    #[test_case(true, vec![vec![1, 2]], 1; "full update")]
+    fn reserved_slots(full_update: bool, expected: Vec<Vec<u64>>, waitlisted: usize) {
+        let splits = [split(&["", ""])];
+        let reserved = |steam_id| BasicDriver {
+            reserved: true,
+            ..paid(steam_id, 30)
+        };
+        let drivers = [paid(1, 0), paid(3, 10), reserved(2)];
+        let mut report = Report::default();
+        let allocation = allocate(
+            &splits,
+            &drivers,
+            SplitPolicy::FillFirst,
+            full_update,
+            &[],
+            &ReservedSlots(HashMap::from([("GT3".to_string(), 1)])),
+            &mut report,
+        );
+        assert_eq!(steam_ids(&allocation), expected);
+        assert_eq!(report.problems.len(), waitlisted);
+        assert!(report.problems[0].message.contains("steam_id=3"));
+    }
+
+    #[test_case("GT3:2", Some(("GT3", 2)))]
+    #[test_case("LMP: Pro:1", Some(("LMP: Pro", 1)))]
+    #[test_case("GT3", None)]
+    #[test_case("GT3:x", None)]
+    fn parses_reserved(entry: &str, expected: Option<(&str, usize)>) {
+        let parsed = parse_reserved(entry).ok();
+        assert_eq!(
+            parsed
+                .as_ref()
+                .map(|(class, count)| (class.as_str(), *count)),
+            expected
+        );
+    }
+
+    #[test]
+    fn waitlist() {
+        let splits = [
+            split(&["1", ""]),
+            vec![ClassSlots {
+                name: "GT4".to_string(),
+                cars: vec!["gt4".to_string()],
+                guids: vec!["4".to_string()],
+            }],
+        ];
+        let gt4 = |steam_id| BasicDriver {
+            car: "gt4".to_string(),
+            ..driver(steam_id, 0.0)
+        };
+        let drivers = [
+            driver(1, 0.0),
+            driver(2, 0.0),
+            driver(3, 0.0),
+            gt4(4),
+            gt4(5),
+            BasicDriver {
+                car: "lmp1".to_string(),
+                ..driver(6, 0.0)
+            },
+        ];
+        let waitlist = waitlist_by_class(&splits, &drivers, &[3]);
+        assert_eq!(
+            waitlist,
+            HashMap::from([("GT3".to_string(), 1), ("GT4".to_string(), 1)])
+        );
+    }
+}
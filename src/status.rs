@@ -0,0 +1,277 @@
+use anyhow::Result;
+use axum::{extract, Json};
+use axum_macros::debug_handler;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+use tokio::time::Instant;
+
+use crate::{acsm, report::ProblemKind, sandbox, timezone, State};
+
+/// When something happened and how it went
+#[derive(Debug, Clone, Serialize)]
+pub struct Outcome {
+    #[serde(serialize_with = "timezone::serialize")]
+    pub time: DateTime<Utc>,
+    pub success: bool,
+    pub message: String,
+}
+
+impl Outcome {
+    pub fn new<T, E: Debug>(message: &str, result: &Result<T, E>) -> Outcome {
+        Outcome {
+            time: Utc::now(),
+            success: result.is_ok(),
+            message: match result {
+                Ok(_) => message.to_string(),
+                Err(e) => format!("{}: {:?}", message, e),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SyncStatus {
+    pub last_full_update: Option<Outcome>,
+    #[serde(serialize_with = "timezone::serialize_option")]
+    pub next_full_update: Option<DateTime<Utc>>,
+    pub last_webhook: Option<Outcome>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClassStatus {
+    pub file: String,
+    pub class: String,
+    pub slots: usize,
+    pub filled: usize,
+    /// Filled slots of ignored Steam IDs, which syncs leave alone
+    pub ignored: usize,
+    /// Drivers for the class that didn't fit during the last full update. They
+    /// aren't in any file, so they're counted in the first file with the class.
+    pub waitlisted: usize,
+}
+
+/// Slot use of every class in every file
+pub async fn class_statuses(state: &State) -> Vec<ClassStatus> {
+    let ignored_steam_ids: Vec<_> = state
+        .ignored_steam_ids()
+        .await
+        .iter()
+        .map(u64::to_string)
+        .collect();
+    let mut waitlist = state.class_waitlist.lock().await.clone();
+    let mut classes = Vec::new();
+    for json_file in state.acsm_json_files.lock().await.iter() {
+        match acsm::class_slots(json_file).await {
+            Ok(class_slots) => classes.extend(class_slots.into_iter().map(|class| {
+                ClassStatus {
+                    file: json_file.display().to_string(),
+                    slots: class.guids.len(),
+                    filled: class.guids.len() - class.free_slots(),
+                    ignored: class
+                        .guids
+                        .iter()
+                        .filter(|guid| ignored_steam_ids.contains(guid))
+                        .count(),
+                    waitlisted: waitlist.remove(&class.name).unwrap_or_default(),
+                    class: class.name,
+                }
+            })),
+            Err(e) => error!("Failed to read {}: {:?}", json_file.display(), e),
+        }
+    }
+    classes
+}
+
+#[derive(Debug, Serialize)]
+pub struct OAuth2Status {
+    pub has_token: bool,
+    pub expires_in_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub sync: SyncStatus,
+    pub classes: Vec<ClassStatus>,
+    /// Drivers that didn't fit or came too late during the last full update
+    pub waitlist: usize,
+    #[serde(serialize_with = "timezone::serialize_option")]
+    pub registration_closes: Option<DateTime<Utc>>,
+    pub retrying_updates: usize,
+    /// Not calling Eventix after too many failures, full updates use the
+    /// drivers from the last successful one
+    pub eventix_circuit_open: bool,
+    pub writes_paused: bool,
+    pub session_live: bool,
+    /// In one of the MAINTENANCE_WINDOWS
+    pub maintenance_window: bool,
+    /// After EVENT_END, nothing is written anymore
+    pub archived: bool,
+    /// SANDBOX is on, nothing is written
+    pub sandbox: bool,
+    pub queued_drivers: usize,
+    pub full_update_queued: bool,
+    /// By source, for those that use OAuth2
+    pub oauth2: BTreeMap<&'static str, OAuth2Status>,
+}
+
+/// Drivers that didn't fit or came too late during the last full update
+pub async fn waitlist(state: &State) -> usize {
+    state
+        .last_report
+        .lock()
+        .await
+        .problems
+        .iter()
+        .filter(|problem| {
+            matches!(
+                problem.kind,
+                ProblemKind::NoFreeSlot | ProblemKind::RegistrationClosed | ProblemKind::TeamFull
+            )
+        })
+        .count()
+}
+
+#[debug_handler]
+pub async fn handle_status(extract::State(state): extract::State<Arc<State>>) -> Json<Status> {
+    let classes = class_statuses(&state).await;
+    let waitlist = waitlist(&state).await;
+    let write_gate = state.write_gate.lock().await;
+    let (
+        writes_paused,
+        session_live,
+        maintenance_window,
+        archived,
+        queued_drivers,
+        full_update_queued,
+    ) = (
+        write_gate.paused,
+        write_gate.session_live,
+        write_gate.maintenance,
+        write_gate.archived,
+        write_gate.queued_drivers.len(),
+        write_gate.full_update_queued,
+    );
+    drop(write_gate);
+    let mut oauth2 = BTreeMap::new();
+    for source in &state.sources {
+        if let Some(oauth2_state) = source.oauth2() {
+            let oauth2_state = oauth2_state.lock().await;
+            oauth2.insert(
+                source.name(),
+                OAuth2Status {
+                    has_token: oauth2_state.token.is_some(),
+                    expires_in_seconds: oauth2_state
+                        .token_expires
+                        .map(|expires| expires.saturating_duration_since(Instant::now()).as_secs()),
+                },
+            );
+        }
+    }
+    Json(Status {
+        sync: state.sync_status.lock().await.clone(),
+        classes,
+        waitlist,
+        registration_closes: state.registration_closes().await,
+        retrying_updates: acsm::retrying_updates(),
+        eventix_circuit_open: state.eventix_breaker.lock().await.is_open(),
+        writes_paused,
+        session_live,
+        maintenance_window,
+        archived,
+        sandbox: sandbox::is_on(),
+        queued_drivers,
+        full_update_queued,
+        oauth2,
+    })
+}
+
+/// Escape a label value for the Prometheus text format
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// One per-class gauge in the Prometheus text format
+fn gauge(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    classes: &[ClassStatus],
+    value: impl Fn(&ClassStatus) -> usize,
+) {
+    text.push_str(&format!(
+        "# HELP eventix2acsm_class_{name} {help}\n# TYPE eventix2acsm_class_{name} gauge\n"
+    ));
+    for class in classes {
+        text.push_str(&format!(
+            "eventix2acsm_class_{}{{file=\"{}\",class=\"{}\"}} {}\n",
+            name,
+            label_value(&class.file),
+            label_value(&class.class),
+            value(class)
+        ));
+    }
+}
+
+fn metrics(classes: &[ClassStatus]) -> String {
+    let mut text = String::new();
+    gauge(&mut text, "slots", "Slots in the class", classes, |class| {
+        class.slots
+    });
+    gauge(
+        &mut text,
+        "filled",
+        "Filled slots in the class",
+        classes,
+        |class| class.filled,
+    );
+    gauge(
+        &mut text,
+        "ignored",
+        "Filled slots of ignored Steam IDs in the class",
+        classes,
+        |class| class.ignored,
+    );
+    gauge(
+        &mut text,
+        "waitlisted",
+        "Drivers for the class that didn't fit during the last full update",
+        classes,
+        |class| class.waitlisted,
+    );
+    text
+}
+
+#[debug_handler]
+pub async fn handle_metrics(extract::State(state): extract::State<Arc<State>>) -> String {
+    metrics(&class_statuses(&state).await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prometheus_format() {
+        let classes = [ClassStatus {
+            file: "C:\\acsm\\gt3.json".to_string(),
+            class: "GT3 \"Pro\"".to_string(),
+            slots: 40,
+            filled: 35,
+            ignored: 2,
+            waitlisted: 3,
+        }];
+        let text = metrics(&classes);
+        assert!(text.contains("# TYPE eventix2acsm_class_slots gauge\n"));
+        assert!(text.contains(
+            "eventix2acsm_class_filled{file=\"C:\\\\acsm\\\\gt3.json\",class=\"GT3 \\\"Pro\\\"\"} 35\n"
+        ));
+        assert!(text.contains("eventix2acsm_class_waitlisted{"));
+        assert!(text.ends_with("} 3\n"));
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, error, warn};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    events::{Event, EventKind},
+    redact::{self, Secret},
+    sandbox,
+    status::{self, ClassStatus, Outcome},
+    timezone, State,
+};
+
+const SIGNATURE_HEADER: &str = "x-eventix2acsm-signature";
+
+/// Where to push a summary after every sync, for sites that would otherwise
+/// poll `/status`
+pub struct StatusWebhook {
+    url: String,
+    /// Signs the body like Eventix signs its webhooks to us
+    secret: Option<Secret<String>>,
+}
+
+impl StatusWebhook {
+    /// From STATUS_WEBHOOK_URL and STATUS_WEBHOOK_SECRET
+    pub fn from_env() -> Option<StatusWebhook> {
+        let url = dotenv::var("STATUS_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        Some(StatusWebhook {
+            url,
+            secret: dotenv::var("STATUS_WEBHOOK_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .map(Secret::new),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Summary<'a> {
+    #[serde(serialize_with = "timezone::serialize")]
+    time: DateTime<Utc>,
+    /// `full_update`, `webhook`, or the type of the event
+    reason: &'a str,
+    /// The event it was sent for, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'a Event>,
+    classes: Vec<ClassStatus>,
+    waitlist: usize,
+    last_full_update: Option<Outcome>,
+    last_webhook: Option<Outcome>,
+    /// With SANDBOX on, the status of a run that writes nothing
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    sandbox: bool,
+}
+
+/// `sha256=<hex>` of the HMAC-SHA256 of the body
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn post(state: &State, webhook: &StatusWebhook, summary: &Summary<'_>) -> Result<()> {
+    let body = serde_json::to_vec(summary)?;
+    let mut request = state
+        .http
+        .status_webhook
+        .client()
+        .post(&webhook.url)
+        .header("content-type", "application/json");
+    if let Some(secret) = &webhook.secret {
+        request = request.header(SIGNATURE_HEADER, signature(secret.expose(), &body));
+    }
+    state
+        .http
+        .status_webhook
+        .send(request.body(body))
+        .await
+        .with_context(|| {
+            format!(
+                "Sending status to {} failed",
+                redact::redact_url(&webhook.url)
+            )
+        })?
+        .error_for_status()
+        .context("Status webhook returned error")?;
+    Ok(())
+}
+
+/// Push the current status, if STATUS_WEBHOOK_URL is set. Failures are only
+/// logged, so they never hold up syncing.
+pub async fn send(state: &State, reason: &str, event: Option<&Event>) {
+    let Some(webhook) = &state.status_webhook else {
+        return;
+    };
+    let sync = state.sync_status.lock().await.clone();
+    let summary = Summary {
+        time: Utc::now(),
+        reason,
+        event,
+        classes: status::class_statuses(state).await,
+        waitlist: status::waitlist(state).await,
+        last_full_update: sync.last_full_update,
+        last_webhook: sync.last_webhook,
+        sandbox: sandbox::is_on(),
+    };
+    debug!("Sending status webhook for {}", reason);
+    if let Err(e) = post(state, webhook, &summary).await {
+        error!("{:?}", e);
+    }
+}
+
+/// Whether an event is worth pushing the status for on its own
+fn is_significant(kind: &EventKind) -> Option<&'static str> {
+    match kind {
+        EventKind::ClassCapacity { .. } => Some("class_capacity"),
+        EventKind::SalesChanged { .. } => Some("sales_changed"),
+        EventKind::Attendance { .. } => Some("attendance"),
+        EventKind::Drift { .. } => Some("drift"),
+        EventKind::TeamFull { .. } => Some("team_full"),
+        EventKind::Bumped { .. } => Some("bumped"),
+        EventKind::PriorityPlaced { .. } => Some("priority_placed"),
+        EventKind::RoundStarted { .. } => Some("round_started"),
+        EventKind::Archived { .. } => Some("archived"),
+        EventKind::Chargeback { .. } => Some("chargeback"),
+        EventKind::Error { .. } => Some("error"),
+        _ => None,
+    }
+}
+
+/// Push the status on significant events, for as long as the service runs
+pub async fn event_task(state: Arc<State>) {
+    if state.status_webhook.is_none() {
+        return;
+    }
+    let mut events = state.events.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if let Some(reason) = is_significant(&event.kind) {
+                    send(&state, reason, Some(&event)).await;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Status webhook missed {} events", missed);
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signed_like_eventix() {
+        let body = br#"{"reason":"full_update"}"#;
+        let signature = signature("secret", body);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let expected = hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap();
+        assert!(mac.verify_slice(&expected).is_ok());
+    }
+
+    #[test]
+    fn summary_format() {
+        let event = Event {
+            time: Utc::now(),
+            kind: EventKind::Error {
+                message: "Full update failed".to_string(),
+            },
+        };
+        let summary = Summary {
+            time: Utc::now(),
+            reason: "error",
+            event: Some(&event),
+            classes: vec![ClassStatus {
+                file: "gt3.json".to_string(),
+                class: "GT3".to_string(),
+                slots: 40,
+                filled: 38,
+                ignored: 1,
+                waitlisted: 0,
+            }],
+            waitlist: 0,
+            last_full_update: None,
+            last_webhook: None,
+            sandbox: false,
+        };
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["reason"], "error");
+        assert_eq!(json["event"]["type"], "error");
+        assert_eq!(json["classes"][0]["filled"], 38);
+        assert!(json["last_full_update"].is_null());
+        assert!(json.get("sandbox").is_none());
+    }
+}
@@ -0,0 +1,494 @@
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    acsm::BasicDriver,
+    http::HttpPolicy,
+    redact::Secret,
+    report::{ProblemKind, Report},
+    State,
+};
+
+const API_URL: &str = "https://api.steampowered.com";
+
+/// Steam app ID of Assetto Corsa
+const ASSETTO_CORSA: &str = "244210";
+
+/// How long a failed check stands before asking Steam again, e.g. after the
+/// driver made their profile public
+const RECHECK_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// The upper 32 bits of every SteamID64 of an individual account in the
+/// public universe
+const INDIVIDUAL_ACCOUNT: u64 = 0x0110_0001;
+
+/// A Steam profile as a driver would give it
+#[derive(Debug, PartialEq)]
+pub enum Profile {
+    Id(u64),
+    /// The custom part of `steamcommunity.com/id/<vanity>`
+    Vanity(String),
+}
+
+fn is_individual(steam_id: u64) -> bool {
+    steam_id >> 32 == INDIVIDUAL_ACCOUNT
+}
+
+/// Accepts a bare SteamID64 or a profile URL, with or without scheme
+pub fn parse_profile(input: &str) -> Result<Profile> {
+    let input = input.trim();
+    let path = input
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.");
+    let profile = if let Some(rest) = path.strip_prefix("steamcommunity.com/") {
+        let mut parts = rest.split('/');
+        match (parts.next(), parts.next()) {
+            (Some("profiles"), Some(id)) => Profile::Id(
+                id.parse()
+                    .with_context(|| format!("Not a Steam ID: {:?}", id))?,
+            ),
+            (Some("id"), Some(vanity)) if !vanity.is_empty() => Profile::Vanity(vanity.to_string()),
+            _ => return Err(anyhow!("Not a Steam profile URL: {:?}", input)),
+        }
+    } else {
+        Profile::Id(
+            input
+                .parse()
+                .with_context(|| format!("Not a Steam ID or profile URL: {:?}", input))?,
+        )
+    };
+    if let Profile::Id(steam_id) = profile {
+        if !is_individual(steam_id) {
+            return Err(anyhow!("Not the Steam ID of a player: {}", steam_id));
+        }
+    }
+    Ok(profile)
+}
+
+/// Whether an account can join, as far as the Steam Web API tells
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ownership {
+    Owns,
+    DoesNotOwn,
+    /// The profile or its game details aren't public
+    Private,
+    NoProfile,
+}
+
+/// From the player summaries and owned games with only Assetto Corsa in the
+/// filter
+fn ownership(players: &[Value], owned_games: &Value) -> Ownership {
+    let Some(player) = players.first() else {
+        return Ownership::NoProfile;
+    };
+    // 3 is public, anything else shows nothing of the profile
+    if player["communityvisibilitystate"] != 3 {
+        return Ownership::Private;
+    }
+    // Without `game_count` the game details are private
+    match owned_games["response"]["game_count"].as_u64() {
+        None => Ownership::Private,
+        Some(0) => Ownership::DoesNotOwn,
+        Some(_) => Ownership::Owns,
+    }
+}
+
+/// From a group list, the account IDs of the groups. None if it says the profile
+/// is private.
+fn group_ids(response: &Value) -> Option<Vec<u32>> {
+    if response["response"]["success"] != true {
+        return None;
+    }
+    Some(
+        response["response"]["groups"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|group| group["gid"].as_str()?.parse().ok())
+            .collect(),
+    )
+}
+
+/// Lowercase letters and digits only, in ASCII, to compare names by
+fn normalize(name: &str) -> String {
+    any_ascii::any_ascii(name)
+        .to_lowercase()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+/// From 0 for nothing alike to 1 for the same, as the best of the Steam names.
+/// A part of the ticket name in the Steam name counts as the same, like
+/// `Gabe Newell` and `gaben_1962`.
+fn name_similarity(ticket_name: &str, steam_names: &[String]) -> f64 {
+    let ticket_name_parts: Vec<_> = ticket_name
+        .split_whitespace()
+        .map(normalize)
+        .filter(|part| part.len() >= 3)
+        .collect();
+    let ticket_name = normalize(ticket_name);
+    steam_names
+        .iter()
+        .map(|steam_name| normalize(steam_name))
+        .map(|steam_name| {
+            if ticket_name_parts
+                .iter()
+                .any(|part| steam_name.contains(part.as_str()))
+            {
+                1.0
+            } else {
+                strsim::normalized_levenshtein(&ticket_name, &steam_name)
+            }
+        })
+        .fold(0.0, f64::max)
+}
+
+/// The Steam Web API, which is optional. Without a key only bare IDs and
+/// `/profiles/` URLs can be checked, and only for their format.
+#[derive(Debug)]
+pub struct Steam {
+    api_key: Option<Secret<String>>,
+    /// From STEAM_CHECK_OWNERSHIP, report drivers without Assetto Corsa
+    check_ownership: bool,
+    /// By Steam ID, when it was checked and how it went
+    ownership: Mutex<HashMap<u64, (Instant, Ownership)>>,
+    /// From STEAM_NAME_MIN_SIMILARITY, report drivers whose name on the
+    /// ticket is less like their Steam name than this
+    min_name_similarity: Option<f64>,
+    /// Persona name and real name by Steam ID, as far as they're public
+    names: Mutex<HashMap<u64, Vec<String>>>,
+}
+
+impl Steam {
+    pub fn has_api_key(&self) -> bool {
+        self.api_key.is_some()
+    }
+
+    pub fn from_env() -> Result<Steam> {
+        let api_key = dotenv::var("STEAM_API_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(Secret::new);
+        let check_ownership =
+            dotenv::var("STEAM_CHECK_OWNERSHIP").is_ok_and(|value| value == "true");
+        if check_ownership && api_key.is_none() {
+            return Err(anyhow!("STEAM_CHECK_OWNERSHIP needs STEAM_API_KEY"));
+        }
+        let min_name_similarity = dotenv::var("STEAM_NAME_MIN_SIMILARITY")
+            .ok()
+            .filter(|similarity| !similarity.is_empty())
+            .map(|similarity| similarity.parse::<f64>())
+            .transpose()
+            .context("STEAM_NAME_MIN_SIMILARITY is not a number")?;
+        if min_name_similarity.is_some() && api_key.is_none() {
+            return Err(anyhow!("STEAM_NAME_MIN_SIMILARITY needs STEAM_API_KEY"));
+        }
+        Ok(Steam {
+            api_key,
+            check_ownership,
+            ownership: Mutex::new(HashMap::new()),
+            min_name_similarity,
+            names: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The key goes in the query string, so errors are stripped of the URL
+    async fn get_json(
+        &self,
+        http: &HttpPolicy,
+        api_key: &Secret<String>,
+        path: &str,
+        query: &[(&str, &str)],
+        what: &str,
+    ) -> Result<Value> {
+        let request = http
+            .client()
+            .get(format!("{}{}", API_URL, path))
+            .query(&[("key", api_key.expose().as_str())])
+            .query(query);
+        http.send(request)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.without_url())
+            .with_context(|| format!("Failed to get {}", what))?
+            .json()
+            .await
+            .map_err(|e| e.without_url())
+            .with_context(|| format!("Failed to parse {}", what))
+    }
+
+    /// Turn what a driver gave us into the SteamID64 of an existing account
+    pub async fn resolve(&self, http: &HttpPolicy, input: &str) -> Result<u64> {
+        let profile = parse_profile(input)?;
+        let Some(api_key) = &self.api_key else {
+            return match profile {
+                Profile::Id(steam_id) => Ok(steam_id),
+                Profile::Vanity(_) => Err(anyhow!(
+                    "Custom profile URLs are not supported, use the Steam ID instead"
+                )),
+            };
+        };
+        let steam_id = match profile {
+            Profile::Id(steam_id) => steam_id,
+            Profile::Vanity(vanity) => {
+                let response = self
+                    .get_json(
+                        http,
+                        api_key,
+                        "/ISteamUser/ResolveVanityURL/v1/",
+                        &[("vanityurl", &vanity)],
+                        "vanity URL",
+                    )
+                    .await?;
+                if response["response"]["success"] != 1 {
+                    return Err(anyhow!("No Steam profile found for {:?}", vanity));
+                }
+                response["response"]["steamid"]
+                    .as_str()
+                    .context("Resolved vanity URL is missing steamid")?
+                    .parse()
+                    .context("Resolved Steam ID is not a number")?
+            }
+        };
+        if self.players(http, api_key, steam_id).await?.is_empty() {
+            return Err(anyhow!("No Steam profile found for {}", steam_id));
+        }
+        Ok(steam_id)
+    }
+
+    /// The player summary of the Steam ID, none if there's no such account
+    async fn players(
+        &self,
+        http: &HttpPolicy,
+        api_key: &Secret<String>,
+        steam_id: u64,
+    ) -> Result<Vec<Value>> {
+        let mut response = self
+            .get_json(
+                http,
+                api_key,
+                "/ISteamUser/GetPlayerSummaries/v2/",
+                &[("steamids", &steam_id.to_string())],
+                "player summary",
+            )
+            .await?;
+        match response["response"]["players"].take() {
+            Value::Array(players) => Ok(players),
+            _ => Err(anyhow!("Player summaries is missing players")),
+        }
+    }
+
+    /// Account IDs of the Steam groups the account is in, none if its profile
+    /// is private
+    pub async fn group_ids(&self, http: &HttpPolicy, steam_id: u64) -> Result<Option<Vec<u32>>> {
+        let api_key = self.api_key.as_ref().context("No STEAM_API_KEY")?;
+        let response = self
+            .get_json(
+                http,
+                api_key,
+                "/ISteamUser/GetUserGroupList/v1/",
+                &[("steamid", &steam_id.to_string())],
+                "group list",
+            )
+            .await?;
+        Ok(group_ids(&response))
+    }
+
+    /// The persona name, and the real name if the driver filled it in, asked
+    /// once per Steam ID
+    async fn names(&self, http: &HttpPolicy, steam_id: u64) -> Result<Vec<String>> {
+        if let Some(names) = self.names.lock().await.get(&steam_id) {
+            return Ok(names.clone());
+        }
+        let api_key = self.api_key.as_ref().context("No STEAM_API_KEY")?;
+        let names: Vec<String> = self
+            .players(http, api_key, steam_id)
+            .await?
+            .first()
+            .into_iter()
+            .flat_map(|player| [&player["personaname"], &player["realname"]])
+            .filter_map(|name| Some(name.as_str()?.to_string()))
+            .filter(|name| !name.is_empty())
+            .collect();
+        self.names.lock().await.insert(steam_id, names.clone());
+        Ok(names)
+    }
+
+    /// Ask Steam whether the account owns Assetto Corsa, unless it was asked
+    /// before
+    async fn ownership(&self, http: &HttpPolicy, steam_id: u64) -> Result<Ownership> {
+        if let Some((checked, ownership)) = self.ownership.lock().await.get(&steam_id) {
+            if *ownership == Ownership::Owns || checked.elapsed() < RECHECK_AFTER {
+                return Ok(*ownership);
+            }
+        }
+        let api_key = self.api_key.as_ref().context("No STEAM_API_KEY")?;
+        let players = self.players(http, api_key, steam_id).await?;
+        let owned_games = if players.is_empty() {
+            Value::Null
+        } else {
+            self.get_json(
+                http,
+                api_key,
+                "/IPlayerService/GetOwnedGames/v1/",
+                &[
+                    ("steamid", &steam_id.to_string()),
+                    ("include_played_free_games", "true"),
+                    ("appids_filter[0]", ASSETTO_CORSA),
+                ],
+                "owned games",
+            )
+            .await?
+        };
+        let ownership = ownership(&players, &owned_games);
+        self.ownership
+            .lock()
+            .await
+            .insert(steam_id, (Instant::now(), ownership));
+        Ok(ownership)
+    }
+}
+
+/// With STEAM_CHECK_OWNERSHIP, report drivers whose Steam ID isn't of an
+/// account with Assetto Corsa, like console players who missed that the event
+/// is on PC. They're still placed, a private profile may well own it.
+pub async fn check_ownership(state: &State, drivers: &[BasicDriver], report: &mut Report) {
+    if !state.steam.check_ownership {
+        return;
+    }
+    for driver in drivers {
+        let problem = match state
+            .steam
+            .ownership(&state.http.steam, driver.steam_id)
+            .await
+        {
+            Ok(Ownership::Owns) => continue,
+            Ok(Ownership::DoesNotOwn) => "doesn't own Assetto Corsa",
+            Ok(Ownership::Private) => "has a private profile, can't tell if it owns Assetto Corsa",
+            Ok(Ownership::NoProfile) => "is not a Steam account",
+            Err(e) => {
+                warn!(
+                    "Failed to check Steam ID {} of {}: {:?}",
+                    driver.steam_id, driver.name, e
+                );
+                continue;
+            }
+        };
+        report.add(
+            ProblemKind::SteamProfile,
+            driver.order_guid.as_deref(),
+            driver.ticket_guid.as_deref(),
+            format!("{} steam_id={} {}", driver.name, driver.steam_id, problem),
+        );
+    }
+}
+
+/// With STEAM_NAME_MIN_SIMILARITY, report drivers whose Steam name is nothing
+/// like the name on their ticket, which often means a mistyped Steam ID of
+/// someone else's account
+pub async fn check_names(state: &State, drivers: &[BasicDriver], report: &mut Report) {
+    let Some(min_similarity) = state.steam.min_name_similarity else {
+        return;
+    };
+    for driver in drivers {
+        let steam_names = match state.steam.names(&state.http.steam, driver.steam_id).await {
+            Ok(steam_names) if !steam_names.is_empty() => steam_names,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(
+                    "Failed to get Steam name of steam_id={}: {:?}",
+                    driver.steam_id, e
+                );
+                continue;
+            }
+        };
+        if name_similarity(&driver.name, &steam_names) < min_similarity {
+            report.add(
+                ProblemKind::SteamNameMismatch,
+                driver.order_guid.as_deref(),
+                driver.ticket_guid.as_deref(),
+                format!(
+                    "{} steam_id={} is {} on Steam, is the Steam ID right?",
+                    driver.name,
+                    driver.steam_id,
+                    steam_names.join(" / ")
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("76561197960287930", Profile::Id(76561197960287930); "bare")]
+    #[test_case(" 76561197960287930 ", Profile::Id(76561197960287930); "whitespace")]
+    #[test_case("https://steamcommunity.com/profiles/76561197960287930/", Profile::Id(76561197960287930); "profile URL")]
+    #[test_case("steamcommunity.com/profiles/76561197960287930", Profile::Id(76561197960287930); "without scheme")]
+    #[test_case("https://steamcommunity.com/id/gabelogannewell", Profile::Vanity("gabelogannewell".to_string()); "vanity URL")]
+    fn profile(input: &str, expected: Profile) {
+        assert_eq!(parse_profile(input).unwrap(), expected);
+    }
+
+    #[test_case("7656119796028793"; "too short")]
+    #[test_case("STEAM_0:0:11101"; "legacy format")]
+    #[test_case("https://steamcommunity.com/groups/something"; "group")]
+    #[test_case("https://example.com/profiles/76561197960287930"; "other site")]
+    #[test_case("https://steamcommunity.com/id/"; "empty vanity")]
+    fn bad_profile(input: &str) {
+        assert!(parse_profile(input).is_err());
+    }
+
+    #[test_case("Gabe Newell", &["gaben_1962"], true; "part of the name")]
+    #[test_case("Gabe Newell", &["xX_Sniper_Xx", "Gabe Newell"], true; "real name")]
+    #[test_case("Jörg Müller", &["jorg_m"], true; "transliterated")]
+    #[test_case("Jorg Muller", &["Jorg Mueller"], true; "typo")]
+    #[test_case("Gabe Newell", &["Robin Walker"], false; "someone else")]
+    fn similar_names(ticket_name: &str, steam_names: &[&str], similar: bool) {
+        let steam_names: Vec<_> = steam_names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(name_similarity(ticket_name, &steam_names) >= 0.5, similar);
+    }
+
+    #[test]
+    fn groups() {
+        let response = serde_json::json!({"response": {"success": true, "groups": [{"gid": "4"}, {"gid": "103582"}]}});
+        assert_eq!(group_ids(&response), Some(vec![4, 103582]));
+        let private =
+            serde_json::json!({"response": {"success": false, "error": "Private profile"}});
+        assert_eq!(group_ids(&private), None);
+    }
+
+    #[test]
+    fn owns_game() {
+        let public = [serde_json::json!({"communityvisibilitystate": 3})];
+        let private = [serde_json::json!({"communityvisibilitystate": 1})];
+        let owned = |json: &str| serde_json::from_str::<Value>(json).unwrap();
+        assert_eq!(
+            ownership(
+                &public,
+                &owned(r#"{"response": {"game_count": 1, "games": [{"appid": 244210}]}}"#)
+            ),
+            Ownership::Owns
+        );
+        assert_eq!(
+            ownership(&public, &owned(r#"{"response": {"game_count": 0}}"#)),
+            Ownership::DoesNotOwn
+        );
+        assert_eq!(
+            ownership(&public, &owned(r#"{"response": {}}"#)),
+            Ownership::Private
+        );
+        assert_eq!(
+            ownership(&private, &owned(r#"{"response": {}}"#)),
+            Ownership::Private
+        );
+        assert_eq!(ownership(&[], &Value::Null), Ownership::NoProfile);
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+
+use crate::{
+    acsm::BasicDriver,
+    report::{ProblemKind, Report},
+    State,
+};
+
+const DEFAULT_SUBJECT: &str = "Join our Steam group to get on the grid";
+
+const DEFAULT_BODY: &str = "Hi {name},\n\nTo race with us, your Steam account needs \
+    to be in our Steam group. Join it at {group_url} and you'll be on the grid after \
+    the next update. If you're in it already, make your Steam profile public so we \
+    can see that.\n";
+
+/// From STEAM_REQUIRED_GROUP, only members go on the grid
+#[derive(Debug)]
+pub struct SteamGroup {
+    /// The account ID, the lower 32 bits of the group's SteamID64
+    id: u32,
+    /// From STEAM_GROUP_URL, where to join
+    url: String,
+    /// From STEAM_GROUP_EMAIL_SUBJECT and STEAM_GROUP_EMAIL_BODY
+    subject: String,
+    body: String,
+    /// By Steam ID, whether it was in the group the last time we asked
+    members: Mutex<HashMap<u64, bool>>,
+}
+
+fn var(name: &str) -> Option<String> {
+    dotenv::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// The group's SteamID64, like `103582791429521408`, or just its account ID
+fn parse_id(text: &str) -> Result<u32> {
+    let id: u64 = text
+        .parse()
+        .with_context(|| format!("STEAM_REQUIRED_GROUP `{}` is not a number", text))?;
+    Ok(id as u32)
+}
+
+/// Fill in `{name}` and `{group_url}`, and turn `\n` into line breaks as .env
+/// values are on one line
+fn fill_in(template: &str, name: &str, group_url: &str) -> String {
+    template
+        .replace("\\n", "\n")
+        .replace("{name}", name)
+        .replace("{group_url}", group_url)
+}
+
+impl SteamGroup {
+    pub fn from_env(has_api_key: bool) -> Result<Option<SteamGroup>> {
+        let Some(id) = var("STEAM_REQUIRED_GROUP") else {
+            return Ok(None);
+        };
+        if !has_api_key {
+            return Err(anyhow!("STEAM_REQUIRED_GROUP needs STEAM_API_KEY"));
+        }
+        Ok(Some(SteamGroup {
+            id: parse_id(&id)?,
+            url: var("STEAM_GROUP_URL").context("STEAM_REQUIRED_GROUP needs STEAM_GROUP_URL")?,
+            subject: var("STEAM_GROUP_EMAIL_SUBJECT").unwrap_or_else(|| DEFAULT_SUBJECT.into()),
+            body: var("STEAM_GROUP_EMAIL_BODY").unwrap_or_else(|| DEFAULT_BODY.into()),
+            members: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Members stay members, others are asked about again every time, to
+    /// place them as soon as they join
+    async fn is_member(&self, state: &State, steam_id: u64) -> Result<bool> {
+        if self.members.lock().await.get(&steam_id) == Some(&true) {
+            return Ok(true);
+        }
+        let is_member = state
+            .steam
+            .group_ids(&state.http.steam, steam_id)
+            .await?
+            .is_some_and(|group_ids| group_ids.contains(&self.id));
+        self.members.lock().await.insert(steam_id, is_member);
+        Ok(is_member)
+    }
+
+    async fn was_member(&self, steam_id: u64) -> Option<bool> {
+        self.members.lock().await.get(&steam_id).copied()
+    }
+}
+
+/// With STEAM_REQUIRED_GROUP, hold back drivers who aren't in the group, or
+/// whose profile is private so we can't tell. If Steam can't be asked, drivers
+/// go through, rather than emptying the grid while it's down.
+pub async fn hold_non_members(state: &State, drivers: &mut Vec<BasicDriver>, report: &mut Report) {
+    let Some(group) = &state.steam_group else {
+        return;
+    };
+    let mut members = Vec::with_capacity(drivers.len());
+    for driver in drivers.drain(..) {
+        match group.is_member(state, driver.steam_id).await {
+            Ok(true) => members.push(driver),
+            Ok(false) => report.add(
+                ProblemKind::NotInSteamGroup,
+                driver.order_guid.as_deref(),
+                driver.ticket_guid.as_deref(),
+                format!(
+                    "{} steam_id={} is not in the Steam group, or has a private profile",
+                    driver.name, driver.steam_id
+                ),
+            ),
+            Err(e) => {
+                warn!(
+                    "Failed to check Steam group of steam_id={}, letting them through: {:?}",
+                    driver.steam_id, e
+                );
+                members.push(driver);
+            }
+        }
+    }
+    *drivers = members;
+}
+
+/// Email the buyers of tickets that were held back for not being in the group
+/// how to join, once per ticket. Needs SMTP_URL.
+pub async fn notify_non_members(state: &State) -> Result<()> {
+    let (Some(group), Some(mailer)) = (&state.steam_group, &state.mailer) else {
+        return Ok(());
+    };
+    let drivers: Vec<BasicDriver> = state
+        .cached_orders
+        .lock()
+        .await
+        .values()
+        .flat_map(|(drivers, _)| drivers.iter().cloned())
+        .collect();
+    for driver in drivers {
+        let (Some(ticket_guid), Some(email)) = (&driver.ticket_guid, &driver.email) else {
+            continue;
+        };
+        if group.was_member(driver.steam_id).await != Some(false)
+            || state
+                .store
+                .lock()
+                .await
+                .data()
+                .steam_group_notified
+                .contains(ticket_guid)
+        {
+            continue;
+        }
+        let body = fill_in(&group.body, &driver.name, &group.url);
+        if let Err(e) = mailer.send(email, &group.subject, body).await {
+            warn!(
+                "Failed to email steam_id={} about the Steam group: {:?}",
+                driver.steam_id, e
+            );
+            continue;
+        }
+        info!(
+            "Emailed {} steam_id={} to join the Steam group",
+            driver.name, driver.steam_id
+        );
+        state
+            .store
+            .lock()
+            .await
+            .update(|data| data.steam_group_notified.push(ticket_guid.clone()))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_id() {
+        assert_eq!(parse_id("103582791429521408").unwrap(), 0);
+        assert_eq!(parse_id("103582791429521412").unwrap(), 4);
+        assert_eq!(parse_id("4").unwrap(), 4);
+        assert!(parse_id("ourclub").is_err());
+    }
+
+    #[test]
+    fn fills_in_template() {
+        assert_eq!(
+            fill_in(
+                "Hi {name},\\nJoin at {group_url}",
+                "Gabe",
+                "https://steamcommunity.com/groups/club"
+            ),
+            "Hi Gabe,\nJoin at https://steamcommunity.com/groups/club"
+        );
+    }
+}
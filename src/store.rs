@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+use crate::{
+    acsm::BasicDriver,
+    chargebacks::Chargeback,
+    os_keyring,
+    redact::Secret,
+    self_service::{AuditEntry, DriverEdit},
+    snapshots::Snapshot,
+};
+
+/// Everything we keep across restarts that doesn't come from Eventix
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreData {
+    /// Drivers added through the admin API, which full updates keep
+    pub manual_drivers: Vec<BasicDriver>,
+    /// Ignored on top of IGNORED_STEAM_IDS, added through the admin API
+    pub ignored_steam_ids: Vec<u64>,
+    /// Eventix order or ticket GUIDs to leave out, on top of IGNORED_GUIDS
+    pub ignored_guids: Vec<String>,
+    /// Steam IDs by ticket GUID, taking precedence over the ticket metadata
+    pub steam_id_overrides: HashMap<String, u64>,
+    /// Names corrected by the drivers themselves, by ticket GUID
+    pub driver_edits: HashMap<String, DriverEdit>,
+    /// Names an admin let through the name filter, by ticket GUID
+    pub approved_names: HashMap<String, DriverEdit>,
+    /// Every change to drivers after their tickets, oldest first
+    pub audit_log: Vec<AuditEntry>,
+    /// OAuth2 refresh tokens by source, so a restart doesn't need a new
+    /// authorization. Kept in the OS keyring instead with
+    /// CREDENTIAL_STORE=keyring.
+    pub refresh_tokens: HashMap<String, Secret<String>>,
+    /// Ticket types we closed the sales of because their class was full, so
+    /// we only reopen those
+    pub closed_ticket_types: Vec<String>,
+    /// SHA-256 of the ACSM results we emailed the drivers about, to only do
+    /// that once
+    pub emailed_results: Vec<String>,
+    /// Tickets whose buyer we emailed to join STEAM_REQUIRED_GROUP
+    pub steam_group_notified: Vec<String>,
+    /// Named copies of the ACSM files, taken through the admin API
+    pub snapshots: Vec<Snapshot>,
+    /// Drivers kept off the grid after a disputed payment, until an admin
+    /// clears them
+    pub chargebacks: Vec<Chargeback>,
+    /// EVENT_END times whose final sync and export are done, so a restart
+    /// doesn't write again
+    pub archived: Vec<DateTime<Utc>>,
+}
+
+pub struct Store {
+    path: PathBuf,
+    data: StoreData,
+    /// Keep refresh tokens in the OS keyring instead of the file
+    keyring: bool,
+}
+
+impl Store {
+    /// Load the store, starting empty if the file doesn't exist yet
+    pub async fn load(path: &Path) -> Result<Store> {
+        let data = match fs::read_to_string(path).await {
+            Ok(json_text) => serde_json::from_str(&json_text)
+                .with_context(|| format!("Failed to parse state file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoreData::default(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read state file {}", path.display()))
+            }
+        };
+        Ok(Store {
+            path: path.to_path_buf(),
+            data,
+            keyring: false,
+        })
+    }
+
+    /// Load the store at STATE_FILE
+    pub async fn from_env() -> Result<Store> {
+        let mut store = Store::load(&PathBuf::from(
+            dotenv::var("STATE_FILE").unwrap_or_else(|_| "eventix2acsm-state.json".into()),
+        ))
+        .await?;
+        store.keyring = os_keyring::enabled()?;
+        Ok(store)
+    }
+
+    pub fn data(&self) -> &StoreData {
+        &self.data
+    }
+
+    /// The OAuth2 refresh token of the source, from wherever it's kept
+    pub async fn refresh_token(&self, source: &str) -> Result<Option<Secret<String>>> {
+        if self.keyring {
+            return os_keyring::refresh_token(source).await;
+        }
+        Ok(self.data.refresh_tokens.get(source).cloned())
+    }
+
+    pub async fn set_refresh_token(
+        &mut self,
+        source: &str,
+        refresh_token: Secret<String>,
+    ) -> Result<()> {
+        if self.keyring {
+            return os_keyring::set_refresh_token(source, refresh_token).await;
+        }
+        self.update(|data| {
+            data.refresh_tokens
+                .insert(source.to_string(), refresh_token);
+        })
+        .await
+    }
+
+    /// Change the data and write it out
+    pub async fn update<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut StoreData),
+    {
+        f(&mut self.data);
+        let mut tmp_filename = self.path.as_os_str().to_os_string();
+        tmp_filename.push(".tmp");
+        fs::write(&tmp_filename, serde_json::to_string_pretty(&self.data)?)
+            .await
+            .context("Failed to write state file")?;
+        fs::rename(&tmp_filename, &self.path)
+            .await
+            .context("Failed to replace state file")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use tokio::net::TcpListener;
+
+/// First file descriptor systemd passes, see sd_listen_fds(3)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Listening sockets passed by systemd socket activation, split into the main
+/// ones and those for the admin routes. Sockets go to the admin routes when
+/// their `FileDescriptorName=` is `admin`.
+#[cfg(unix)]
+pub fn listeners() -> Result<Option<(Vec<TcpListener>, Vec<TcpListener>)>> {
+    use std::os::fd::FromRawFd;
+
+    // These come from systemd, not .env
+    let Ok(pid) = std::env::var("LISTEN_PID") else {
+        return Ok(None);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count: i32 = std::env::var("LISTEN_FDS")
+        .context("LISTEN_PID set without LISTEN_FDS")?
+        .parse()
+        .context("LISTEN_FDS is not a number")?;
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    // Not for any child processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if count < 1 {
+        return Err(anyhow!("systemd passed no sockets"));
+    }
+    let mut listeners = Vec::new();
+    let mut admin_listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd hands us these descriptors, and nothing else in
+        // this process uses them
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)
+            .with_context(|| format!("systemd socket {} is not a TCP listener", fd))?;
+        info!("Using socket {} from systemd", listener.local_addr()?);
+        if names.next() == Some("admin") {
+            admin_listeners.push(listener);
+        } else {
+            listeners.push(listener);
+        }
+    }
+    if listeners.is_empty() {
+        return Err(anyhow!(
+            "systemd passed only admin sockets, nothing would serve the main routes"
+        ));
+    }
+    Ok(Some((listeners, admin_listeners)))
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Result<Option<(Vec<TcpListener>, Vec<TcpListener>)>> {
+    Ok(None)
+}
@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::Mutex;
+
+use crate::{
+    acsm::{BasicDriver, ClassSlots},
+    events::EventKind,
+    report::{ProblemKind, Report},
+    tickets, State,
+};
+
+/// From MAX_CARS_PER_TEAM, how many cars a team may field per class
+#[derive(Debug)]
+pub struct TeamCap {
+    max: usize,
+    /// Steam IDs of drivers we notified about, to only do that once
+    notified: Mutex<HashSet<u64>>,
+}
+
+impl TeamCap {
+    pub fn from_env() -> Result<Option<TeamCap>> {
+        let Some(max) = dotenv::var("MAX_CARS_PER_TEAM")
+            .ok()
+            .filter(|max| !max.is_empty())
+        else {
+            return Ok(None);
+        };
+        let max: usize = max.parse().context("MAX_CARS_PER_TEAM is not a number")?;
+        if max == 0 {
+            return Ok(None);
+        }
+        Ok(Some(TeamCap {
+            max,
+            notified: Mutex::new(HashSet::new()),
+        }))
+    }
+}
+
+/// So `Team Foo` and `team-foo` are one team
+fn normalize(team_name: &str) -> String {
+    any_ascii::any_ascii(team_name)
+        .to_lowercase()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+/// Indices into `drivers`, with their team and class, of those whose team
+/// already has `max` cars in that class, counting earliest payment first.
+/// `others` are the drivers from other orders, which count but are never left
+/// out themselves.
+fn over_cap(
+    drivers: &[BasicDriver],
+    others: &[BasicDriver],
+    classes: &[ClassSlots],
+    max: usize,
+) -> Vec<(usize, String, String)> {
+    let mut candidates = drivers
+        .iter()
+        .enumerate()
+        .map(|(index, driver)| (driver, Some(index)))
+        .chain(others.iter().map(|driver| (driver, None)))
+        .filter_map(|(driver, index)| {
+            let team = driver
+                .team_name
+                .as_deref()
+                .filter(|team| !normalize(team).is_empty())?;
+            Some((
+                driver.paid_at,
+                index,
+                team,
+                tickets::class_of(driver, classes),
+            ))
+        })
+        .collect::<Vec<_>>();
+    // Drivers without a payment time, like manual ones, go first
+    candidates.sort_by_key(|&(paid_at, _, _, _)| paid_at);
+    let mut cars: HashMap<(String, String), usize> = HashMap::new();
+    let mut over = Vec::new();
+    for (_, index, team, class) in candidates {
+        let count = cars.entry((normalize(team), class.clone())).or_default();
+        if *count < max {
+            *count += 1;
+        } else if let Some(index) = index {
+            over.push((index, team.to_string(), class));
+        }
+    }
+    over
+}
+
+/// With MAX_CARS_PER_TEAM, put drivers of teams that already have that many
+/// cars in their class on the waitlist, and notify once about each
+pub async fn hold_over_cap(
+    state: &State,
+    drivers: &mut Vec<BasicDriver>,
+    others: &[BasicDriver],
+    classes: &[ClassSlots],
+    report: &mut Report,
+) {
+    let Some(team_cap) = &state.team_cap else {
+        return;
+    };
+    let over = over_cap(drivers, others, classes, team_cap.max);
+    let mut notified = team_cap.notified.lock().await;
+    for (index, team, class) in &over {
+        let driver = &drivers[*index];
+        report.add(
+            ProblemKind::TeamFull,
+            driver.order_guid.as_deref(),
+            driver.ticket_guid.as_deref(),
+            format!(
+                "{} already has {} cars in {}, {} steam_id={} is on the waitlist",
+                team, team_cap.max, class, driver.name, driver.steam_id
+            ),
+        );
+        if notified.insert(driver.steam_id) {
+            info!(
+                "{} steam_id={} is on the waitlist, {} is full in {}",
+                driver.name, driver.steam_id, team, class
+            );
+            state.events.emit(EventKind::TeamFull {
+                team: team.clone(),
+                class: class.clone(),
+                steam_id: driver.steam_id,
+            });
+        }
+    }
+    let over: HashSet<usize> = over.into_iter().map(|(index, _, _)| index).collect();
+    *drivers = drivers
+        .drain(..)
+        .enumerate()
+        .filter(|(index, _)| !over.contains(index))
+        .map(|(_, driver)| driver)
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn driver(steam_id: u64, team: Option<&str>, car: &str, paid_at: &str) -> BasicDriver {
+        BasicDriver {
+            team_name: team.map(str::to_string),
+            paid_at: Some(paid_at.parse().unwrap()),
+            ..BasicDriver::test(steam_id, car)
+        }
+    }
+
+    fn classes() -> Vec<ClassSlots> {
+        ["gt3", "gt4"]
+            .into_iter()
+            .map(|car| ClassSlots {
+                name: car.to_uppercase(),
+                cars: vec![car.to_string()],
+                guids: vec![String::new(); 10],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn caps_teams_per_class() {
+        let drivers = vec![
+            driver(3, Some("Team Foo"), "gt3", "2026-01-03T00:00:00Z"),
+            driver(1, Some("team-foo"), "gt3", "2026-01-01T00:00:00Z"),
+            driver(2, Some("TEAM FOO"), "gt3", "2026-01-02T00:00:00Z"),
+            driver(4, Some("Team Foo"), "gt4", "2026-01-04T00:00:00Z"),
+            driver(5, None, "gt3", "2026-01-05T00:00:00Z"),
+            driver(6, None, "gt3", "2026-01-06T00:00:00Z"),
+            driver(7, None, "gt3", "2026-01-07T00:00:00Z"),
+        ];
+        let over = over_cap(&drivers, &[], &classes(), 2);
+        assert_eq!(over, vec![(0, "Team Foo".to_string(), "GT3".to_string())]);
+    }
+
+    #[test_case("2026-01-01T00:00:00Z", vec![]; "paid before others")]
+    #[test_case("2026-01-09T00:00:00Z", vec![0]; "others paid first")]
+    fn counts_others(paid_at: &str, expected: Vec<usize>) {
+        let others = vec![
+            driver(1, Some("Foo"), "gt3", "2026-01-02T00:00:00Z"),
+            driver(2, Some("Foo"), "gt3", "2026-01-03T00:00:00Z"),
+        ];
+        let drivers = vec![driver(3, Some("Foo"), "gt3", paid_at)];
+        let over: Vec<usize> = over_cap(&drivers, &others, &classes(), 2)
+            .into_iter()
+            .map(|(index, _, _)| index)
+            .collect();
+        assert_eq!(over, expected);
+    }
+}
@@ -0,0 +1,121 @@
+#[cfg(feature = "otel")]
+use anyhow::Context as _;
+use anyhow::Result;
+#[cfg(feature = "otel")]
+use log::info;
+#[cfg(not(feature = "otel"))]
+use log::warn;
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::WithExportConfig;
+use std::future::Future;
+
+/// Key and value of a span or event attribute
+pub type Attributes = Vec<(&'static str, String)>;
+
+/// Flushes the last spans when dropped at exit
+pub struct Guard {
+    #[cfg(feature = "otel")]
+    enabled: bool,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.enabled {
+            global::shutdown_tracer_provider();
+        }
+    }
+}
+
+fn endpoint_from_env() -> Option<String> {
+    dotenv::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+/// Export spans over OTLP/HTTP to OTEL_EXPORTER_OTLP_ENDPOINT, if set
+#[cfg(feature = "otel")]
+pub fn init() -> Result<Guard> {
+    let Some(endpoint) = endpoint_from_env() else {
+        return Ok(Guard { enabled: false });
+    };
+    let service_name = dotenv::var("OTEL_SERVICE_NAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "eventix2acsm".to_string());
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+            opentelemetry_sdk::Resource::new([KeyValue::new("service.name", service_name)]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+        .context("Failed to set up the OTLP exporter")?;
+    info!("Exporting traces to {}", endpoint);
+    Ok(Guard { enabled: true })
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init() -> Result<Guard> {
+    if endpoint_from_env().is_some() {
+        warn!("OTEL_EXPORTER_OTLP_ENDPOINT is set, but this build doesn't have the otel feature");
+    }
+    Ok(Guard {})
+}
+
+/// Run the future in a span, as a child of the span it's called in
+pub async fn in_span<F: Future>(name: String, attributes: Attributes, future: F) -> F::Output {
+    #[cfg(feature = "otel")]
+    {
+        let tracer = global::tracer("eventix2acsm");
+        let span = tracer
+            .span_builder(name)
+            .with_attributes(
+                attributes
+                    .into_iter()
+                    .map(|(key, value)| KeyValue::new(key, value)),
+            )
+            .start(&tracer);
+        let context = Context::current_with_span(span);
+        let output = future.with_context(context.clone()).await;
+        context.span().end();
+        output
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = (name, attributes);
+        future.await
+    }
+}
+
+/// Note something that happened during the current span, like a retry
+pub fn event(name: &'static str, attributes: Attributes) {
+    #[cfg(feature = "otel")]
+    Context::current().span().add_event(
+        name,
+        attributes
+            .into_iter()
+            .map(|(key, value)| KeyValue::new(key, value))
+            .collect(),
+    );
+    #[cfg(not(feature = "otel"))]
+    let _ = (name, attributes);
+}
+
+/// Mark the current span as failed
+pub fn set_error(message: String) {
+    #[cfg(feature = "otel")]
+    Context::current().span().set_status(Status::error(message));
+    #[cfg(not(feature = "otel"))]
+    let _ = message;
+}
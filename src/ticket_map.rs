@@ -0,0 +1,355 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+use crate::acsm::EntrySettings;
+
+/// What a ticket type gets on the grid
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TicketMapping {
+    pub car: String,
+    /// Put the drivers in the class with this name, instead of the first class
+    /// that has the car
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    /// The drivers may take the slots kept free with RESERVED_SLOTS
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reserved: bool,
+    /// The drivers go ahead of those with other ticket types, like season
+    /// pass holders
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub priority: bool,
+    #[serde(flatten)]
+    pub entry: EntrySettings,
+}
+
+impl TicketMapping {
+    /// Just the car, the rest as it is in the slot
+    pub fn new(car: String) -> TicketMapping {
+        TicketMapping {
+            car,
+            class: None,
+            reserved: false,
+            priority: false,
+            entry: EntrySettings::default(),
+        }
+    }
+}
+
+/// A mapping for ticket types by name, so it still works when cloning the
+/// event gives them new GUIDs
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct NamePattern {
+    /// With `*` for any text and `?` for any one character
+    pub name: String,
+    #[serde(flatten)]
+    pub mapping: TicketMapping,
+}
+
+/// Whether the text matches the pattern, with `*` and `?` as wildcards
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it matched up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more character
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Add-on tickets have no car of their own, so they get an empty one until
+/// `attach_add_ons` moves them
+pub static ADD_ON: TicketMapping = TicketMapping {
+    car: String::new(),
+    class: None,
+    reserved: false,
+    priority: false,
+    entry: EntrySettings {
+        ballast: None,
+        restrictor: None,
+        skins: Vec::new(),
+        race_numbers: None,
+        fixed_setup: None,
+    },
+};
+
+/// TICKET_MAP_FILE, if set
+pub fn path_from_env() -> Option<PathBuf> {
+    dotenv::var("TICKET_MAP_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Ticket types, or the source's equivalent, to what they get on the grid
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TicketMap {
+    /// By ticket GUID, sorted to write them out the same way every time
+    #[serde(default)]
+    pub tickets: BTreeMap<String, TicketMapping>,
+    /// For ticket types that aren't in `tickets`, the first that matches
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patterns: Vec<NamePattern>,
+    /// Ticket types that aren't for drivers, like spectator tickets or merch,
+    /// by GUID or name pattern. They're left out without a problem report.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+}
+
+impl TicketMap {
+    /// From the JSON file at TICKET_MAP_FILE, or else the plain
+    /// TICKET_ID_TO_CAR_MAP
+    pub fn from_env() -> Result<TicketMap> {
+        let car_map = dotenv::var("TICKET_ID_TO_CAR_MAP")
+            .ok()
+            .filter(|car_map| !car_map.is_empty());
+        let ticket_map = match path_from_env() {
+            Some(_) if car_map.is_some() => {
+                return Err(anyhow!(
+                    "Set TICKET_MAP_FILE or TICKET_ID_TO_CAR_MAP, not both"
+                ))
+            }
+            Some(path) => return TicketMap::load(&path).context("Invalid TICKET_MAP_FILE"),
+            None => TicketMap::from_car_map(&car_map.context("TICKET_ID_TO_CAR_MAP not set")?)?,
+        };
+        ticket_map.check()?;
+        Ok(ticket_map)
+    }
+
+    /// From a file in the TICKET_MAP_FILE format
+    pub fn load(path: &Path) -> Result<TicketMap> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let ticket_map: TicketMap = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        ticket_map.check()?;
+        Ok(ticket_map)
+    }
+
+    /// From a comma separated list of `guid:car`
+    pub fn from_car_map(text: &str) -> Result<TicketMap> {
+        let tickets = text
+            .split(',')
+            .map(|pair| {
+                let (ticket_id, car) = pair
+                    .split_once(':')
+                    .context("Missing : separator in TICKET_ID_TO_CAR_MAP")?;
+                Ok((ticket_id.to_string(), TicketMapping::new(car.to_string())))
+            })
+            .collect::<Result<_>>()?;
+        Ok(TicketMap {
+            tickets,
+            patterns: Vec::new(),
+            ignore: Vec::new(),
+        })
+    }
+
+    /// Write it out in the TICKET_MAP_FILE format, replacing the file in one
+    /// go
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_filename = path.as_os_str().to_os_string();
+        tmp_filename.push(".tmp");
+        fs::write(&tmp_filename, serde_json::to_string_pretty(self)?)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        fs::rename(&tmp_filename, path)
+            .await
+            .with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// Catch what the file format can't
+    pub fn check(&self) -> Result<()> {
+        if self.tickets.is_empty() && self.patterns.is_empty() {
+            return Err(anyhow!("The ticket map is empty"));
+        }
+        for (ticket, mapping) in self.mappings() {
+            if mapping.car.is_empty() {
+                return Err(anyhow!("No car for {}", ticket));
+            }
+            if let Some(race_numbers) = &mapping.entry.race_numbers {
+                if race_numbers.first > race_numbers.last {
+                    return Err(anyhow!(
+                        "Race numbers for {} go from {} down to {}",
+                        ticket,
+                        race_numbers.first,
+                        race_numbers.last
+                    ));
+                }
+            }
+        }
+        if let Some(ticket_id) = self.tickets.keys().find(|id| self.ignore.contains(id)) {
+            return Err(anyhow!("Ticket {} is both mapped and ignored", ticket_id));
+        }
+        Ok(())
+    }
+
+    /// By GUID, or else by the name of the ticket type if there is one
+    pub fn get(&self, ticket_id: &str, name: Option<&str>) -> Option<&TicketMapping> {
+        self.tickets.get(ticket_id).or_else(|| {
+            let name = name?;
+            self.patterns
+                .iter()
+                .find(|pattern| glob_match(&pattern.name, name))
+                .map(|pattern| &pattern.mapping)
+        })
+    }
+
+    /// Whether the ticket type is on the ignore list, by GUID or name
+    pub fn is_ignored(&self, ticket_id: &str, name: Option<&str>) -> bool {
+        self.ignore
+            .iter()
+            .any(|ignore| ignore == ticket_id || name.is_some_and(|name| glob_match(ignore, name)))
+    }
+
+    /// Whether anything goes by the names of the ticket types
+    pub fn has_patterns(&self) -> bool {
+        !self.patterns.is_empty() || !self.ignore.is_empty()
+    }
+
+    /// Every mapping, described as `ticket <guid>` or `tickets named <pattern>`
+    pub fn mappings(&self) -> impl Iterator<Item = (String, &TicketMapping)> {
+        self.tickets
+            .iter()
+            .map(|(ticket_id, mapping)| (format!("ticket {}", ticket_id), mapping))
+            .chain(
+                self.patterns
+                    .iter()
+                    .map(|pattern| (format!("tickets named {}", pattern.name), &pattern.mapping)),
+            )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acsm::RaceNumbers;
+    use test_case::test_case;
+
+    #[test]
+    fn file_format() {
+        let ticket_map: TicketMap = serde_json::from_str(
+            r#"{
+                "tickets": {
+                    "pro": {
+                        "car": "bmw_m6_gt3",
+                        "class": "Pro",
+                        "ballast": 20,
+                        "skins": ["red", "blue"],
+                        "race_numbers": {"first": 1, "last": 99}
+                    },
+                    "am": {"car": "bmw_m6_gt3"}
+                }
+            }"#,
+        )
+        .unwrap();
+        ticket_map.check().unwrap();
+        let pro = ticket_map.get("pro", None).unwrap();
+        assert_eq!(pro.class.as_deref(), Some("Pro"));
+        assert_eq!(pro.entry.ballast, Some(20));
+        assert_eq!(pro.entry.restrictor, None);
+        assert_eq!(pro.entry.skins, ["red", "blue"]);
+        assert_eq!(
+            pro.entry.race_numbers,
+            Some(RaceNumbers { first: 1, last: 99 })
+        );
+        assert_eq!(
+            ticket_map.get("am", None).unwrap().entry,
+            EntrySettings::default()
+        );
+    }
+
+    #[test]
+    fn bad_race_numbers() {
+        let ticket_map: TicketMap = serde_json::from_str(
+            r#"{"tickets": {"pro": {"car": "a", "race_numbers": {"first": 9, "last": 1}}}}"#,
+        )
+        .unwrap();
+        assert!(ticket_map.check().is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        let ticket_map: TicketMap = serde_json::from_str(
+            r#"{
+                "tickets": {"guid-1": {"car": "exact"}},
+                "patterns": [
+                    {"name": "GT3 Entry*", "car": "gt3", "class": "GT3"},
+                    {"name": "*Entry", "car": "other"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        ticket_map.check().unwrap();
+        let car = |ticket_id, name| ticket_map.get(ticket_id, name).map(|m| m.car.as_str());
+        assert_eq!(car("guid-1", Some("GT3 Entry")), Some("exact"));
+        assert_eq!(car("guid-2", Some("GT3 Entry - Early Bird")), Some("gt3"));
+        assert_eq!(car("guid-2", Some("GT4 Entry")), Some("other"));
+        assert_eq!(car("guid-2", Some("Merch")), None);
+        assert_eq!(car("guid-2", None), None);
+    }
+
+    #[test]
+    fn ignore() {
+        let ticket_map: TicketMap = serde_json::from_str(
+            r#"{
+                "tickets": {"guid-1": {"car": "gt3"}},
+                "ignore": ["guid-2", "Spectator*"]
+            }"#,
+        )
+        .unwrap();
+        ticket_map.check().unwrap();
+        assert!(ticket_map.is_ignored("guid-2", None));
+        assert!(ticket_map.is_ignored("guid-3", Some("Spectator - Sunday")));
+        assert!(!ticket_map.is_ignored("guid-3", Some("Merch")));
+        assert!(!ticket_map.is_ignored("guid-1", Some("GT3 Entry")));
+        let ticket_map = TicketMap {
+            ignore: vec!["guid-1".to_string()],
+            ..ticket_map
+        };
+        assert!(ticket_map.check().is_err());
+    }
+
+    #[test_case("GT3 Entry*", "GT3 Entry", true)]
+    #[test_case("GT3 Entry*", "GT3 Entry - Early Bird", true)]
+    #[test_case("GT3 Entry*", "GT4 Entry", false)]
+    #[test_case("GT? Entry", "GT4 Entry", true)]
+    #[test_case("*Pro*", "GT3 Pro Entry", true)]
+    #[test_case("*Pro*", "GT3 Am Entry", false)]
+    #[test_case("a*b*c", "aXbYbZc", true)]
+    #[test_case("Entry", "entry", false)]
+    fn glob(pattern: &str, text: &str, expected: bool) {
+        assert_eq!(glob_match(pattern, text), expected);
+    }
+
+    #[test]
+    fn car_map() {
+        let ticket_map = TicketMap::from_car_map("1:bmw_m6_gt3,2:ks_audi_r8_lms").unwrap();
+        assert_eq!(ticket_map.get("2", None).unwrap().car, "ks_audi_r8_lms");
+        assert!(TicketMap::from_car_map("1=bmw_m6_gt3").is_err());
+    }
+}
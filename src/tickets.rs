@@ -0,0 +1,605 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use log::{debug, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use crate::{
+    acsm::{BasicDriver, ClassSlots},
+    nation,
+    report::{ProblemKind, Report},
+    self_service::AuditEntry,
+    ticket_map::{self, TicketMap, TicketMapping},
+};
+
+/// IDs of the metadata fields (Eventix) or questions (Pretix) that hold the
+/// driver's details
+#[derive(Debug)]
+pub struct MetaDataIDs {
+    pub first_name: String,
+    pub last_name: String,
+    pub team_name: String,
+    pub steam_id: String,
+    /// More Steam ID fields on the same ticket, for team tickets in driver
+    /// swaps. Their drivers become co-drivers of the entry.
+    pub co_driver_steam_ids: Vec<String>,
+    pub nationality: Option<String>,
+    pub skill: Option<String>,
+    pub pace: Option<String>,
+}
+
+/// What to do with tickets whose ticket type has no car mapped, e.g. merch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmappedTicketPolicy {
+    /// Leave the ticket out and add it to the problem report
+    Skip,
+    /// Fail the whole batch
+    Fail,
+}
+
+impl FromStr for UnmappedTicketPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(UnmappedTicketPolicy::Skip),
+            "fail" => Ok(UnmappedTicketPolicy::Fail),
+            _ => Err(anyhow!("Unknown unmapped ticket policy: {}", s)),
+        }
+    }
+}
+
+/// What to do when the same Steam ID is on several paid tickets for the same
+/// car
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Keep the ticket that was paid first
+    Earliest,
+    /// Keep the ticket that was paid last
+    Latest,
+    /// Leave them all out until an admin ignores all but one
+    Flag,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "earliest" => Ok(DuplicatePolicy::Earliest),
+            "latest" => Ok(DuplicatePolicy::Latest),
+            "flag" => Ok(DuplicatePolicy::Flag),
+            _ => Err(anyhow!("Unknown duplicate Steam ID policy: {}", s)),
+        }
+    }
+}
+
+/// How tolerant to be of individual bad tickets
+#[derive(Debug, Clone, Copy)]
+pub struct TicketPolicy {
+    pub unmapped: UnmappedTicketPolicy,
+    /// Fail a full sync if more than this fraction of driver tickets has
+    /// missing or malformed metadata. That many points at misconfigured
+    /// metadata IDs rather than buyer typos.
+    pub max_bad_fraction: f64,
+    /// Only tickets that were scanned at the venue count, for on-site events
+    pub require_check_in: bool,
+    pub duplicates: DuplicatePolicy,
+}
+
+impl MetaDataIDs {
+    /// Read `<prefix>_FIRST_NAME` and so on
+    pub fn from_env(prefix: &str) -> Result<MetaDataIDs> {
+        let required = |name: &str| {
+            let var = format!("{}_{}", prefix, name);
+            dotenv::var(&var).with_context(|| format!("{} not set", var))
+        };
+        let optional = |name: &str| {
+            dotenv::var(format!("{}_{}", prefix, name))
+                .ok()
+                .filter(|id| !id.is_empty())
+        };
+        Ok(MetaDataIDs {
+            first_name: required("FIRST_NAME")?,
+            last_name: required("LAST_NAME")?,
+            team_name: required("TEAM_NAME")?,
+            steam_id: required("STEAM_ID")?,
+            co_driver_steam_ids: optional("CO_DRIVER_STEAM_IDS")
+                .map(|ids| ids.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            nationality: optional("NATIONALITY"),
+            skill: optional("SKILL"),
+            pace: optional("PACE"),
+        })
+    }
+}
+
+impl TicketPolicy {
+    /// Fail if too many tickets had bad metadata
+    pub fn check_bad_fraction(&self, good_tickets: usize, bad_tickets: usize) -> Result<()> {
+        let total_tickets = good_tickets + bad_tickets;
+        if total_tickets > 0 && bad_tickets as f64 / total_tickets as f64 > self.max_bad_fraction {
+            return Err(anyhow!(
+                "{} of {} tickets have missing or malformed metadata, check the metadata IDs",
+                bad_tickets,
+                total_tickets
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Everything needed to turn a source's tickets into drivers
+pub struct TicketContext<'a> {
+    /// Ticket type, or the source's equivalent, to car and entry settings
+    pub ticket_map: &'a TicketMap,
+    /// Ticket type names by ticket type, for the ticket map's name patterns.
+    /// Without them, the ticket type itself is the name, like with CSV.
+    pub ticket_names: Option<&'a HashMap<String, String>>,
+    /// Ticket types for an extra driver in the buyer's main entry
+    pub add_on_tickets: &'a HashSet<String>,
+    pub metadata_ids: &'a MetaDataIDs,
+    pub policy: &'a TicketPolicy,
+    /// Corrected Steam IDs by ticket GUID, taking precedence over the metadata
+    pub steam_id_overrides: &'a HashMap<String, u64>,
+}
+
+impl TicketContext<'_> {
+    fn name<'b>(&'b self, ticket_type: &'b str) -> Option<&'b str> {
+        match self.ticket_names {
+            Some(ticket_names) => ticket_names.get(ticket_type).map(String::as_str),
+            None => Some(ticket_type),
+        }
+    }
+
+    /// The car and entry settings for the ticket type, and `ADD_ON` for
+    /// add-on tickets
+    pub fn mapping(&self, ticket_type: &str) -> Option<&TicketMapping> {
+        match self.ticket_map.get(ticket_type, self.name(ticket_type)) {
+            Some(mapping) => Some(mapping),
+            None => self
+                .add_on_tickets
+                .contains(ticket_type)
+                .then_some(&ticket_map::ADD_ON),
+        }
+    }
+
+    /// Leave out a ticket without a mapping, or fail or report it if it's
+    /// not on the ticket map's ignore list
+    pub fn skip_unmapped(
+        &self,
+        ticket_type: &str,
+        message: String,
+        report: &mut Report,
+        order_guid: Option<&str>,
+        ticket_guid: Option<&str>,
+    ) -> Result<()> {
+        if self
+            .ticket_map
+            .is_ignored(ticket_type, self.name(ticket_type))
+        {
+            debug!("Skipping ignored ticket {:?}", ticket_guid);
+            return Ok(());
+        }
+        if self.policy.unmapped == UnmappedTicketPolicy::Fail {
+            return Err(anyhow!(message));
+        }
+        report.add(
+            ProblemKind::UnmappedTicket,
+            order_guid,
+            ticket_guid,
+            message,
+        );
+        Ok(())
+    }
+}
+
+/// Build a driver from a ticket's metadata, as `(id, value)` pairs
+pub fn driver_from_metadata<'a>(
+    mapping: &TicketMapping,
+    metadata: impl IntoIterator<Item = (&'a str, Option<&'a str>)>,
+    tickets: &TicketContext,
+    order_guid: Option<&str>,
+    ticket_guid: Option<&str>,
+) -> Result<BasicDriver> {
+    let metadata_ids = tickets.metadata_ids;
+    let mut first_name = None;
+    let mut last_name = None;
+    let mut team_name = None;
+    // Every value, as the field can be repeated, and then those of the
+    // co-drivers by the position of their field
+    let mut steam_ids = Vec::new();
+    let mut co_driver_steam_ids = Vec::new();
+    let mut nationality = None;
+    let mut skill = None;
+    let mut pace = None;
+    for (metadata_id, value) in metadata {
+        // Optional fields that were left empty can come back as null
+        let value = value.map(str::trim);
+        if metadata_id == metadata_ids.first_name {
+            first_name = value;
+        } else if metadata_id == metadata_ids.last_name {
+            last_name = value;
+        } else if metadata_id == metadata_ids.team_name {
+            team_name = value;
+        } else if metadata_id == metadata_ids.steam_id {
+            steam_ids.extend(value.filter(|value| !value.is_empty()));
+        } else if let Some(position) = metadata_ids
+            .co_driver_steam_ids
+            .iter()
+            .position(|id| id == metadata_id)
+        {
+            co_driver_steam_ids.extend(
+                value
+                    .filter(|value| !value.is_empty())
+                    .map(|value| (position, value)),
+            );
+        } else if Some(metadata_id) == metadata_ids.nationality.as_deref() {
+            nationality = value.filter(|value| !value.is_empty());
+        } else if Some(metadata_id) == metadata_ids.skill.as_deref() {
+            skill = value.filter(|value| !value.is_empty());
+        } else if Some(metadata_id) == metadata_ids.pace.as_deref() {
+            pace = value.and_then(|value| value.replace(',', ".").parse().ok());
+        }
+    }
+    let steam_id_override =
+        ticket_guid.and_then(|ticket_guid| tickets.steam_id_overrides.get(ticket_guid));
+    let (Some(first_name), Some(last_name)) = (first_name, last_name) else {
+        return Err(anyhow!("Missing metadata for ticket: {:?}", ticket_guid));
+    };
+    co_driver_steam_ids.sort_by_key(|&(position, _)| position);
+    let mut values = steam_ids
+        .into_iter()
+        .chain(co_driver_steam_ids.into_iter().map(|(_, value)| value));
+    // The override corrects the first one
+    let mut steam_ids = match steam_id_override {
+        Some(steam_id) => {
+            values.next();
+            vec![*steam_id]
+        }
+        None => Vec::new(),
+    };
+    for value in values {
+        steam_ids.push(
+            value
+                .parse()
+                .with_context(|| format!("Steam ID is not a number: {:?}", value))?,
+        );
+    }
+    let mut steam_ids = steam_ids.into_iter().unique();
+    let Some(steam_id) = steam_ids.next() else {
+        return Err(anyhow!("Missing metadata for ticket: {:?}", ticket_guid));
+    };
+    let nation = nationality.and_then(|nationality| {
+        let nation = nation::normalize(nationality);
+        if nation.is_none() {
+            warn!(
+                "Unrecognized nationality {:?} for ticket: {:?}",
+                nationality, ticket_guid
+            );
+        }
+        nation
+    });
+
+    Ok(BasicDriver {
+        name: format!("{} {}", first_name, last_name),
+        car: mapping.car.clone(),
+        steam_id,
+        team_name: team_name.map(|x| x.to_string()),
+        class: mapping.class.clone(),
+        skill: skill.map(str::to_string),
+        pace,
+        nation: nation.map(str::to_string),
+        order_guid: order_guid.map(str::to_string),
+        ticket_guid: ticket_guid.map(str::to_string),
+        // Up to the source, from the order
+        paid_at: None,
+        co_drivers: steam_ids.collect(),
+        reserved: mapping.reserved,
+        priority: mapping.priority,
+        entry: mapping.entry.clone(),
+        // Also up to the source
+        email: None,
+    })
+}
+
+/// Turn the drivers of add-on tickets into co-drivers of the first entry with
+/// a car in the same order, for driver swaps. Add-ons without one are left out
+/// and reported.
+pub fn attach_add_ons(drivers: &mut Vec<BasicDriver>, report: &mut Report) {
+    let (add_ons, mut entries): (Vec<_>, Vec<_>) =
+        drivers.drain(..).partition(|driver| driver.car.is_empty());
+    for add_on in add_ons {
+        let entry = entries
+            .iter_mut()
+            .find(|entry| add_on.order_guid.is_some() && entry.order_guid == add_on.order_guid);
+        match entry {
+            Some(entry) => {
+                entry.co_drivers.push(add_on.steam_id);
+                entry.co_drivers.extend(add_on.co_drivers);
+            }
+            None => {
+                warn!(
+                    "No entry for add-on {} steam_id={} in order {:?}",
+                    add_on.name, add_on.steam_id, add_on.order_guid
+                );
+                report.add(
+                    ProblemKind::AddOnWithoutEntry,
+                    add_on.order_guid.as_deref(),
+                    add_on.ticket_guid.as_deref(),
+                    format!(
+                        "No entry in the order for add-on {} steam_id={}",
+                        add_on.name, add_on.steam_id
+                    ),
+                );
+            }
+        }
+    }
+    *drivers = entries;
+}
+
+/// An RFC 3339 timestamp in the ticket source's data
+pub fn parse_time(value: &serde_json::Value) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.as_str()?)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// Who left out a duplicate ticket, in the audit log
+const DUPLICATE_POLICY: &str = "duplicate Steam ID policy";
+
+/// The name of the first of `classes` the driver fits in. A car that isn't in
+/// any of them counts as a class of its own.
+pub fn class_of(driver: &BasicDriver, classes: &[ClassSlots]) -> String {
+    classes
+        .iter()
+        .find(|class| class.fits(driver))
+        .map(|class| class.name.clone())
+        .unwrap_or_else(|| driver.car.clone())
+}
+
+/// Leave out the drivers whose Steam ID is on another ticket in the same class
+/// too, as the policy says. `others` are the drivers already known from other
+/// orders, which compete but are never left out themselves. Returns the audit
+/// entries for the tickets left out.
+pub fn resolve_duplicates(
+    drivers: &mut Vec<BasicDriver>,
+    others: &[BasicDriver],
+    classes: &[ClassSlots],
+    policy: DuplicatePolicy,
+    report: &mut Report,
+) -> Vec<AuditEntry> {
+    let mut left_out = Vec::new();
+    let groups = drivers
+        .iter()
+        .chain(others)
+        .filter(|driver| !driver.car.is_empty())
+        .into_group_map_by(|driver| (driver.steam_id, class_of(driver, classes)));
+    for ((steam_id, class), mut group) in groups {
+        if group.len() < 2 {
+            continue;
+        }
+        group.sort_by_key(|driver| (driver.paid_at, driver.ticket_guid.clone()));
+        let kept = match policy {
+            DuplicatePolicy::Earliest => group.first(),
+            DuplicatePolicy::Latest => group.last(),
+            DuplicatePolicy::Flag => None,
+        }
+        .and_then(|driver| driver.ticket_guid.clone());
+        let tickets = group
+            .iter()
+            .map(|driver| driver.ticket_guid.as_deref().unwrap_or("-"))
+            .join(", ");
+        warn!(
+            "steam_id={} class={} is on tickets {}, keeping {:?}",
+            steam_id, class, tickets, kept
+        );
+        for driver in group {
+            if driver.ticket_guid == kept
+                || !drivers
+                    .iter()
+                    .any(|ours| ours.ticket_guid == driver.ticket_guid)
+            {
+                continue;
+            }
+            report.add(
+                ProblemKind::DuplicateSteamId,
+                driver.order_guid.as_deref(),
+                driver.ticket_guid.as_deref(),
+                format!(
+                    "steam_id={} class={} is on tickets {}, {}",
+                    steam_id,
+                    class,
+                    tickets,
+                    match &kept {
+                        Some(kept) => format!("keeping {}", kept),
+                        None => "ignore all but one".to_string(),
+                    }
+                ),
+            );
+            if let Some(ticket_guid) = &driver.ticket_guid {
+                left_out.push(AuditEntry {
+                    time: Utc::now(),
+                    ticket_guid: ticket_guid.clone(),
+                    by: DUPLICATE_POLICY.to_string(),
+                    field: "steam_id".to_string(),
+                    old: Some(steam_id.to_string()),
+                    new: None,
+                });
+            }
+        }
+    }
+    drivers.retain(|driver| {
+        !left_out
+            .iter()
+            .any(|entry| driver.ticket_guid.as_ref() == Some(&entry.ticket_guid))
+    });
+    left_out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn driver(steam_id: u64, car: &str, order_guid: &str) -> BasicDriver {
+        BasicDriver {
+            order_guid: Some(order_guid.to_string()),
+            ticket_guid: Some(format!("ticket-{}", order_guid)),
+            ..BasicDriver::test(steam_id, car)
+        }
+    }
+
+    fn paid(paid_at: &str, driver: BasicDriver) -> BasicDriver {
+        BasicDriver {
+            paid_at: Some(paid_at.parse().unwrap()),
+            ..driver
+        }
+    }
+
+    fn classes() -> Vec<ClassSlots> {
+        [("GT3", &["gt3", "gt3_evo"][..]), ("GT4", &["gt4"][..])]
+            .into_iter()
+            .map(|(name, cars)| ClassSlots {
+                name: name.to_string(),
+                cars: cars.iter().map(|car| car.to_string()).collect(),
+                guids: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn ticket_guids(drivers: &[BasicDriver]) -> Vec<&str> {
+        drivers
+            .iter()
+            .filter_map(|driver| driver.ticket_guid.as_deref())
+            .collect()
+    }
+
+    #[test_case(DuplicatePolicy::Earliest, vec!["ticket-b", "ticket-d"]; "earliest")]
+    #[test_case(DuplicatePolicy::Latest, vec!["ticket-a", "ticket-d"]; "latest")]
+    #[test_case(DuplicatePolicy::Flag, vec!["ticket-d"]; "flag")]
+    fn duplicates(policy: DuplicatePolicy, expected: Vec<&str>) {
+        let mut drivers = vec![
+            paid("2024-01-01T12:03:00Z", driver(1, "gt3", "a")),
+            paid("2024-01-01T12:01:00Z", driver(1, "gt3", "b")),
+            // Another car in the same class
+            paid("2024-01-01T12:02:00Z", driver(1, "gt3_evo", "c")),
+            paid("2024-01-01T12:04:00Z", driver(1, "gt4", "d")),
+        ];
+        let mut report = Report::default();
+        let left_out = resolve_duplicates(&mut drivers, &[], &classes(), policy, &mut report);
+        assert_eq!(ticket_guids(&drivers), expected);
+        assert_eq!(left_out.len(), 4 - expected.len());
+        assert_eq!(report.problems.len(), 4 - expected.len());
+    }
+
+    #[test_case(DuplicatePolicy::Earliest, vec![]; "earliest")]
+    #[test_case(DuplicatePolicy::Latest, vec!["ticket-b"]; "latest")]
+    fn duplicate_in_other_order(policy: DuplicatePolicy, expected: Vec<&str>) {
+        let mut drivers = vec![paid("2024-01-01T12:02:00Z", driver(1, "gt3", "b"))];
+        let others = [paid("2024-01-01T12:01:00Z", driver(1, "gt3", "a"))];
+        let mut report = Report::default();
+        resolve_duplicates(&mut drivers, &others, &classes(), policy, &mut report);
+        assert_eq!(ticket_guids(&drivers), expected);
+    }
+
+    #[test_case(&[("steam", "1"), ("steam_2", "2"), ("steam_3", "3")], &[], Some((1, vec![2, 3])); "indexed")]
+    #[test_case(&[("steam_3", "3"), ("steam", "1"), ("steam_2", "")], &[], Some((1, vec![3])); "empty field")]
+    #[test_case(&[("steam", "1"), ("steam", "2"), ("steam_2", "1")], &[], Some((1, vec![2])); "repeated")]
+    #[test_case(&[("steam_2", "2")], &[], Some((2, vec![])); "only co-driver")]
+    #[test_case(&[("steam", "x"), ("steam_2", "2")], &[("ticket", 1)], Some((1, vec![2])); "corrected")]
+    #[test_case(&[("steam", "1"), ("steam_2", "x")], &[], None; "bad co-driver")]
+    #[test_case(&[("steam_2", "")], &[], None; "none")]
+    fn team_tickets(
+        steam_ids: &[(&str, &str)],
+        overrides: &[(&str, u64)],
+        expected: Option<(u64, Vec<u64>)>,
+    ) {
+        let metadata_ids = MetaDataIDs {
+            first_name: "first".to_string(),
+            last_name: "last".to_string(),
+            team_name: "team".to_string(),
+            steam_id: "steam".to_string(),
+            co_driver_steam_ids: vec!["steam_2".to_string(), "steam_3".to_string()],
+            nationality: None,
+            skill: None,
+            pace: None,
+        };
+        let policy = TicketPolicy {
+            unmapped: UnmappedTicketPolicy::Skip,
+            max_bad_fraction: 1.0,
+            require_check_in: false,
+            duplicates: DuplicatePolicy::Earliest,
+        };
+        let steam_id_overrides = overrides
+            .iter()
+            .map(|&(ticket_guid, steam_id)| (ticket_guid.to_string(), steam_id))
+            .collect();
+        let tickets = TicketContext {
+            ticket_map: &TicketMap::default(),
+            ticket_names: None,
+            add_on_tickets: &HashSet::new(),
+            metadata_ids: &metadata_ids,
+            policy: &policy,
+            steam_id_overrides: &steam_id_overrides,
+        };
+        let metadata = [("first", Some("Max")), ("last", Some("Power"))]
+            .into_iter()
+            .chain(steam_ids.iter().map(|&(id, value)| (id, Some(value))));
+        let driver = driver_from_metadata(
+            &TicketMapping::new("gt3".to_string()),
+            metadata,
+            &tickets,
+            None,
+            Some("ticket"),
+        );
+        assert_eq!(
+            driver
+                .ok()
+                .map(|driver| (driver.steam_id, driver.co_drivers)),
+            expected
+        );
+    }
+
+    #[test]
+    fn cars_without_class_are_their_own() {
+        let mut drivers = vec![
+            paid("2024-01-01T12:01:00Z", driver(1, "lmp1", "a")),
+            paid("2024-01-01T12:02:00Z", driver(1, "lmp2", "b")),
+        ];
+        let mut report = Report::default();
+        resolve_duplicates(
+            &mut drivers,
+            &[],
+            &classes(),
+            DuplicatePolicy::Earliest,
+            &mut report,
+        );
+        assert_eq!(ticket_guids(&drivers), vec!["ticket-a", "ticket-b"]);
+    }
+
+    #[test]
+    fn add_ons() {
+        let mut drivers = vec![
+            driver(2, "", "order-1"),
+            driver(1, "gt3", "order-1"),
+            driver(3, "gt3", "order-2"),
+            driver(4, "", "order-3"),
+        ];
+        let mut report = Report::default();
+        attach_add_ons(&mut drivers, &mut report);
+        assert_eq!(
+            drivers
+                .iter()
+                .map(|driver| (driver.steam_id, driver.co_drivers.clone()))
+                .collect::<Vec<_>>(),
+            vec![(1, vec![2]), (3, vec![])]
+        );
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].kind, ProblemKind::AddOnWithoutEntry);
+        assert_eq!(report.problems[0].order_guid.as_deref(), Some("order-3"));
+    }
+}
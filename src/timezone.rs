@@ -0,0 +1,140 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, LocalResult, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serializer;
+use std::sync::OnceLock;
+
+/// From TIMEZONE, set once at startup. The server's own time zone if not set.
+static TIMEZONE: OnceLock<Option<Tz>> = OnceLock::new();
+
+fn parse(name: &str) -> Result<Option<Tz>> {
+    if name.is_empty() {
+        return Ok(None);
+    }
+    name.parse()
+        .map(Some)
+        .map_err(|_| anyhow!("TIMEZONE `{}` is not an IANA time zone", name))
+}
+
+/// Read TIMEZONE, for everything below to use from now on
+pub fn init_from_env() -> Result<()> {
+    let timezone = parse(&dotenv::var("TIMEZONE").unwrap_or_default())?;
+    TIMEZONE.get_or_init(|| timezone);
+    Ok(())
+}
+
+fn timezone() -> Option<Tz> {
+    TIMEZONE.get().copied().flatten()
+}
+
+/// The wall clock time where the event is
+pub fn now() -> NaiveDateTime {
+    to_local(Utc::now())
+}
+
+fn to_local(time: DateTime<Utc>) -> NaiveDateTime {
+    match timezone() {
+        Some(timezone) => time.with_timezone(&timezone).naive_local(),
+        None => time.with_timezone(&Local).naive_local(),
+    }
+}
+
+fn from_local_in<T: TimeZone>(timezone: &T, time: NaiveDateTime) -> Result<DateTime<Utc>> {
+    match timezone.from_local_datetime(&time) {
+        LocalResult::Single(time) => Ok(time.with_timezone(&Utc)),
+        // When the clocks go back, take the first
+        LocalResult::Ambiguous(earliest, _) => Ok(earliest.with_timezone(&Utc)),
+        LocalResult::None => Err(anyhow!("{} doesn't exist, the clocks go forward", time)),
+    }
+}
+
+/// A wall clock time where the event is
+pub fn from_local(time: NaiveDateTime) -> Result<DateTime<Utc>> {
+    match timezone() {
+        Some(timezone) => from_local_in(&timezone, time),
+        None => from_local_in(&Local, time),
+    }
+}
+
+fn format_in<T: TimeZone>(timezone: &T, time: DateTime<Utc>) -> String
+where
+    T::Offset: std::fmt::Display,
+{
+    time.with_timezone(timezone)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// RFC3339 in TIMEZONE, or in UTC if not set, to show to people
+pub fn format(time: DateTime<Utc>) -> String {
+    match timezone() {
+        Some(timezone) => format_in(&timezone, time),
+        None => format_in(&Utc, time),
+    }
+}
+
+/// For `#[serde(serialize_with)]`, like [`format`]
+pub fn serialize<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(*time))
+}
+
+/// For `#[serde(serialize_with)]` on an `Option`, like [`format`]
+pub fn serialize_option<S: Serializer>(
+    time: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn naive(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, day)
+            .unwrap()
+            .and_time(chrono::NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn parses() {
+        assert_eq!(parse("").unwrap(), None);
+        assert_eq!(
+            parse("Europe/Amsterdam").unwrap(),
+            Some(chrono_tz::Europe::Amsterdam)
+        );
+        assert!(parse("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn converts_local_times() {
+        let amsterdam = chrono_tz::Europe::Amsterdam;
+        assert_eq!(
+            from_local_in(&amsterdam, naive(30, "19:00"))
+                .unwrap()
+                .to_rfc3339(),
+            "2024-03-30T18:00:00+00:00"
+        );
+        // After the switch to summer time
+        assert_eq!(
+            from_local_in(&amsterdam, naive(31, "19:00"))
+                .unwrap()
+                .to_rfc3339(),
+            "2024-03-31T17:00:00+00:00"
+        );
+        assert!(from_local_in(&amsterdam, naive(31, "02:30")).is_err());
+    }
+
+    #[test]
+    fn formats() {
+        let time = naive(31, "17:00").and_utc();
+        assert_eq!(
+            format_in(&chrono_tz::Europe::Amsterdam, time),
+            "2024-03-31T19:00:00+02:00"
+        );
+        assert_eq!(format_in(&Utc, time), "2024-03-31T17:00:00Z");
+    }
+}
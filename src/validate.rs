@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use std::{collections::HashSet, path::Path};
+
+use crate::{acsm, classes::SkillClasses, content, eventix, s3, sftp, ticket_map::TicketMap};
+
+/// Check that every car in the ticket map is available in some class of the
+/// ACSM file, or in the class the ticket map names.
+pub async fn validate_acsm_file(json_file: &Path, ticket_map: &TicketMap) -> Result<()> {
+    let classes = acsm::class_slots(json_file).await?;
+    let missing_cars = ticket_map
+        .mappings()
+        .filter(|(_, mapping)| {
+            !classes.iter().any(|class| {
+                class.cars.contains(&mapping.car)
+                    && mapping
+                        .class
+                        .as_ref()
+                        .is_none_or(|name| *name == class.name)
+            })
+        })
+        .map(|(ticket, mapping)| match &mapping.class {
+            Some(class) => format!("{} in class {} ({})", mapping.car, class, ticket),
+            None => format!("{} ({})", mapping.car, ticket),
+        })
+        .collect::<Vec<_>>();
+    if !missing_cars.is_empty() {
+        return Err(anyhow!(
+            "Cars not in any class of {}: {}",
+            json_file.display(),
+            missing_cars.join(", ")
+        ));
+    }
+    info!("All mapped cars found in {}", json_file.display());
+    Ok(())
+}
+
+/// Check that every car in the ticket map is installed on the server, to catch
+/// typos that would otherwise only show when placing drivers
+pub fn validate_car_models(ticket_map: &TicketMap, models: &HashSet<String>) -> Result<()> {
+    let missing_cars = ticket_map
+        .mappings()
+        .filter(|(_, mapping)| !models.contains(&mapping.car))
+        .map(
+            |(ticket, mapping)| match content::closest(models, &mapping.car) {
+                Some(model) => format!("{} ({}, did you mean {}?)", mapping.car, ticket, model),
+                None => format!("{} ({})", mapping.car, ticket),
+            },
+        )
+        .collect::<Vec<_>>();
+    if !missing_cars.is_empty() {
+        return Err(anyhow!(
+            "Cars not installed on the server: {}",
+            missing_cars.join(", ")
+        ));
+    }
+    info!("All mapped cars installed on the server");
+    Ok(())
+}
+
+/// Check that all classes drivers can be routed to exist in the ACSM file
+pub async fn validate_classes(
+    json_file: &Path,
+    class_names: impl Iterator<Item = &String>,
+) -> Result<()> {
+    let existing_class_names = acsm::class_names(json_file).await?;
+    let missing_classes = class_names
+        .filter(|class_name| !existing_class_names.contains(class_name))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !missing_classes.is_empty() {
+        return Err(anyhow!(
+            "Classes not in {}: {}",
+            json_file.display(),
+            missing_classes.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Check that drivers of every skill can be routed to a class with the car of
+/// their ticket. Tickets mapped to a class of their own don't need one.
+pub async fn validate_skill_classes(
+    json_file: &Path,
+    skill_classes: &SkillClasses,
+    ticket_map: &TicketMap,
+) -> Result<()> {
+    if !skill_classes.is_enabled() {
+        return Ok(());
+    }
+    let classes = acsm::class_slots(json_file).await?;
+    let cars: Vec<&String> = ticket_map
+        .mappings()
+        .filter(|(_, mapping)| mapping.class.is_none())
+        .map(|(_, mapping)| &mapping.car)
+        .collect();
+    let missing_cars = skill_classes.missing_cars(&classes, cars.into_iter());
+    if !missing_cars.is_empty() {
+        return Err(anyhow!(
+            "Skill classes in {} without the car: {}",
+            json_file.display(),
+            missing_cars.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Check that we can replace the ACSM file. Updates write a temporary file next
+/// to it and rename it into place, so the directory has to be writable too.
+pub async fn check_writable(json_file: &Path) -> Result<()> {
+    if sftp::Location::parse(json_file).is_some() {
+        return sftp::check_writable(json_file).await;
+    }
+    if let Some(object) = s3::Object::parse(json_file) {
+        return object?.check_writable().await;
+    }
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(json_file)
+        .await
+        .with_context(|| format!("{} is not writable", json_file.display()))?;
+    let directory = match json_file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    tempfile::tempfile_in(directory)
+        .with_context(|| format!("Directory {} is not writable", directory.display()))?;
+    Ok(())
+}
+
+/// Check that every ticket GUID in the ticket map exists for the event in
+/// Eventix, and warn about ticket types that are neither mapped nor add-ons.
+pub async fn validate_eventix_tickets(
+    api: eventix::Api<'_>,
+    event_guid: &str,
+    ticket_map: &TicketMap,
+    add_on_ticket_ids: &HashSet<String>,
+) -> Result<()> {
+    let ticket_types = eventix::get_ticket_types(api, event_guid).await?;
+    for ticket_type in &ticket_types {
+        if ticket_map
+            .get(&ticket_type.guid, Some(&ticket_type.name))
+            .is_none()
+            && !add_on_ticket_ids.contains(&ticket_type.guid)
+            && !ticket_map.is_ignored(&ticket_type.guid, Some(&ticket_type.name))
+        {
+            warn!(
+                "Ticket type {} ({}) has no car mapped",
+                ticket_type.guid, ticket_type.name
+            );
+        }
+    }
+    let unknown_tickets = ticket_map
+        .tickets
+        .keys()
+        .filter(|ticket_id| {
+            !ticket_types
+                .iter()
+                .any(|ticket_type| &&ticket_type.guid == ticket_id)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if !unknown_tickets.is_empty() {
+        return Err(anyhow!(
+            "Ticket GUIDs not found in Eventix event {}: {}",
+            event_guid,
+            unknown_tickets.join(", ")
+        ));
+    }
+    info!("All mapped ticket GUIDs found in Eventix");
+    Ok(())
+}
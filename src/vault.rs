@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::time::sleep;
+
+use crate::{
+    config,
+    http::{self, HttpPolicy},
+    redact::Secret,
+    State,
+};
+
+/// Exit code when the secrets changed, for the service manager to restart us
+const EXIT_SECRETS_CHANGED: i32 = 75;
+
+enum Auth {
+    Token(Secret<String>),
+    AppRole {
+        role_id: String,
+        secret_id: Secret<String>,
+    },
+}
+
+/// Where the secret settings come from instead of the environment, enabled by
+/// VAULT_ADDR
+pub struct Vault {
+    address: String,
+    auth: Auth,
+    /// API path of the secret, like `secret/data/eventix2acsm` for KV version 2
+    path: String,
+    /// How often to check for rotated secrets
+    refresh: Option<Duration>,
+    http: HttpPolicy,
+}
+
+fn var(name: &str) -> Option<String> {
+    dotenv::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl Vault {
+    /// From VAULT_ADDR, VAULT_TOKEN or VAULT_ROLE_ID and VAULT_SECRET_ID,
+    /// VAULT_SECRET_PATH and VAULT_REFRESH_SECONDS
+    pub fn from_env() -> Result<Option<Vault>> {
+        let Some(address) = var("VAULT_ADDR") else {
+            return Ok(None);
+        };
+        let auth = match (
+            var("VAULT_TOKEN"),
+            var("VAULT_ROLE_ID"),
+            var("VAULT_SECRET_ID"),
+        ) {
+            (Some(token), None, None) => Auth::Token(Secret::new(token)),
+            (None, Some(role_id), Some(secret_id)) => Auth::AppRole {
+                role_id,
+                secret_id: Secret::new(secret_id),
+            },
+            _ => {
+                return Err(anyhow!(
+                    "VAULT_ADDR needs either VAULT_TOKEN, or VAULT_ROLE_ID and VAULT_SECRET_ID"
+                ))
+            }
+        };
+        let refresh_seconds: u64 = var("VAULT_REFRESH_SECONDS")
+            .unwrap_or_else(|| "300".to_string())
+            .parse()
+            .context("VAULT_REFRESH_SECONDS is not a number")?;
+        Ok(Some(Vault {
+            address: address.trim_end_matches('/').to_string(),
+            auth,
+            path: var("VAULT_SECRET_PATH")
+                .context("VAULT_ADDR is set, but VAULT_SECRET_PATH is not")?
+                .trim_matches('/')
+                .to_string(),
+            refresh: (refresh_seconds > 0).then(|| Duration::from_secs(refresh_seconds)),
+            http: HttpPolicy::from_env("VAULT_HTTP", "Vault", http::proxy_from_env()?.as_ref())?,
+        }))
+    }
+
+    /// A token for this read. AppRole logs in every time, so the token never
+    /// outlives its TTL.
+    async fn token(&self) -> Result<String> {
+        let (role_id, secret_id) = match &self.auth {
+            Auth::Token(token) => return Ok(token.expose().clone()),
+            Auth::AppRole { role_id, secret_id } => (role_id, secret_id),
+        };
+        let response: Value = self
+            .http
+            .send(
+                self.http
+                    .client()
+                    .post(format!("{}/v1/auth/approle/login", self.address))
+                    .json(&json!({"role_id": role_id, "secret_id": secret_id.expose()})),
+            )
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("Vault AppRole login failed")?
+            .json()
+            .await
+            .context("Failed to parse Vault login response")?;
+        Ok(response["auth"]["client_token"]
+            .as_str()
+            .context("No client_token in Vault login response")?
+            .to_string())
+    }
+
+    async fn read(&self) -> Result<HashMap<String, String>> {
+        let response: Value = self
+            .http
+            .send(
+                self.http
+                    .client()
+                    .get(format!("{}/v1/{}", self.address, self.path))
+                    .header("X-Vault-Token", self.token().await?),
+            )
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to read {} from Vault", self.path))?
+            .json()
+            .await
+            .context("Failed to parse Vault response")?;
+        secrets(&response)
+    }
+
+    /// Read the secrets and put them in the environment, where the settings
+    /// are read from. Secrets in Vault can't be set elsewhere too.
+    pub async fn load(&self) -> Result<()> {
+        for (name, value) in self.read().await? {
+            if var(&name).is_some() {
+                return Err(anyhow!("{} is in Vault, don't set it elsewhere too", name));
+            }
+            std::env::set_var(&name, value);
+        }
+        info!("Loaded secrets from Vault at {}", self.path);
+        Ok(())
+    }
+}
+
+/// The secret settings in a KV version 2 or version 1 response
+fn secrets(response: &Value) -> Result<HashMap<String, String>> {
+    let data = match &response["data"]["data"] {
+        Value::Object(data) => data,
+        _ => response["data"]
+            .as_object()
+            .context("No data in Vault response")?,
+    };
+    let mut secrets = HashMap::new();
+    for (name, value) in data {
+        // Vault's own credentials can't come from Vault
+        if !config::is_secret_setting(name) || name.starts_with("VAULT_") {
+            warn!("Ignoring {} from Vault, it's not a secret setting", name);
+            continue;
+        }
+        let value = value
+            .as_str()
+            .with_context(|| format!("{} from Vault is not a string", name))?;
+        secrets.insert(name.clone(), value.to_string());
+    }
+    Ok(secrets)
+}
+
+/// Check Vault for rotated secrets. Most are only read at startup, so when
+/// one changed, exit once no write is in progress and let the service manager
+/// restart us with the new ones.
+pub async fn refresh_task(state: Arc<State>) {
+    let Some(vault) = &state.vault else {
+        return;
+    };
+    let Some(refresh) = vault.refresh else {
+        return;
+    };
+    loop {
+        sleep(refresh).await;
+        let secrets = match vault.read().await {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                // Keep going with the secrets we have
+                error!("Failed to check Vault for rotated secrets: {:?}", e);
+                continue;
+            }
+        };
+        let changed: Vec<_> = secrets
+            .iter()
+            .filter(|(name, value)| std::env::var(name).ok().as_ref() != Some(value))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+        warn!(
+            "Secrets changed in Vault: {}, exiting to restart with them",
+            changed.join(", ")
+        );
+        let _write_gate = state.write_gate.lock().await;
+        std::process::exit(EXIT_SECRETS_CHANGED);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(json!({"data": {"data": {"ADMIN_TOKEN": "a"}, "metadata": {"version": 3}}}); "kv version 2")]
+    #[test_case(json!({"data": {"ADMIN_TOKEN": "a"}}); "kv version 1")]
+    #[test_case(json!({"data": {"ADMIN_TOKEN": "a", "ACSM_JSON_FILE": "x", "VAULT_TOKEN": "t"}}); "not secrets")]
+    fn secrets_from_response(response: Value) {
+        assert_eq!(
+            secrets(&response).unwrap(),
+            HashMap::from([("ADMIN_TOKEN".to_string(), "a".to_string())])
+        );
+    }
+
+    #[test]
+    fn not_a_string() {
+        assert!(secrets(&json!({"data": {"ADMIN_TOKEN": 1}})).is_err());
+    }
+}
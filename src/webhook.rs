@@ -0,0 +1,330 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    body::Bytes,
+    extract,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use axum_macros::debug_handler;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::sync::Arc;
+
+use crate::{receive_order_paid, redact, State, WebhookPayload};
+
+/// Fields that some webhook configurations wrap the payload in
+const ENVELOPE_FIELDS: [&str; 3] = ["payload", "data", "body"];
+
+const SIGNATURE_HEADER: &str = "x-eventix-signature";
+
+/// Payload of Eventix's newer webhooks, which wrap the subject in `data`
+#[derive(Debug, Deserialize)]
+pub struct WebhookV2Payload {
+    /// Unique per delivery
+    pub id: String,
+    /// Dotted, like `order.paid`
+    pub event: String,
+    pub created_at: String,
+    pub data: WebhookV2Data,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookV2Data {
+    pub guid: String,
+}
+
+impl From<WebhookV2Payload> for WebhookPayload {
+    fn from(payload: WebhookV2Payload) -> Self {
+        WebhookPayload {
+            date_time: payload.created_at,
+            event: payload.event.replace('.', "-"),
+            event_key: payload.id,
+            guid: payload.data.guid,
+        }
+    }
+}
+
+/// One webhook, or several in one delivery like after downtime
+#[derive(Debug)]
+enum Delivery {
+    Single(WebhookPayload),
+    Batch(Vec<Value>),
+}
+
+/// Result for one item of a batch delivery
+#[derive(Debug, Serialize)]
+pub struct ItemResult {
+    pub guid: Option<String>,
+    pub status: u16,
+    pub message: String,
+}
+
+/// Parse a legacy webhook body, either JSON or form encoded, possibly wrapped
+/// in an envelope
+fn parse_legacy(content_type: Option<&str>, body: &[u8]) -> Result<Delivery> {
+    let value = if content_type
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"))
+    {
+        Value::Object(
+            url::form_urlencoded::parse(body)
+                .map(|(key, value)| (key.into_owned(), Value::String(value.into_owned())))
+                .collect(),
+        )
+    } else {
+        serde_json::from_slice(body).context("Body is not JSON")?
+    };
+    unwrap_envelope(value)
+}
+
+fn unwrap_envelope(mut value: Value) -> Result<Delivery> {
+    // Envelopes in envelopes happen, but not very deep
+    for _ in 0..4 {
+        if let Value::Array(items) = value {
+            return Ok(Delivery::Batch(items));
+        }
+        if let Ok(payload) = serde_json::from_value(value.clone()) {
+            return Ok(Delivery::Single(payload));
+        }
+        let Some(inner) = ENVELOPE_FIELDS
+            .iter()
+            .find_map(|field| value.get_mut(*field).map(Value::take))
+        else {
+            break;
+        };
+        value = match inner {
+            // Form fields and some envelopes carry the payload as a string
+            Value::String(inner) => {
+                serde_json::from_str(&inner).context("Wrapped payload is not JSON")?
+            }
+            inner => inner,
+        };
+    }
+    Err(anyhow!("Unrecognized webhook payload"))
+}
+
+/// Log what we couldn't make sense of, with credentials masked
+fn log_unrecognized(e: &anyhow::Error, content_type: Option<&str>, body: &[u8]) {
+    let mut logged = serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()));
+    redact::redact_json(&mut logged);
+    warn!(
+        "Ignoring webhook ({:?}, content type {:?}): {}",
+        e, content_type, logged
+    );
+}
+
+/// Run each item through the normal pipeline. Fails as a whole if any item
+/// failed on our side, so it gets redelivered; processing an order twice is
+/// harmless.
+async fn receive_batch(
+    state: &State,
+    items: Vec<Result<WebhookPayload>>,
+) -> (StatusCode, Json<Vec<ItemResult>>) {
+    info!("Received batch of {} webhooks", items.len());
+    let mut results = Vec::new();
+    for item in items {
+        results.push(match item {
+            Ok(payload) => {
+                let guid = payload.guid.clone();
+                match receive_order_paid(state, "eventix", payload).await {
+                    Ok(Html(message)) => ItemResult {
+                        guid: Some(guid),
+                        status: StatusCode::OK.as_u16(),
+                        message: message.to_string(),
+                    },
+                    Err(status) => ItemResult {
+                        guid: Some(guid),
+                        status: status.as_u16(),
+                        message: status.to_string(),
+                    },
+                }
+            }
+            Err(e) => {
+                warn!("Ignoring batch item: {:?}", e);
+                ItemResult {
+                    guid: None,
+                    status: StatusCode::OK.as_u16(),
+                    message: "unrecognized, ignored".to_string(),
+                }
+            }
+        });
+    }
+    let status = if results
+        .iter()
+        .any(|result| result.status >= StatusCode::INTERNAL_SERVER_ERROR.as_u16())
+    {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(results))
+}
+
+#[debug_handler]
+pub async fn handle_order_paid(
+    extract::State(state): extract::State<Arc<State>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    match parse_legacy(content_type, &body) {
+        Ok(Delivery::Single(payload)) => receive_order_paid(&state, "eventix", payload)
+            .await
+            .into_response(),
+        Ok(Delivery::Batch(items)) => {
+            let items = items
+                .into_iter()
+                .map(|item| match unwrap_envelope(item)? {
+                    Delivery::Single(payload) => Ok(payload),
+                    Delivery::Batch(_) => Err(anyhow!("Batch inside a batch")),
+                })
+                .collect();
+            receive_batch(&state, items).await.into_response()
+        }
+        Err(e) => {
+            // Acknowledge, erroring only makes Eventix redeliver the same thing
+            log_unrecognized(&e, content_type, &body);
+            Html("unrecognized, ignored").into_response()
+        }
+    }
+}
+
+/// Check the `X-Eventix-Signature: sha256=<hex>` header, an HMAC-SHA256 of the
+/// raw body with the webhook secret
+fn verify_signature(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let Some(signature) = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.strip_prefix("sha256=").unwrap_or(value))
+        .and_then(|value| hex::decode(value).ok())
+    else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[debug_handler]
+pub async fn handle_order_paid_v2(
+    extract::State(state): extract::State<Arc<State>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    let Some(secret) = &state.webhook_secret else {
+        warn!("Received v2 webhook, but EVENTIX_WEBHOOK_SECRET is not set");
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    if !verify_signature(secret.expose(), &headers, &body) {
+        warn!("Rejected v2 webhook with missing or bad signature");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let payload: Value = serde_json::from_slice(&body).map_err(|e| {
+        warn!("Bad v2 webhook payload: {:?}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+    if let Value::Array(items) = payload {
+        let items = items
+            .into_iter()
+            .map(|item| {
+                let payload: WebhookV2Payload = serde_json::from_value(item)?;
+                Ok(payload.into())
+            })
+            .collect();
+        return Ok(receive_batch(&state, items).await.into_response());
+    }
+    let payload: WebhookV2Payload = serde_json::from_value(payload).map_err(|e| {
+        warn!("Bad v2 webhook payload: {:?}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
+    debug!("v2 webhook {} {}", payload.event, payload.id);
+    Ok(receive_order_paid(&state, "eventix", payload.into())
+        .await
+        .into_response())
+}
+
+/// At `/<source>/webhook/v1`, for sources whose webhooks just name an order
+pub async fn handle_source_webhook(
+    state: Arc<State>,
+    source: &'static str,
+    payload: Value,
+) -> Result<Html<&'static str>, StatusCode> {
+    let Some(registration_source) = state.source(source) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    match registration_source.parse_webhook(payload) {
+        Ok(Some(payload)) => receive_order_paid(&state, source, payload).await,
+        Ok(None) => Ok(Html("ignored")),
+        Err(e) => {
+            warn!("Bad {} webhook: {:?}", source, e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn signed(secret: &str, body: &[u8]) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SIGNATURE_HEADER,
+            format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+                .parse()
+                .unwrap(),
+        );
+        headers
+    }
+
+    #[test_case("secret", b"{}", true; "good")]
+    #[test_case("other", b"{}", false; "wrong secret")]
+    #[test_case("secret", b"{ }", false; "tampered body")]
+    fn signature(signing_secret: &str, body: &[u8], expected: bool) {
+        let headers = signed(signing_secret, b"{}");
+        assert_eq!(verify_signature("secret", &headers, body), expected);
+    }
+
+    #[test_case(None, r#"{"dateTime":"d","event":"order-paid","eventKey":"k","guid":"g"}"#; "plain")]
+    #[test_case(None, r#"{"payload":{"dateTime":"d","event":"order-paid","eventKey":"k","guid":"g"}}"#; "envelope")]
+    #[test_case(None, r#"{"data":{"body":"{\"dateTime\":\"d\",\"event\":\"order-paid\",\"eventKey\":\"k\",\"guid\":\"g\"}"}}"#; "nested string envelope")]
+    #[test_case(Some("application/x-www-form-urlencoded"), "dateTime=d&event=order-paid&eventKey=k&guid=g"; "form")]
+    #[test_case(Some("application/x-www-form-urlencoded; charset=utf-8"), "payload=%7B%22dateTime%22%3A%22d%22%2C%22event%22%3A%22order-paid%22%2C%22eventKey%22%3A%22k%22%2C%22guid%22%3A%22g%22%7D"; "form envelope")]
+    fn legacy_payload(content_type: Option<&str>, body: &str) {
+        let Delivery::Single(payload) = parse_legacy(content_type, body.as_bytes()).unwrap() else {
+            panic!("Not a single payload");
+        };
+        assert_eq!(payload.guid, "g");
+        assert_eq!(payload.event, "order-paid");
+    }
+
+    #[test_case(r#"[{"guid":"g1"},{"guid":"g2"}]"#; "plain")]
+    #[test_case(r#"{"data":[{"guid":"g1"},{"guid":"g2"}]}"#; "envelope")]
+    fn batch_payload(body: &str) {
+        let Delivery::Batch(items) = parse_legacy(None, body.as_bytes()).unwrap() else {
+            panic!("Not a batch");
+        };
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test_case(None, "not json"; "garbage")]
+    #[test_case(None, r#"{"something":"else"}"#; "unknown shape")]
+    fn unrecognized_legacy_payload(content_type: Option<&str>, body: &str) {
+        assert!(parse_legacy(content_type, body.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn missing_signature() {
+        assert!(!verify_signature("secret", &HeaderMap::new(), b"{}"));
+    }
+}
@@ -0,0 +1,507 @@
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use log::error;
+use oauth2::{
+    basic::BasicClient, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, RedirectUrl,
+    TokenResponse, TokenUrl,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+    config, eventix,
+    http::{self, HttpPolicies},
+    oauth2::{local_callback_address, OAuth2CallbackParameters},
+    portal::escape,
+    redact::Secret,
+    setup::{self, METADATA_SETTINGS},
+    store::Store,
+    ticket_map::{TicketMap, TicketMapping},
+};
+
+/// The browser setup, shared by its pages
+struct Wizard {
+    http: HttpPolicies,
+    store: Mutex<Store>,
+    address: SocketAddr,
+    env_file: PathBuf,
+    progress: Mutex<Progress>,
+    done: mpsc::Sender<()>,
+}
+
+/// What's been filled in so far
+#[derive(Default)]
+struct Progress {
+    settings: Vec<(String, String)>,
+    client: Option<BasicClient>,
+    csrf_token: Option<String>,
+    token: Option<Secret<String>>,
+    event_guid: Option<String>,
+    /// Every car once, with the class it's in
+    cars: Vec<(String, String)>,
+    ticket_types: Vec<eventix::TicketType>,
+    fields: Vec<eventix::MetadataField>,
+    /// Shown on the next page, after something went wrong
+    message: Option<String>,
+}
+
+impl Progress {
+    fn set(&mut self, name: &str, value: String) {
+        self.settings.retain(|(known, _)| known != name);
+        self.settings.push((name.to_string(), value));
+    }
+
+    /// Show the message on the first page, where the organizer can try again
+    fn fail(&mut self, message: String) -> Response {
+        self.message = Some(message);
+        Redirect::to("/").into_response()
+    }
+}
+
+/// Started without ACSM_JSON_FILE or CALENDAR_FILE, with Eventix as the ticket
+/// source, which is what the browser setup does
+pub fn is_needed(acsm_json_files: &[PathBuf]) -> bool {
+    acsm_json_files.is_empty()
+        && dotenv::var("CALENDAR_FILE").map_or(true, |path| path.is_empty())
+        && dotenv::var("TICKET_SOURCE")
+            .map_or(true, |source| source.is_empty() || source == "eventix")
+}
+
+/// Serve the setup pages on OAUTH2_LOCAL_CALLBACK_ADDRESS until the settings
+/// are written
+pub async fn run() -> Result<()> {
+    let address = local_callback_address()?;
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to listen on {}", address))?;
+    let (done, mut finished) = mpsc::channel(1);
+    let wizard = Arc::new(Wizard {
+        http: HttpPolicies::from_env()?,
+        store: Mutex::new(Store::from_env().await?),
+        address: listener.local_addr()?,
+        env_file: setup::env_file(),
+        progress: Mutex::new(Progress::default()),
+        done,
+    });
+    let app = Router::new()
+        .route("/", get(handle_page))
+        .route("/setup/v1/credentials", post(handle_credentials))
+        .route("/eventix/oauth2/v1/callback", get(handle_callback))
+        .route("/setup/v1/event", post(handle_event))
+        .route("/setup/v1/tickets", post(handle_tickets))
+        .with_state(wizard.clone());
+    let server = tokio::spawn(async move { axum::serve(listener, app).await });
+    println!(
+        "Not set up yet. Browse to http://{}/ to set up.",
+        wizard.address
+    );
+    finished.recv().await.context("Setup server stopped")?;
+    // Give the last page time to get to the browser
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    server.abort();
+    println!("Setup done, start eventix2acsm again to sync");
+    Ok(())
+}
+
+fn page(body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width\">\
+        <title>eventix2acsm setup</title></head>\n<body>\n<h1>eventix2acsm setup</h1>\n{}\n\
+        </body></html>\n",
+        body
+    ))
+}
+
+fn message_html(message: Option<String>) -> String {
+    message
+        .map(|message| format!("<p><strong>{}</strong></p>\n", escape(&message)))
+        .unwrap_or_default()
+}
+
+/// `FIRST_NAME` as `First name`
+fn label(setting: &str) -> String {
+    let text = setting.replace('_', " ").to_lowercase();
+    let mut chars = text.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn option(value: &str, text: &str, selected: bool) -> String {
+    format!(
+        "<option value=\"{}\"{}>{}</option>",
+        escape(value),
+        if selected { " selected" } else { "" },
+        escape(text)
+    )
+}
+
+/// The step the organizer is at
+async fn handle_page(extract::State(wizard): extract::State<Arc<Wizard>>) -> Html<String> {
+    let mut progress = wizard.progress.lock().await;
+    let message = message_html(progress.message.take());
+    let Some(token) = &progress.token else {
+        return page(&format!(
+            "<h2>1. Eventix</h2>\n{}\
+            <p>Ask Eventix for an OAuth2 client with the redirect URL \
+            <code>http://{}/eventix/oauth2/v1/callback</code>, and fill in what you get. \
+            You'll then log in to Eventix to allow access to your events.</p>\n\
+            <form action=\"/setup/v1/credentials\" method=\"post\">\
+            <p><label>Client ID <input name=\"client_id\" required value=\"{}\"></label></p>\
+            <p><label>Client secret <input name=\"client_secret\" type=\"password\" \
+            required></label></p>\
+            <button>Log in to Eventix</button></form>",
+            message,
+            wizard.address,
+            escape(&dotenv::var("EVENTIX_OAUTH2_CLIENT_ID").unwrap_or_default())
+        ));
+    };
+    if progress.event_guid.is_none() {
+        let api = eventix::Api {
+            http: &wizard.http.eventix,
+            token: token.expose(),
+        };
+        let event = match eventix::get_events(api).await {
+            Ok(events) if !events.is_empty() => format!(
+                "<select name=\"event_guid\">{}</select>",
+                events
+                    .iter()
+                    .map(|event| option(&event.guid, &event.name, false))
+                    .collect::<String>()
+            ),
+            result => {
+                if let Err(e) = result {
+                    error!("Failed to get the events: {:?}", e);
+                }
+                "<input name=\"event_guid\" required placeholder=\"Event GUID\">".to_string()
+            }
+        };
+        return page(&format!(
+            "<h2>2. Event and entry list</h2>\n{}\
+            <form action=\"/setup/v1/event\" method=\"post\">\
+            <p><label>Event {}</label></p>\
+            <p><label>Championship JSON file in ACSM, where the drivers go \
+            <input name=\"acsm_json_file\" required size=\"60\" value=\"{}\"></label><br>\
+            Separate them with commas to fill several.</p>\
+            <button>Next</button></form>",
+            message,
+            event,
+            escape(&dotenv::var("ACSM_JSON_FILE").unwrap_or_default())
+        ));
+    }
+    let cars: String = progress
+        .cars
+        .iter()
+        .map(|(car, class)| option(car, &format!("{} ({})", car, class), false))
+        .collect();
+    let tickets: String = progress
+        .ticket_types
+        .iter()
+        .map(|ticket_type| {
+            format!(
+                "<p><label>{} <select name=\"ticket-{}\">{}{}{}</select></label></p>",
+                escape(&ticket_type.name),
+                escape(&ticket_type.guid),
+                option("", "Not for drivers", false),
+                option("add-on", "Extra driver for the buyer's entry", false),
+                cars
+            )
+        })
+        .collect();
+    let fields: String = METADATA_SETTINGS
+        .iter()
+        .map(|(setting, word, required)| {
+            let guess = setup::guess_field(&progress.fields, word);
+            let none = if *required {
+                String::new()
+            } else {
+                option("", "None", guess.is_none())
+            };
+            format!(
+                "<p><label>{} <select name=\"field-{}\">{}{}</select></label></p>",
+                label(setting),
+                setting,
+                none,
+                progress
+                    .fields
+                    .iter()
+                    .enumerate()
+                    .map(|(index, field)| option(&field.guid, &field.name, guess == Some(index)))
+                    .collect::<String>()
+            )
+        })
+        .collect();
+    page(&format!(
+        "<h2>3. Tickets</h2>\n{}\
+        <form action=\"/setup/v1/tickets\" method=\"post\">\
+        <p>Which car goes with each ticket type?</p>\n{}\
+        <p>Which questions on the ticket hold the driver's details?</p>\n{}\
+        <button>Save</button></form>",
+        message, tickets, fields
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    client_id: String,
+    client_secret: String,
+}
+
+/// A setting, or else its default from `.env-template`
+fn setting_or_default(name: &str) -> String {
+    dotenv::var(name)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .or_else(|| config::template_value(name).map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn oauth2_client(address: SocketAddr, credentials: &Credentials) -> Result<BasicClient> {
+    Ok(BasicClient::new(
+        ClientId::new(credentials.client_id.trim().to_string()),
+        Some(ClientSecret::new(
+            credentials.client_secret.trim().to_string(),
+        )),
+        AuthUrl::new(setting_or_default("EVENTIX_OAUTH2_AUTH_URL"))
+            .context("Invalid EVENTIX_OAUTH2_AUTH_URL")?,
+        Some(
+            TokenUrl::new(setting_or_default("EVENTIX_OAUTH2_TOKEN_URL"))
+                .context("Invalid EVENTIX_OAUTH2_TOKEN_URL")?,
+        ),
+    )
+    .set_redirect_uri(
+        RedirectUrl::new(format!("http://{}/eventix/oauth2/v1/callback", address))
+            .context("Failed to create OAuth2 RedirectURL")?,
+    ))
+}
+
+/// Off to Eventix to log in
+async fn handle_credentials(
+    extract::State(wizard): extract::State<Arc<Wizard>>,
+    extract::Form(credentials): extract::Form<Credentials>,
+) -> Response {
+    let mut progress = wizard.progress.lock().await;
+    let client = match oauth2_client(wizard.address, &credentials) {
+        Ok(client) => client,
+        Err(e) => return progress.fail(format!("{:#}", e)),
+    };
+    let (url, csrf_token) = client.authorize_url(CsrfToken::new_random).url();
+    progress.client = Some(client);
+    progress.csrf_token = Some(csrf_token.secret().clone());
+    progress.set("EVENTIX_OAUTH2_CLIENT_ID", credentials.client_id);
+    progress.set("EVENTIX_OAUTH2_CLIENT_SECRET", credentials.client_secret);
+    Redirect::to(url.as_str()).into_response()
+}
+
+/// Back from Eventix, keep the token for the next steps and the refresh token
+/// for when it runs for real
+async fn handle_callback(
+    extract::State(wizard): extract::State<Arc<Wizard>>,
+    extract::Query(query): extract::Query<OAuth2CallbackParameters>,
+) -> Response {
+    let mut progress = wizard.progress.lock().await;
+    let client = match (&progress.client, &progress.csrf_token) {
+        (Some(client), Some(csrf_token)) if *csrf_token == query.state => client.clone(),
+        _ => return progress.fail("That login has expired, try again".to_string()),
+    };
+    let token_result = client
+        .exchange_code(AuthorizationCode::new(query.code))
+        .request_async(|request| http::oauth2_request(&wizard.http.oauth2, request))
+        .await;
+    let token_result = match token_result {
+        Ok(token_result) => token_result,
+        Err(e) => return progress.fail(format!("Eventix didn't give a token: {}", e)),
+    };
+    if let Some(refresh_token) = token_result.refresh_token() {
+        let result = wizard
+            .store
+            .lock()
+            .await
+            .set_refresh_token("eventix", Secret::new(refresh_token.secret().clone()))
+            .await;
+        if let Err(e) = result {
+            return progress.fail(format!("Failed to store the refresh token: {:#}", e));
+        }
+    }
+    progress.csrf_token = None;
+    progress.token = Some(Secret::new(token_result.access_token().secret().clone()));
+    Redirect::to("/").into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct EventChoice {
+    event_guid: String,
+    acsm_json_file: String,
+}
+
+/// Read the cars from the ACSM files and the ticket types from Eventix
+async fn handle_event(
+    extract::State(wizard): extract::State<Arc<Wizard>>,
+    extract::Form(choice): extract::Form<EventChoice>,
+) -> Response {
+    let mut progress = wizard.progress.lock().await;
+    let Some(token) = progress.token.clone() else {
+        return Redirect::to("/").into_response();
+    };
+    let event_guid = choice.event_guid.trim().to_string();
+    let json_files = choice
+        .acsm_json_file
+        .split(',')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(",");
+    let cars = match setup::cars(&json_files).await {
+        Ok(cars) => cars,
+        Err(e) => return progress.fail(format!("{:#}", e)),
+    };
+    let api = eventix::Api {
+        http: &wizard.http.eventix,
+        token: token.expose(),
+    };
+    let ticket_types = match eventix::get_ticket_types(api, &event_guid).await {
+        Ok(ticket_types) if !ticket_types.is_empty() => ticket_types,
+        Ok(_) => return progress.fail("The event has no ticket types".to_string()),
+        Err(e) => return progress.fail(format!("{:#}", e)),
+    };
+    let fields = match setup::metadata_fields(api, &ticket_types).await {
+        Ok(fields) => fields,
+        Err(e) => return progress.fail(format!("{:#}", e)),
+    };
+    progress.set("EVENTIX_EVENT_GUID", event_guid.clone());
+    progress.set("ACSM_JSON_FILE", json_files);
+    progress.event_guid = Some(event_guid);
+    progress.cars = cars;
+    progress.ticket_types = ticket_types;
+    progress.fields = fields;
+    Redirect::to("/").into_response()
+}
+
+/// The ticket map, add-on tickets and metadata settings from the tickets form
+fn ticket_choices(
+    form: &HashMap<String, String>,
+    progress: &Progress,
+) -> Result<(TicketMap, Vec<(String, String)>)> {
+    let mut ticket_map = TicketMap::default();
+    let mut add_ons = Vec::new();
+    for ticket_type in &progress.ticket_types {
+        let choice = form
+            .get(&format!("ticket-{}", ticket_type.guid))
+            .map(String::as_str)
+            .unwrap_or_default();
+        if choice == "add-on" {
+            add_ons.push(ticket_type.guid.clone());
+        } else if progress.cars.iter().any(|(car, _)| car == choice) {
+            ticket_map.tickets.insert(
+                ticket_type.guid.clone(),
+                TicketMapping::new(choice.to_string()),
+            );
+        }
+    }
+    if ticket_map.tickets.is_empty() {
+        return Err(anyhow!("Pick a car for at least one ticket type"));
+    }
+    let mut settings = vec![("ADD_ON_TICKET_IDS".to_string(), add_ons.join(","))];
+    for (setting, _, required) in METADATA_SETTINGS {
+        let field = form
+            .get(&format!("field-{}", setting))
+            .filter(|guid| progress.fields.iter().any(|field| field.guid == **guid));
+        if required && field.is_none() {
+            return Err(anyhow!("Pick the question for {}", label(setting)));
+        }
+        settings.push((
+            format!("EVENTIX_METADATA_{}", setting),
+            field.cloned().unwrap_or_default(),
+        ));
+    }
+    Ok((ticket_map, settings))
+}
+
+/// Write it all out and stop
+async fn handle_tickets(
+    extract::State(wizard): extract::State<Arc<Wizard>>,
+    extract::Form(form): extract::Form<HashMap<String, String>>,
+) -> Response {
+    let mut progress = wizard.progress.lock().await;
+    if progress.event_guid.is_none() {
+        return Redirect::to("/").into_response();
+    }
+    let (ticket_map, settings) = match ticket_choices(&form, &progress) {
+        Ok(choices) => choices,
+        Err(e) => return progress.fail(format!("{:#}", e)),
+    };
+    for (name, value) in settings {
+        progress.set(&name, value);
+    }
+    if let Err(e) = setup::save(&wizard.env_file, progress.settings.clone(), &ticket_map).await {
+        return progress.fail(format!("{:#}", e));
+    }
+    let _ = wizard.done.send(()).await;
+    page(&format!(
+        "<h2>Done</h2>\n<p>The settings are in <code>{}</code>. Start eventix2acsm again to \
+        fill the grid. Everything else can be changed in that file too.</p>",
+        escape(&wizard.env_file.display().to_string())
+    ))
+    .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        assert_eq!(label("FIRST_NAME"), "First name");
+        assert_eq!(label("PACE"), "Pace");
+    }
+
+    #[test]
+    fn choices() {
+        let progress = Progress {
+            cars: vec![("bmw_m6_gt3".to_string(), "GT3".to_string())],
+            ticket_types: ["entry", "extra", "merch"]
+                .into_iter()
+                .map(|guid| eventix::TicketType {
+                    guid: guid.to_string(),
+                    name: guid.to_string(),
+                })
+                .collect(),
+            fields: ["first", "last", "team", "steam"]
+                .into_iter()
+                .map(|guid| eventix::MetadataField {
+                    guid: guid.to_string(),
+                    name: guid.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut form: HashMap<String, String> = [
+            ("ticket-entry", "bmw_m6_gt3"),
+            ("ticket-extra", "add-on"),
+            ("ticket-merch", ""),
+            ("field-FIRST_NAME", "first"),
+            ("field-LAST_NAME", "last"),
+            ("field-TEAM_NAME", "team"),
+            ("field-STEAM_ID", "steam"),
+            ("field-PACE", ""),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let (ticket_map, settings) = ticket_choices(&form, &progress).unwrap();
+        assert_eq!(ticket_map.get("entry", None).unwrap().car, "bmw_m6_gt3");
+        assert!(ticket_map.get("merch", None).is_none());
+        assert!(settings.contains(&("ADD_ON_TICKET_IDS".to_string(), "extra".to_string())));
+        assert!(settings.contains(&("EVENTIX_METADATA_STEAM_ID".to_string(), "steam".to_string())));
+        assert!(settings.contains(&("EVENTIX_METADATA_PACE".to_string(), String::new())));
+        form.remove("field-STEAM_ID");
+        assert!(ticket_choices(&form, &progress).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use axum::{extract, http::StatusCode, response::Html};
+use axum_macros::debug_handler;
+use log::{error, info};
+use std::sync::Arc;
+
+use crate::{
+    acsm::{self, BasicDriver},
+    reload,
+    report::Report,
+    State,
+};
+
+/// Whether writes to the ACSM files are allowed right now, and what came in
+/// while they weren't
+#[derive(Debug, Default)]
+pub struct WriteGate {
+    /// Through the admin API or `--paused`
+    pub paused: bool,
+    /// An ACSM session is running, and changing its files makes ACSM reload
+    pub session_live: bool,
+    /// In one of the MAINTENANCE_WINDOWS
+    pub maintenance: bool,
+    /// Started by a handover, until the previous process exits
+    pub handover: bool,
+    /// After EVENT_END and the final sync, for good
+    pub archived: bool,
+    /// Drivers from webhooks and the admin API, to add once writes resume
+    pub queued_drivers: Vec<BasicDriver>,
+    /// Steam IDs ignored at runtime, to take off the grid once writes resume
+    pub queued_removals: Vec<u64>,
+    /// A full update was skipped, so run one once writes resume
+    pub full_update_queued: bool,
+    /// The entry list changed during a session, so reload ACSM after it
+    pub reload_queued: bool,
+}
+
+impl WriteGate {
+    pub fn is_held(&self) -> bool {
+        self.paused || self.session_live || self.maintenance || self.handover || self.archived
+    }
+
+    /// Queue the drivers if writes are held back, returning whether they were
+    pub fn queue_if_held(&mut self, drivers: &[BasicDriver], full_update: bool) -> bool {
+        if !self.is_held() {
+            return false;
+        }
+        if self.archived {
+            info!("Event archived, not writing");
+            return true;
+        }
+        if full_update {
+            info!("Writes held back, queueing full update");
+            self.full_update_queued = true;
+        } else {
+            info!("Writes held back, queueing {} drivers", drivers.len());
+            self.queued_drivers.extend(drivers.iter().cloned());
+        }
+        true
+    }
+
+    /// Queue taking the driver off the grid if writes are held back,
+    /// returning whether it was
+    pub fn queue_removal_if_held(&mut self, steam_id: u64) -> bool {
+        if !self.is_held() {
+            return false;
+        }
+        if self.archived {
+            info!("Event archived, not writing");
+        } else {
+            info!(
+                "Writes held back, queueing removal of steam_id={}",
+                steam_id
+            );
+            self.queued_removals.push(steam_id);
+        }
+        true
+    }
+}
+
+/// Take the driver off every ACSM file, returning whether they were on one.
+/// Only while holding the write gate, with writes not held back.
+pub async fn remove_driver(
+    state: &State,
+    write_gate: &mut WriteGate,
+    steam_id: u64,
+) -> anyhow::Result<bool> {
+    let mut removed = false;
+    for json_file in state.acsm_json_files.lock().await.iter() {
+        removed |= acsm::remove_driver(json_file, steam_id).await?;
+    }
+    if removed {
+        reload::after_change(state, write_gate).await;
+    }
+    Ok(removed)
+}
+
+#[debug_handler]
+pub async fn handle_pause(extract::State(state): extract::State<Arc<State>>) -> Html<&'static str> {
+    // Waits for a write in progress, so nothing gets written after this returns
+    state.write_gate.lock().await.paused = true;
+    info!("Writes paused");
+    Html("writes paused")
+}
+
+#[debug_handler]
+pub async fn handle_resume(
+    extract::State(state): extract::State<Arc<State>>,
+) -> Result<Html<&'static str>, StatusCode> {
+    state.write_gate.lock().await.paused = false;
+    info!("Writes resumed");
+    release(state).await.map_err(|e| {
+        error!("Failed to apply queued changes: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Html("writes resumed"))
+}
+
+/// Apply the queued changes, unless writes are still held back for another
+/// reason
+pub async fn release(state: Arc<State>) -> anyhow::Result<()> {
+    let mut write_gate = state.write_gate.lock().await;
+    if write_gate.is_held() {
+        return Ok(());
+    }
+    for steam_id in std::mem::take(&mut write_gate.queued_removals) {
+        if let Err(e) = remove_driver(&state, &mut write_gate, steam_id).await {
+            error!("Failed to remove steam_id={}: {:?}", steam_id, e);
+        }
+    }
+    let queued_drivers = std::mem::take(&mut write_gate.queued_drivers);
+    let full_update_queued = std::mem::take(&mut write_gate.full_update_queued);
+    drop(write_gate);
+    flush(state, queued_drivers, full_update_queued).await
+}
+
+/// Apply what was queued. A full update covers everything, so then the queued
+/// drivers don't matter.
+async fn flush(
+    state: Arc<State>,
+    queued_drivers: Vec<BasicDriver>,
+    full_update_queued: bool,
+) -> anyhow::Result<()> {
+    if full_update_queued {
+        crate::full_update(state).await
+    } else if !queued_drivers.is_empty() {
+        let mut report = Report::default();
+        let result = state
+            .place_drivers(&queued_drivers, false, &mut report)
+            .await;
+        report.log();
+        result.map(|_| ())
+    } else {
+        Ok(())
+    }
+}